@@ -1,12 +1,424 @@
+use serde::Deserialize;
+use thiserror::Error;
+
 use crate::container::{Container, ReadFileError};
 
 use super::markdown::strip_wrapping_markdown_code_fences;
+use super::protected::ProtectedPaths;
+use super::secrets::Redactor;
 
 pub async fn read_file(container: &Container, filename: &str) -> Result<String, ReadFileError> {
     container.read_file(filename).await
 }
 
-pub async fn write_file(container: &Container, filename: &str, content: &str) {
+#[derive(Error, Debug)]
+pub enum WriteFileError {
+    #[error("The path is protected")]
+    Protected,
+    #[error("{0}")]
+    Other(String),
+}
+
+/// Write a file, redacting any secrets that were not part of the `original` content
+///
+/// The final newline and the line endings of the `original` content are preserved, see
+/// [`prepare_content`]. Writing to protected paths is rejected.
+pub async fn write_file(
+    container: &Container,
+    redactor: &Redactor,
+    protected_paths: &ProtectedPaths,
+    line_endings: LineEndingMode,
+    filename: &str,
+    content: &str,
+    original: &str,
+) -> Result<(), WriteFileError> {
+    if protected_paths.is_protected(filename) {
+        log::warn!("Rejected write to protected path `{}`", filename);
+        return Err(WriteFileError::Protected);
+    }
+    let content = prepare_content(redactor, line_endings, content, original);
+    container.write_file(filename, &content).await.map_err(WriteFileError::Other)
+}
+
+/// Write several files at once, redacting any secrets that were not part of the `originals`
+///
+/// Either all files are written, or none. If any of the paths is protected, none is written.
+pub async fn write_files(
+    container: &Container,
+    redactor: &Redactor,
+    protected_paths: &ProtectedPaths,
+    line_endings: LineEndingMode,
+    files: &[(String, String)],
+    originals: &[String],
+) -> Result<(), WriteFileError> {
+    if let Some((filename, _)) = files.iter().find(|(f, _)| protected_paths.is_protected(f)) {
+        log::warn!("Rejected write to protected path `{}`", filename);
+        return Err(WriteFileError::Protected);
+    }
+    let contents: Vec<String> = files
+        .iter()
+        .zip(originals)
+        .map(|((_, content), original)| prepare_content(redactor, line_endings, content, original))
+        .collect();
+    let files: Vec<(&str, &str)> =
+        files.iter().zip(&contents).map(|((f, _), c)| (f.as_str(), c.as_str())).collect();
+    container.write_files(&files).await.map_err(WriteFileError::Other)
+}
+
+/// Turn the content provided by the model into the content that is written over `original`
+///
+/// Wrapping code fences are stripped, secrets that were not part of the `original` content are
+/// redacted and the final newline and line endings are made consistent with the `original`.
+pub fn prepare_content(
+    redactor: &Redactor,
+    line_endings: LineEndingMode,
+    content: &str,
+    original: &str,
+) -> String {
     let content = strip_wrapping_markdown_code_fences(content);
-    container.write_file(filename, &content).await.unwrap()
+    let content = redactor.redact_except(&content, original);
+    let content = apply_final_newline(&content, original);
+    match line_endings {
+        LineEndingMode::Auto => LineEnding::detect(original).unwrap_or_default().apply(&content),
+        LineEndingMode::Lf => LineEnding::Lf.apply(&content),
+        LineEndingMode::Crlf => LineEnding::CrLf.apply(&content),
+        LineEndingMode::Keep => content,
+    }
+}
+
+/// How the line endings of written files are chosen
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum LineEndingMode {
+    /// Use the predominant line ending of the original file, `\n` for new files
+    #[default]
+    Auto,
+    /// Always use `\n`
+    Lf,
+    /// Always use `\r\n`
+    Crlf,
+    /// Write the line endings produced by the model as they are
+    Keep,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LineEnding {
+    #[default]
+    Lf,
+    CrLf,
+}
+
+impl LineEnding {
+    /// The predominant line ending of the content, or `None` if it consists of a single line
+    pub fn detect(content: &str) -> Option<Self> {
+        let lines = content.matches('\n').count();
+        let crlf = content.matches("\r\n").count();
+        match lines {
+            0 => None,
+            _ if crlf * 2 > lines => Some(Self::CrLf),
+            _ => Some(Self::Lf),
+        }
+    }
+
+    /// Convert all line endings of the content to this line ending
+    pub fn apply(self, content: &str) -> String {
+        let content = content.replace("\r\n", "\n");
+        match self {
+            Self::Lf => content,
+            Self::CrLf => content.replace('\n', "\r\n"),
+        }
+    }
+}
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum ReplaceError {
+    #[error("The search string was not found in the file")]
+    NotFound,
+    #[error("The search string was found {0} times in the file, but it must match exactly once")]
+    Ambiguous(usize),
+}
+
+/// Replace the only occurrence of `search` in `content`
+pub fn replace_unique(content: &str, search: &str, replace: &str) -> Result<String, ReplaceError> {
+    if search.is_empty() {
+        return Err(ReplaceError::NotFound);
+    }
+    match content.matches(search).count() {
+        0 => Err(ReplaceError::NotFound),
+        1 => Ok(content.replacen(search, replace, 1)),
+        count => Err(ReplaceError::Ambiguous(count)),
+    }
+}
+
+const SEARCH_MARKER: &str = "<<<<<<< SEARCH";
+const DIVIDER_MARKER: &str = "=======";
+const REPLACE_MARKER: &str = ">>>>>>> REPLACE";
+
+/// Parse a search/replace block in the format of the `replace-in-file` action
+///
+/// ```text
+/// <<<<<<< SEARCH
+/// old lines
+/// =======
+/// new lines
+/// >>>>>>> REPLACE
+/// ```
+pub fn parse_search_replace(message: &str) -> Result<(String, String), String> {
+    let mut lines = message.lines().skip_while(|line| line.trim_end() != SEARCH_MARKER);
+    if lines.next().is_none() {
+        return Err(format!("The `{}` line is missing", SEARCH_MARKER));
+    }
+    let mut section = |marker: &str| {
+        let mut section = Vec::new();
+        loop {
+            match lines.next() {
+                Some(line) if line.trim_end() == marker => break Ok(section.join("\n")),
+                Some(line) => section.push(line),
+                None => break Err(format!("The `{}` line is missing", marker)),
+            }
+        }
+    };
+    let search = section(DIVIDER_MARKER)?;
+    let replace = section(REPLACE_MARKER)?;
+    Ok((search, replace))
+}
+
+/// The header line of a line range edit, e.g. `LINES 12-20`
+const LINE_RANGE_PREFIX: &str = "LINES ";
+
+/// Parse a line range edit, i.e. a `LINES <start>-<end>` line followed by the replacement
+///
+/// Returns `None` if the message does not start with such a line, i.e. if it is a whole file.
+pub fn parse_line_range_edit(message: &str) -> Option<(usize, usize, &str)> {
+    let (header, replacement) = message.split_once('\n').unwrap_or((message, ""));
+    let (start, end) = header.trim().strip_prefix(LINE_RANGE_PREFIX)?.split_once('-')?;
+    Some((start.trim().parse().ok()?, end.trim().parse().ok()?, replacement))
+}
+
+/// Replace the lines `start` to `end` (inclusive, starting at 1) of the content
+///
+/// An empty replacement removes the lines. The lines of the result end with `\n`.
+pub fn splice_lines(
+    content: &str,
+    start: usize,
+    end: usize,
+    replacement: &str,
+) -> Result<String, String> {
+    let content = content.replace("\r\n", "\n");
+    let mut lines: Vec<&str> = content.lines().collect();
+    if start == 0 || start > end || end > lines.len() {
+        return Err(format!(
+            "The line range {}-{} is invalid, the file has {} lines",
+            start,
+            end,
+            lines.len()
+        ));
+    }
+    lines.splice(start - 1..end, replacement.lines());
+    let mut result = lines.join("\n");
+    if content.ends_with('\n') && !result.is_empty() {
+        result.push('\n');
+    }
+    Ok(result)
+}
+
+/// Number the lines of the content, e.g. `  12 | fn main() {`
+pub fn number_lines(content: &str) -> String {
+    let width = content.lines().count().to_string().len();
+    content
+        .lines()
+        .enumerate()
+        .map(|(i, line)| format!("{:>width$} | {}\n", i + 1, line, width = width))
+        .collect()
+}
+
+/// Make the content end like the `original` content
+///
+/// Language models are inconsistent about final newlines, which would cause spurious diffs.
+/// If the original content ends with a newline (`\n` or `\r\n`), the content ends with exactly
+/// one, otherwise with none. New (empty) files end with a single `\n`.
+pub fn apply_final_newline(content: &str, original: &str) -> String {
+    let body = content.trim_end_matches(['\n', '\r']);
+    if body.is_empty() {
+        return String::new();
+    }
+    let newline = if original.is_empty() {
+        "\n"
+    } else if original.ends_with("\r\n") {
+        "\r\n"
+    } else if original.ends_with('\n') {
+        "\n"
+    } else {
+        ""
+    };
+    format!("{}{}", body, newline)
+}
+
+/// The line that starts a file in the message of the `write-files` action
+const FILE_HEADER_START: &str = "--- FILE: ";
+const FILE_HEADER_END: &str = " ---";
+
+/// Parse files in the format of the `write-files` action
+///
+/// Every file starts with a header line `--- FILE: <path> ---`, followed by its content.
+pub fn parse_files(message: &str) -> Result<Vec<(String, String)>, String> {
+    let mut files: Vec<(String, String)> = Vec::new();
+    for line in message.lines() {
+        let header = line
+            .trim_end()
+            .strip_prefix(FILE_HEADER_START)
+            .and_then(|rest| rest.strip_suffix(FILE_HEADER_END));
+        match (header, files.last_mut()) {
+            (Some(path), _) => {
+                let path = path.trim().trim_matches('`');
+                if path.is_empty() {
+                    return Err("A file header without a path was found".to_owned());
+                }
+                if files.iter().any(|(f, _)| f == path) {
+                    return Err(format!("The file `{}` is listed more than once", path));
+                }
+                files.push((path.to_owned(), String::new()));
+            }
+            (None, Some((_, content))) => {
+                content.push_str(line);
+                content.push('\n');
+            }
+            (None, None) if line.trim().is_empty() => {}
+            (None, None) => return Err("The message must start with a file header".to_owned()),
+        }
+    }
+    if files.is_empty() {
+        return Err("No files were found".to_owned());
+    }
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_splice_lines() {
+        let content = "a\nb\nc\n";
+        assert_eq!(splice_lines(content, 1, 1, "x\ny").unwrap(), "x\ny\nb\nc\n");
+        assert_eq!(splice_lines(content, 3, 3, "z\n").unwrap(), "a\nb\nz\n");
+        assert_eq!(splice_lines(content, 2, 3, "").unwrap(), "a\n");
+        assert_eq!(splice_lines(content, 1, 3, "").unwrap(), "");
+        assert_eq!(splice_lines("a\r\nb\r\n", 2, 2, "c").unwrap(), "a\nc\n");
+        assert!(splice_lines(content, 0, 1, "x").is_err());
+        assert!(splice_lines(content, 3, 4, "x").is_err());
+        assert!(splice_lines(content, 2, 1, "x").is_err());
+    }
+
+    #[test]
+    fn test_parse_line_range_edit() {
+        assert_eq!(parse_line_range_edit("LINES 3-5\nfoo\nbar"), Some((3, 5, "foo\nbar")));
+        assert_eq!(parse_line_range_edit("LINES 7-7"), Some((7, 7, "")));
+        assert_eq!(parse_line_range_edit("fn main() {}\n"), None);
+        assert_eq!(parse_line_range_edit("LINES three-five\n"), None);
+        assert_eq!(number_lines("a\nb\n"), "1 | a\n2 | b\n");
+    }
+
+    #[test]
+    fn test_final_newline_of_new_file() {
+        assert_eq!(apply_final_newline("foo", ""), "foo\n");
+        assert_eq!(apply_final_newline("foo\n", ""), "foo\n");
+        assert_eq!(apply_final_newline("foo\n\n\n", ""), "foo\n");
+        assert_eq!(apply_final_newline("\n", ""), "");
+    }
+
+    #[test]
+    fn test_final_newline_is_preserved() {
+        assert_eq!(apply_final_newline("bar", "foo\n"), "bar\n");
+        assert_eq!(apply_final_newline("bar\n\n", "foo\n"), "bar\n");
+        assert_eq!(apply_final_newline("bar\n", "foo"), "bar");
+        assert_eq!(apply_final_newline("bar", "foo"), "bar");
+        assert_eq!(apply_final_newline("a\r\nb\n", "foo\r\n"), "a\r\nb\r\n");
+    }
+
+    #[test]
+    fn test_crlf_file_keeps_crlf() {
+        let redactor = Redactor::new(Vec::new());
+        let original = "foo\r\nbar\r\n";
+        let content = prepare_content(&redactor, LineEndingMode::Auto, "foo\nbaz\n", original);
+        assert_eq!(content, "foo\r\nbaz\r\n");
+        let content = prepare_content(&redactor, LineEndingMode::Auto, "foo\r\nbaz\n", original);
+        assert_eq!(content, "foo\r\nbaz\r\n");
+    }
+
+    #[test]
+    fn test_line_endings_of_new_and_lf_files() {
+        let redactor = Redactor::new(Vec::new());
+        let content = prepare_content(&redactor, LineEndingMode::Auto, "a\r\nb\r\n", "");
+        assert_eq!(content, "a\nb\n");
+        let content = prepare_content(&redactor, LineEndingMode::Auto, "a\r\nb\n", "x\ny\n");
+        assert_eq!(content, "a\nb\n");
+    }
+
+    #[test]
+    fn test_line_ending_override() {
+        let redactor = Redactor::new(Vec::new());
+        let original = "foo\r\nbar\r\n";
+        let content = prepare_content(&redactor, LineEndingMode::Lf, "a\r\nb\n", original);
+        assert_eq!(content, "a\nb\n");
+        let content = prepare_content(&redactor, LineEndingMode::Crlf, "a\nb\n", "");
+        assert_eq!(content, "a\r\nb\r\n");
+        let content = prepare_content(&redactor, LineEndingMode::Keep, "a\r\nb\n", "x\ny\n");
+        assert_eq!(content, "a\r\nb\n");
+    }
+
+    #[test]
+    fn test_detect_predominant_line_ending() {
+        assert_eq!(LineEnding::detect("foo"), None);
+        assert_eq!(LineEnding::detect("a\r\nb\r\nc\n"), Some(LineEnding::CrLf));
+        assert_eq!(LineEnding::detect("a\r\nb\nc\n"), Some(LineEnding::Lf));
+    }
+
+    #[test]
+    fn test_replace_unique_match() {
+        let content = "fn foo() {\n    1\n}\n\nfn bar() {\n    1\n}\n";
+        let replaced = replace_unique(content, "fn bar() {\n    1", "fn bar() {\n    2").unwrap();
+        assert_eq!(replaced, "fn foo() {\n    1\n}\n\nfn bar() {\n    2\n}\n");
+    }
+
+    #[test]
+    fn test_replace_no_match() {
+        assert_eq!(replace_unique("foo\n", "bar", "baz"), Err(ReplaceError::NotFound));
+        assert_eq!(replace_unique("foo\n", "", "baz"), Err(ReplaceError::NotFound));
+    }
+
+    #[test]
+    fn test_replace_ambiguous_match() {
+        let content = "fn foo() {\n    1\n}\n\nfn bar() {\n    1\n}\n";
+        assert_eq!(replace_unique(content, "    1", "    2"), Err(ReplaceError::Ambiguous(2)));
+    }
+
+    #[test]
+    fn test_parse_search_replace() {
+        let message = "```\n<<<<<<< SEARCH\nfoo\nbar\n=======\nbaz\n>>>>>>> REPLACE\n```";
+        let parsed = parse_search_replace(message).unwrap();
+        assert_eq!(parsed, ("foo\nbar".to_owned(), "baz".to_owned()));
+        assert!(parse_search_replace("foo\n=======\nbar\n>>>>>>> REPLACE").is_err());
+        assert!(parse_search_replace("<<<<<<< SEARCH\nfoo\n=======\nbar\n").is_err());
+    }
+
+    #[test]
+    fn test_parse_files() {
+        let message = "--- FILE: src/foo.rs ---\npub fn foo() {}\n--- FILE: `tests/foo.rs` ---\n\n#[test]\nfn foo() {}\n";
+        let files = parse_files(message).unwrap();
+        assert_eq!(
+            files,
+            vec![
+                ("src/foo.rs".to_owned(), "pub fn foo() {}\n".to_owned()),
+                ("tests/foo.rs".to_owned(), "\n#[test]\nfn foo() {}\n".to_owned()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_files_invalid() {
+        assert!(parse_files("").is_err());
+        assert!(parse_files("pub fn foo() {}\n--- FILE: src/foo.rs ---\n").is_err());
+        assert!(parse_files("--- FILE: a ---\nx\n--- FILE: a ---\ny\n").is_err());
+    }
 }
@@ -1,45 +1,519 @@
-use std::path::Path;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, MutexGuard};
 
-use git2::{build::RepoBuilder, Repository};
+use git2::{
+    build::RepoBuilder, BranchType, Cred, Diff, DiffFormat, DiffOptions, Direction, FetchOptions,
+    PushOptions, RemoteCallbacks, Repository, StatusOptions,
+};
+use serde::Deserialize;
 use url::Url;
 
+/// The message of commits if there is no summary of the changes
+const DEFAULT_COMMIT_MESSAGE: &str = "Commit from minionrt";
+/// The maximum number of characters of the subject line of commit messages
+const MAX_SUBJECT_CHARS: usize = 72;
+
+/// What to do if the workspace has changes before the agent starts
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DirtyWorkspace {
+    /// Carry on, the changes become part of the commit of the agent
+    Ignore,
+    /// Log the changed paths and carry on
+    #[default]
+    Warn,
+    /// Fail the task
+    Fail,
+}
+
+/// How to authenticate against the remote
+pub enum Credentials {
+    /// An access token, e.g. the API token
+    Token(String),
+    /// A git credential helper, e.g. `store` or `!f() { echo password=$TOKEN; }; f`
+    Helper(String),
+}
+
 pub struct Repo {
-    repo: Repository,
+    /// Locked, as libgit2 repositories can not be used by several threads at the same time
+    repo: Mutex<Repository>,
+    workdir: PathBuf,
+    /// The branch that was cloned
+    base_branch: String,
+    /// The branch that is committed to and pushed
     branch: String,
+    credentials: Credentials,
 }
 
 impl Repo {
     /// Clone (and configure) a git repository
+    ///
+    /// Only the last `depth` commits of the branch are cloned, unless it is `0`. The credentials
+    /// are only passed to git on demand, they are not persisted in the clone.
     pub fn clone<P: AsRef<Path>>(
         clone_to: P,
         url: &Url,
         branch: &str,
         user_name: &str,
         user_email: &str,
-    ) -> Self {
-        let mut repo_builder = RepoBuilder::new();
-        repo_builder.branch(branch);
-        let repo = repo_builder.clone(url.as_str(), clone_to.as_ref()).unwrap();
-        let mut config = repo.config().unwrap();
-        config.set_str("user.name", user_name).unwrap();
-        config.set_str("user.email", user_email).unwrap();
-
-        Self { repo, branch: branch.to_owned() }
-    }
-
-    pub fn commit_and_push(&self) {
-        let mut index = self.repo.index().unwrap();
-        index.add_all(["*"].iter(), git2::IndexAddOption::DEFAULT, None).unwrap();
-        let oid = index.write_tree().unwrap();
-        let tree = self.repo.find_tree(oid).unwrap();
-        let head = self.repo.head().unwrap();
-        let parent = self.repo.find_commit(head.target().unwrap()).unwrap();
-        let sig = self.repo.signature().unwrap();
-        let message = "Commit from minionrt";
-        self.repo.commit(Some("HEAD"), &sig, &sig, message, &tree, &[&parent]).unwrap();
-        let mut remote = self.repo.find_remote("origin").unwrap();
-        remote
-            .push(&[format!("refs/heads/{}:refs/heads/{}", self.branch, self.branch)], None)
-            .unwrap();
+        credentials: Credentials,
+        depth: u32,
+    ) -> Result<Self, git2::Error> {
+        let repo = if depth > 0 {
+            shallow_clone(clone_to.as_ref(), url, branch, depth, &credentials)
+                .map_err(|e| git2::Error::from_str(&e))?
+        } else {
+            let mut fetch_options = FetchOptions::new();
+            fetch_options.remote_callbacks(remote_callbacks(&credentials));
+            let mut repo_builder = RepoBuilder::new();
+            repo_builder.branch(branch);
+            repo_builder.fetch_options(fetch_options);
+            repo_builder.clone(url.as_str(), clone_to.as_ref())?
+        };
+        let mut config = repo.config()?;
+        config.set_str("user.name", user_name)?;
+        config.set_str("user.email", user_email)?;
+
+        let workdir = repo.workdir().expect("The repository has no working directory").to_owned();
+        Ok(Self {
+            repo: Mutex::new(repo),
+            workdir,
+            base_branch: branch.to_owned(),
+            branch: branch.to_owned(),
+            credentials,
+        })
+    }
+
+    fn repo(&self) -> MutexGuard<'_, Repository> {
+        self.repo.lock().expect("A thread panicked while using the repository")
+    }
+
+    /// The branch that was cloned
+    pub fn base_branch(&self) -> &str {
+        &self.base_branch
+    }
+
+    /// The branch that is pushed to
+    pub fn branch(&self) -> &str {
+        &self.branch
+    }
+
+    /// Create a branch at HEAD and check it out, so it is pushed to instead of the cloned branch
+    ///
+    /// If a local or remote branch with the name exists, a numeric suffix is appended (e.g.
+    /// `minion/1234-2`). Returns the name of the new branch.
+    pub fn checkout_new_branch(&mut self, name: &str) -> Result<String, git2::Error> {
+        // Shallow clones only fetch the cloned branch, so the remote is asked for the others
+        let shallow = self.repo().is_shallow();
+        let remote_branches = if shallow { self.remote_branches()? } else { vec![] };
+        let name = {
+            let repo = self.repo();
+            let name = unique_branch_name(name, |candidate| {
+                repo.find_branch(candidate, BranchType::Local).is_ok()
+                    || remote_branches.iter().any(|branch| branch == candidate)
+                    || repo
+                        .find_branch(&format!("origin/{}", candidate), BranchType::Remote)
+                        .is_ok()
+            });
+            let head = repo.head()?.peel_to_commit()?;
+            let branch = repo.branch(&name, &head, false)?;
+            let reference = branch.get().name().expect("Branch names are UTF-8").to_owned();
+            repo.set_head(&reference)?;
+            name
+        };
+        self.branch = name.clone();
+        Ok(name)
+    }
+
+    /// The names of the branches of `origin`, as listed by the remote itself
+    fn remote_branches(&self) -> Result<Vec<String>, git2::Error> {
+        let repo = self.repo();
+        let mut remote = repo.find_remote("origin")?;
+        let callbacks = remote_callbacks(&self.credentials);
+        let connection = remote.connect_auth(Direction::Fetch, Some(callbacks), None)?;
+        let heads = connection.list()?;
+        Ok(heads
+            .iter()
+            .filter_map(|head| head.name().strip_prefix("refs/heads/"))
+            .map(str::to_owned)
+            .collect())
+    }
+
+    /// Commit all changes and push the branch
+    ///
+    /// Fails if the remote can not be reached, authentication fails or the remote rejects the
+    /// branch, e.g. because it was changed in the meantime.
+    pub fn commit_and_push(&self, message: &str) -> Result<(), git2::Error> {
+        let repo = self.repo();
+        let mut index = repo.index()?;
+        index.add_all(["*"].iter(), git2::IndexAddOption::DEFAULT, None)?;
+        let oid = index.write_tree()?;
+        let tree = repo.find_tree(oid)?;
+        let parent = repo.head()?.peel_to_commit()?;
+        let sig = repo.signature()?;
+        repo.commit(Some("HEAD"), &sig, &sig, message, &tree, &[&parent])?;
+        let refspec = format!("refs/heads/{}:refs/heads/{}", self.branch, self.branch);
+        // libgit2 fails to push from shallow clones, as it misses the parents of their commits
+        if repo.is_shallow() {
+            return git_push(self.workdir(), &refspec, &self.credentials)
+                .map_err(|e| git2::Error::from_str(&e));
+        }
+        let mut remote = repo.find_remote("origin")?;
+        let mut push_options = PushOptions::new();
+        push_options.remote_callbacks(remote_callbacks(&self.credentials));
+        remote.push(&[refspec], Some(&mut push_options))
+    }
+
+    /// The working directory of the repository
+    pub fn workdir(&self) -> &Path {
+        &self.workdir
+    }
+
+    /// The ID of the commit HEAD points to
+    pub fn head_commit(&self) -> Result<String, git2::Error> {
+        Ok(self.repo().head()?.peel_to_commit()?.id().to_string())
+    }
+
+    /// The paths of all tracked files
+    pub fn tracked_files(&self) -> Result<Vec<String>, git2::Error> {
+        let index = self.repo().index()?;
+        Ok(index.iter().map(|entry| String::from_utf8_lossy(&entry.path).into_owned()).collect())
+    }
+
+    /// The paths with uncommitted changes, including untracked files
+    pub fn changed_paths(&self) -> Result<Vec<String>, git2::Error> {
+        let mut options = StatusOptions::new();
+        options.include_untracked(true).recurse_untracked_dirs(true);
+        let repo = self.repo();
+        let statuses = repo.statuses(Some(&mut options))?;
+        Ok(statuses.iter().filter_map(|entry| entry.path().map(str::to_owned)).collect())
+    }
+
+    /// Whether there are uncommitted changes, i.e. whether there is anything to commit
+    pub fn has_changes(&self) -> Result<bool, git2::Error> {
+        Ok(!self.changed_paths()?.is_empty())
+    }
+
+    /// The uncommitted changes, including untracked files, as a patch
+    pub fn diff(&self) -> Result<String, git2::Error> {
+        let repo = self.repo();
+        let head_tree = repo.head()?.peel_to_tree()?;
+        let mut options = DiffOptions::new();
+        options.include_untracked(true).recurse_untracked_dirs(true).show_untracked_content(true);
+        let diff = repo.diff_tree_to_workdir_with_index(Some(&head_tree), Some(&mut options))?;
+        patch_text(&diff)
+    }
+
+    /// The changes of HEAD since its merge base with the given ref, as a patch
+    pub fn diff_since(&self, base: &str) -> Result<String, git2::Error> {
+        let repo = self.repo();
+        let head = repo.head()?.peel_to_commit()?;
+        let base = repo.revparse_single(base)?.peel_to_commit()?;
+        let merge_base = repo.find_commit(repo.merge_base(base.id(), head.id())?)?;
+        let diff = repo.diff_tree_to_tree(Some(&merge_base.tree()?), Some(&head.tree()?), None)?;
+        patch_text(&diff)
+    }
+
+    /// Read a file as it is at the given ref (e.g. a commit, `HEAD~1` or `origin/main`)
+    pub fn read_file_at(&self, reference: &str, path: &str) -> Result<String, String> {
+        let path = path.trim_start_matches("./");
+        let repo = self.repo();
+        let object = repo
+            .revparse_single(&format!("{}:{}", reference, path))
+            .map_err(|e| e.message().to_owned())?;
+        let blob = object.peel_to_blob().map_err(|_| "The path is not a file".to_owned())?;
+        String::from_utf8(blob.content().to_vec()).map_err(|_| "The file is not UTF-8".to_owned())
+    }
+}
+
+/// A commit message from the summary of the changes
+///
+/// The first line of the summary, shortened if need be, is the subject. The whole summary is the
+/// body, unless it is just the subject.
+pub fn commit_message(summary: &str) -> String {
+    let summary = summary.trim();
+    let Some(first_line) = summary.lines().next() else {
+        return DEFAULT_COMMIT_MESSAGE.to_owned();
+    };
+    // e.g. a Markdown heading
+    let first_line = first_line.trim_start_matches(['#', ' ']);
+    let subject = if first_line.chars().count() > MAX_SUBJECT_CHARS {
+        let shortened: String = first_line.chars().take(MAX_SUBJECT_CHARS - 3).collect();
+        format!("{}...", shortened.trim_end())
+    } else {
+        first_line.to_owned()
+    };
+    if subject == summary {
+        subject
+    } else {
+        format!("{}\n\n{}\n", subject, summary)
+    }
+}
+
+/// The name, or the name with the first numeric suffix that is not taken
+fn unique_branch_name(name: &str, exists: impl Fn(&str) -> bool) -> String {
+    if !exists(name) {
+        return name.to_owned();
+    }
+    (2..)
+        .map(|i| format!("{}-{}", name, i))
+        .find(|candidate| !exists(candidate))
+        .expect("Ran out of branch names")
+}
+
+/// Render a diff as a patch
+fn patch_text(diff: &Diff) -> Result<String, git2::Error> {
+    let mut patch = Vec::new();
+    diff.print(DiffFormat::Patch, |_delta, _hunk, line| {
+        if matches!(line.origin(), '+' | '-' | ' ') {
+            patch.push(line.origin() as u8);
+        }
+        patch.extend_from_slice(line.content());
+        true
+    })?;
+    Ok(String::from_utf8_lossy(&patch).into_owned())
+}
+
+fn remote_callbacks(credentials: &Credentials) -> RemoteCallbacks<'_> {
+    let mut callbacks = RemoteCallbacks::new();
+    // git keeps asking for credentials as long as authentication fails, so only try once
+    let mut attempted = false;
+    callbacks.credentials(move |url, username, _allowed_types| {
+        if std::mem::replace(&mut attempted, true) {
+            return Err(git2::Error::from_str("Authentication with the remote failed"));
+        }
+        match credentials {
+            Credentials::Token(token) => Cred::userpass_plaintext("x-access-token", token),
+            Credentials::Helper(helper) => {
+                Cred::credential_helper(&helper_config(helper)?, url, username)
+            }
+        }
+    });
+    // Rejected references do not fail the push itself
+    callbacks.push_update_reference(|reference, status| match status {
+        Some(reason) => {
+            Err(git2::Error::from_str(&format!("The remote rejected `{}`: {}", reference, reason)))
+        }
+        None => Ok(()),
+    });
+    callbacks
+}
+
+/// Clone the last `depth` commits of a branch with the git command line, as libgit2 can not
+/// clone shallowly
+fn shallow_clone(
+    clone_to: &Path,
+    url: &Url,
+    branch: &str,
+    depth: u32,
+    credentials: &Credentials,
+) -> Result<Repository, String> {
+    let mut command = git_command(credentials);
+    command.args(["clone", "--quiet", "--depth", &depth.to_string(), "--branch", branch]);
+    run_git(command.arg(url.as_str()).arg(clone_to))?;
+    Repository::open(clone_to).map_err(|e| e.message().to_owned())
+}
+
+/// Push to `origin` with the git command line
+fn git_push(workdir: &Path, refspec: &str, credentials: &Credentials) -> Result<(), String> {
+    let mut command = git_command(credentials);
+    run_git(command.current_dir(workdir).args(["push", "--quiet", "origin", refspec]))
+}
+
+/// A git command that authenticates with the credentials
+///
+/// The credentials are passed as a credential helper for the command only, the token via the
+/// environment, so it does not show up in the process list.
+fn git_command(credentials: &Credentials) -> Command {
+    let mut command = Command::new("git");
+    let helper = match credentials {
+        Credentials::Token(token) => {
+            command.env("MINION_GIT_TOKEN", token);
+            r#"!f() { echo username=x-access-token; echo "password=$MINION_GIT_TOKEN"; }; f"#
+        }
+        Credentials::Helper(helper) => helper.as_str(),
+    };
+    // An empty helper first clears the helpers configured elsewhere
+    command.args(["-c", "credential.helper=", "-c"]).arg(format!("credential.helper={}", helper));
+    command.env("GIT_TERMINAL_PROMPT", "0");
+    command
+}
+
+fn run_git(command: &mut Command) -> Result<(), String> {
+    let output = command.output().map_err(|e| format!("Failed to run git: {}", e))?;
+    if !output.status.success() {
+        return Err(format!("git failed: {}", String::from_utf8_lossy(&output.stderr).trim()));
+    }
+    Ok(())
+}
+
+/// A git configuration that only configures the credential helper
+///
+/// libgit2 can only set values in a file, so the helper is written to a file of its own, which is
+/// removed once the configuration has been read into memory.
+fn helper_config(helper: &str) -> Result<git2::Config, git2::Error> {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let path = std::env::temp_dir().join(format!(
+        "minion-credential-helper-{}-{}.gitconfig",
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    let config = git2::Config::open(&path).and_then(|mut config| {
+        config.set_str("credential.helper", helper)?;
+        config.snapshot()
+    });
+    let _ = fs::remove_file(&path);
+    config
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A bare repository with three commits on `main`
+    fn bare_repo(path: &Path) -> Repository {
+        let repo = Repository::init_bare(path).unwrap();
+        let sig = git2::Signature::now("Test", "test@example.com").unwrap();
+        let mut parent = None;
+        for i in 0..3 {
+            let blob = repo.blob(format!("version {}\n", i).as_bytes()).unwrap();
+            let mut tree = repo.treebuilder(None).unwrap();
+            tree.insert("file.txt", blob, 0o100644).unwrap();
+            let tree = repo.find_tree(tree.write().unwrap()).unwrap();
+            let parents: Vec<_> =
+                parent.iter().map(|oid| repo.find_commit(*oid).unwrap()).collect();
+            let parents: Vec<_> = parents.iter().collect();
+            let message = format!("Commit {}", i);
+            let commit =
+                repo.commit(Some("refs/heads/main"), &sig, &sig, &message, &tree, &parents);
+            parent = Some(commit.unwrap());
+        }
+        repo
+    }
+
+    /// A clone of the `main` branch of the bare repository `remote.git` in the directory
+    fn clone_main(dir: &Path, depth: u32) -> Repo {
+        let url = Url::from_file_path(dir.join("remote.git")).unwrap();
+        let credentials = Credentials::Token("token".to_owned());
+        let (name, email) = ("Test", "test@example.com");
+        Repo::clone(dir.join("clone"), &url, "main", name, email, credentials, depth).unwrap()
+    }
+
+    #[test]
+    fn test_push_work_branch() {
+        let dir = std::env::temp_dir().join(format!("minion-work-branch-{}", std::process::id()));
+        let remote = bare_repo(&dir.join("remote.git"));
+        let base = remote.refname_to_id("refs/heads/main").unwrap();
+        let mut repo = clone_main(&dir, 0);
+
+        std::fs::write(repo.workdir().join("file.txt"), "changed\n").unwrap();
+        assert_eq!(repo.checkout_new_branch("minion/1").unwrap(), "minion/1");
+        assert_eq!((repo.base_branch(), repo.branch()), ("main", "minion/1"));
+        repo.commit_and_push("Change the file").unwrap();
+
+        let pushed = remote.find_branch("minion/1", BranchType::Local).unwrap();
+        let commit = pushed.get().peel_to_commit().unwrap();
+        let unchanged = remote.refname_to_id("refs/heads/main").unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(commit.parent_id(0).unwrap(), base);
+        assert_eq!(unchanged, base);
+    }
+
+    #[test]
+    fn test_push_failure() {
+        let dir = std::env::temp_dir().join(format!("minion-push-failure-{}", std::process::id()));
+        bare_repo(&dir.join("remote.git"));
+        let repo = clone_main(&dir, 0);
+
+        std::fs::remove_dir_all(dir.join("remote.git")).unwrap();
+        std::fs::write(repo.workdir().join("file.txt"), "changed\n").unwrap();
+        let result = repo.commit_and_push("Change the file");
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_has_changes() {
+        let dir = std::env::temp_dir().join(format!("minion-changes-{}", std::process::id()));
+        bare_repo(&dir.join("remote.git"));
+        let repo = clone_main(&dir, 0);
+
+        let unchanged = repo.has_changes().unwrap();
+        std::fs::write(repo.workdir().join("new.txt"), "new\n").unwrap();
+        let changed = repo.has_changes().unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(!unchanged);
+        assert!(changed);
+    }
+
+    #[test]
+    fn test_shallow_clone() {
+        let dir = std::env::temp_dir().join(format!("minion-shallow-{}", std::process::id()));
+        let remote = bare_repo(&dir.join("remote.git"));
+        let mut repo = clone_main(&dir, 1);
+
+        let git_repo = repo.repo();
+        let mut revwalk = git_repo.revwalk().unwrap();
+        revwalk.push_head().unwrap();
+        assert_eq!(revwalk.count(), 1);
+        drop(git_repo);
+
+        // A single new commit can be pushed from a shallow clone
+        std::fs::write(repo.workdir().join("file.txt"), "changed\n").unwrap();
+        repo.checkout_new_branch("minion/1").unwrap();
+        repo.commit_and_push("Change the file").unwrap();
+        let pushed = remote.find_branch("minion/1", BranchType::Local).map(|_| ());
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(pushed.is_ok());
+    }
+
+    #[test]
+    fn test_shallow_clone_branch_collision() {
+        let dir = std::env::temp_dir().join(format!("minion-collision-{}", std::process::id()));
+        let remote = bare_repo(&dir.join("remote.git"));
+        let main = remote.refname_to_id("refs/heads/main").unwrap();
+        let head = remote.find_commit(main).unwrap();
+        remote.branch("minion/1", &head, false).unwrap();
+        // The shallow clone does not know about `minion/1`, but the remote does
+        let mut repo = clone_main(&dir, 1);
+
+        let name = repo.checkout_new_branch("minion/1");
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(name.unwrap(), "minion/1-2");
+    }
+
+    #[test]
+    fn test_commit_message() {
+        assert_eq!(commit_message("  \n"), DEFAULT_COMMIT_MESSAGE);
+        assert_eq!(commit_message("Fix the parser\n"), "Fix the parser");
+        assert_eq!(
+            commit_message("# Fix the parser\n\nIt now handles tabs."),
+            "Fix the parser\n\n# Fix the parser\n\nIt now handles tabs.\n"
+        );
+        let long = "word ".repeat(20);
+        let subject = commit_message(&long).lines().next().unwrap().to_owned();
+        assert!(subject.ends_with("word..."));
+        assert!(subject.chars().count() <= MAX_SUBJECT_CHARS);
+    }
+
+    #[test]
+    fn test_helper_config() {
+        let config = helper_config("store").unwrap();
+        assert_eq!(config.get_string("credential.helper").unwrap(), "store");
+        let leftover = fs::read_dir(std::env::temp_dir()).unwrap().flatten().any(|entry| {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            name.starts_with(&format!("minion-credential-helper-{}-", std::process::id()))
+        });
+        assert!(!leftover);
+    }
+
+    #[test]
+    fn test_unique_branch_name() {
+        let taken = ["minion/42", "minion/42-2"];
+        assert_eq!(unique_branch_name("minion/7", |n| taken.contains(&n)), "minion/7");
+        assert_eq!(unique_branch_name("minion/42", |n| taken.contains(&n)), "minion/42-3");
     }
 }
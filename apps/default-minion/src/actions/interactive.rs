@@ -0,0 +1,97 @@
+/// The maximum number of bytes of all responses of an interactive script
+pub const MAX_INPUT_BYTES: usize = 4096;
+
+/// A command and the responses to the prompts it is expected to show
+#[derive(Debug, PartialEq, Eq)]
+pub struct InteractiveScript {
+    pub command: String,
+    pub interactions: Vec<Interaction>,
+}
+
+/// A response that is sent once the program printed the expected prompt
+#[derive(Debug, PartialEq, Eq)]
+pub struct Interaction {
+    pub expect: String,
+    pub send: String,
+}
+
+/// Parse an interactive script in the format of the `run-interactive` action
+///
+/// ```text
+/// command: npm init
+/// expect: package name:
+/// send: my-package
+/// ```
+pub fn parse_interactive_script(message: &str) -> Result<InteractiveScript, String> {
+    let mut command = None;
+    let mut interactions = Vec::new();
+    let mut expect: Option<String> = None;
+    for line in message.lines() {
+        let line = line.trim_start();
+        if let Some(rest) = line.strip_prefix("command:") {
+            if command.is_some() {
+                return Err("Only one `command:` line is allowed".to_owned());
+            }
+            command = Some(rest.trim().to_owned());
+        } else if let Some(rest) = line.strip_prefix("expect:") {
+            if expect.is_some() {
+                return Err("Every `expect:` line must be followed by a `send:` line".to_owned());
+            }
+            let rest = rest.trim();
+            if rest.is_empty() {
+                return Err("An `expect:` line must not be empty".to_owned());
+            }
+            expect = Some(rest.to_owned());
+        } else if let Some(rest) = line.strip_prefix("send:") {
+            let expect = expect.take().ok_or("Every `send:` line must follow an `expect:` line")?;
+            interactions.push(Interaction { expect, send: rest.trim().to_owned() });
+        } else if !line.trim().is_empty() && !line.starts_with("```") {
+            return Err(format!("Unexpected line `{}`", line));
+        }
+    }
+    if expect.is_some() {
+        return Err("The last `expect:` line is not followed by a `send:` line".to_owned());
+    }
+    let command = command.filter(|c| !c.is_empty()).ok_or("The `command:` line is missing")?;
+    let input_bytes: usize = interactions.iter().map(|i| i.send.len() + 1).sum();
+    if input_bytes > MAX_INPUT_BYTES {
+        return Err(format!(
+            "The responses consist of {} bytes, at most {} are allowed",
+            input_bytes, MAX_INPUT_BYTES
+        ));
+    }
+    Ok(InteractiveScript { command, interactions })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_interactive_script() {
+        let message = "command: npm init\nexpect: package name:\nsend: my-package\n\nexpect: version:\nsend:\n";
+        assert_eq!(
+            parse_interactive_script(message).unwrap(),
+            InteractiveScript {
+                command: "npm init".to_owned(),
+                interactions: vec![
+                    Interaction {
+                        expect: "package name:".to_owned(),
+                        send: "my-package".to_owned()
+                    },
+                    Interaction { expect: "version:".to_owned(), send: String::new() },
+                ],
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_invalid_interactive_script() {
+        assert!(parse_interactive_script("expect: name:\nsend: foo").is_err());
+        assert!(parse_interactive_script("command: npm init\nexpect: name:").is_err());
+        assert!(parse_interactive_script("command: npm init\nsend: foo").is_err());
+        assert!(parse_interactive_script("command: npm init\nfoo").is_err());
+        let long = format!("command: cat\nexpect: >\nsend: {}", "a".repeat(MAX_INPUT_BYTES));
+        assert!(parse_interactive_script(&long).is_err());
+    }
+}
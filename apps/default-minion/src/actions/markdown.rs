@@ -1,7 +1,14 @@
 use once_cell::sync::Lazy;
 use regex::Regex;
 
-static CODE_FENCE_REGEX: Lazy<Regex> = Lazy::new(|| Regex::new(r"(?m)^```.*$").unwrap());
+static CODE_FENCE_REGEX: Lazy<Regex> = Lazy::new(|| Regex::new(r"(?m)^(`{3,}|~{3,}).*$").unwrap());
+
+/// The character and length of the fence a line starts with, e.g. `('~', 4)` for `~~~~python`
+fn fence(line: &str) -> Option<(char, usize)> {
+    let marker = line.chars().next().filter(|c| *c == '`' || *c == '~')?;
+    let length = line.chars().take_while(|c| *c == marker).count();
+    (length >= 3).then_some((marker, length))
+}
 
 /// Strip wrapping Markdown code fences
 ///
@@ -10,29 +17,55 @@ static CODE_FENCE_REGEX: Lazy<Regex> = Lazy::new(|| Regex::new(r"(?m)^```.*$").u
 /// This function tries to detect these code fences and strip them from the text.
 /// It uses heuristics to allow actual Markdown content to pass through.
 pub fn strip_wrapping_markdown_code_fences(content: &str) -> String {
+    strip_wrapping_markdown_code_fences_with_language(content).0
+}
+
+/// Strip wrapping Markdown code fences like [`strip_wrapping_markdown_code_fences`], and return
+/// the language of the opening fence, e.g. `python` for ```` ```python ````, if there is one
+pub fn strip_wrapping_markdown_code_fences_with_language(
+    content: &str,
+) -> (String, Option<String>) {
     let trimmed = content.trim().to_owned();
     let code_fence_count = CODE_FENCE_REGEX.find_iter(&trimmed).count();
     if code_fence_count == 0 {
         // There are no code fences in the text.
-        return content.to_owned();
+        return (content.to_owned(), None);
     }
     // Strip the first code fence
     let mut start_idx = 0;
+    let mut language = None;
+    let mut opening = None;
     if let Some(first_line) = trimmed.lines().next() {
-        if CODE_FENCE_REGEX.is_match(first_line) {
+        if let Some((marker, length)) = fence(first_line) {
             start_idx = first_line.len() + 1;
+            opening = Some((marker, length));
+            // The info string may contain more than the language, e.g. ```` ```rust,ignore ````
+            language = first_line
+                .trim_start_matches(marker)
+                .split(|c: char| c.is_whitespace() || c == ',' || c == '{')
+                .next()
+                .filter(|language| !language.is_empty())
+                .map(str::to_owned);
         }
     }
 
     // Strip the last code fence under two conditions:
-    // * There is an odd number of code fences. This indicates invalid Markdown.
-    // * There was a code fence at the beginning. This indicates the whole content is wrapped in code fences.
+    // * There was a code fence at the beginning, which the last line closes. This indicates the
+    //   whole content is wrapped in code fences. As in CommonMark, a closing fence uses the same
+    //   character as the opening one, is at least as long and has no info string, so shorter
+    //   fences, e.g. ```` ``` ```` within ```` ```` ````, are part of the content.
+    // * There was none, and there is an odd number of code fences. This indicates invalid Markdown.
     let mut end_idx = trimmed.len();
-    if code_fence_count % 2 == 1 || start_idx != 0 {
-        if let Some(last_line) = trimmed.lines().last() {
-            if CODE_FENCE_REGEX.is_match(last_line) {
-                end_idx -= last_line.len();
+    if let Some(last_line) = trimmed.lines().last() {
+        let is_closing = match (opening, fence(last_line)) {
+            (Some((marker, length)), Some((last_marker, last_length))) => {
+                last_marker == marker && last_length >= length && last_line.len() == last_length
             }
+            (None, Some(_)) => code_fence_count % 2 == 1,
+            (_, None) => false,
+        };
+        if is_closing {
+            end_idx -= last_line.len();
         }
     }
 
@@ -45,10 +78,10 @@ pub fn strip_wrapping_markdown_code_fences(content: &str) -> String {
     let has_been_stripped = start_idx != 0 || end_idx != trimmed.len();
 
     if has_been_stripped {
-        trimmed[start_idx..end_idx].to_owned()
+        (trimmed[start_idx..end_idx].to_owned(), language)
     } else {
         // If nothing has been stripped, return the original, untrimmed content
-        content.to_owned()
+        (content.to_owned(), None)
     }
 }
 
@@ -63,6 +96,45 @@ mod tests {
         assert_eq!(strip_wrapping_markdown_code_fences(input), expected);
     }
 
+    #[test]
+    fn test_language() {
+        let strip = strip_wrapping_markdown_code_fences_with_language;
+        let (code, language) = strip("```python\nprint(\"Hello World\")\n```");
+        assert_eq!(code, "print(\"Hello World\")\n");
+        assert_eq!(language.as_deref(), Some("python"));
+        assert_eq!(strip("```rust\nfn main() {}\n```").1.as_deref(), Some("rust"));
+        assert_eq!(strip("```rust,ignore\nfn main() {}\n```").1.as_deref(), Some("rust"));
+        assert_eq!(strip("```\ncode\n```"), ("code\n".to_owned(), None));
+        // Inner code fences are kept, so their language is not the language of the content
+        assert_eq!(strip("Text\n\n```python\npass\n```\n\nText").1, None);
+    }
+
+    #[test]
+    fn test_tilde_fence() {
+        let strip = strip_wrapping_markdown_code_fences_with_language;
+        let (code, language) = strip("~~~python\nprint(\"Hello World\")\n~~~");
+        assert_eq!(code, "print(\"Hello World\")\n");
+        assert_eq!(language.as_deref(), Some("python"));
+        assert_eq!(strip_wrapping_markdown_code_fences("Some code\n~~~"), "Some code\n");
+    }
+
+    #[test]
+    fn test_long_fence() {
+        let input = "````markdown\n# Title\n\n```rust\nfn main() {}\n```\n````";
+        let expected = "# Title\n\n```rust\nfn main() {}\n```\n";
+        assert_eq!(strip_wrapping_markdown_code_fences(input), expected);
+        // A longer closing fence is fine
+        assert_eq!(strip_wrapping_markdown_code_fences("```\ncode\n`````"), "code\n");
+    }
+
+    #[test]
+    fn test_mismatched_fence() {
+        // Shorter fences and fences of the other character do not close the opening fence
+        assert_eq!(strip_wrapping_markdown_code_fences("````\ncode\n```"), "code\n```");
+        assert_eq!(strip_wrapping_markdown_code_fences("~~~\ncode\n```"), "code\n```");
+        assert_eq!(strip_wrapping_markdown_code_fences("```\ncode\n~~~"), "code\n~~~");
+    }
+
     #[test]
     fn test_strip_with_inner_code_fence() {
         let input = "Some text\n\n```python\nprint(\"Hello World\")\n```\n\nSome more text";
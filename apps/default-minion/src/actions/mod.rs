@@ -1,3 +1,8 @@
 pub mod files;
 pub mod git;
+pub mod interactive;
 pub mod markdown;
+pub mod packages;
+pub mod patch;
+pub mod protected;
+pub mod secrets;
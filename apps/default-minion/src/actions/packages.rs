@@ -0,0 +1,180 @@
+use std::collections::BTreeSet;
+
+use globset::{Glob, GlobSet, GlobSetBuilder};
+
+/// A script that prints the name of the first package manager that is available
+pub const DETECT_PACKAGE_MANAGER: &str = r#"for pm in apt-get apk dnf yum zypper pacman; do
+    if command -v "$pm" >/dev/null 2>&1; then echo "$pm"; exit 0; fi
+done
+exit 1
+"#;
+
+/// The system package managers packages can be installed with
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PackageManager {
+    Apt,
+    Apk,
+    Dnf,
+    Yum,
+    Zypper,
+    Pacman,
+}
+
+impl PackageManager {
+    /// Parse the output of [`DETECT_PACKAGE_MANAGER`]
+    pub fn from_detected(output: &str) -> Option<Self> {
+        match output.trim() {
+            "apt-get" => Some(PackageManager::Apt),
+            "apk" => Some(PackageManager::Apk),
+            "dnf" => Some(PackageManager::Dnf),
+            "yum" => Some(PackageManager::Yum),
+            "zypper" => Some(PackageManager::Zypper),
+            "pacman" => Some(PackageManager::Pacman),
+            _ => None,
+        }
+    }
+
+    /// A script that installs the packages without prompting
+    ///
+    /// The package index is only refreshed if `refresh_index` is set, which is only necessary
+    /// for the first installation of a task. The package names must be valid.
+    pub fn install_script(&self, packages: &[String], refresh_index: bool) -> String {
+        let packages = packages.join(" ");
+        let (refresh, install) = match self {
+            PackageManager::Apt => (
+                "apt-get update -qq",
+                "DEBIAN_FRONTEND=noninteractive apt-get install -y -qq --no-install-recommends",
+            ),
+            PackageManager::Apk => ("apk update -q", "apk add -q"),
+            PackageManager::Dnf => ("true", "dnf install -y -q"),
+            PackageManager::Yum => ("true", "yum install -y -q"),
+            PackageManager::Zypper => {
+                ("zypper --non-interactive refresh", "zypper --non-interactive install")
+            }
+            PackageManager::Pacman => ("pacman -Sy --noconfirm", "pacman -S --noconfirm --needed"),
+        };
+        if refresh_index {
+            format!("{} && {} {}\n", refresh, install, packages)
+        } else {
+            format!("{} {}\n", install, packages)
+        }
+    }
+}
+
+/// Whether the name is a plausible package name, optionally with a version or architecture
+///
+/// This also ensures that the name can be passed to the shell as is.
+pub fn is_valid_package_name(name: &str) -> bool {
+    name.chars().next().is_some_and(|c| c.is_ascii_alphanumeric())
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || "._+-:=~@".contains(c))
+}
+
+/// The packages the model may install, as globs (e.g. `python3-*`)
+pub struct PackageAllowlist {
+    globs: GlobSet,
+}
+
+impl PackageAllowlist {
+    /// The allowlist of the patterns, which must have been checked with
+    /// [`PackageAllowlist::check_patterns`]
+    pub fn new(patterns: &[String]) -> Self {
+        Self { globs: Self::glob_set(patterns).expect("Checked when loading the config") }
+    }
+
+    /// Check that the patterns are valid globs
+    pub fn check_patterns(patterns: &[String]) -> Result<(), globset::Error> {
+        Self::glob_set(patterns).map(|_| ())
+    }
+
+    fn glob_set(patterns: &[String]) -> Result<GlobSet, globset::Error> {
+        let mut builder = GlobSetBuilder::new();
+        for pattern in patterns.iter().map(|p| p.trim()).filter(|p| !p.is_empty()) {
+            builder.add(Glob::new(pattern)?);
+        }
+        builder.build()
+    }
+
+    /// Whether the package may be installed, versions and architectures are ignored
+    pub fn allows(&self, package: &str) -> bool {
+        let name = package.split(['=', ':']).next().unwrap_or(package);
+        self.globs.is_match(name)
+    }
+}
+
+/// What is known about the packages of the container, which is kept for the whole task
+#[derive(Default)]
+pub struct PackageState {
+    /// The detected package manager, `None` until the first installation
+    pub manager: Option<PackageManager>,
+    /// Whether the package index has been refreshed
+    pub index_refreshed: bool,
+    /// The packages that have been installed successfully
+    pub installed: BTreeSet<String>,
+    /// Whether the environment turned out to have no network access
+    pub offline: bool,
+}
+
+/// Messages of package managers and common tools when there is no network access
+const OFFLINE_MESSAGES: [&str; 9] = [
+    "temporary failure resolving",
+    "temporary failure in name resolution",
+    "could not resolve host",
+    "network is unreachable",
+    "name or service not known",
+    "no address associated with hostname",
+    "failed to establish a new connection",
+    "dns lookup failed",
+    "unable to connect to",
+];
+
+/// Whether the output indicates that the environment has no network access
+pub fn looks_offline(output: &str) -> bool {
+    let output = output.to_lowercase();
+    OFFLINE_MESSAGES.iter().any(|message| output.contains(message))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_install_script() {
+        let packages = vec!["jq".to_owned(), "ripgrep".to_owned()];
+        assert_eq!(
+            PackageManager::Apk.install_script(&packages, true),
+            "apk update -q && apk add -q jq ripgrep\n"
+        );
+        assert_eq!(
+            PackageManager::Dnf.install_script(&packages, false),
+            "dnf install -y -q jq ripgrep\n"
+        );
+        assert_eq!(PackageManager::from_detected("apt-get\n"), Some(PackageManager::Apt));
+        assert_eq!(PackageManager::from_detected(""), None);
+    }
+
+    #[test]
+    fn test_is_valid_package_name() {
+        assert!(is_valid_package_name("libssl-dev"));
+        assert!(is_valid_package_name("python3.11"));
+        assert!(is_valid_package_name("gcc=4:12.2.0-3"));
+        assert!(!is_valid_package_name("-y"));
+        assert!(!is_valid_package_name("jq; rm -rf /"));
+        assert!(!is_valid_package_name("$(id)"));
+    }
+
+    #[test]
+    fn test_allowlist() {
+        let allowlist = PackageAllowlist::new(&["python3-*".to_owned(), "jq".to_owned()]);
+        assert!(allowlist.allows("python3-venv"));
+        assert!(allowlist.allows("jq=1.6-2"));
+        assert!(!allowlist.allows("curl"));
+        assert!(!PackageAllowlist::new(&[String::new()]).allows("jq"));
+    }
+
+    #[test]
+    fn test_looks_offline() {
+        assert!(looks_offline("Err:1 http://deb.debian.org Temporary failure resolving 'deb'"));
+        assert!(looks_offline("curl: (6) Could not resolve host: example.com"));
+        assert!(!looks_offline("E: Unable to locate package foo"));
+    }
+}
@@ -0,0 +1,257 @@
+//! Applying unified diffs written by the model
+//!
+//! Models often get the line numbers of hunk headers wrong and sometimes the indentation of
+//! context lines, so hunks are searched for near their stated position, and failing that, with
+//! whitespace ignored. Only if a hunk can not be found at all, it is rejected.
+
+/// A line of a hunk
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum HunkLine {
+    Context(String),
+    Remove(String),
+    Add(String),
+}
+
+/// A hunk of a unified diff
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Hunk {
+    /// The first line of the hunk in the original file, starting at 1, or for hunks that only add
+    /// lines, the line they are added before
+    old_start: usize,
+    lines: Vec<HunkLine>,
+}
+
+impl Hunk {
+    /// The lines the hunk expects in the file
+    fn old_lines(&self) -> impl Iterator<Item = &str> {
+        self.lines.iter().filter_map(|line| match line {
+            HunkLine::Context(text) | HunkLine::Remove(text) => Some(text.as_str()),
+            HunkLine::Add(_) => None,
+        })
+    }
+
+    /// The hunk as it appears in a diff
+    fn to_diff(&self) -> String {
+        let mut text = format!("@@ -{} @@\n", self.old_start);
+        for line in &self.lines {
+            let (prefix, line) = match line {
+                HunkLine::Context(line) => (' ', line),
+                HunkLine::Remove(line) => ('-', line),
+                HunkLine::Add(line) => ('+', line),
+            };
+            text.push(prefix);
+            text.push_str(line);
+            text.push('\n');
+        }
+        text
+    }
+}
+
+/// Parse the hunks of a unified diff of a single file
+///
+/// File headers (`---`/`+++`) and other lines before the first hunk are ignored. Within hunks,
+/// `---` and `+++` lines are only taken as file headers as a pair, as removed and added lines may
+/// start with `--` and `++` (e.g. SQL comments). Empty lines within hunks are taken as empty
+/// context lines, as models tend to drop the leading space.
+pub fn parse_patch(patch: &str) -> Result<Vec<Hunk>, String> {
+    let mut hunks: Vec<Hunk> = Vec::new();
+    let lines: Vec<&str> = patch.lines().collect();
+    let mut in_header = false;
+    for (index, line) in lines.iter().enumerate() {
+        if let Some(header) = line.strip_prefix("@@") {
+            let mut range = header
+                .trim_start()
+                .strip_prefix('-')
+                .and_then(|range| range.split(' ').next())
+                .unwrap_or_default()
+                .split(',');
+            let old_start: Option<usize> = range.next().and_then(|start| start.parse().ok());
+            let old_count: Option<usize> = range.next().map(str::parse).transpose().ok().flatten();
+            let old_start = old_start.ok_or_else(|| format!("Invalid hunk header `{}`", line))?;
+            // A hunk without old lines adds them after its start line
+            let old_start = if old_count == Some(0) { old_start + 1 } else { old_start };
+            hunks.push(Hunk { old_start, lines: Vec::new() });
+            in_header = false;
+            continue;
+        }
+        let next = lines.get(index + 1);
+        if line.starts_with("--- ") && next.is_some_and(|next| next.starts_with("+++ ")) {
+            in_header = true;
+        }
+        let Some(hunk) = hunks.last_mut().filter(|_| !in_header) else {
+            continue;
+        };
+        let hunk_line = match line.chars().next() {
+            Some(' ') => HunkLine::Context(line[1..].to_owned()),
+            Some('-') => HunkLine::Remove(line[1..].to_owned()),
+            Some('+') => HunkLine::Add(line[1..].to_owned()),
+            None => HunkLine::Context(String::new()),
+            // e.g. `\ No newline at end of file`
+            _ => continue,
+        };
+        hunk.lines.push(hunk_line);
+    }
+    if hunks.is_empty() {
+        return Err("The patch contains no hunks, they must start with `@@`".to_owned());
+    }
+    Ok(hunks)
+}
+
+/// The result of applying a patch
+#[derive(Debug, PartialEq, Eq)]
+pub struct Applied {
+    /// The patched content, with `\n` line endings
+    pub content: String,
+    /// The number of hunks that only applied with whitespace ignored
+    pub fuzzy: usize,
+    /// The hunks that could not be applied, as they appear in a diff
+    pub rejected: Vec<String>,
+}
+
+/// Apply the hunks to the content, in order
+///
+/// A hunk is applied where its lines match closest to its stated position, relative to the
+/// preceding hunks. Context lines that only match with whitespace ignored are kept as they are in
+/// the file.
+pub fn apply_patch(content: &str, hunks: &[Hunk]) -> Applied {
+    let content = content.replace("\r\n", "\n");
+    let final_newline = content.ends_with('\n') || content.is_empty();
+    let mut lines: Vec<String> = content.lines().map(str::to_owned).collect();
+    let mut fuzzy = 0;
+    let mut rejected = Vec::new();
+    // The shift of the lines of the original file by the preceding hunks
+    let mut offset: isize = 0;
+
+    for hunk in hunks {
+        let old: Vec<&str> = hunk.old_lines().collect();
+        let expected = (hunk.old_start.saturating_sub(1) as isize + offset).max(0) as usize;
+        let exact = find_lines(&lines, &old, expected, |a, b| a == b);
+        let position = exact.or_else(|| {
+            let position = find_lines(&lines, &old, expected, |a, b| a.trim() == b.trim());
+            fuzzy += usize::from(position.is_some());
+            position
+        });
+        let Some(position) = position else {
+            rejected.push(hunk.to_diff());
+            continue;
+        };
+
+        let mut replacement = Vec::new();
+        let mut cursor = position;
+        for line in &hunk.lines {
+            match line {
+                HunkLine::Context(_) => {
+                    replacement.push(lines[cursor].clone());
+                    cursor += 1;
+                }
+                HunkLine::Remove(_) => cursor += 1,
+                HunkLine::Add(text) => replacement.push(text.clone()),
+            }
+        }
+        let added = replacement.len() as isize - old.len() as isize;
+        lines.splice(position..cursor, replacement);
+        offset = position as isize - hunk.old_start.saturating_sub(1) as isize + added;
+    }
+
+    let mut content = lines.join("\n");
+    if final_newline && !content.is_empty() {
+        content.push('\n');
+    }
+    Applied { content, fuzzy, rejected }
+}
+
+/// The position of the lines that is closest to `expected`
+fn find_lines(
+    lines: &[String],
+    needle: &[&str],
+    expected: usize,
+    matches: impl Fn(&str, &str) -> bool,
+) -> Option<usize> {
+    if needle.len() > lines.len() {
+        return None;
+    }
+    let last = lines.len() - needle.len();
+    let expected = expected.min(last);
+    let is_match = |position: usize| {
+        lines[position..position + needle.len()].iter().zip(needle).all(|(a, b)| matches(a, b))
+    };
+    (0..=last)
+        .flat_map(|distance| {
+            let before = expected.checked_sub(distance);
+            let after = Some(expected + distance).filter(|_| distance > 0);
+            [before, after]
+        })
+        .flatten()
+        .filter(|&position| position <= last)
+        .find(|&position| is_match(position))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ORIGINAL: &str = "fn main() {\n    let x = 1;\n    println!(\"{}\", x);\n}\n\nfn helper() {\n    todo!()\n}\n";
+
+    #[test]
+    fn test_apply_clean() {
+        let patch = "--- a/src/main.rs\n+++ b/src/main.rs\n@@ -1,4 +1,4 @@\n fn main() {\n-    let x = 1;\n+    let x = 2;\n     println!(\"{}\", x);\n }\n@@ -6,3 +6,3 @@\n fn helper() {\n-    todo!()\n+    unimplemented!()\n }\n";
+        let applied = apply_patch(ORIGINAL, &parse_patch(patch).unwrap());
+        assert_eq!(applied.rejected, Vec::<String>::new());
+        assert_eq!(applied.fuzzy, 0);
+        assert_eq!(applied.content, ORIGINAL.replace("1;", "2;").replace("todo", "unimplemented"));
+    }
+
+    #[test]
+    fn test_apply_fuzzy() {
+        // Wrong line numbers, a dropped leading space of an empty line and wrong indentation
+        let patch = "@@ -20,3 +20,4 @@\n }\n\n fn helper() {\n+    // TODO\n@@ -1,2 +1,2 @@\n fn main() {\n-  let x = 1;\n+    let x = 3;\n";
+        let hunks = parse_patch(patch).unwrap();
+        let applied = apply_patch(ORIGINAL, &hunks);
+        assert_eq!(applied.rejected, Vec::<String>::new());
+        assert_eq!(applied.fuzzy, 1);
+        let expected =
+            ORIGINAL.replace("fn helper() {\n", "fn helper() {\n    // TODO\n").replace("1;", "3;");
+        assert_eq!(applied.content, expected);
+    }
+
+    #[test]
+    fn test_apply_rejected() {
+        let patch = "@@ -1,2 +1,2 @@\n fn main() {\n-    let y = 1;\n+    let y = 2;\n@@ -7 +7 @@\n-    todo!()\n+    42\n";
+        let applied = apply_patch(ORIGINAL, &parse_patch(patch).unwrap());
+        assert_eq!(
+            applied.rejected,
+            ["@@ -1 @@\n fn main() {\n-    let y = 1;\n+    let y = 2;\n"]
+        );
+        assert_eq!(applied.content, ORIGINAL.replace("todo!()", "42"));
+    }
+
+    #[test]
+    fn test_apply_double_dash_lines() {
+        let original = "SELECT 1;\n-- old comment\n++counter;\n";
+        let patch = "--- a/query.sql\n+++ b/query.sql\n@@ -1,3 +1,3 @@\n SELECT 1;\n--- old comment\n+-- new comment\n-++counter;\n+++ counter;\n";
+        let applied = apply_patch(original, &parse_patch(patch).unwrap());
+        assert_eq!(applied.rejected, Vec::<String>::new());
+        assert_eq!(applied.content, "SELECT 1;\n-- new comment\n++ counter;\n");
+    }
+
+    #[test]
+    fn test_apply_insertion() {
+        // Without old lines, the lines are added after the start line
+        let patch = "@@ -2,0 +3,1 @@\n+    let y = 2;\n";
+        let applied = apply_patch(ORIGINAL, &parse_patch(patch).unwrap());
+        let expected = ORIGINAL.replace("1;\n", "1;\n    let y = 2;\n");
+        assert_eq!(applied.content, expected);
+
+        let applied = apply_patch("", &parse_patch("@@ -0,0 +1 @@\n+a\n").unwrap());
+        assert_eq!(applied.content, "a\n");
+    }
+
+    #[test]
+    fn test_parse_patch() {
+        assert!(parse_patch("just prose").is_err());
+        assert!(parse_patch("@@ garbage @@\n-a\n").is_err());
+        let hunks = parse_patch("@@ -3 +3 @@\n-a\n+b\n\\ No newline at end of file\n").unwrap();
+        assert_eq!(hunks[0].old_start, 3);
+        assert_eq!(hunks[0].lines.len(), 2);
+    }
+}
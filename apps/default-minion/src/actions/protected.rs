@@ -0,0 +1,104 @@
+use std::path::{Component, Path, PathBuf};
+
+use globset::{Glob, GlobSet, GlobSetBuilder};
+
+/// Paths the model must not modify, regardless of what it decides
+///
+/// Patterns are globs relative to the workspace directory:
+///
+/// * Patterns without a `/` match at any depth, e.g. `*.lock` or `Cargo.lock`
+/// * Patterns ending with a `/` match everything inside that directory, e.g. `.github/`
+pub struct ProtectedPaths {
+    workspace_dir: PathBuf,
+    globs: GlobSet,
+}
+
+impl ProtectedPaths {
+    /// The protected paths of the workspace, the patterns must have been checked with
+    /// [`ProtectedPaths::check_patterns`]
+    pub fn new<P: AsRef<Path>>(workspace_dir: P, patterns: &[String]) -> Self {
+        let globs = glob_set(patterns).expect("Checked when loading the config");
+        Self { workspace_dir: workspace_dir.as_ref().to_owned(), globs }
+    }
+
+    /// Check that the patterns are valid globs
+    pub fn check_patterns(patterns: &[String]) -> Result<(), globset::Error> {
+        glob_set(patterns).map(|_| ())
+    }
+
+    /// Whether the path (relative to the workspace directory, or absolute) is protected
+    pub fn is_protected<P: AsRef<Path>>(&self, path: P) -> bool {
+        let path = normalize(&self.workspace_dir.join(path));
+        match path.strip_prefix(&self.workspace_dir) {
+            Ok(relative) => self.globs.is_match(relative),
+            // Paths outside of the workspace are not covered by the patterns
+            Err(_) => false,
+        }
+    }
+}
+
+fn glob_set(patterns: &[String]) -> Result<GlobSet, globset::Error> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        let mut pattern = pattern.trim().to_owned();
+        if pattern.is_empty() {
+            continue;
+        }
+        if pattern.ends_with('/') {
+            pattern.push_str("**");
+        } else if !pattern.contains('/') {
+            pattern = format!("**/{}", pattern);
+        }
+        builder.add(Glob::new(pattern.trim_start_matches('/'))?);
+    }
+    builder.build()
+}
+
+/// Resolve `.` and `..` components without accessing the file system
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            component => normalized.push(component),
+        }
+    }
+    normalized
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn protected_paths() -> ProtectedPaths {
+        let patterns = [".github/".to_owned(), "infra/**/*.tf".to_owned(), "*.lock".to_owned()];
+        ProtectedPaths::new("/workspaces/project", &patterns)
+    }
+
+    #[test]
+    fn test_directory_pattern() {
+        let protected = protected_paths();
+        assert!(protected.is_protected(".github/workflows/ci.yml"));
+        assert!(protected.is_protected("/workspaces/project/.github/workflows/ci.yml"));
+        assert!(protected.is_protected("src/../.github/workflows/ci.yml"));
+        assert!(!protected.is_protected("src/.github/ci.yml"));
+    }
+
+    #[test]
+    fn test_glob_pattern() {
+        let protected = protected_paths();
+        assert!(protected.is_protected("infra/modules/main.tf"));
+        assert!(!protected.is_protected("infra/README.md"));
+    }
+
+    #[test]
+    fn test_pattern_without_separator() {
+        let protected = protected_paths();
+        assert!(protected.is_protected("Cargo.lock"));
+        assert!(protected.is_protected("libs/foo/Cargo.lock"));
+        assert!(!protected.is_protected("src/main.rs"));
+    }
+}
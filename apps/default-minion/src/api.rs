@@ -0,0 +1,138 @@
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use agent_api::types::task::{Task, TaskComplete, TaskFailure};
+use backoff::{Error as BackoffError, ExponentialBackoffBuilder};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::actions::secrets::Redactor;
+use crate::output::write_redacted;
+
+/// Where outcomes that could not be reported are written to, unless configured otherwise
+const DEFAULT_OUTCOME_PATH: &str = "minion-outcome.json";
+
+/// The outcome of a task as it is reported to the API
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "status", rename_all = "kebab-case")]
+pub enum ReportedOutcome {
+    Complete(TaskComplete),
+    Failure(TaskFailure),
+}
+
+#[derive(Error, Debug)]
+pub enum ApiError {
+    #[error("Request to the agent API failed: {0}")]
+    Request(#[from] agent_api::Error),
+    #[error("Request to the agent API timed out after {0:?}")]
+    Timeout(Duration),
+}
+
+/// The agent API client, with timeouts and retries
+///
+/// Outcomes that can not be reported are written to a file, so they can be re-submitted with
+/// `--resubmit-outcome <file>` instead of being lost.
+pub struct ApiClient {
+    client: agent_api::Client,
+    timeout: Duration,
+    max_retry_time: Duration,
+    outcome_path: PathBuf,
+    redactor: Arc<Redactor>,
+}
+
+impl ApiClient {
+    pub fn new(
+        client: agent_api::Client,
+        timeout: Duration,
+        max_retry_time: Duration,
+        redactor: Arc<Redactor>,
+    ) -> Self {
+        let outcome_path = DEFAULT_OUTCOME_PATH.into();
+        Self { client, timeout, max_retry_time, outcome_path, redactor }
+    }
+
+    /// Write outcomes that could not be reported to the given file
+    pub fn with_outcome_path<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.outcome_path = path.as_ref().to_owned();
+        self
+    }
+
+    pub async fn get_task(&self) -> Result<Task, ApiError> {
+        self.retry("get the task", || self.client.get_task()).await
+    }
+
+    /// Report the outcome, writing it to a file if that fails
+    ///
+    /// Returns whether the outcome was reported.
+    pub async fn report(&self, outcome: ReportedOutcome) -> bool {
+        let result = match &outcome {
+            ReportedOutcome::Complete(info) => {
+                self.retry("complete the task", || self.client.complete_task(info.clone())).await
+            }
+            ReportedOutcome::Failure(info) => {
+                self.retry("fail the task", || self.client.fail_task(info.clone())).await
+            }
+        };
+        let Err(err) = result else {
+            return true;
+        };
+        log::error!("Failed to report the outcome: {}", err);
+        let json = serde_json::to_string_pretty(&outcome).expect("Failed to serialize outcome");
+        match write_redacted(&self.redactor, &self.outcome_path, json.as_bytes()) {
+            Ok(()) => log::error!(
+                "The outcome was written to `{}`, re-submit it with `--resubmit-outcome {}`",
+                self.outcome_path.display(),
+                self.outcome_path.display()
+            ),
+            Err(err) => log::error!(
+                "Failed to write the outcome to `{}`: {}",
+                self.outcome_path.display(),
+                err
+            ),
+        }
+        false
+    }
+
+    /// Run a request with a timeout, retrying it with exponential backoff
+    async fn retry<T, F, Fut>(&self, description: &str, mut request: F) -> Result<T, ApiError>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, agent_api::Error>>,
+    {
+        let strategy = ExponentialBackoffBuilder::default()
+            .with_max_elapsed_time(Some(self.max_retry_time))
+            .build();
+        backoff::future::retry(strategy, || {
+            let request = tokio::time::timeout(self.timeout, request());
+            async move {
+                let err = match request.await {
+                    Ok(Ok(value)) => return Ok(value),
+                    Ok(Err(err)) => ApiError::Request(err),
+                    Err(_) => ApiError::Timeout(self.timeout),
+                };
+                log::warn!("Failed to {}, retrying: {}", description, err);
+                Err(BackoffError::transient(err))
+            }
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_outcome_round_trip() {
+        let outcome =
+            ReportedOutcome::Complete(TaskComplete { description: "Fixed the bug".to_owned() });
+        let json = serde_json::to_string(&outcome).unwrap();
+        assert_eq!(json, r#"{"status":"complete","description":"Fixed the bug"}"#);
+        let ReportedOutcome::Complete(info) = serde_json::from_str(&json).unwrap() else {
+            panic!("Expected a complete outcome");
+        };
+        assert_eq!(info.description, "Fixed the bug");
+    }
+}
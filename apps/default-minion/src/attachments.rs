@@ -0,0 +1,74 @@
+use once_cell::sync::Lazy;
+use regex::Regex;
+use url::Url;
+
+use crate::llm::ContentItem;
+
+/// Markdown images, e.g. `![Screenshot](https://example.com/screenshot.png)`
+static MARKDOWN_IMAGE_REGEX: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"!\[[^\]]*\]\(\s*<?([^)\s>]+)>?(?:\s+[^)]*)?\)").unwrap());
+
+/// The URLs of the images that are referenced in a task description
+///
+/// Only HTTP(S) URLs are considered, each one at most once.
+pub fn image_urls(description: &str) -> Vec<Url> {
+    let mut urls: Vec<Url> = Vec::new();
+    for captures in MARKDOWN_IMAGE_REGEX.captures_iter(description) {
+        let Ok(url) = Url::parse(&captures[1]) else {
+            continue;
+        };
+        if matches!(url.scheme(), "http" | "https") && !urls.contains(&url) {
+            urls.push(url);
+        }
+    }
+    urls
+}
+
+/// Fetch the images referenced in a task description, so they can be shown to the model
+///
+/// Images that cannot be fetched or decoded are replaced by a note.
+pub async fn load_images(http_client: &reqwest::Client, description: &str) -> Vec<ContentItem> {
+    let mut items = Vec::new();
+    for url in image_urls(description) {
+        match load_image(http_client, &url).await {
+            Ok(image) => {
+                items.push(ContentItem::Text { text: format!("The image `{}`:", url) });
+                items.push(ContentItem::from_rgba_image(image));
+            }
+            Err(err) => {
+                log::warn!("Failed to load image `{}`: {}", url, err);
+                let text = format!("The image `{}` could not be loaded: {}", url, err);
+                items.push(ContentItem::Text { text });
+            }
+        }
+    }
+    items
+}
+
+async fn load_image(http_client: &reqwest::Client, url: &Url) -> Result<image::RgbaImage, String> {
+    let response = http_client
+        .get(url.clone())
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(|e| e.to_string())?;
+    let bytes = response.bytes().await.map_err(|e| e.to_string())?;
+    let image = image::load_from_memory(&bytes).map_err(|e| e.to_string())?;
+    Ok(image.to_rgba8())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_image_urls() {
+        let description = r#"The button is misaligned:
+
+![Screenshot](https://example.com/a.png "Title")
+![](<https://example.com/b.jpg>) and ![again](https://example.com/a.png "Title")
+![local](screenshots/c.png) [not an image](https://example.com/d.png)"#;
+        let urls: Vec<String> = image_urls(description).iter().map(Url::to_string).collect();
+        assert_eq!(urls, vec!["https://example.com/a.png", "https://example.com/b.jpg"]);
+    }
+}
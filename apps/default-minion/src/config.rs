@@ -1,14 +1,518 @@
+use std::collections::BTreeMap;
+use std::num::NonZeroUsize;
+use std::path::PathBuf;
+
 use serde::Deserialize;
+use thiserror::Error;
 use url::Url;
 
+use crate::actions::files::LineEndingMode;
+use crate::actions::git::DirtyWorkspace;
+use crate::actions::packages::PackageAllowlist;
+use crate::actions::protected::ProtectedPaths;
+use crate::actions::secrets::Redactor;
+use crate::container::{VolumeMount, WorkspaceMount};
+use crate::interaction_loop::{ModelPrice, SummaryFormat, TaskDifficulty};
+
 #[derive(Deserialize)]
 pub struct Config {
     pub api_base_url: Option<Url>,
     pub api_token: Option<String>,
+    /// The timeout of requests to the agent API in seconds
+    #[serde(default = "default_api_timeout")]
+    pub api_timeout: u64,
+    /// How long failed requests to the agent API are retried in seconds
+    #[serde(default = "default_api_max_retry_time")]
+    pub api_max_retry_time: u64,
+    /// A git credential helper to authenticate against the repository, instead of the API token
+    pub git_credential_helper: Option<String>,
+    /// The number of commits of the history to clone, the whole history if `0`
+    #[serde(default)]
+    pub clone_depth: u32,
+    /// The directory the repository is cloned into, in a folder named after the repository
+    #[serde(default = "default_workspaces_dir")]
+    pub workspaces_dir: PathBuf,
+    /// The number of tasks to fetch and run before exiting
+    #[serde(default = "default_one")]
+    pub max_tasks: NonZeroUsize,
+    /// The number of tasks that run at the same time, each with its own workspace and container
+    #[serde(default = "default_one")]
+    pub max_concurrent_tasks: NonZeroUsize,
+    /// A directory to write the transcript, LLM trace, diff and usage of each run to
+    pub output_dir: Option<PathBuf>,
+    /// A directory to write the LLM trace of each run to as `<task id>.jsonl`, e.g. to collect
+    /// the traces of all runs in one place
+    pub transcript_dir: Option<PathBuf>,
+    /// What to do if the workspace has changes before the agent starts (`ignore`, `warn` or `fail`)
+    #[serde(default)]
+    pub dirty_workspace: DirtyWorkspace,
+    /// The prefix of the branch `<prefix><task id>` that is pushed instead of the task branch
+    #[serde(default = "default_branch_prefix")]
+    pub branch_prefix: String,
+    /// The name of the branch that is pushed, instead of `<prefix><task id>`
+    pub branch_name: Option<String>,
+    /// Push onto the task branch instead of a new branch
+    #[serde(default)]
+    pub push_to_task_branch: bool,
+    /// The message of the commit of the changes, instead of one from the task summary
+    pub commit_message: Option<String>,
+    /// Review the changes of the task branch since its merge base with this ref, instead of
+    /// making changes
+    pub review_base: Option<String>,
+    /// Environment variables whose values are redacted from the model output, logs and output
+    /// files (comma-separated)
+    #[serde(default)]
+    pub redact_env: Vec<String>,
+    /// Regular expressions of secrets that are redacted, in addition to the built-in formats
+    /// (whitespace-separated, as patterns may contain commas)
+    pub redact_patterns: Option<String>,
+    /// A PEM file with additional CA certificates to trust, e.g. of a corporate proxy
+    pub ca_cert: Option<PathBuf>,
+    /// Files with instructions for agents at the root of the repository, the first one that
+    /// exists is included in the prompt (comma-separated)
+    #[serde(default = "default_agent_instructions")]
+    pub agent_instructions: Vec<String>,
+    /// The maximum number of bytes of the agent instructions
+    #[serde(default = "default_always_include_max_bytes")]
+    pub agent_instructions_max_bytes: usize,
+    /// Offer a `retrieve` action that searches an embedding index of the repository
+    #[serde(default)]
+    pub retrieval: bool,
+    /// The model used to embed the repository and the queries for retrieval
+    #[serde(default = "default_embedding_model")]
+    pub embedding_model: String,
+    /// The directory the retrieval index is cached in, per repository and commit
+    #[serde(default = "default_retrieval_cache_dir")]
+    pub retrieval_cache_dir: PathBuf,
+    /// The maximum number of chunks of the repository that are indexed for retrieval
+    #[serde(default = "default_retrieval_max_chunks")]
+    pub retrieval_max_chunks: usize,
+    /// Files that are focused from the start, the model can change the focus (comma-separated)
+    #[serde(default)]
+    pub focus: Vec<String>,
+    /// Files that are included in the prompt of every action (comma-separated)
+    #[serde(default)]
+    pub always_include: Vec<String>,
+    /// The maximum number of bytes included per always-included file
+    #[serde(default = "default_always_include_max_bytes")]
+    pub always_include_max_bytes: usize,
+    /// The maximum number of bytes of the recently read or changed files that are included in
+    /// the prompt of every action with their current content, none if `0`
+    #[serde(default)]
+    pub open_files_max_bytes: usize,
+    /// Allow the operator to cancel and steer actions via stdin
+    #[serde(default)]
+    pub interactive: bool,
+    /// How the workspace is made available in the container (`read-write`, `read-only` or `copy`)
+    #[serde(default)]
+    pub workspace_mount: WorkspaceMount,
+    /// Named volumes mounted into the container as `<name>:<path>` (comma-separated)
+    #[serde(default)]
+    pub volumes: Vec<VolumeMount>,
+    /// The directory in the container for scripts and other temporary files of the agent
+    #[serde(default = "default_scratch_dir")]
+    pub scratch_dir: String,
+    /// The maximum number of actions per task, afterwards the task fails, no limit if `0`
+    #[serde(default = "default_max_actions")]
+    pub max_actions: usize,
+    /// The number of recent actions that are kept in full, older actions are replaced by their
+    /// summaries, no limit if `0`
+    #[serde(default = "default_history_max_actions")]
+    pub history_max_actions: usize,
+    /// The estimated number of tokens of the recent actions that are kept in full, no limit if `0`
+    #[serde(default = "default_history_max_tokens")]
+    pub history_max_tokens: usize,
+    /// The number of summaries of older actions after which they are consolidated into one, never
+    /// if `0`
+    #[serde(default = "default_history_max_summaries")]
+    pub history_max_summaries: usize,
+    /// The memory limit of the container in MiB, no limit if `0`
+    #[serde(default = "default_memory_limit_mb")]
+    pub memory_limit_mb: u64,
+    /// The number of CPUs the container may use, no limit if `0`
+    #[serde(default)]
+    pub cpus: f64,
+    /// The maximum number of processes in the container, no limit if `0`
+    #[serde(default = "default_pids_limit")]
+    pub pids_limit: i64,
+    /// A command that is run in the container after the task, before the container is removed
+    pub cleanup_command: Option<String>,
+    /// The number of seconds after which the cleanup command is aborted
+    #[serde(default = "default_cleanup_timeout")]
+    pub cleanup_timeout: u64,
+    /// An LLM trace whose completions are replayed instead of prompting the models
+    pub replay_trace: Option<PathBuf>,
+    /// Resume the task from the transcript in the output directory, if a previous run of the task
+    /// was interrupted
+    #[serde(default = "default_true")]
+    pub resume: bool,
+    /// Globs of paths the model must not modify (comma-separated)
+    #[serde(default)]
+    pub protected_paths: Vec<String>,
+    /// How the line endings of written files are chosen (`auto`, `lf`, `crlf` or `keep`)
+    #[serde(default)]
+    pub line_endings: LineEndingMode,
+    /// Globs of the system packages the model may install, none if empty (comma-separated)
+    #[serde(default = "default_allowed_packages")]
+    pub allowed_packages: Vec<String>,
+    /// Show the stdout of bash actions to the model
+    #[serde(default = "default_true")]
+    pub bash_show_stdout: bool,
+    /// Show the stderr of bash actions to the model
+    #[serde(default = "default_true")]
+    pub bash_show_stderr: bool,
+    /// The maximum number of bytes of each output stream of bash actions shown to the model, no
+    /// limit if `0`
+    #[serde(default = "default_bash_max_output_bytes")]
+    pub bash_max_output_bytes: usize,
+    /// Show stdout and stderr of bash actions interleaved, if both are shown
+    #[serde(default = "default_true")]
+    pub bash_combined_output: bool,
+    /// Log the output of bash actions as it arrives
+    #[serde(default)]
+    pub bash_stream_output: bool,
+    /// Keep the working directory and exported variables of bash actions for the next one
+    #[serde(default)]
+    pub persistent_shell: bool,
+    /// The number of seconds after which a bash action is aborted
+    #[serde(default = "default_command_timeout")]
+    pub command_timeout: u64,
+    /// The default command of the `run-tests` action, instead of the one of the detected project
+    /// kind
+    #[serde(default)]
+    pub test_command: Option<String>,
+    /// How often the container is recreated if it stops responding, before the task is failed
+    #[serde(default = "default_max_container_restarts")]
+    pub max_container_restarts: usize,
+    /// How often the model is prompted again if it responds with none of the options, e.g. an
+    /// unknown action, before the task fails
+    #[serde(default = "default_max_reprompts")]
+    pub max_reprompts: usize,
+    /// Challenge the model once if it completes a task without any changes
+    #[serde(default = "default_true")]
+    pub challenge_no_changes: bool,
+    /// How actions are summarized for the compressed history (`text` or `structured`)
+    #[serde(default)]
+    pub summary_format: SummaryFormat,
+    /// Route steps that are estimated to be easy to the basic model
+    #[serde(default)]
+    pub model_routing: bool,
+    /// The difficulty of the task (`trivial`, `normal` or `hard`), instead of the hint in the task
+    pub task_difficulty: Option<TaskDifficulty>,
+    /// The maximum number of smart model calls per task, afterwards the basic model is used
+    pub thinking_budget: Option<usize>,
+    /// The maximum number of tokens per task, afterwards the task fails
+    pub max_tokens: Option<u64>,
+    /// The maximum estimated cost in USD per task, afterwards the task fails
+    pub max_cost: Option<f64>,
+    /// Model prices as `<model>=<prompt>/<completion>` in USD per million tokens (comma-separated)
+    #[serde(default)]
+    pub model_prices: Vec<ModelPrice>,
+    /// The model for hard steps
+    #[serde(default = "default_smart_model")]
+    pub smart_model: String,
+    /// The model for easy and trivial steps
+    #[serde(default = "default_basic_model")]
+    pub basic_model: String,
+    /// Stream completions and log their progress
+    #[serde(default)]
+    pub stream_completions: bool,
+    /// The number of seconds after which an attempt to prompt a model is aborted and retried, no
+    /// timeout if `0`
+    #[serde(default = "default_llm_timeout")]
+    pub llm_timeout: u64,
+    /// Return the completions of identical prompts with a temperature of 0 from a cache
+    #[serde(default)]
+    pub prompt_cache: bool,
+    /// A JSON Lines file to keep the prompt cache in across runs, which enables the cache
+    pub prompt_cache_file: Option<PathBuf>,
+    /// The maximum number of tokens of each completion, e.g. to limit the cost of runaway
+    /// completions
+    pub max_completion_tokens: Option<u32>,
+    /// Models that can respond with JSON constrained to a schema, including their versions
+    /// (comma-separated), e.g. to select actions in a single prompt
+    #[serde(default)]
+    pub json_mode_models: Vec<String>,
+    /// Models that are prompted in order if prompting a model fails (comma-separated)
+    #[serde(default)]
+    pub fallback_models: Vec<String>,
+    /// Models that take neither system messages nor a temperature, including their versions
+    /// (comma-separated)
+    #[serde(default = "default_restricted_models")]
+    pub restricted_models: Vec<String>,
+    /// Models that support images, including their versions (comma-separated)
+    #[serde(default = "default_vision_models")]
+    pub vision_models: Vec<String>,
+    /// The model prompts with images are sent to if the chosen model does not support images,
+    /// otherwise the images are omitted
+    pub vision_fallback_model: Option<String>,
+    /// Model and temperature overrides per phase, see the README for details
+    pub discuss_model: Option<String>,
+    pub discuss_temperature: Option<f32>,
+    pub select_model: Option<String>,
+    pub select_temperature: Option<f32>,
+    pub bash_model: Option<String>,
+    pub bash_temperature: Option<f32>,
+    pub edit_model: Option<String>,
+    pub edit_temperature: Option<f32>,
+    pub summarize_model: Option<String>,
+    pub summarize_temperature: Option<f32>,
+}
+
+/// The configuration file that is read if it exists and `MINION_CONFIG` is not set
+const DEFAULT_CONFIG_FILE: &str = "minion.toml";
+
+/// The environment variables that must be set and not empty
+const REQUIRED_VARS: [&str; 2] = ["MINION_API_BASE_URL", "MINION_API_TOKEN"];
+
+#[derive(Error, Debug)]
+pub enum ConfigError {
+    #[error("Missing required environment variables: {}", .0.join(", "))]
+    Missing(Vec<&'static str>),
+    #[error("Invalid configuration: {0}")]
+    Invalid(#[from] envy::Error),
+    #[error("Invalid configuration file: {0}")]
+    File(String),
+    #[error("Invalid pattern in {name}: {message}")]
+    Pattern { name: &'static str, message: String },
 }
 
 impl Config {
-    pub fn load() -> Self {
-        envy::prefixed("MINION_").from_env::<Config>().unwrap()
+    /// Load the configuration from the `MINION_` environment variables and the configuration file
+    ///
+    /// The file is `MINION_CONFIG`, or `minion.toml` if it exists. Environment variables take
+    /// precedence over the file.
+    pub fn load() -> Result<Self, ConfigError> {
+        Self::from_env_and_file(std::env::vars().collect())
+    }
+
+    fn from_env_and_file(env: Vec<(String, String)>) -> Result<Self, ConfigError> {
+        let path = match env.iter().find(|(name, _)| name == "MINION_CONFIG") {
+            Some((_, path)) => Some(PathBuf::from(path)),
+            None => Some(PathBuf::from(DEFAULT_CONFIG_FILE)).filter(|path| path.is_file()),
+        };
+        let file = match path {
+            Some(path) => crate::config_file::read(&path).map_err(ConfigError::File)?,
+            None => Vec::new(),
+        };
+        Self::from_vars(file.into_iter().chain(env))
+    }
+
+    /// Load the configuration from environment variables, of which later ones take precedence
+    fn from_vars(vars: impl IntoIterator<Item = (String, String)>) -> Result<Self, ConfigError> {
+        let vars: BTreeMap<_, _> = vars
+            .into_iter()
+            .filter(|(name, value)| !(REQUIRED_VARS.contains(&name.as_str()) && value.is_empty()))
+            .collect();
+        let missing: Vec<_> =
+            REQUIRED_VARS.into_iter().filter(|required| !vars.contains_key(*required)).collect();
+        if !missing.is_empty() {
+            return Err(ConfigError::Missing(missing));
+        }
+        let config: Self = envy::prefixed("MINION_").from_iter(vars)?;
+        config.check_patterns()?;
+        Ok(config)
+    }
+
+    /// Check the patterns, so that invalid ones fail here rather than during the task
+    fn check_patterns(&self) -> Result<(), ConfigError> {
+        let invalid = |name, message| ConfigError::Pattern { name, message };
+        ProtectedPaths::check_patterns(&self.protected_paths)
+            .map_err(|err| invalid("MINION_PROTECTED_PATHS", err.to_string()))?;
+        PackageAllowlist::check_patterns(&self.allowed_packages)
+            .map_err(|err| invalid("MINION_ALLOWED_PACKAGES", err.to_string()))?;
+        Redactor::check_patterns(self.redact_patterns.as_deref())
+            .map_err(|err| invalid("MINION_REDACT_PATTERNS", err.to_string()))
+    }
+}
+
+fn default_workspaces_dir() -> PathBuf {
+    PathBuf::from("./workspaces")
+}
+
+fn default_one() -> NonZeroUsize {
+    NonZeroUsize::MIN
+}
+
+fn default_branch_prefix() -> String {
+    "minion/".to_owned()
+}
+
+fn default_api_timeout() -> u64 {
+    30
+}
+
+fn default_api_max_retry_time() -> u64 {
+    5 * 60
+}
+
+fn default_agent_instructions() -> Vec<String> {
+    vec!["AGENTS.md".to_owned(), "CONVENTIONS.md".to_owned()]
+}
+
+fn default_max_reprompts() -> usize {
+    2
+}
+
+fn default_bash_max_output_bytes() -> usize {
+    32768
+}
+
+fn default_cleanup_timeout() -> u64 {
+    60
+}
+
+fn default_max_actions() -> usize {
+    50
+}
+
+fn default_history_max_actions() -> usize {
+    5
+}
+
+fn default_history_max_summaries() -> usize {
+    10
+}
+
+fn default_history_max_tokens() -> usize {
+    20_000
+}
+
+fn default_memory_limit_mb() -> u64 {
+    8192
+}
+
+fn default_pids_limit() -> i64 {
+    4096
+}
+
+fn default_scratch_dir() -> String {
+    "/tmp/minion".to_owned()
+}
+
+fn default_llm_timeout() -> u64 {
+    120
+}
+
+fn default_smart_model() -> String {
+    "o1-mini".to_owned()
+}
+
+fn default_basic_model() -> String {
+    "gpt-4o-mini".to_owned()
+}
+
+fn default_restricted_models() -> Vec<String> {
+    vec!["o1-mini".to_owned(), "o1-preview".to_owned()]
+}
+
+fn default_vision_models() -> Vec<String> {
+    ["gpt-4o", "gpt-4o-mini", "gpt-4-turbo", "o1"].map(str::to_owned).to_vec()
+}
+
+fn default_allowed_packages() -> Vec<String> {
+    vec!["*".to_owned()]
+}
+
+fn default_embedding_model() -> String {
+    "text-embedding-3-small".to_owned()
+}
+
+fn default_retrieval_cache_dir() -> PathBuf {
+    PathBuf::from("retrieval-cache")
+}
+
+fn default_retrieval_max_chunks() -> usize {
+    10_000
+}
+
+fn default_always_include_max_bytes() -> usize {
+    16 * 1024
+}
+
+fn default_command_timeout() -> u64 {
+    30 * 60
+}
+
+fn default_max_container_restarts() -> usize {
+    2
+}
+
+fn default_true() -> bool {
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars(vars: &[(&str, &str)]) -> Vec<(String, String)> {
+        vars.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect()
+    }
+
+    #[test]
+    fn test_required_vars() {
+        let err = Config::from_vars(Vec::new()).err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "Missing required environment variables: MINION_API_BASE_URL, MINION_API_TOKEN"
+        );
+        let url = ("MINION_API_BASE_URL", "http://localhost:8080");
+        let err = Config::from_vars(vars(&[url, ("MINION_API_TOKEN", "")])).err().unwrap();
+        assert_eq!(err.to_string(), "Missing required environment variables: MINION_API_TOKEN");
+
+        let config = Config::from_vars(vars(&[url, ("MINION_API_TOKEN", "secret")])).unwrap();
+        assert_eq!(config.api_token.as_deref(), Some("secret"));
+        assert_eq!(config.branch_prefix, "minion/");
+        assert_eq!(config.max_concurrent_tasks.get(), 1);
+    }
+
+    #[test]
+    fn test_config_file() {
+        let path = std::env::temp_dir().join(format!("minion-config-{}.toml", std::process::id()));
+        let file = "api_base_url = \"http://localhost:8080\"\nsmart_model = \"gpt-4o\"\nmax_actions = 10\n";
+        std::fs::write(&path, file).unwrap();
+        let env = vars(&[
+            ("MINION_CONFIG", path.to_str().unwrap()),
+            ("MINION_API_TOKEN", "secret"),
+            ("MINION_MAX_ACTIONS", "20"),
+        ]);
+        let config = Config::from_env_and_file(env);
+        std::fs::remove_file(&path).unwrap();
+
+        let config = config.ok().unwrap();
+        assert_eq!(config.api_base_url.unwrap().as_str(), "http://localhost:8080/");
+        assert_eq!(config.smart_model, "gpt-4o");
+        assert_eq!(config.max_actions, 20);
+    }
+
+    #[test]
+    fn test_invalid_var() {
+        let invalid = vars(&[
+            ("MINION_API_BASE_URL", "http://localhost:8080"),
+            ("MINION_API_TOKEN", "secret"),
+            ("MINION_MAX_ACTIONS", "many"),
+        ]);
+        assert!(matches!(Config::from_vars(invalid), Err(ConfigError::Invalid(_))));
+        let invalid = vars(&[
+            ("MINION_API_BASE_URL", "http://localhost:8080"),
+            ("MINION_API_TOKEN", "secret"),
+            ("MINION_MAX_CONCURRENT_TASKS", "0"),
+        ]);
+        assert!(matches!(Config::from_vars(invalid), Err(ConfigError::Invalid(_))));
+    }
+
+    #[test]
+    fn test_invalid_patterns() {
+        let url = ("MINION_API_BASE_URL", "http://localhost:8080");
+        let token = ("MINION_API_TOKEN", "secret");
+        for (name, value) in [
+            ("MINION_PROTECTED_PATHS", "src/[a-"),
+            ("MINION_ALLOWED_PACKAGES", "python3-{a"),
+            ("MINION_REDACT_PATTERNS", "key-(\\d+"),
+        ] {
+            let err = Config::from_vars(vars(&[url, token, (name, value)])).err().unwrap();
+            assert!(matches!(err, ConfigError::Pattern { name: invalid, .. } if invalid == name));
+        }
     }
 }
@@ -0,0 +1,191 @@
+//! Reading the configuration from a TOML file
+//!
+//! The configuration is flat, so only top-level `key = value` pairs are supported, with strings,
+//! numbers, booleans and single-line arrays of those as values. Keys are the names of the
+//! environment variables without the `MINION_` prefix in snake case, e.g. `smart_model` for
+//! `MINION_SMART_MODEL`. The pairs are turned into environment variables, so the file is
+//! deserialized like the environment, e.g. arrays become comma-separated lists. Anything else,
+//! e.g. tables, multi-line strings or array items containing commas, is rejected with an error
+//! rather than misread.
+
+use std::fs;
+use std::path::Path;
+
+/// Read a configuration file as `MINION_` environment variables
+pub fn read(path: &Path) -> Result<Vec<(String, String)>, String> {
+    let text = fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read `{}`: {}", path.display(), e))?;
+    parse(&text).map_err(|e| format!("Failed to parse `{}`: {}", path.display(), e))
+}
+
+fn parse(text: &str) -> Result<Vec<(String, String)>, String> {
+    let mut vars = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let error = |message: &str| format!("{} on line {}", message, i + 1);
+        if line.starts_with('[') {
+            return Err(error("Tables are not supported"));
+        }
+        let (key, value) = line.split_once('=').ok_or_else(|| error("Expected `key = value`"))?;
+        let key = key.trim();
+        if key.contains(['.', '"', '\'']) {
+            return Err(error("Dotted and quoted keys are not supported"));
+        }
+        if key.is_empty() || !key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            return Err(error("Invalid key"));
+        }
+        let value = parse_value(value.trim()).map_err(|e| error(&e))?;
+        vars.push((format!("MINION_{}", key.to_uppercase()), value));
+    }
+    Ok(vars)
+}
+
+/// Parse a value with an optional trailing comment
+fn parse_value(text: &str) -> Result<String, String> {
+    if let Some(items) = text.strip_prefix('[') {
+        let mut values = Vec::new();
+        let mut rest = items.trim_start();
+        loop {
+            if let Some(rest) = rest.strip_prefix(']') {
+                expect_end(rest)?;
+                return Ok(values.join(","));
+            }
+            if rest.starts_with('[') {
+                return Err("Nested arrays are not supported".to_owned());
+            }
+            let (value, after) = parse_scalar(rest)?;
+            // The items are joined with commas, so a comma would split the item
+            if value.contains(',') {
+                return Err(format!("The array item `{}` must not contain `,`", value));
+            }
+            values.push(value);
+            rest = after.trim_start();
+            rest = rest.strip_prefix(',').unwrap_or(rest).trim_start();
+            if rest.is_empty() {
+                return Err("Unterminated array, arrays must be on a single line".to_owned());
+            }
+        }
+    }
+    let (value, rest) = parse_scalar(text)?;
+    expect_end(rest)?;
+    Ok(value)
+}
+
+/// Parse a string, number or boolean at the start of the text, returning it and the rest
+fn parse_scalar(text: &str) -> Result<(String, &str), String> {
+    if text.starts_with("\"\"\"") || text.starts_with("'''") {
+        return Err("Multi-line strings are not supported".to_owned());
+    }
+    if text.starts_with('{') {
+        return Err("Inline tables are not supported".to_owned());
+    }
+    if let Some(literal) = text.strip_prefix('\'') {
+        let end = literal.find('\'').ok_or("Unterminated string")?;
+        return Ok((literal[..end].to_owned(), &literal[end + 1..]));
+    }
+    if let Some(basic) = text.strip_prefix('"') {
+        let mut value = String::new();
+        let mut chars = basic.char_indices();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '"' => return Ok((value, &basic[i + 1..])),
+                '\\' => match chars.next().map(|(_, c)| c) {
+                    Some('n') => value.push('\n'),
+                    Some('t') => value.push('\t'),
+                    Some('r') => value.push('\r'),
+                    Some('b') => value.push('\u{8}'),
+                    Some('f') => value.push('\u{c}'),
+                    Some(c @ ('"' | '\\')) => value.push(c),
+                    Some(c @ ('u' | 'U')) => {
+                        let digits = if c == 'u' { 4 } else { 8 };
+                        let hex: String = chars.by_ref().take(digits).map(|(_, c)| c).collect();
+                        let unicode = u32::from_str_radix(&hex, 16).ok().and_then(char::from_u32);
+                        value.push(
+                            unicode.ok_or(format!("Invalid escape sequence `\\{}{}`", c, hex))?,
+                        );
+                    }
+                    Some(c) => return Err(format!("Invalid escape sequence `\\{}`", c)),
+                    None => return Err("Unterminated string".to_owned()),
+                },
+                c => value.push(c),
+            }
+        }
+        return Err("Unterminated string".to_owned());
+    }
+    let end = text.find([',', ']', '#', ' ']).unwrap_or(text.len());
+    let value = &text[..end];
+    let is_number = value.replace('_', "").parse::<f64>().is_ok();
+    if !(is_number || value == "true" || value == "false") {
+        return Err(format!(
+            "Unsupported value `{}`, only strings, numbers, booleans and arrays of them are \
+             supported, and strings must be quoted",
+            value
+        ));
+    }
+    Ok((value.to_owned(), &text[end..]))
+}
+
+/// Check that nothing but a comment follows a value
+fn expect_end(rest: &str) -> Result<(), String> {
+    let rest = rest.trim_start();
+    if rest.is_empty() || rest.starts_with('#') {
+        Ok(())
+    } else {
+        Err(format!("Unexpected `{}` after the value", rest))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let text = r#"
+# Models
+smart_model = "gpt-4o" # the model of hard steps
+max_actions = 50
+retrieval = true
+focus = ["src/main.rs", 'src/lib.rs']
+redact_patterns = 'ghp_\w+'
+"#;
+        let vars = parse(text).unwrap();
+        let vars: Vec<_> = vars.iter().map(|(k, v)| (k.as_str(), v.as_str())).collect();
+        assert_eq!(
+            vars,
+            [
+                ("MINION_SMART_MODEL", "gpt-4o"),
+                ("MINION_MAX_ACTIONS", "50"),
+                ("MINION_RETRIEVAL", "true"),
+                ("MINION_FOCUS", "src/main.rs,src/lib.rs"),
+                ("MINION_REDACT_PATTERNS", r"ghp_\w+"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!(parse("[models]").unwrap_err(), "Tables are not supported on line 1");
+        assert!(parse("smart_model = gpt-4o").unwrap_err().contains("must be quoted"));
+        assert!(parse("focus = [\"a\",\n\"b\"]").unwrap_err().contains("single line"));
+        assert!(parse("a = \"b\" c").unwrap_err().contains("Unexpected `c`"));
+        assert!(parse("redact_patterns = ['\\d{2,5}']")
+            .unwrap_err()
+            .contains("must not contain `,`"));
+        assert!(parse("a = \"\"\"b\"\"\"").unwrap_err().contains("Multi-line strings"));
+        assert!(parse("a = { b = 1 }").unwrap_err().contains("Inline tables"));
+        assert!(parse("a.b = 1").unwrap_err().contains("Dotted and quoted keys"));
+        assert!(parse("a = [[1], [2]]").unwrap_err().contains("Nested arrays"));
+        assert!(parse("a = 1979-05-27").unwrap_err().contains("Unsupported value"));
+    }
+
+    #[test]
+    fn test_parse_escapes() {
+        let vars = parse(r#"a = "tab\tquote\"\u00e9\U0001F600""#).unwrap();
+        assert_eq!(vars[0].1, "tab\tquote\"\u{e9}\u{1F600}");
+        assert!(parse(r#"a = "\x""#).unwrap_err().contains(r"Invalid escape sequence `\x`"));
+    }
+}
@@ -1,170 +1,863 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs;
 use std::io;
 use std::io::Read;
-use std::path::{Path, PathBuf};
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, Instant};
 
+use backoff::{Error as BackoffError, ExponentialBackoffBuilder};
 use bollard::container::LogOutput;
 use bollard::exec::{StartExecOptions, StartExecResults};
 use bollard::{image::CreateImageOptions, Docker};
 use futures_util::stream::TryStreamExt;
 use futures_util::StreamExt;
 use rand::{distributions::Alphanumeric, Rng};
+use serde::Deserialize;
+use thiserror::Error;
+use tokio::io::AsyncWriteExt;
+
+use devcontainer::{LifecycleCommand, Mount};
+
+use crate::actions::interactive::Interaction;
+
+const PULL_MAX_ELAPSED_TIME_IN_SECS: u64 = 300;
+/// The interval in which the progress of running commands is logged
+const PROGRESS_INTERVAL_IN_SECS: u64 = 30;
+/// How long a trivial command may take before the container is considered unresponsive
+const LIVENESS_TIMEOUT_IN_SECS: u64 = 10;
 
 pub struct Container {
     docker: Docker,
-    id: String,
+    /// The name of the container, which is unique per run and kept across restarts
+    name: String,
+    /// The ID of the current container, which changes when it is restarted
+    id: RwLock<String>,
+    /// The configuration the container was created with, to recreate it on restart
+    create_config: bollard::container::Config<String>,
     workspace_dir_container: String,
+    workspace_dir_host: PathBuf,
+    workspace_mount: WorkspaceMount,
+    shell: String,
+    /// The directory for scripts and other temporary files of the agent
+    scratch_dir: String,
+    /// The user commands are run as, the default user of the container if `None`
+    user: Option<String>,
+    /// The user and group ID of `user`, which own the files that are written
+    owner: Option<(u64, u64)>,
+    /// The `remoteEnv` of the devcontainer configuration as `KEY=VALUE`, set for every command
+    remote_env: Vec<String>,
+    /// The lifecycle commands of the devcontainer configuration, in the order they are run
+    lifecycle_commands: Vec<(&'static str, LifecycleCommand)>,
+    /// Whether the container has been removed by [`Container::stop`]
+    removed: bool,
+    /// The tag of the image that was built for the container, which is removed with it
+    built_image: Option<String>,
+    /// The paths that were written, deleted or moved since [`Container::take_written_paths`]
+    written_paths: Mutex<BTreeSet<String>>,
+}
+
+/// Shells that scripts can be run with, in order of preference
+const SHELLS: &[&str] = &["/bin/bash", "/bin/sh"];
+
+/// Runs a command (`$@`) while its PID is recorded in a file (`$0`), so it can be killed
+const TRACK_SCRIPT: &str = r#"{ mkdir -p "${0%/*}" && echo $$ > "$0"; } 2>/dev/null
+"$@"
+status=$?
+rm -f "$0"
+exit $status"#;
+
+/// Kills the commands whose PIDs are recorded in the files in a directory (`$1`), including the
+/// processes they started
+const KILL_SCRIPT: &str = r#"pids=$(echo $(cat "$1"/* 2>/dev/null))
+rm -f "$1"/*
+[ -n "$pids" ] || exit 0
+while :; do
+    added=
+    for status in /proc/[0-9]*/status; do
+        pid=${status#/proc/}
+        pid=${pid%/status}
+        ppid=
+        while read -r key value; do
+            if [ "$key" = PPid: ]; then ppid=$value; break; fi
+        done 2>/dev/null < "$status"
+        case " $pids " in
+            *" $pid "*) ;;
+            *" $ppid "*) pids="$pids $pid"; added=1 ;;
+        esac
+    done
+    [ -n "$added" ] || break
+done
+kill -9 $pids 2>/dev/null
+exit 0"#;
+
+/// How the workspace on the host is made available in the container
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum WorkspaceMount {
+    /// Bind mount the workspace, changes are applied to the host directly
+    #[default]
+    ReadWrite,
+    /// Bind mount the workspace read-only, the workspace cannot be changed
+    ReadOnly,
+    /// Copy the workspace into the container, changes are only synced back on request
+    Copy,
+}
+
+/// Resource limits of the container, so runaway commands can not exhaust the host
+///
+/// A limit of `0` means no limit.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ResourceLimits {
+    /// The memory limit in MiB, swap is not used beyond it
+    pub memory_mb: u64,
+    /// The number of CPUs, e.g. `1.5`
+    pub cpus: f64,
+    /// The maximum number of processes
+    pub pids: i64,
+}
+
+impl ResourceLimits {
+    fn apply(&self, host_config: &mut bollard::models::HostConfig) {
+        if self.memory_mb > 0 {
+            let bytes = i64::try_from(self.memory_mb * 1024 * 1024).unwrap_or(i64::MAX);
+            host_config.memory = Some(bytes);
+            host_config.memory_swap = Some(bytes);
+        }
+        if self.cpus > 0.0 {
+            host_config.nano_cpus = Some((self.cpus * 1e9) as i64);
+        }
+        if self.pids > 0 {
+            host_config.pids_limit = Some(self.pids);
+        }
+    }
+}
+
+/// A named Docker volume that is mounted into the container, e.g. for dependency caches
+///
+/// Volumes are configured as `<name>:<path>`. They are created if absent and outlive the
+/// container, so their content is reused across runs.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct VolumeMount {
+    pub name: String,
+    pub path: String,
+}
+
+impl FromStr for VolumeMount {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, path) = s
+            .trim()
+            .split_once(':')
+            .ok_or_else(|| format!("Volume `{}` is not in the format `<name>:<path>`", s))?;
+        let valid_name = name.starts_with(|c: char| c.is_ascii_alphanumeric())
+            && name.chars().all(|c| c.is_ascii_alphanumeric() || "_.-".contains(c));
+        if !valid_name {
+            return Err(format!("Invalid volume name `{}`", name));
+        }
+        if !path.starts_with('/') {
+            return Err(format!("The mount path `{}` of volume `{}` is not absolute", path, name));
+        }
+        Ok(Self { name: name.to_owned(), path: path.to_owned() })
+    }
+}
+
+impl<'de> Deserialize<'de> for VolumeMount {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?.parse().map_err(serde::de::Error::custom)
+    }
 }
 
 impl Container {
-    pub async fn start<P1: AsRef<Path>>(workspace_dir_host: P1, workspace_dir_name: &str) -> Self {
+    pub async fn start<P1: AsRef<Path>>(
+        workspace_dir_host: P1,
+        workspace_dir_name: &str,
+        workspace_mount: WorkspaceMount,
+        volumes: &[VolumeMount],
+        scratch_dir: &str,
+        limits: ResourceLimits,
+    ) -> Result<Self, StartError> {
+        // The path is quoted in shell commands, so it must not contain quotes
+        if !scratch_dir.starts_with('/') || scratch_dir.contains('\'') {
+            return Err(StartError::InvalidScratchDir { path: scratch_dir.to_owned() });
+        }
         let workspace_dir = workspace_dir_host.as_ref();
-        let workspace_dir_container = format!("/workspaces/{}", workspace_dir_name);
+
+        // The workspace is referred to by its absolute path, so it does not depend on the current
+        // working directory of the process
+        let workspace_dir = workspace_dir.canonicalize().map_err(|source| {
+            StartError::InvalidWorkspace { path: workspace_dir.to_owned(), source }
+        })?;
+        if !workspace_dir.is_dir() {
+            return Err(StartError::InvalidWorkspace {
+                path: workspace_dir,
+                source: io::Error::other("Not a directory"),
+            });
+        }
+        let workspace_dir = workspace_dir.as_path();
 
         // Check for a devcontainer configuration
-        let devcontainer =
-            devcontainer::load(workspace_dir).expect("Failed to load devcontainer.json");
-        let docker_image = devcontainer.image.expect("No image specified in devcontainer.json");
+        let resolved = devcontainer::resolve(workspace_dir)
+            .map_err(|err| StartError::Devcontainer(err.to_string()))?;
+        let devcontainer = &resolved.devcontainer;
 
-        let docker = Docker::connect_with_local_defaults().expect("Failed to connect to Docker");
-        let mut create_image = docker.create_image(
-            Some(CreateImageOptions { from_image: docker_image.clone(), ..Default::default() }),
-            None,
-            None,
+        // The workspace is always the source of the mount, as the changes are taken from it, so
+        // only the target of `workspaceMount` is used
+        let workspace_dir_container = match devcontainer.workspace_mount_target() {
+            Some(target) => target.trim_end_matches('/').to_owned(),
+            None => format!("/workspaces/{}", workspace_dir_name),
+        };
+        if let Some(folder) = &devcontainer.workspace_folder {
+            if folder.trim_end_matches('/') != workspace_dir_container {
+                log::warn!(
+                    "`workspaceFolder` `{}` is not the target of `workspaceMount`, using `{}`",
+                    folder,
+                    workspace_dir_container
+                );
+            }
+        }
+
+        // Unless disabled, replace the command of the image to ensure the container stays running.
+        // Otherwise, the command of the image (e.g. a service) is expected to keep it running.
+        let cmd = if devcontainer.override_command.unwrap_or(true) {
+            Some(vec!["tail".to_owned(), "-f".to_owned(), "/dev/null".to_owned()])
+        } else {
+            None
+        };
+
+        let bind = format!("{}:{}", workspace_dir.display(), workspace_dir_container);
+        let binds = match workspace_mount {
+            WorkspaceMount::ReadWrite => Some(vec![bind]),
+            WorkspaceMount::ReadOnly => Some(vec![format!("{}:ro", bind)]),
+            WorkspaceMount::Copy => None,
+        };
+
+        let random_str: String =
+            rand::thread_rng().sample_iter(&Alphanumeric).take(8).map(char::from).collect();
+        let suffix = random_str.to_lowercase();
+
+        let docker = Docker::connect_with_local_defaults().map_err(StartError::Connect)?;
+        // A prebuilt image takes precedence over building one
+        let docker_image = match (&devcontainer.image, resolved.build_paths()) {
+            (Some(image), _) => {
+                pull_image(&docker, image).await?;
+                image.clone()
+            }
+            (None, Some((dockerfile, context))) => {
+                // Concurrent tasks of the same repository may build different Dockerfiles
+                let tag = image_tag(workspace_dir_name, &suffix);
+                let args = &devcontainer.build.as_ref().expect("Build paths without build").args;
+                build_image(&docker, &dockerfile, &context, args, &tag).await?;
+                tag
+            }
+            (None, None) => return Err(StartError::NoImage),
+        };
+        let built_image = devcontainer.image.is_none().then(|| docker_image.clone());
+
+        let mut mounts = Vec::new();
+        for volume in volumes {
+            ensure_volume(&docker, &volume.name)
+                .await
+                .map_err(|source| StartError::Volume { name: volume.name.clone(), source })?;
+            mounts.push(bollard::models::Mount {
+                target: Some(volume.path.clone()),
+                source: Some(volume.name.clone()),
+                typ: Some(bollard::models::MountTypeEnum::VOLUME),
+                ..Default::default()
+            });
+        }
+
+        // `${containerEnv:NAME}` refers to the environment of the image for `containerEnv`, and
+        // additionally to `containerEnv` for `remoteEnv`
+        let mut env = image_env(&docker, &docker_image).await.map_err(StartError::InspectImage)?;
+        let container_env = devcontainer::resolve_env(
+            &devcontainer.container_env.clone().unwrap_or_default(),
+            &env,
         );
-        while let Some(_status) = create_image.try_next().await.unwrap() {}
+        env.extend(container_env.clone());
+        let remote_env =
+            devcontainer::resolve_env(&devcontainer.remote_env.clone().unwrap_or_default(), &env);
+
+        for mount in devcontainer.mounts.iter().flatten() {
+            let Some(docker_mount) = docker_mount(mount) else {
+                log::warn!("Skipping mount of unsupported type `{}`", mount.mount_type);
+                continue;
+            };
+            if let (Some(name), "volume") = (&mount.source, mount.mount_type.as_str()) {
+                ensure_volume(&docker, name)
+                    .await
+                    .map_err(|source| StartError::Volume { name: name.clone(), source })?;
+            }
+            mounts.push(docker_mount);
+        }
+
+        let mut host_config =
+            bollard::models::HostConfig { binds, mounts: Some(mounts), ..Default::default() };
+        limits.apply(&mut host_config);
+        apply_run_args(devcontainer.run_args.as_deref().unwrap_or_default(), &mut host_config);
 
         let config = bollard::container::Config {
             image: Some(docker_image),
-            host_config: Some(bollard::models::HostConfig {
-                binds: Some(vec![format!(
-                    "{}:{}",
-                    workspace_dir.canonicalize().unwrap().to_str().unwrap(),
-                    workspace_dir_container
-                )]),
-                ..Default::default()
-            }),
-            // Ensure the container stays running
+            env: Some(env_list(container_env)),
+            host_config: Some(host_config),
             tty: Some(true),
-            cmd: Some(vec!["tail".to_owned(), "-f".to_owned(), "/dev/null".to_owned()]),
+            cmd,
+            user: devcontainer.container_user.clone(),
             ..Default::default()
         };
+        let user = devcontainer.remote_user.clone().or(devcontainer.container_user.clone());
 
-        let response = docker
-            .create_container(
-                Some(bollard::container::CreateContainerOptions {
-                    name: "minion-devcontainer",
-                    platform: None,
-                }),
-                config,
-            )
-            .await
-            .expect("Failed to create container");
+        let lifecycle_commands = [
+            ("onCreateCommand", &devcontainer.on_create_command),
+            ("postCreateCommand", &devcontainer.post_create_command),
+            ("postStartCommand", &devcontainer.post_start_command),
+        ]
+        .into_iter()
+        .filter_map(|(name, command)| Some((name, command.clone()?)))
+        .collect();
 
-        docker
-            .start_container(
-                &response.id,
-                None::<bollard::container::StartContainerOptions<String>>,
-            )
-            .await
-            .expect("Failed to start container");
+        let name = container_name(workspace_dir_name, &suffix);
+        let id =
+            create_and_start(&docker, &name, config.clone()).await.map_err(StartError::Create)?;
 
-        Self { docker, id: response.id, workspace_dir_container }
+        let container = Self {
+            docker,
+            name,
+            id: RwLock::new(id),
+            create_config: config,
+            workspace_dir_container,
+            workspace_dir_host: workspace_dir.to_owned(),
+            workspace_mount,
+            shell: String::new(),
+            scratch_dir: scratch_dir.trim_end_matches('/').to_owned(),
+            user,
+            owner: None,
+            remote_env: env_list(remote_env),
+            lifecycle_commands,
+            removed: false,
+            built_image,
+            written_paths: Mutex::default(),
+        };
+        let container = container.probe_capabilities().await?;
+
+        if workspace_mount == WorkspaceMount::Copy {
+            container.copy_workspace_to_container().await?;
+        }
+        container.run_lifecycle_commands().await?;
+
+        Ok(container)
     }
 
     pub fn workspace_dir_container(&self) -> &str {
         &self.workspace_dir_container
     }
 
-    pub async fn run_script(&self, code: &str) -> Output {
-        // Generate a unique filename for the script
-        let random_str: String =
-            rand::thread_rng().sample_iter(&Alphanumeric).take(16).map(char::from).collect();
+    /// The name of the container, e.g. `minion-my-repo-k3x9q2ab`
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn id(&self) -> String {
+        self.id.read().unwrap().clone()
+    }
+
+    /// The ID of the image the container was created from
+    pub async fn image_id(&self) -> Result<String, bollard::errors::Error> {
+        let inspect = self.docker.inspect_container(&self.id(), None).await?;
+        Ok(inspect.image.unwrap_or_default())
+    }
+
+    /// Whether the container still runs commands in a timely manner
+    pub async fn is_responsive(&self) -> bool {
+        let probe = self.exec(vec![&self.shell, "-c", "true"]);
+        matches!(
+            tokio::time::timeout(Duration::from_secs(LIVENESS_TIMEOUT_IN_SECS), probe).await,
+            Ok(Ok(output)) if output.exit_code == 0
+        )
+    }
+
+    /// Replace the container by a new one that is set up the same way, e.g. if it is wedged
+    ///
+    /// Everything outside of the workspace is reset. With [`WorkspaceMount::Copy`], changes to the
+    /// workspace that have not been synced to the host are lost as well.
+    pub async fn restart(&self) -> Result<(), StartError> {
+        remove_container(&self.docker, &self.id()).await.map_err(StartError::Restart)?;
+
+        let id = create_and_start(&self.docker, &self.name, self.create_config.clone())
+            .await
+            .map_err(StartError::Restart)?;
+        *self.id.write().unwrap() = id;
+
+        if !self.is_responsive().await {
+            return Err(StartError::Unresponsive);
+        }
+        if let Err(problem) = self.prepare_scratch_dir().await {
+            return Err(StartError::MissingCapabilities { missing: vec![problem] });
+        }
+        if self.workspace_mount == WorkspaceMount::Copy {
+            self.copy_workspace_to_container().await?;
+        }
+        // The new container has not been set up yet
+        self.run_lifecycle_commands().await
+    }
+
+    /// Run the lifecycle commands (e.g. `postCreateCommand`) in order, logging their output
+    ///
+    /// Named commands, which are meant to run in parallel, are run one after another.
+    async fn run_lifecycle_commands(&self) -> Result<(), StartError> {
+        for (name, command) in &self.lifecycle_commands {
+            for command in flatten_lifecycle_command(command) {
+                log::info!("Running {}: {:?}", name, command);
+                let mut log_chunk = |chunk: OutputChunk| match chunk {
+                    OutputChunk::Stdout(text) | OutputChunk::Stderr(text) => {
+                        log::info!("[{}] {}", name, text.trim_end())
+                    }
+                };
+                let output = match command {
+                    LifecycleCommand::Shell(script) => {
+                        self.run_script_streaming(script, &mut log_chunk).await.map_err(|err| {
+                            StartError::LifecycleCommand { name, reason: err.to_string() }
+                        })?
+                    }
+                    LifecycleCommand::Exec(args) => {
+                        let args = args.iter().map(String::as_str).collect();
+                        self.exec_streaming(args, &mut log_chunk).await.map_err(|err| {
+                            StartError::LifecycleCommand { name, reason: err.to_string() }
+                        })?
+                    }
+                    LifecycleCommand::Parallel(_) => unreachable!("Commands are flattened"),
+                };
+                if output.exit_code != 0 {
+                    let reason = format!("exit status {}", output.exit_code);
+                    return Err(StartError::LifecycleCommand { name, reason });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Remove the container, including its processes and anything outside of the workspace
+    ///
+    /// If a container is dropped without being stopped, e.g. due to a panic, it is removed on a
+    /// best-effort basis.
+    pub async fn stop(mut self) -> Result<(), bollard::errors::Error> {
+        self.remove().await?;
+        self.removed = true;
+        Ok(())
+    }
+
+    /// Remove the container and the image that was built for it
+    ///
+    /// Only the tag and the top layer of the image are removed, its parents are kept as the build
+    /// cache of the next task.
+    async fn remove(&self) -> Result<(), bollard::errors::Error> {
+        remove_container(&self.docker, &self.id()).await?;
+        if let Some(image) = &self.built_image {
+            let options = bollard::image::RemoveImageOptions { noprune: true, force: false };
+            if let Err(err) = self.docker.remove_image(image, Some(options), None).await {
+                log::warn!("Failed to remove the image `{}`: {}", image, err);
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether commands are run as root, which is assumed if the user is unknown
+    pub fn is_root(&self) -> bool {
+        self.owner.is_none_or(|(uid, _)| uid == 0)
+    }
+
+    /// Make the user commands are run as the owner of a file in an archive
+    fn set_owner(&self, header: &mut tar::Header) {
+        if let Some((uid, gid)) = self.owner {
+            header.set_uid(uid);
+            header.set_gid(gid);
+        }
+    }
+
+    /// Whether scripts are run with bash, rather than a fallback shell
+    pub fn has_bash(&self) -> bool {
+        self.shell == SHELLS[0]
+    }
+
+    /// Check that the container provides what the actions rely on
+    ///
+    /// Scripts are run with bash, falling back to sh if bash is not available. They are uploaded
+    /// to the scratch directory, which is created if necessary and must be writable and allow
+    /// executing files.
+    async fn probe_capabilities(mut self) -> Result<Self, StartError> {
+        let mut missing = Vec::new();
+
+        for shell in SHELLS {
+            match self.exec(vec![shell, "-c", "true"]).await {
+                Ok(output) if output.exit_code == 0 => {
+                    self.shell = (*shell).to_owned();
+                    break;
+                }
+                _ => log::warn!("Shell `{}` is not available in the container", shell),
+            }
+        }
+        if self.shell.is_empty() {
+            missing.push(format!("a shell ({})", SHELLS.join(" or ")));
+        } else {
+            match self.exec(vec![&self.shell, "-c", "id -u && id -g"]).await {
+                Ok(output) if output.exit_code == 0 => self.owner = parse_ids(&output.stdout),
+                _ => match &self.user {
+                    Some(user) => missing.push(format!("the user `{}`", user)),
+                    None => log::warn!("Failed to determine the user of the container"),
+                },
+            }
+            if let Err(problem) = self.prepare_scratch_dir().await {
+                missing.push(problem);
+            }
+        }
+
+        if !missing.is_empty() {
+            return Err(StartError::MissingCapabilities { missing });
+        }
+        if self.shell != SHELLS[0] {
+            log::warn!("Running scripts with `{}` instead of `{}`", self.shell, SHELLS[0]);
+        }
+        Ok(self)
+    }
+
+    /// Create the scratch directory, only accessible by the user of the agent, and check that
+    /// files in it can be written and executed
+    ///
+    /// Returns a description of the problem otherwise.
+    async fn prepare_scratch_dir(&self) -> Result<(), String> {
+        let dir = &self.scratch_dir;
+        let create = format!("mkdir -p '{}' && chmod 700 '{}'", dir, dir);
+        match self.exec(vec![&self.shell, "-c", &create]).await {
+            Ok(output) if output.exit_code == 0 => {}
+            Ok(output) => {
+                let reason = output.stderr.trim().to_owned();
+                return Err(format!("a scratch directory `{}` ({})", dir, reason));
+            }
+            Err(err) => return Err(format!("a scratch directory `{}` ({})", dir, err)),
+        }
 
-        let script_filename = format!("minion-script-{}.sh", random_str);
-        let script_path_container = format!("/tmp/{}", script_filename);
+        let probe = format!("{}/minion-probe.sh", dir);
+        if let Err(err) = self.upload_file(&probe, b"#!/bin/sh\ntrue\n").await {
+            return Err(format!("a writable scratch directory `{}` ({})", dir, err));
+        }
+        match self.exec(vec![&probe]).await {
+            Ok(output) if output.exit_code == 0 => Ok(()),
+            // e.g. if the directory is on a file system that is mounted with `noexec`
+            _ => Err(format!("a scratch directory `{}` that allows executing files", dir)),
+        }
+    }
 
-        // Create a tar archive containing the script file
+    /// Upload a single file, replacing any existing file
+    async fn upload_file(&self, path: &str, content: &[u8]) -> Result<(), bollard::errors::Error> {
         let mut tar_buffer = Vec::new();
         {
             let mut tar_builder = tar::Builder::new(&mut tar_buffer);
             let mut header = tar::Header::new_gnu();
-            header.set_size(code.len() as u64);
+            header.set_size(content.len() as u64);
             header.set_mode(0o755);
+            self.set_owner(&mut header);
             header.set_cksum();
             // Convert absolute path to relative path for the tar archive
-            let script_path_in_tar =
-                script_path_container.strip_prefix('/').unwrap_or(&script_path_container);
+            let path_in_tar = path.strip_prefix('/').unwrap_or(path);
             tar_builder
-                .append_data(&mut header, script_path_in_tar, code.as_bytes())
+                .append_data(&mut header, path_in_tar, content)
                 .expect("Failed to append data to tar archive");
             tar_builder.finish().expect("Failed to finish tar archive");
         }
 
-        // Upload the script to the container
         let options =
             bollard::container::UploadToContainerOptions { path: "/", ..Default::default() };
-        self.docker
-            .upload_to_container(&self.id, Some(options), tar_buffer.into())
-            .await
-            .expect("Failed to upload script to container");
+        self.docker.upload_to_container(&self.id(), Some(options), tar_buffer.into()).await
+    }
 
-        // Execute the script in the container
+    /// Run a command in the workspace directory and collect its output
+    async fn exec(&self, cmd: Vec<&str>) -> Result<Output, bollard::errors::Error> {
+        self.exec_streaming(cmd, &mut |_| {}).await
+    }
+
+    /// Run a command in the workspace directory, passing its output to `on_chunk` as it arrives
+    ///
+    /// The progress of long-running commands is logged periodically.
+    async fn exec_streaming(
+        &self,
+        cmd: Vec<&str>,
+        on_chunk: &mut (dyn FnMut(OutputChunk) + Send),
+    ) -> Result<Output, bollard::errors::Error> {
         let config = bollard::exec::CreateExecOptions {
-            cmd: Some(vec!["/bin/bash", &script_path_container]),
+            cmd: Some(cmd),
             working_dir: Some(self.workspace_dir_container()),
+            user: self.user.as_deref(),
+            env: Some(self.remote_env.iter().map(String::as_str).collect()),
             attach_stdout: Some(true),
             attach_stderr: Some(true),
             ..Default::default()
         };
 
-        let exec_instance = self
-            .docker
-            .create_exec(&self.id, config)
-            .await
-            .expect("Failed to create exec instance");
-        let exec_id = exec_instance.id;
+        let exec_id = self.docker.create_exec(&self.id(), config).await?.id;
 
         let start_options = StartExecOptions { detach: false, tty: false, output_capacity: None };
 
-        let StartExecResults::Attached { mut output, .. } = self
-            .docker
-            .start_exec(&exec_id, Some(start_options))
-            .await
-            .expect("Failed to start exec")
+        let StartExecResults::Attached { mut output, .. } =
+            self.docker.start_exec(&exec_id, Some(start_options)).await?
         else {
             panic!("Failed to start exec in attached mode")
         };
 
         let mut stdout = Vec::new();
         let mut stderr = Vec::new();
+        let mut combined = Vec::new();
 
-        while let Some(msg) = output.next().await {
-            match msg.expect("Failed to read exec output") {
-                LogOutput::StdOut { message } => stdout.extend_from_slice(&message),
-                LogOutput::StdErr { message } => stderr.extend_from_slice(&message),
+        let started = Instant::now();
+        let mut progress = tokio::time::interval(Duration::from_secs(PROGRESS_INTERVAL_IN_SECS));
+        // The first tick completes immediately
+        progress.tick().await;
+
+        loop {
+            let msg = tokio::select! {
+                msg = output.next() => msg,
+                _ = progress.tick() => {
+                    log::info!(
+                        "Command still running after {}s ({} bytes of output so far)",
+                        started.elapsed().as_secs(),
+                        stdout.len() + stderr.len()
+                    );
+                    continue;
+                }
+            };
+            let Some(msg) = msg else {
+                break;
+            };
+            match msg? {
+                LogOutput::StdOut { message } => {
+                    on_chunk(OutputChunk::Stdout(&String::from_utf8_lossy(&message)));
+                    stdout.extend_from_slice(&message);
+                    combined.extend_from_slice(&message);
+                }
+                LogOutput::StdErr { message } => {
+                    on_chunk(OutputChunk::Stderr(&String::from_utf8_lossy(&message)));
+                    stderr.extend_from_slice(&message);
+                    combined.extend_from_slice(&message);
+                }
                 _ => {}
             }
         }
 
-        let exec_inspect =
-            self.docker.inspect_exec(&exec_id).await.expect("Failed to inspect exec");
+        let exec_inspect = self.docker.inspect_exec(&exec_id).await?;
 
         let exit_code = exec_inspect.exit_code.unwrap_or(0);
 
-        Output {
+        Ok(Output {
             exit_code,
             stdout: String::from_utf8_lossy(&stdout).to_string(),
             stderr: String::from_utf8_lossy(&stderr).to_string(),
+            combined: String::from_utf8_lossy(&combined).to_string(),
+        })
+    }
+
+    async fn copy_workspace_to_container(&self) -> Result<(), StartError> {
+        let mut tar_buffer = Vec::new();
+        {
+            let mut tar_builder = tar::Builder::new(&mut tar_buffer);
+            let workspace_dir_in_tar = self
+                .workspace_dir_container
+                .strip_prefix('/')
+                .unwrap_or(&self.workspace_dir_container);
+            tar_builder
+                .append_dir_all(workspace_dir_in_tar, &self.workspace_dir_host)
+                .and_then(|_| tar_builder.finish())
+                .map_err(|err| StartError::CopyWorkspace(err.to_string()))?;
+        }
+
+        let options =
+            bollard::container::UploadToContainerOptions { path: "/", ..Default::default() };
+        self.docker
+            .upload_to_container(&self.id(), Some(options), tar_buffer.into())
+            .await
+            .map_err(|err| StartError::CopyWorkspace(err.to_string()))
+    }
+
+    /// Sync changes to the workspace in the container back to the host
+    ///
+    /// This is only required for [`WorkspaceMount::Copy`], otherwise it does nothing.
+    /// The `.git` directory on the host is left untouched.
+    pub async fn sync_workspace_to_host(&self) -> Result<(), String> {
+        if self.workspace_mount != WorkspaceMount::Copy {
+            return Ok(());
+        }
+
+        let options = bollard::container::DownloadFromContainerOptions {
+            path: self.workspace_dir_container.as_str(),
+        };
+        let bytes = self
+            .docker
+            .download_from_container(&self.id(), Some(options))
+            .try_fold(Vec::new(), |mut bytes, chunk| async move {
+                bytes.extend_from_slice(&chunk);
+                Ok(bytes)
+            })
+            .await
+            .map_err(|e| e.to_string())?;
+
+        // Remove the old files, so files deleted in the container are deleted on the host as well
+        for entry in fs::read_dir(&self.workspace_dir_host).map_err(|e| e.to_string())? {
+            let path = entry.map_err(|e| e.to_string())?.path();
+            if path.file_name() == Some(".git".as_ref()) {
+                continue;
+            }
+            if path.is_dir() {
+                fs::remove_dir_all(&path).map_err(|e| e.to_string())?;
+            } else {
+                fs::remove_file(&path).map_err(|e| e.to_string())?;
+            }
         }
+
+        let mut archive = tar::Archive::new(io::Cursor::new(bytes));
+        for entry in archive.entries().map_err(|e| e.to_string())? {
+            let mut entry = entry.map_err(|e| e.to_string())?;
+            let path = entry.path().map_err(|e| e.to_string())?.into_owned();
+            // Entries are prefixed with the name of the workspace directory
+            let relative: PathBuf = path.components().skip(1).collect();
+            let is_git = relative.components().next() == Some(Component::Normal(".git".as_ref()));
+            let is_unsafe = relative.components().any(|c| !matches!(c, Component::Normal(_)));
+            if relative.as_os_str().is_empty() || is_git || is_unsafe {
+                continue;
+            }
+            let target = self.workspace_dir_host.join(&relative);
+            if let Some(parent) = target.parent() {
+                fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+            }
+            entry.unpack(&target).map_err(|e| e.to_string())?;
+        }
+
+        Ok(())
+    }
+
+    /// Run a script, passing its output to `on_chunk` as it arrives
+    pub async fn run_script_streaming(
+        &self,
+        code: &str,
+        on_chunk: &mut (dyn FnMut(OutputChunk) + Send),
+    ) -> Result<Output, bollard::errors::Error> {
+        let script_path_container = self.upload_script(code).await?;
+
+        // Execute the script in the container
+        let cmd = self.tracked_command(&[&self.shell, &script_path_container]);
+        self.exec_streaming(cmd.iter().map(String::as_str).collect(), on_chunk).await
+    }
+
+    /// A command that runs `cmd` so that it can be killed by [`Container::kill_running_commands`]
+    fn tracked_command(&self, cmd: &[&str]) -> Vec<String> {
+        let random_str: String =
+            rand::thread_rng().sample_iter(&Alphanumeric).take(16).map(char::from).collect();
+        let pid_file = format!("{}/running/{}", self.scratch_dir, random_str);
+        let mut tracked =
+            vec![self.shell.clone(), "-c".to_owned(), TRACK_SCRIPT.to_owned(), pid_file];
+        tracked.extend(cmd.iter().map(|arg| arg.to_string()));
+        tracked
+    }
+
+    /// Kill the scripts and interactive commands that are still running, e.g. after the action
+    /// that started them was cancelled, including the processes they started
+    ///
+    /// Processes that detached from the commands, e.g. daemons, keep running.
+    pub async fn kill_running_commands(&self) -> Result<(), bollard::errors::Error> {
+        let running_dir = format!("{}/running", self.scratch_dir);
+        self.exec(vec![&self.shell, "-c", KILL_SCRIPT, "kill", &running_dir]).await.map(|_| ())
+    }
+
+    /// The paths that were written, deleted or moved since the last call, e.g. by an action that
+    /// was cancelled before it was recorded
+    pub fn take_written_paths(&self) -> BTreeSet<String> {
+        std::mem::take(&mut self.written_paths.lock().unwrap())
+    }
+
+    fn record_written_path<P: AsRef<Path>>(&self, path: P) {
+        let path = path.as_ref().to_string_lossy().into_owned();
+        self.written_paths.lock().unwrap().insert(path);
+    }
+
+    /// Run a script like [`Container::run_script_streaming`], but in the session of the previous
+    /// session scripts
+    ///
+    /// The working directory and the exported variables (e.g. of an activated virtualenv) at the
+    /// end of a session script are restored before the next one. Shell functions, aliases and
+    /// variables that are not exported are not kept. The session is reset on restart.
+    pub async fn run_session_script_streaming(
+        &self,
+        code: &str,
+        on_chunk: &mut (dyn FnMut(OutputChunk) + Send),
+    ) -> Result<Output, bollard::errors::Error> {
+        let script_path_container = self.upload_script(code).await?;
+        let state_dir = format!("{}/session", self.scratch_dir);
+        let wrapper = session_script(&state_dir, &script_path_container);
+
+        let cmd = self.tracked_command(&[&self.shell, "-c", &wrapper]);
+        self.exec_streaming(cmd.iter().map(String::as_str).collect(), on_chunk).await
+    }
+
+    /// Upload a script to a unique path in the scratch directory, returning the path
+    async fn upload_script(&self, code: &str) -> Result<String, bollard::errors::Error> {
+        let random_str: String =
+            rand::thread_rng().sample_iter(&Alphanumeric).take(16).map(char::from).collect();
+
+        let script_path_container = format!("{}/minion-script-{}.sh", self.scratch_dir, random_str);
+
+        self.upload_file(&script_path_container, code.as_bytes()).await?;
+        Ok(script_path_container)
     }
 
+    /// Read a text file, failing with [`ReadFileError::NotText`] if it is not valid UTF-8
     pub async fn read_file<P: AsRef<Path>>(&self, file_path: P) -> Result<String, ReadFileError> {
-        let file_path = self.resolve_path(file_path);
+        let bytes = self.read_file_bytes(file_path).await?;
+        String::from_utf8(bytes).map_err(|_| ReadFileError::NotText)
+    }
+
+    /// Read a file, which may be binary
+    pub async fn read_file_bytes<P: AsRef<Path>>(
+        &self,
+        file_path: P,
+    ) -> Result<Vec<u8>, ReadFileError> {
+        let bytes = self.download(file_path).await?;
+
+        let mut archive = tar::Archive::new(io::Cursor::new(bytes));
+        let mut content = Vec::new();
+
+        if let Some(entry) =
+            archive.entries().map_err(|e| ReadFileError::Other(e.to_string()))?.next()
+        {
+            let mut file = entry.map_err(|e| ReadFileError::Other(e.to_string()))?;
+            // Downloading a directory yields an archive of the directory
+            if file.header().entry_type().is_dir() {
+                return Err(ReadFileError::IsDirectory);
+            }
+            file.read_to_end(&mut content).map_err(|e| ReadFileError::Other(e.to_string()))?;
+        } else {
+            return Err(ReadFileError::NotFound);
+        }
+
+        Ok(content)
+    }
+
+    /// List the entries of a directory, sorted by name
+    pub async fn list_dir<P: AsRef<Path>>(&self, path: P) -> Result<Vec<DirEntry>, ListDirError> {
+        let bytes = self.download(path).await.map_err(|err| match err {
+            ReadFileError::NotFound => ListDirError::NotFound,
+            ReadFileError::PermissionDenied => ListDirError::PermissionDenied,
+            err => ListDirError::Other(err.to_string()),
+        })?;
+        dir_entries(tar::Archive::new(io::Cursor::new(bytes)))
+    }
+
+    /// Download a file or directory as a tar archive
+    async fn download<P: AsRef<Path>>(&self, path: P) -> Result<Vec<u8>, ReadFileError> {
+        let file_path = self.resolve_path(path);
 
         let options =
             bollard::container::DownloadFromContainerOptions { path: file_path.to_str().unwrap() };
 
-        let mut stream = self.docker.download_from_container(&self.id, Some(options));
+        let mut stream = self.docker.download_from_container(&self.id(), Some(options));
 
         let mut bytes = Vec::new();
         loop {
@@ -177,33 +870,24 @@ impl Container {
                 }
                 Err(e) => {
                     if let bollard::errors::Error::DockerResponseServerError {
-                        status_code, ..
+                        status_code,
+                        message,
                     } = &e
                     {
                         if *status_code == 404 {
                             // File not found
                             return Err(ReadFileError::NotFound);
                         }
+                        if message.to_lowercase().contains("permission denied") {
+                            return Err(ReadFileError::PermissionDenied);
+                        }
                     }
                     // Other errors
                     return Err(ReadFileError::Other(e.to_string()));
                 }
             }
         }
-
-        let mut archive = tar::Archive::new(io::Cursor::new(bytes));
-        let mut content = String::new();
-
-        if let Some(entry) =
-            archive.entries().map_err(|e| ReadFileError::Other(e.to_string()))?.next()
-        {
-            let mut file = entry.map_err(|e| ReadFileError::Other(e.to_string()))?;
-            file.read_to_string(&mut content).map_err(|e| ReadFileError::Other(e.to_string()))?;
-        } else {
-            return Err(ReadFileError::NotFound);
-        }
-
-        Ok(content)
+        Ok(bytes)
     }
 
     pub async fn write_file<P: AsRef<Path>>(
@@ -211,22 +895,29 @@ impl Container {
         file_path: P,
         content: &str,
     ) -> Result<(), String> {
-        let file_path = self.resolve_path(file_path);
+        self.write_files(&[(file_path, content)]).await
+    }
 
-        // Create a tar archive containing the file and necessary directories
+    /// Write several files at once
+    ///
+    /// The files are uploaded in a single archive, so either all of them are written or none.
+    pub async fn write_files<P: AsRef<Path>>(&self, files: &[(P, &str)]) -> Result<(), String> {
+        // Create a tar archive containing the files and necessary directories
         let mut tar_buffer = Vec::new();
         {
             let mut tar_builder = tar::Builder::new(&mut tar_buffer);
 
-            // Collect all parent directories of the file path
-            let mut dirs = Vec::new();
-            let mut current = file_path.parent();
-            while let Some(parent) = current {
-                dirs.push(parent.to_path_buf());
-                current = parent.parent();
+            // Collect all parent directories of the file paths
+            // The set is ordered, so directories are created from root to leaf
+            let mut dirs = BTreeSet::new();
+            for (file_path, _) in files {
+                let file_path = self.resolve_path(file_path);
+                let mut current = file_path.parent();
+                while let Some(parent) = current {
+                    dirs.insert(parent.to_path_buf());
+                    current = parent.parent();
+                }
             }
-            // Reverse to ensure directories are created from root to leaf
-            dirs.reverse();
 
             // Add directory entries to the tar archive
             for dir in dirs {
@@ -237,21 +928,30 @@ impl Container {
                     header.set_entry_type(tar::EntryType::Directory);
                     header.set_mode(0o755);
                     header.set_size(0);
+                    // Only directories in the workspace are created for the user, the others
+                    // (e.g. `/workspaces`) keep their owner
+                    if dir.starts_with(&self.workspace_dir_container) {
+                        self.set_owner(&mut header);
+                    }
                     header.set_cksum();
                     tar_builder.append(&header, &[] as &[u8]).map_err(|e| e.to_string())?;
                 }
             }
 
-            // Add the file entry to the tar archive
-            let file_path_in_tar = file_path.strip_prefix("/").unwrap_or(&file_path);
-            let mut header = tar::Header::new_gnu();
-            header.set_path(file_path_in_tar).map_err(|e| e.to_string())?;
-            header.set_size(content.len() as u64);
-            header.set_mode(0o644);
-            header.set_cksum();
-            tar_builder
-                .append_data(&mut header, file_path_in_tar, content.as_bytes())
-                .map_err(|e| e.to_string())?;
+            // Add the file entries to the tar archive
+            for (file_path, content) in files {
+                let file_path = self.resolve_path(file_path);
+                let file_path_in_tar = file_path.strip_prefix("/").unwrap_or(&file_path);
+                let mut header = tar::Header::new_gnu();
+                header.set_path(file_path_in_tar).map_err(|e| e.to_string())?;
+                header.set_size(content.len() as u64);
+                header.set_mode(0o644);
+                self.set_owner(&mut header);
+                header.set_cksum();
+                tar_builder
+                    .append_data(&mut header, file_path_in_tar, content.as_bytes())
+                    .map_err(|e| e.to_string())?;
+            }
             tar_builder.finish().map_err(|e| e.to_string())?;
         }
 
@@ -262,13 +962,177 @@ impl Container {
         };
 
         self.docker
-            .upload_to_container(&self.id, Some(options), tar_buffer.into())
+            .upload_to_container(&self.id(), Some(options), tar_buffer.into())
             .await
             .map_err(|e| e.to_string())?;
+        for (file_path, _) in files {
+            self.record_written_path(file_path);
+        }
 
         Ok(())
     }
 
+    /// Run a command in a terminal, sending each response once its prompt has been printed
+    ///
+    /// Waiting for the command stops after `timeout`, e.g. if a prompt does not appear.
+    pub async fn run_interactive(
+        &self,
+        command: &str,
+        interactions: &[Interaction],
+        timeout: Duration,
+    ) -> Result<InteractiveOutput, bollard::errors::Error> {
+        let cmd = self.tracked_command(&[&self.shell, "-c", command]);
+        let config = bollard::exec::CreateExecOptions {
+            cmd: Some(cmd.iter().map(String::as_str).collect()),
+            working_dir: Some(self.workspace_dir_container()),
+            user: self.user.as_deref(),
+            env: Some(self.remote_env.iter().map(String::as_str).collect()),
+            attach_stdin: Some(true),
+            attach_stdout: Some(true),
+            attach_stderr: Some(true),
+            // Interactive programs usually only prompt if they are connected to a terminal
+            tty: Some(true),
+            ..Default::default()
+        };
+        let exec_id = self.docker.create_exec(&self.id(), config).await?.id;
+
+        let start_options = StartExecOptions { detach: false, tty: true, output_capacity: None };
+        let StartExecResults::Attached { mut output, mut input } =
+            self.docker.start_exec(&exec_id, Some(start_options)).await?
+        else {
+            panic!("Failed to start exec in attached mode")
+        };
+
+        let mut transcript = String::new();
+        // Prompts are only searched for in the output after the previous prompt
+        let mut search_from = 0;
+        let mut interactions = interactions.iter().peekable();
+        let deadline = tokio::time::sleep(timeout);
+        tokio::pin!(deadline);
+
+        let finished = loop {
+            let msg = tokio::select! {
+                msg = output.next() => msg,
+                _ = &mut deadline => break false,
+            };
+            let Some(msg) = msg else {
+                break true;
+            };
+            transcript.push_str(&String::from_utf8_lossy(&msg?.into_bytes()));
+            while let Some(interaction) = interactions.peek() {
+                let Some(position) = transcript[search_from..].find(&interaction.expect) else {
+                    break;
+                };
+                search_from += position + interaction.expect.len();
+                let response = format!("{}\n", interaction.send);
+                if let Err(err) = input.write_all(response.as_bytes()).await {
+                    log::warn!("Failed to send input to the command: {}", err);
+                }
+                input.flush().await.ok();
+                interactions.next();
+            }
+        };
+
+        let exit_code = if finished {
+            Some(self.docker.inspect_exec(&exec_id).await?.exit_code.unwrap_or(0))
+        } else {
+            None
+        };
+        Ok(InteractiveOutput {
+            transcript,
+            exit_code,
+            missing_prompt: interactions.next().map(|i| i.expect.clone()),
+        })
+    }
+
+    /// Delete a file (or a symbolic link), but not a directory
+    pub async fn delete_file<P: AsRef<Path>>(&self, path: P) -> Result<(), FileOpError> {
+        const SCRIPT: &str = r#"if [ -d "$1" ] && [ ! -L "$1" ]; then echo directory; elif [ -e "$1" ] || [ -L "$1" ]; then rm -f -- "$1" && echo done; else echo missing; fi"#;
+        self.file_op(SCRIPT, &[self.resolve_path(&path)]).await?;
+        self.record_written_path(path);
+        Ok(())
+    }
+
+    /// Move a file, replacing the file at the destination and creating its parent directories
+    pub async fn move_file<P1: AsRef<Path>, P2: AsRef<Path>>(
+        &self,
+        from: P1,
+        to: P2,
+    ) -> Result<(), FileOpError> {
+        // `mv` would move the file into a directory at the destination instead of replacing it
+        const SCRIPT: &str = r#"if [ -d "$1" ] && [ ! -L "$1" ]; then echo directory; elif [ ! -e "$1" ] && [ ! -L "$1" ]; then echo missing; elif [ -d "$2" ]; then echo destination-directory; else mkdir -p -- "$(dirname -- "$2")" && mv -f -- "$1" "$2" && echo done; fi"#;
+        self.file_op(SCRIPT, &[self.resolve_path(&from), self.resolve_path(&to)]).await?;
+        self.record_written_path(from);
+        self.record_written_path(to);
+        Ok(())
+    }
+
+    /// Run a script of a file operation with the paths as arguments, which reports its outcome
+    async fn file_op(&self, script: &str, paths: &[PathBuf]) -> Result<(), FileOpError> {
+        let mut cmd = vec![self.shell.as_str(), "-c", script, "file-op"];
+        for path in paths {
+            cmd.push(
+                path.to_str().ok_or(FileOpError::Other("The path is not valid UTF-8".into()))?,
+            );
+        }
+        let output = self.exec(cmd).await.map_err(|e| FileOpError::Other(e.to_string()))?;
+        match output.stdout.lines().last() {
+            Some("done") => Ok(()),
+            Some("missing") => Err(FileOpError::NotFound),
+            Some("directory") => Err(FileOpError::IsDirectory),
+            Some("destination-directory") => Err(FileOpError::DestinationIsDirectory),
+            _ => Err(FileOpError::Other(output.stderr.trim().to_owned())),
+        }
+    }
+
+    /// Check whether a path exists, and whether it is a file or a directory
+    pub async fn stat<P: AsRef<Path>>(&self, path: P) -> Result<PathStat, String> {
+        const SCRIPT: &str = r#"if [ -d "$1" ]; then echo directory; elif [ -e "$1" ]; then echo file; wc -c < "$1"; else echo missing; fi"#;
+        let path = self.resolve_path(path);
+        let path = path.to_str().ok_or("The path is not valid UTF-8")?;
+        let output = self
+            .exec(vec![&self.shell, "-c", SCRIPT, "stat", path])
+            .await
+            .map_err(|e| e.to_string())?;
+        let mut lines = output.stdout.lines();
+        match lines.next() {
+            Some("directory") => Ok(PathStat::Directory),
+            Some("missing") => Ok(PathStat::Missing),
+            Some("file") => {
+                let size = lines.next().and_then(|size| size.trim().parse().ok());
+                Ok(PathStat::File { size })
+            }
+            _ => Err(format!("Unexpected output: {}{}", output.stdout, output.stderr)),
+        }
+    }
+
+    /// Search the files of the workspace for lines matching a regular expression, with ripgrep
+    /// if it is installed and with grep otherwise
+    ///
+    /// Ripgrep skips files ignored by git, grep only the `.git` directory. The glob restricts the
+    /// search to matching files, e.g. `*.rs`.
+    pub async fn search(
+        &self,
+        pattern: &str,
+        path_glob: Option<&str>,
+        max_matches: usize,
+    ) -> Result<SearchResult, String> {
+        const SCRIPT: &str = r#"if command -v rg >/dev/null 2>&1; then
+  if [ -n "$2" ]; then rg -n --no-heading --color never -g "$2" -e "$1" .; else rg -n --no-heading --color never -e "$1" .; fi
+else
+  if [ -n "$2" ]; then grep -rnIE --exclude-dir=.git --include="$2" -e "$1" .; else grep -rnIE --exclude-dir=.git -e "$1" .; fi
+fi | head -n "$3""#;
+        // One more match than shown tells whether there are more
+        let limit = (max_matches + 1).to_string();
+        let cmd =
+            vec![&*self.shell, "-c", SCRIPT, "search", pattern, path_glob.unwrap_or(""), &limit];
+        let output = self.exec(cmd).await.map_err(|e| e.to_string())?;
+        if output.stdout.trim().is_empty() && !output.stderr.trim().is_empty() {
+            return Err(output.stderr.trim().to_owned());
+        }
+        Ok(parse_search_output(&output.stdout, max_matches))
+    }
+
     fn resolve_path<P: AsRef<Path>>(&self, path: P) -> PathBuf {
         let path = path.as_ref();
         if path.is_absolute() {
@@ -279,13 +1143,916 @@ impl Container {
     }
 }
 
+impl Drop for Container {
+    fn drop(&mut self) {
+        if self.removed {
+            return;
+        }
+        // Blocking in place is only possible on the multi-threaded runtime
+        let handle = tokio::runtime::Handle::try_current()
+            .ok()
+            .filter(|handle| handle.runtime_flavor() == tokio::runtime::RuntimeFlavor::MultiThread);
+        let Some(handle) = handle else {
+            log::warn!("Container `{}` could not be removed and may still be running", self.name);
+            return;
+        };
+        let removed = tokio::task::block_in_place(|| handle.block_on(self.remove()));
+        match removed {
+            Ok(()) => log::info!("Removed container `{}`", self.name),
+            Err(err) => log::warn!("Failed to remove container `{}`: {}", self.name, err),
+        }
+    }
+}
+
+/// Remove a container, including its processes
+async fn remove_container(docker: &Docker, id: &str) -> Result<(), bollard::errors::Error> {
+    let options = bollard::container::RemoveContainerOptions { force: true, ..Default::default() };
+    docker.remove_container(id, Some(options)).await
+}
+
+/// The name of a container for the workspace, which is unique due to the random suffix
+///
+/// Characters that Docker does not allow in names are replaced.
+fn container_name(workspace_dir_name: &str, suffix: &str) -> String {
+    let workspace: String = workspace_dir_name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || "_.-".contains(c) { c } else { '-' })
+        .collect();
+    format!("minion-{}-{}", workspace, suffix)
+}
+
+/// Create and start the container, returning its ID
+async fn create_and_start(
+    docker: &Docker,
+    name: &str,
+    config: bollard::container::Config<String>,
+) -> Result<String, bollard::errors::Error> {
+    let options = bollard::container::CreateContainerOptions { name, platform: None };
+    let id = docker.create_container(Some(options), config).await?.id;
+    docker.start_container(&id, None::<bollard::container::StartContainerOptions<String>>).await?;
+    Ok(id)
+}
+
+/// The environment variables the image sets
+async fn image_env(
+    docker: &Docker,
+    image: &str,
+) -> Result<HashMap<String, String>, bollard::errors::Error> {
+    let inspect = docker.inspect_image(image).await?;
+    let env = inspect.config.and_then(|config| config.env).unwrap_or_default();
+    Ok(env
+        .iter()
+        .filter_map(|variable| variable.split_once('='))
+        .map(|(name, value)| (name.to_owned(), value.to_owned()))
+        .collect())
+}
+
+/// Environment variables in the `KEY=VALUE` form of Docker
+fn env_list(env: BTreeMap<String, String>) -> Vec<String> {
+    env.into_iter().map(|(name, value)| format!("{}={}", name, value)).collect()
+}
+
+/// A mount of the devcontainer configuration as a Docker mount, `None` for unsupported types
+fn docker_mount(mount: &Mount) -> Option<bollard::models::Mount> {
+    let typ = match mount.mount_type.as_str() {
+        "bind" => bollard::models::MountTypeEnum::BIND,
+        "volume" => bollard::models::MountTypeEnum::VOLUME,
+        "tmpfs" => bollard::models::MountTypeEnum::TMPFS,
+        _ => return None,
+    };
+    Some(bollard::models::Mount {
+        target: Some(mount.target.clone()),
+        source: mount.source.clone(),
+        typ: Some(typ),
+        read_only: Some(mount.read_only),
+        ..Default::default()
+    })
+}
+
+/// Apply the `runArgs` of the devcontainer configuration that map onto the host configuration
+///
+/// Only capabilities, the network mode and security options are supported, other arguments are
+/// logged and skipped.
+fn apply_run_args(run_args: &[String], host_config: &mut bollard::models::HostConfig) {
+    let mut args = run_args.iter();
+    while let Some(arg) = args.next() {
+        // Values are either part of the argument (`--cap-add=SYS_PTRACE`) or the next argument
+        let (name, inline_value) = match arg.split_once('=') {
+            Some((name, value)) => (name, Some(value.to_owned())),
+            None => (arg.as_str(), None),
+        };
+        let mut value = || inline_value.clone().or_else(|| args.next().cloned());
+        match name {
+            "--cap-add" => host_config.cap_add.get_or_insert_with(Vec::new).extend(value()),
+            "--cap-drop" => host_config.cap_drop.get_or_insert_with(Vec::new).extend(value()),
+            "--network" | "--net" => host_config.network_mode = value(),
+            "--security-opt" => {
+                host_config.security_opt.get_or_insert_with(Vec::new).extend(value())
+            }
+            _ => log::warn!("Skipping unsupported `runArgs` argument `{}`", arg),
+        }
+    }
+}
+
+/// A script that sources a script in the session kept in `state_dir`
+///
+/// The exported variables are restored before the working directory, so the restored `PWD` is
+/// consistent. The state is saved on exit, even if the script calls `exit`. Both paths are in the
+/// scratch directory, which does not contain quotes.
+fn session_script(state_dir: &str, script_path: &str) -> String {
+    format!(
+        r#"__minion_state='{state_dir}'
+if [ -f "$__minion_state/env" ]; then . "$__minion_state/env" 2>/dev/null; fi
+if [ -f "$__minion_state/cwd" ]; then cd "$(cat "$__minion_state/cwd")" 2>/dev/null; fi
+__minion_save() {{ mkdir -p "$__minion_state" && pwd > "$__minion_state/cwd" && export -p > "$__minion_state/env"; }}
+trap __minion_save EXIT
+. '{script_path}'
+"#
+    )
+}
+
+/// Parse the output of `id -u && id -g`
+fn parse_ids(output: &str) -> Option<(u64, u64)> {
+    let mut lines = output.lines().map(|line| line.trim().parse().ok());
+    Some((lines.next()??, lines.next()??))
+}
+
+/// The commands of a lifecycle command, resolving named commands in the order of their names
+fn flatten_lifecycle_command(command: &LifecycleCommand) -> Vec<&LifecycleCommand> {
+    match command {
+        LifecycleCommand::Parallel(commands) => {
+            commands.values().flat_map(flatten_lifecycle_command).collect()
+        }
+        command => vec![command],
+    }
+}
+
+/// The tag of the image that is built for a container, which is unique due to the random suffix
+fn image_tag(workspace_dir_name: &str, suffix: &str) -> String {
+    let workspace: String = workspace_dir_name
+        .to_lowercase()
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || "_.-".contains(c) { c } else { '-' })
+        .collect();
+    format!("minion-{}:{}", workspace.trim_start_matches(['_', '.', '-']), suffix)
+}
+
+/// Build an image from a Dockerfile and tag it, logging the output of the build
+async fn build_image(
+    docker: &Docker,
+    dockerfile: &Path,
+    context: &Path,
+    args: &HashMap<String, String>,
+    tag: &str,
+) -> Result<(), StartError> {
+    let build_error = |message: String| StartError::BuildImage { message };
+    let context =
+        context.canonicalize().map_err(|e| build_error(format!("Invalid build context: {}", e)))?;
+    let dockerfile =
+        dockerfile.canonicalize().map_err(|e| build_error(format!("Invalid Dockerfile: {}", e)))?;
+    let dockerfile = dockerfile
+        .strip_prefix(&context)
+        .map_err(|_| build_error("The Dockerfile is outside of the build context".to_owned()))?;
+
+    log::info!("Building image `{}` from `{}`", tag, dockerfile.display());
+    let mut context_tar = Vec::new();
+    {
+        let mut tar_builder = tar::Builder::new(&mut context_tar);
+        tar_builder.follow_symlinks(false);
+        tar_builder
+            .append_dir_all(".", &context)
+            .map_err(|e| build_error(format!("Failed to archive the build context: {}", e)))?;
+        tar_builder
+            .finish()
+            .map_err(|e| build_error(format!("Failed to archive the build context: {}", e)))?;
+    }
+
+    let options = bollard::image::BuildImageOptions {
+        dockerfile: dockerfile.to_string_lossy().into_owned(),
+        t: tag.to_owned(),
+        buildargs: args.clone(),
+        rm: true,
+        ..Default::default()
+    };
+    let mut build = docker.build_image(options, None, Some(context_tar.into()));
+    while let Some(info) = build.next().await {
+        let info = info.map_err(|e| build_error(e.to_string()))?;
+        if let Some(message) = info.error {
+            return Err(build_error(message));
+        }
+        if let Some(line) = info.stream.as_deref().map(str::trim).filter(|s| !s.is_empty()) {
+            log::info!("[build] {}", line);
+        }
+    }
+    Ok(())
+}
+
+/// Create a named volume, unless it exists already
+async fn ensure_volume(docker: &Docker, name: &str) -> Result<(), bollard::errors::Error> {
+    match docker.inspect_volume(name).await {
+        Ok(_) => Ok(()),
+        Err(bollard::errors::Error::DockerResponseServerError { status_code: 404, .. }) => {
+            log::info!("Creating volume `{}`", name);
+            let options = bollard::volume::CreateVolumeOptions {
+                name: name.to_owned(),
+                ..Default::default()
+            };
+            docker.create_volume(options).await.map(|_| ())
+        }
+        Err(err) => Err(err),
+    }
+}
+
+/// Pull an image, retrying on transient errors
+///
+/// Docker keeps the layers that were already downloaded, so a retry resumes a partial pull.
+async fn pull_image(docker: &Docker, image: &str) -> Result<(), PullImageError> {
+    let strategy = ExponentialBackoffBuilder::default()
+        .with_max_elapsed_time(Some(Duration::from_secs(PULL_MAX_ELAPSED_TIME_IN_SECS)))
+        .build();
+
+    backoff::future::retry(strategy, || async {
+        let mut create_image = docker.create_image(
+            Some(CreateImageOptions { from_image: image, ..Default::default() }),
+            None,
+            None,
+        );
+        loop {
+            let err = match create_image.try_next().await {
+                Ok(Some(info)) => match info.error {
+                    Some(message) => PullImageError::from_message(image, message),
+                    None => continue,
+                },
+                Ok(None) => return Ok(()),
+                Err(err) => PullImageError::from_docker_error(image, err),
+            };
+            return if err.is_transient() {
+                log::warn!("{}", err);
+                log::warn!("Retrying ...");
+                Err(BackoffError::transient(err))
+            } else {
+                Err(BackoffError::Permanent(err))
+            };
+        }
+    })
+    .await
+}
+
+/// An entry of a directory
+#[derive(Debug, PartialEq, Eq)]
+pub struct DirEntry {
+    pub name: String,
+    pub is_dir: bool,
+    /// The size in bytes, `0` for directories
+    pub size: u64,
+}
+
+/// The immediate entries of a downloaded directory
+///
+/// Downloading a directory yields an archive with the directory as its first entry, followed by
+/// its whole content.
+fn dir_entries<R: Read>(mut archive: tar::Archive<R>) -> Result<Vec<DirEntry>, ListDirError> {
+    let mut entries = archive.entries().map_err(|e| ListDirError::Other(e.to_string()))?;
+    match entries.next() {
+        Some(Ok(entry)) if entry.header().entry_type().is_dir() => {}
+        Some(Ok(_)) => return Err(ListDirError::NotADirectory),
+        Some(Err(e)) => return Err(ListDirError::Other(e.to_string())),
+        None => return Err(ListDirError::NotFound),
+    }
+
+    let mut result = Vec::new();
+    for entry in entries {
+        let entry = entry.map_err(|e| ListDirError::Other(e.to_string()))?;
+        let path = entry.path().map_err(|e| ListDirError::Other(e.to_string()))?;
+        let mut components = path.components().skip(1);
+        let (Some(name), None) = (components.next(), components.next()) else {
+            continue;
+        };
+        let is_dir = entry.header().entry_type().is_dir();
+        result.push(DirEntry {
+            name: name.as_os_str().to_string_lossy().into_owned(),
+            is_dir,
+            size: if is_dir { 0 } else { entry.header().size().unwrap_or(0) },
+        });
+    }
+    result.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(result)
+}
+
+/// A line matching a search, see [`Container::search`]
+#[derive(Debug, PartialEq, Eq)]
+pub struct SearchMatch {
+    /// The path relative to the workspace
+    pub path: String,
+    /// The line number, starting at 1
+    pub line: u64,
+    pub text: String,
+}
+
+/// The matches of a search, up to a maximum number
+pub struct SearchResult {
+    pub matches: Vec<SearchMatch>,
+    /// Whether there are more matches than the maximum
+    pub truncated: bool,
+}
+
+/// The maximum number of characters of a matching line, as minified files have very long lines
+const MAX_MATCH_CHARS: usize = 300;
+
+/// Parse the `<path>:<line>:<text>` lines of grep or ripgrep
+fn parse_search_output(stdout: &str, max_matches: usize) -> SearchResult {
+    let mut matches: Vec<SearchMatch> = stdout
+        .lines()
+        .filter_map(|line| {
+            let mut parts = line.splitn(3, ':');
+            let path = parts.next()?;
+            let line = parts.next()?.parse().ok()?;
+            let text = parts.next()?;
+            let text = match text.char_indices().nth(MAX_MATCH_CHARS) {
+                Some((end, _)) => format!("{}...", &text[..end]),
+                None => text.to_owned(),
+            };
+            Some(SearchMatch { path: path.trim_start_matches("./").to_owned(), line, text })
+        })
+        .collect();
+    let truncated = matches.len() > max_matches;
+    matches.truncate(max_matches);
+    SearchResult { matches, truncated }
+}
+
+/// Whether a path exists, and what it refers to
+pub enum PathStat {
+    Missing,
+    /// A file (or anything else that is not a directory), with its size in bytes if readable
+    File {
+        size: Option<u64>,
+    },
+    Directory,
+}
+
+/// A chunk of the output of a running command
+pub enum OutputChunk<'a> {
+    Stdout(&'a str),
+    Stderr(&'a str),
+}
+
+/// The output of a command that was run with [`Container::run_interactive`]
+pub struct InteractiveOutput {
+    /// The combined output of the command, including the echoed responses
+    pub transcript: String,
+    /// The exit code, or `None` if the command did not finish in time
+    pub exit_code: Option<i64>,
+    /// The first prompt that did not appear, if any
+    pub missing_prompt: Option<String>,
+}
+
 pub struct Output {
     pub exit_code: i64,
     pub stdout: String,
     pub stderr: String,
+    /// Stdout and stderr in the order in which they were written
+    pub combined: String,
 }
 
+#[derive(Error, Debug)]
 pub enum ReadFileError {
+    #[error("The file does not exist")]
+    NotFound,
+    #[error("Permission denied")]
+    PermissionDenied,
+    #[error("The path is a directory, not a file")]
+    IsDirectory,
+    #[error("The file is not valid UTF-8 text, e.g. a binary file")]
+    NotText,
+    #[error("{0}")]
+    Other(String),
+}
+
+#[derive(Error, Debug)]
+pub enum FileOpError {
+    #[error("The file does not exist")]
     NotFound,
+    #[error("The path is a directory, not a file")]
+    IsDirectory,
+    #[error("The destination is a directory, give the path of the file instead")]
+    DestinationIsDirectory,
+    #[error("{0}")]
     Other(String),
 }
+
+#[derive(Error, Debug)]
+pub enum ListDirError {
+    #[error("The directory does not exist")]
+    NotFound,
+    #[error("The path is a file, not a directory")]
+    NotADirectory,
+    #[error("Permission denied")]
+    PermissionDenied,
+    #[error("{0}")]
+    Other(String),
+}
+
+#[derive(Error, Debug)]
+pub enum StartError {
+    #[error("Invalid devcontainer configuration: {0}")]
+    Devcontainer(String),
+    #[error("Failed to connect to Docker: {0}")]
+    Connect(bollard::errors::Error),
+    #[error("Failed to create the container: {0}")]
+    Create(bollard::errors::Error),
+    #[error("Failed to copy the workspace to the container: {0}")]
+    CopyWorkspace(String),
+    #[error("Invalid workspace directory `{}`: {source}", path.display())]
+    InvalidWorkspace { path: PathBuf, source: io::Error },
+    #[error(transparent)]
+    PullImage(#[from] PullImageError),
+    #[error("devcontainer.json specifies neither an `image` nor a `build`")]
+    NoImage,
+    #[error("Failed to build the image: {message}")]
+    BuildImage { message: String },
+    #[error("Failed to inspect the image: {0}")]
+    InspectImage(bollard::errors::Error),
+    #[error("The devcontainer `{name}` failed: {reason}")]
+    LifecycleCommand { name: &'static str, reason: String },
+    #[error("Invalid scratch directory `{path}`, it must be an absolute path without quotes")]
+    InvalidScratchDir { path: String },
+    #[error("The container lacks {}", missing.join(", "))]
+    MissingCapabilities { missing: Vec<String> },
+    #[error("Failed to set up volume `{name}`: {source}")]
+    Volume { name: String, source: bollard::errors::Error },
+    #[error("Failed to restart the container: {0}")]
+    Restart(bollard::errors::Error),
+    #[error("The restarted container does not respond")]
+    Unresponsive,
+}
+
+#[derive(Error, Debug)]
+pub enum PullImageError {
+    #[error("Registry of image `{image}` is unreachable: {message}")]
+    RegistryUnreachable { image: String, message: String },
+    #[error("Not authorized to pull image `{image}`: {message}")]
+    Unauthorized { image: String, message: String },
+    #[error("Image `{image}` not found: {message}")]
+    NotFound { image: String, message: String },
+    #[error("Failed to pull image `{image}`: {message}")]
+    Other { image: String, message: String },
+}
+
+impl PullImageError {
+    fn from_docker_error(image: &str, err: bollard::errors::Error) -> Self {
+        match err {
+            bollard::errors::Error::DockerResponseServerError {
+                status_code: 401 | 403,
+                message,
+            } => Self::Unauthorized { image: image.to_owned(), message },
+            bollard::errors::Error::DockerResponseServerError { status_code: 404, message } => {
+                Self::NotFound { image: image.to_owned(), message }
+            }
+            bollard::errors::Error::DockerResponseServerError { message, .. }
+            | bollard::errors::Error::DockerStreamError { error: message } => {
+                Self::from_message(image, message)
+            }
+            err => Self::RegistryUnreachable { image: image.to_owned(), message: err.to_string() },
+        }
+    }
+
+    /// Classify an error message reported by the Docker daemon
+    fn from_message(image: &str, message: String) -> Self {
+        let image = image.to_owned();
+        let lowercase = message.to_lowercase();
+        if lowercase.contains("unauthorized")
+            || lowercase.contains("denied")
+            || lowercase.contains("authentication required")
+        {
+            Self::Unauthorized { image, message }
+        } else if lowercase.contains("not found") || lowercase.contains("manifest unknown") {
+            Self::NotFound { image, message }
+        } else if lowercase.contains("no such host")
+            || lowercase.contains("connection refused")
+            || lowercase.contains("timeout")
+            || lowercase.contains("tls handshake")
+        {
+            Self::RegistryUnreachable { image, message }
+        } else {
+            Self::Other { image, message }
+        }
+    }
+
+    fn is_transient(&self) -> bool {
+        matches!(self, Self::RegistryUnreachable { .. } | Self::Other { .. })
+    }
+}
+
+/// A container of a temporary workspace for tests, which is removed together with the workspace
+/// when it is dropped, even if the test fails
+///
+/// The container is removed by [`Container`]'s `Drop`, which requires the multi-threaded runtime.
+#[cfg(test)]
+pub struct TestContainer {
+    container: Option<Container>,
+    dir: PathBuf,
+}
+
+#[cfg(test)]
+impl TestContainer {
+    /// Start a container with the devcontainer configuration on a workspace with the files
+    pub async fn start(name: &str, devcontainer_json: &str, files: &[(&str, &str)]) -> Self {
+        let dir = std::env::temp_dir().join(format!("minion-{}-{}", name, std::process::id()));
+        fs::create_dir_all(dir.join(".devcontainer")).unwrap();
+        fs::write(dir.join(".devcontainer/devcontainer.json"), devcontainer_json).unwrap();
+        for (path, content) in files {
+            fs::write(dir.join(path), content).unwrap();
+        }
+        let limits = ResourceLimits { memory_mb: 1024, cpus: 1.0, pids: 256 };
+        let container =
+            Container::start(&dir, "test", WorkspaceMount::ReadWrite, &[], "/tmp/minion", limits)
+                .await;
+        // The guard is created first, so the workspace is removed if starting fails
+        let mut guard = Self { container: None, dir };
+        guard.container = Some(container.unwrap());
+        guard
+    }
+
+    /// The workspace on the host
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Stop the container explicitly, rather than on drop
+    pub async fn stop(mut self) -> Result<(), bollard::errors::Error> {
+        self.container.take().expect("The container was stopped").stop().await
+    }
+}
+
+#[cfg(test)]
+impl std::ops::Deref for TestContainer {
+    type Target = Container;
+
+    fn deref(&self) -> &Container {
+        self.container.as_ref().expect("The container was stopped")
+    }
+}
+
+#[cfg(test)]
+impl Drop for TestContainer {
+    fn drop(&mut self) {
+        drop(self.container.take());
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_volume_mount() {
+        let volume: VolumeMount = "cargo-registry:/usr/local/cargo/registry".parse().unwrap();
+        assert_eq!(
+            volume,
+            VolumeMount {
+                name: "cargo-registry".to_owned(),
+                path: "/usr/local/cargo/registry".to_owned()
+            }
+        );
+        assert!("cargo-registry".parse::<VolumeMount>().is_err());
+        assert!("cargo-registry:cache".parse::<VolumeMount>().is_err());
+        assert!("-cache:/cache".parse::<VolumeMount>().is_err());
+        assert!("my/cache:/cache".parse::<VolumeMount>().is_err());
+    }
+
+    #[test]
+    fn test_parse_ids() {
+        assert_eq!(parse_ids("1000\n1001\n"), Some((1000, 1001)));
+        assert_eq!(parse_ids("0\n"), None);
+        assert_eq!(parse_ids("uid\ngid\n"), None);
+    }
+
+    #[test]
+    fn test_flatten_lifecycle_command() {
+        let shell = |s: &str| LifecycleCommand::Shell(s.to_owned());
+        let command = LifecycleCommand::Parallel(BTreeMap::from([
+            ("b".to_owned(), shell("second")),
+            ("a".to_owned(), shell("first")),
+        ]));
+        assert_eq!(flatten_lifecycle_command(&command), [&shell("first"), &shell("second")]);
+        assert_eq!(flatten_lifecycle_command(&shell("only")), [&shell("only")]);
+    }
+
+    #[test]
+    fn test_parse_search_output() {
+        let long = "x".repeat(400);
+        let stdout = format!(
+            "./src/main.rs:3:    let x = 1;\n./src/lib.rs:10:a: b\nnot a match\n./min.js:1:{}\n",
+            long
+        );
+        let result = parse_search_output(&stdout, 10);
+        assert!(!result.truncated);
+        assert_eq!(
+            result.matches[..2],
+            [
+                SearchMatch {
+                    path: "src/main.rs".to_owned(),
+                    line: 3,
+                    text: "    let x = 1;".to_owned()
+                },
+                SearchMatch { path: "src/lib.rs".to_owned(), line: 10, text: "a: b".to_owned() },
+            ]
+        );
+        assert_eq!(result.matches[2].text, format!("{}...", &long[..MAX_MATCH_CHARS]));
+
+        let result = parse_search_output(&stdout, 2);
+        assert!(result.truncated);
+        assert_eq!(result.matches.len(), 2);
+    }
+
+    #[test]
+    fn test_dir_entries() {
+        let mut tar_buffer = Vec::new();
+        {
+            let mut tar_builder = tar::Builder::new(&mut tar_buffer);
+            let mut append = |path: &str, content: &[u8], is_dir: bool| {
+                let mut header = tar::Header::new_gnu();
+                header.set_entry_type(if is_dir {
+                    tar::EntryType::Directory
+                } else {
+                    tar::EntryType::Regular
+                });
+                header.set_size(content.len() as u64);
+                header.set_mode(0o755);
+                tar_builder.append_data(&mut header, path, content).unwrap();
+            };
+            append("src/", b"", true);
+            append("src/main.rs", b"fn main() {}", false);
+            append("src/util/", b"", true);
+            append("src/util/mod.rs", b"", false);
+            tar_builder.finish().unwrap();
+        }
+        let entries = dir_entries(tar::Archive::new(io::Cursor::new(tar_buffer))).unwrap();
+        assert_eq!(
+            entries,
+            [
+                DirEntry { name: "main.rs".to_owned(), is_dir: false, size: 12 },
+                DirEntry { name: "util".to_owned(), is_dir: true, size: 0 },
+            ]
+        );
+
+        let mut tar_buffer = Vec::new();
+        {
+            let mut tar_builder = tar::Builder::new(&mut tar_buffer);
+            let mut header = tar::Header::new_gnu();
+            header.set_size(2);
+            tar_builder.append_data(&mut header, "main.rs", &b"{}"[..]).unwrap();
+            tar_builder.finish().unwrap();
+        }
+        let result = dir_entries(tar::Archive::new(io::Cursor::new(tar_buffer)));
+        assert!(matches!(result, Err(ListDirError::NotADirectory)));
+    }
+
+    #[test]
+    fn test_docker_mount() {
+        let mount: Mount =
+            "type=bind,source=/home/user/.ssh,target=/root/.ssh,readonly".parse().unwrap();
+        let bind = docker_mount(&mount).unwrap();
+        assert_eq!(bind.typ, Some(bollard::models::MountTypeEnum::BIND));
+        assert_eq!(bind.source.as_deref(), Some("/home/user/.ssh"));
+        assert_eq!(bind.target.as_deref(), Some("/root/.ssh"));
+        assert_eq!(bind.read_only, Some(true));
+        assert!(docker_mount(&"type=npipe,target=/pipe".parse().unwrap()).is_none());
+    }
+
+    #[test]
+    fn test_apply_run_args() {
+        let mut host_config = bollard::models::HostConfig::default();
+        let run_args =
+            ["--cap-add=SYS_PTRACE", "--cap-add", "NET_ADMIN", "--network=host", "--init"];
+        apply_run_args(&run_args.map(str::to_owned), &mut host_config);
+        assert_eq!(host_config.cap_add.unwrap(), ["SYS_PTRACE", "NET_ADMIN"]);
+        assert_eq!(host_config.network_mode.as_deref(), Some("host"));
+        assert_eq!(host_config.init, None);
+    }
+
+    #[test]
+    fn test_resource_limits() {
+        let mut host_config = bollard::models::HostConfig::default();
+        let limits = ResourceLimits { memory_mb: 512, cpus: 1.5, pids: 0 };
+        limits.apply(&mut host_config);
+        assert_eq!(host_config.memory, Some(512 * 1024 * 1024));
+        assert_eq!(host_config.memory_swap, host_config.memory);
+        assert_eq!(host_config.nano_cpus, Some(1_500_000_000));
+        assert_eq!(host_config.pids_limit, None);
+    }
+
+    #[test]
+    fn test_image_tag() {
+        assert_eq!(image_tag("My-Repo", "k3x9q2ab"), "minion-my-repo:k3x9q2ab");
+        assert_eq!(image_tag("_repo v2", "k3x9q2ab"), "minion-repo-v2:k3x9q2ab");
+    }
+
+    #[test]
+    fn test_container_name() {
+        assert_eq!(container_name("my-repo", "k3x9q2ab"), "minion-my-repo-k3x9q2ab");
+        assert_eq!(container_name("my repo@2", "k3x9q2ab"), "minion-my-repo-2-k3x9q2ab");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore = "requires Docker"]
+    async fn test_stop_removes_container() {
+        let container = TestContainer::start("container", r#"{"image": "alpine:3"}"#, &[]).await;
+        let docker = container.docker.clone();
+        let id = container.id();
+        container.stop().await.unwrap();
+
+        let err = docker.inspect_container(&id, None).await.unwrap_err();
+        assert!(matches!(
+            err,
+            bollard::errors::Error::DockerResponseServerError { status_code: 404, .. }
+        ));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore = "requires Docker"]
+    async fn test_custom_workspace_folder() {
+        let config = r#"{"image": "alpine:3", "workspaceFolder": "/app"}"#;
+        let container = TestContainer::start("folder", config, &[("a.txt", "a")]).await;
+        assert_eq!(container.workspace_dir_container(), "/app");
+        let output = container.run_script_streaming("pwd && cat a.txt", &mut |_| {}).await.unwrap();
+        assert_eq!(output.stdout, "/app\na");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore = "requires Docker"]
+    async fn test_commands_run_as_remote_user() {
+        let config = r#"{"image": "alpine:3", "remoteUser": "nobody"}"#;
+        let container = TestContainer::start("user", config, &[]).await;
+        let output = container.run_script_streaming("whoami", &mut |_| {}).await.unwrap();
+        assert_eq!(output.stdout.trim(), "nobody");
+        assert!(!container.is_root());
+    }
+
+    #[test]
+    fn test_session_script() {
+        let script = session_script("/tmp/minion/session", "/tmp/minion/minion-script-x.sh");
+        assert!(script.starts_with("__minion_state='/tmp/minion/session'\n"));
+        assert!(script.ends_with(". '/tmp/minion/minion-script-x.sh'\n"));
+    }
+
+    /// Whether the process has exited, i.e. it is gone or a zombie that was not reaped yet
+    fn has_exited(pid: &str) -> bool {
+        fs::read_to_string(format!("/proc/{}/status", pid))
+            .map_or(true, |status| status.lines().any(|line| line.starts_with("State:\tZ")))
+    }
+
+    /// Wait for the condition, for at most 10 seconds
+    fn poll(condition: impl Fn() -> bool) -> bool {
+        let deadline = std::time::Instant::now() + Duration::from_secs(10);
+        while !condition() {
+            if std::time::Instant::now() > deadline {
+                return false;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        true
+    }
+
+    // The kill script finds the descendants of the commands in `/proc`
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_kill_tracked_command() {
+        let dir = std::env::temp_dir().join(format!("minion-kill-{}", std::process::id()));
+        let pid_file = dir.join("running/command");
+        let started = dir.join("started");
+        let mut child = std::process::Command::new("sh")
+            .args(["-c", TRACK_SCRIPT])
+            .arg(&pid_file)
+            .args(["sh", "-c", "echo $$ > \"$0.tmp\" && mv \"$0.tmp\" \"$0\" && sleep 60"])
+            .arg(&started)
+            .spawn()
+            .unwrap();
+        // The command runs once it wrote its PID
+        assert!(poll(|| started.exists()));
+        let command_pid = fs::read_to_string(&started).unwrap().trim().to_owned();
+
+        let status = std::process::Command::new("sh")
+            .args(["-c", KILL_SCRIPT, "kill"])
+            .arg(dir.join("running"))
+            .status()
+            .unwrap();
+        child.wait().unwrap();
+        let killed = poll(|| has_exited(&command_pid));
+        let pid_file_left = pid_file.exists();
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(status.success());
+        assert!(killed);
+        assert!(!pid_file_left);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore = "requires Docker"]
+    async fn test_written_paths() {
+        let container = TestContainer::start("written", r#"{"image": "alpine:3"}"#, &[]).await;
+        container.take_written_paths();
+        container.write_file("a.txt", "a").await.unwrap();
+        container.move_file("a.txt", "b.txt").await.unwrap();
+        let written: Vec<String> = container.take_written_paths().into_iter().collect();
+        assert_eq!(written, ["a.txt", "b.txt"]);
+        assert!(container.take_written_paths().is_empty());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore = "requires Docker"]
+    async fn test_session_keeps_directory_and_variables() {
+        let container = TestContainer::start("session", r#"{"image": "alpine:3"}"#, &[]).await;
+        fs::create_dir_all(container.dir().join(".devcontainer/sub")).unwrap();
+        let script = "export GREETING=hello && cd .devcontainer/sub";
+        container.run_session_script_streaming(script, &mut |_| {}).await.unwrap();
+        let output = container
+            .run_session_script_streaming("echo $GREETING; pwd", &mut |_| {})
+            .await
+            .unwrap();
+        assert_eq!(output.stdout, "hello\n/workspaces/test/.devcontainer/sub\n");
+        // Plain scripts are not part of the session
+        let output =
+            container.run_script_streaming("echo \"[$GREETING]\"", &mut |_| {}).await.unwrap();
+        assert_eq!(output.stdout, "[]\n");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore = "requires Docker"]
+    async fn test_combined_output_keeps_order() {
+        let container = TestContainer::start("combined", r#"{"image": "alpine:3"}"#, &[]).await;
+        let script = "echo one; sleep 0.1; echo two >&2; sleep 0.1; echo three";
+        let output = container.run_script_streaming(script, &mut |_| {}).await.unwrap();
+        assert_eq!(output.combined, "one\ntwo\nthree\n");
+        assert_eq!(output.stdout, "one\nthree\n");
+        assert_eq!(output.stderr, "two\n");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore = "requires Docker"]
+    async fn test_delete_and_move_files() {
+        let files = [("a.txt", "a"), ("b.txt", "b")];
+        let container = TestContainer::start("file-ops", r#"{"image": "alpine:3"}"#, &files).await;
+        container.move_file("a.txt", "b.txt").await.unwrap();
+        assert_eq!(container.read_file("b.txt").await.unwrap(), "a");
+        assert!(matches!(container.read_file("a.txt").await, Err(ReadFileError::NotFound)));
+        container.move_file("b.txt", "sub/c.txt").await.unwrap();
+        assert!(matches!(container.move_file("b.txt", "d.txt").await, Err(FileOpError::NotFound)));
+
+        container.delete_file("sub/c.txt").await.unwrap();
+        assert!(matches!(container.delete_file("sub/c.txt").await, Err(FileOpError::NotFound)));
+        assert!(matches!(container.delete_file("sub").await, Err(FileOpError::IsDirectory)));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore = "requires Docker"]
+    async fn test_search() {
+        let container = TestContainer::start("search", r#"{"image": "alpine:3"}"#, &[]).await;
+        container.write_file("src/main.rs", "fn main() {\n    helper();\n}\n").await.unwrap();
+        container.write_file("src/helper.rs", "pub fn helper() {}\n").await.unwrap();
+        container.write_file("README.md", "Call helper() to help.\n").await.unwrap();
+
+        let result = container.search(r"helper\(", Some("*.rs"), 10).await.unwrap();
+        let mut matches: Vec<_> =
+            result.matches.iter().map(|m| (m.path.as_str(), m.line)).collect();
+        matches.sort();
+        assert_eq!(matches, [("src/helper.rs", 1), ("src/main.rs", 2)]);
+        assert!(!result.truncated);
+
+        let result = container.search("helper", None, 2).await.unwrap();
+        assert!(result.truncated);
+        assert!(container
+            .search("nothing matches this", None, 10)
+            .await
+            .unwrap()
+            .matches
+            .is_empty());
+        assert!(container.search("(", None, 10).await.is_err());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore = "requires Docker"]
+    async fn test_read_binary_file() {
+        let container = TestContainer::start("binary", r#"{"image": "alpine:3"}"#, &[]).await;
+        fs::write(container.dir().join("binary"), [b'a', 0xFF, 0xFE, b'b']).unwrap();
+        assert_eq!(container.read_file_bytes("binary").await.unwrap(), [b'a', 0xFF, 0xFE, b'b']);
+        assert!(matches!(container.read_file("binary").await, Err(ReadFileError::NotText)));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore = "requires Docker"]
+    async fn test_container_and_remote_env() {
+        let config = r#"{
+            "image": "alpine:3",
+            "containerEnv": {"APP_ENV": "test", "PATH": "${containerEnv:PATH}:/opt/bin"},
+            "remoteEnv": {"GREETING": "hello from ${containerEnv:APP_ENV}"}
+        }"#;
+        let container = TestContainer::start("env", config, &[]).await;
+        let script = "echo $APP_ENV && echo $GREETING && echo $PATH";
+        let output = container.run_script_streaming(script, &mut |_| {}).await.unwrap();
+        let lines: Vec<&str> = output.stdout.lines().collect();
+        assert_eq!(lines[0], "test");
+        assert_eq!(lines[1], "hello from test");
+        assert!(lines[2].ends_with(":/opt/bin") && lines[2].contains("/usr/bin"));
+    }
+}
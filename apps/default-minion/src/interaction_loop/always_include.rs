@@ -0,0 +1,115 @@
+use crate::actions::secrets::Redactor;
+use crate::container::{Container, ReadFileError};
+use crate::llm::PromptItem;
+
+/// Files that are included in the prompt prefix of every action
+pub struct AlwaysInclude {
+    max_bytes: usize,
+    files: Vec<IncludedFile>,
+}
+
+struct IncludedFile {
+    path: String,
+    content: Option<String>,
+}
+
+impl AlwaysInclude {
+    pub fn new(paths: &[String], max_bytes: usize) -> Self {
+        let files = paths
+            .iter()
+            .map(|path| IncludedFile { path: path.to_owned(), content: None })
+            .collect();
+        Self { max_bytes, files }
+    }
+
+    /// Replace the included files, keeping the content of files that remain included
+    ///
+    /// Returns whether any file with content was removed.
+    pub fn set_paths<'a>(&mut self, paths: impl IntoIterator<Item = &'a String>) -> bool {
+        let paths: Vec<&String> = paths.into_iter().collect();
+        let removed =
+            self.files.iter().any(|file| file.content.is_some() && !paths.contains(&&file.path));
+        self.files.retain(|file| paths.contains(&&file.path));
+        for path in paths {
+            if !self.files.iter().any(|file| &file.path == path) {
+                self.files.push(IncludedFile { path: path.to_owned(), content: None });
+            }
+        }
+        removed
+    }
+
+    /// Re-reads all files from the container and returns whether any of them changed
+    ///
+    /// Secrets are redacted without logging, as the files are read again for every action.
+    pub async fn refresh(&mut self, container: &Container, redactor: &Redactor) -> bool {
+        let mut changed = false;
+        for file in &mut self.files {
+            let content = match container.read_file(&file.path).await {
+                Ok(content) => Some(truncate(redactor.redact_silently(&content), self.max_bytes)),
+                Err(ReadFileError::NotFound) => None,
+                Err(err) => {
+                    log::warn!("Failed to read always-included file `{}`: {}", file.path, err);
+                    None
+                }
+            };
+            if content != file.content {
+                file.content = content;
+                changed = true;
+            }
+        }
+        changed
+    }
+
+    pub fn prompt_items(&self) -> Vec<PromptItem> {
+        let mut items = Vec::new();
+        for file in &self.files {
+            if let Some(content) = &file.content {
+                items.push(PromptItem::System {
+                    text: format!("The content of `{}` is:", file.path),
+                });
+                items.push(PromptItem::System { text: content.to_owned() });
+            }
+        }
+        items
+    }
+}
+
+/// Truncate the content to at most `max_bytes` bytes (on a character boundary)
+pub(super) fn truncate(mut content: String, max_bytes: usize) -> String {
+    if content.len() <= max_bytes {
+        return content;
+    }
+    let mut end = max_bytes;
+    while !content.is_char_boundary(end) {
+        end -= 1;
+    }
+    content.truncate(end);
+    content.push_str("\n[truncated]");
+    content
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_set_paths_keeps_content() {
+        let mut include = AlwaysInclude::new(&["a".to_owned(), "b".to_owned()], 10);
+        include.files[0].content = Some("content".to_owned());
+        assert!(!include.set_paths(&["a".to_owned(), "c".to_owned()]));
+        let paths: Vec<&str> = include.files.iter().map(|f| f.path.as_str()).collect();
+        assert_eq!(paths, ["a", "c"]);
+        assert_eq!(include.files[0].content.as_deref(), Some("content"));
+        assert!(include.set_paths(&["c".to_owned()]));
+    }
+
+    #[test]
+    fn test_truncate_short_content() {
+        assert_eq!(truncate("hello".to_owned(), 10), "hello");
+    }
+
+    #[test]
+    fn test_truncate_on_char_boundary() {
+        assert_eq!(truncate("aä".to_owned(), 2), "a\n[truncated]");
+    }
+}
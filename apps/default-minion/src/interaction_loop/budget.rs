@@ -0,0 +1,148 @@
+use std::collections::BTreeMap;
+use std::str::FromStr;
+
+use serde::Deserialize;
+
+use crate::config::Config;
+use crate::llm::Usage;
+
+/// The price of a model in USD per million tokens
+#[derive(Clone, Debug, PartialEq)]
+pub struct ModelPrice {
+    pub model: String,
+    pub prompt: f64,
+    pub completion: f64,
+}
+
+/// The prices of the default models, which can be overridden by the configuration
+const DEFAULT_PRICES: &[(&str, f64, f64)] = &[("o1-mini", 3.0, 12.0), ("gpt-4o-mini", 0.15, 0.6)];
+
+impl FromStr for ModelPrice {
+    type Err = String;
+
+    /// Parse a price in the format `<model>=<prompt>/<completion>`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let format_error =
+            || format!("Price `{}` is not in the format `<model>=<prompt>/<completion>`", s);
+        let (model, prices) = s.trim().split_once('=').ok_or_else(format_error)?;
+        let (prompt, completion) = prices.split_once('/').ok_or_else(format_error)?;
+        Ok(Self {
+            model: model.to_owned(),
+            prompt: prompt.trim().parse().map_err(|_| format_error())?,
+            completion: completion.trim().parse().map_err(|_| format_error())?,
+        })
+    }
+}
+
+impl<'de> Deserialize<'de> for ModelPrice {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// A limit on the tokens and the estimated cost of a task
+pub struct Budget {
+    max_tokens: Option<u64>,
+    max_cost: Option<f64>,
+    prices: BTreeMap<String, ModelPrice>,
+}
+
+impl Budget {
+    pub fn new(config: &Config) -> Self {
+        let defaults = DEFAULT_PRICES.iter().map(|(model, prompt, completion)| ModelPrice {
+            model: (*model).to_owned(),
+            prompt: *prompt,
+            completion: *completion,
+        });
+        let prices = defaults
+            .chain(config.model_prices.iter().cloned())
+            .map(|price| (price.model.clone(), price))
+            .collect();
+        Self { max_tokens: config.max_tokens, max_cost: config.max_cost, prices }
+    }
+
+    /// The estimated cost in USD, models without a price are not taken into account
+    pub fn cost(&self, usage: &BTreeMap<String, Usage>) -> f64 {
+        usage
+            .iter()
+            .filter_map(|(model, usage)| {
+                let price = self.prices.get(model)?;
+                Some(
+                    (usage.prompt_tokens as f64 * price.prompt
+                        + usage.completion_tokens as f64 * price.completion)
+                        / 1_000_000.0,
+                )
+            })
+            .sum()
+    }
+
+    /// Why the budget is exhausted, or `None` if it is not
+    pub fn exhausted(&self, usage: &BTreeMap<String, Usage>) -> Option<String> {
+        let tokens: u64 = usage.values().map(|u| u.prompt_tokens + u.completion_tokens).sum();
+        if let Some(max_tokens) = self.max_tokens {
+            if tokens >= max_tokens {
+                return Some(format!(
+                    "Budget exhausted: {} tokens were used, the limit is {}",
+                    tokens, max_tokens
+                ));
+            }
+        }
+        if let Some(max_cost) = self.max_cost {
+            let cost = self.cost(usage);
+            if cost >= max_cost {
+                return Some(format!(
+                    "Budget exhausted: an estimated ${:.2} was spent, the limit is ${:.2}",
+                    cost, max_cost
+                ));
+            }
+        }
+        None
+    }
+
+    /// The models that were used, but whose cost can not be estimated
+    pub fn unpriced_models<'a>(&self, usage: &'a BTreeMap<String, Usage>) -> Vec<&'a str> {
+        if self.max_cost.is_none() {
+            return Vec::new();
+        }
+        usage.keys().filter(|model| !self.prices.contains_key(*model)).map(String::as_str).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn budget(max_tokens: Option<u64>, max_cost: Option<f64>) -> Budget {
+        let prices = [("gpt-4o".to_owned(), "gpt-4o=2.5/10".parse().unwrap())].into();
+        Budget { max_tokens, max_cost, prices }
+    }
+
+    fn usage(prompt_tokens: u64, completion_tokens: u64) -> BTreeMap<String, Usage> {
+        let usage = Usage { calls: 1, prompt_tokens, completion_tokens };
+        [("gpt-4o".to_owned(), usage), ("unknown".to_owned(), Usage::default())].into()
+    }
+
+    #[test]
+    fn test_parse_model_price() {
+        let price: ModelPrice = "gpt-4o=2.5/10".parse().unwrap();
+        assert_eq!(price, ModelPrice { model: "gpt-4o".to_owned(), prompt: 2.5, completion: 10.0 });
+        assert!("gpt-4o=2.5".parse::<ModelPrice>().is_err());
+        assert!("gpt-4o".parse::<ModelPrice>().is_err());
+    }
+
+    #[test]
+    fn test_token_budget() {
+        assert_eq!(budget(Some(1000), None).exhausted(&usage(600, 300)), None);
+        assert!(budget(Some(1000), None).exhausted(&usage(600, 400)).is_some());
+        assert_eq!(budget(None, None).exhausted(&usage(600, 400)), None);
+    }
+
+    #[test]
+    fn test_cost_budget() {
+        let budget = budget(None, Some(1.0));
+        assert_eq!(budget.cost(&usage(200_000, 50_000)), 1.0);
+        assert!(budget.exhausted(&usage(200_000, 50_000)).is_some());
+        assert_eq!(budget.exhausted(&usage(200_000, 40_000)), None);
+        assert_eq!(budget.unpriced_models(&usage(0, 0)), vec!["unknown"]);
+    }
+}
@@ -0,0 +1,53 @@
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::sync::mpsc;
+
+/// Operator commands that control a running interaction loop
+pub enum Control {
+    /// A message of the operator
+    ///
+    /// While an action is running, the action is cancelled and the message is passed to the model
+    /// as steering before the next one.
+    /// While the model waits for an answer to a question, the message is the answer.
+    Message { message: String },
+}
+
+/// Read operator commands from stdin
+///
+/// Every non-empty line is a message of the operator.
+pub fn spawn_stdin_reader() -> mpsc::UnboundedReceiver<Control> {
+    let (tx, rx) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        let mut lines = BufReader::new(tokio::io::stdin()).lines();
+        while let Ok(Some(line)) = lines.next_line().await {
+            let message = line.trim();
+            if message.is_empty() {
+                continue;
+            }
+            if tx.send(Control::Message { message: message.to_owned() }).is_err() {
+                break;
+            }
+        }
+    });
+    rx
+}
+
+/// Wait for the next operator command
+///
+/// Returns `None` if there is no control channel or the channel has been closed.
+pub async fn recv_control(
+    control: &mut Option<mpsc::UnboundedReceiver<Control>>,
+) -> Option<Control> {
+    control.as_mut()?.recv().await
+}
+
+/// Wait for the next operator command
+///
+/// Never resolves if there is no control channel or the channel has been closed.
+pub async fn next_control(control: &mut Option<mpsc::UnboundedReceiver<Control>>) -> Control {
+    if let Some(rx) = control {
+        if let Some(command) = rx.recv().await {
+            return command;
+        }
+    }
+    std::future::pending().await
+}
@@ -1,51 +1,298 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::Config;
 use crate::llm::{Prompt, PromptItem};
 
-/// The maximum number of recent actions to keep in their entirety
-const MAX_ACTIONS_TO_KEEP: usize = 5;
+use super::summary::Summary;
 
+#[derive(Serialize, Deserialize)]
 pub struct Action {
     pub number: usize,
+    /// The name of the action, e.g. `bash`
+    pub action_type: String,
     pub messages: Vec<PromptItem>,
+    pub summary: Summary,
+}
+
+/// The note for the model that a task is resumed
+const RESUMED: &str = r#"The task was interrupted and is resumed. The actions so far follow, but the environment was set up again from scratch: the changes of earlier actions to files, installed packages and running processes are gone.
+Check the state of the files before you continue, and make the changes you need again."#;
+
+/// The instructions to consolidate the summaries of older actions
+const CONSOLIDATE: &str = r#"Summarize the progress on the task so far in a few sentences, based on the summaries above.
+Keep everything that is relevant for the rest of the task, e.g. what you found out, changed and tried, and what failed."#;
+
+/// How many of the recent actions are kept in full, older actions are replaced by their summaries
+#[derive(Clone, Copy, Debug, Default)]
+pub struct HistoryConfig {
+    /// The maximum number of actions, no limit if `0`
+    pub max_actions: usize,
+    /// The maximum estimated number of tokens of the actions, no limit if `0`
+    pub max_tokens: usize,
+    /// The number of summaries after which they are consolidated into one, never if `0`
+    pub max_summaries: usize,
+}
+
+impl HistoryConfig {
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            max_actions: config.history_max_actions,
+            max_tokens: config.history_max_tokens,
+            max_summaries: config.history_max_summaries,
+        }
+    }
+}
+
+/// A summary of the progress of the oldest actions, which replaces their individual summaries
+#[derive(Serialize, Deserialize)]
+pub struct Consolidated {
+    /// The number of actions the summary covers
+    pub actions: usize,
     pub summary: String,
 }
 
+/// The history of a task, which is written to the transcript after every action
+#[derive(Serialize, Deserialize)]
 pub struct History {
     pub prefix: Vec<PromptItem>,
     pub actions: Vec<Action>,
+    /// The number of actions that failed and were discarded
+    pub failed_actions: usize,
+    #[serde(default)]
+    pub consolidated: Option<Consolidated>,
+    #[serde(skip)]
+    config: HistoryConfig,
 }
 
 impl History {
-    pub fn new(prefix: Vec<PromptItem>) -> Self {
-        Self { prefix, actions: Vec::new() }
+    pub fn new(prefix: Vec<PromptItem>, config: HistoryConfig) -> Self {
+        Self { prefix, actions: Vec::new(), failed_actions: 0, consolidated: None, config }
+    }
+
+    /// Load the history of an interrupted run from its transcript, to resume the task
+    pub fn load(path: &Path, config: HistoryConfig) -> io::Result<Self> {
+        let history: Self = serde_json::from_str(&fs::read_to_string(path)?)?;
+        Ok(Self { config, ..history })
     }
 
     /// Compresses the history by summarizing older actions and keeping only
-    /// the last N actions in full.
+    /// the most recent actions in full, as many as the config allows.
+    ///
+    /// The last action is always kept in full.
     pub fn compressed_prompt(&self) -> Prompt {
         // Calculate how many actions need to be replaced by their summary
-        let total_actions = self.actions.len();
-        let skip_count = total_actions.saturating_sub(MAX_ACTIONS_TO_KEEP);
+        let skip_count = self.summarized_count();
 
         let mut items = self.prefix.clone();
+        items.extend(self.summaries(skip_count));
+
+        // For the most recent actions, keep their messages in full
+        for action in &self.actions[skip_count..] {
+            items.extend(action.messages.clone());
+        }
+
+        Prompt { items }
+    }
+
+    /// The number of oldest actions that are not kept in full, including the consolidated ones
+    fn summarized_count(&self) -> usize {
+        let HistoryConfig { max_actions, max_tokens, .. } = self.config;
+        let mut tokens = 0;
+        let kept = self
+            .actions
+            .iter()
+            .rev()
+            .enumerate()
+            .take_while(|(i, action)| {
+                tokens += action.messages.iter().map(PromptItem::estimated_tokens).sum::<usize>();
+                (max_actions == 0 || *i < max_actions) && (max_tokens == 0 || tokens <= max_tokens)
+            })
+            .count();
+        let summarized = self.actions.len() - kept.max(1).min(self.actions.len());
+        summarized.max(self.consolidated_count())
+    }
 
-        // For the skipped (older) actions, store their summaries
-        for action in &self.actions[..skip_count] {
+    /// The summaries of the oldest `count` actions, of which the consolidated ones share one
+    fn summaries(&self, count: usize) -> Vec<PromptItem> {
+        let mut items = Vec::new();
+        if let Some(Consolidated { actions, summary }) = &self.consolidated {
+            items.push(PromptItem::System {
+                text: format!("Summary of actions 0 to {}: {}", actions - 1, summary),
+            });
+        }
+        for action in &self.actions[self.consolidated_count()..count] {
             items.push(PromptItem::System {
                 text: format!("Summary for action {}: {}", action.number, action.summary),
             });
         }
+        items
+    }
 
-        // For the most recent actions, keep their messages in full
-        for action in &self.actions[skip_count..] {
-            items.extend(action.messages.clone());
+    /// The number of oldest actions that are covered by the consolidated summary
+    fn consolidated_count(&self) -> usize {
+        self.consolidated.as_ref().map_or(0, |consolidated| consolidated.actions)
+    }
+
+    /// The prompt to consolidate the summaries of older actions into one, if there are more than
+    /// the config allows, with the number of actions the consolidated summary will cover
+    pub fn consolidation_prompt(&self) -> Option<(usize, Prompt)> {
+        let summarized = self.summarized_count();
+        let summaries = summarized - self.consolidated_count();
+        if self.config.max_summaries == 0 || summaries <= self.config.max_summaries {
+            return None;
         }
+        let mut items = self.prefix.clone();
+        items.extend(self.summaries(summarized));
+        items.push(PromptItem::System { text: CONSOLIDATE.to_owned() });
+        Some((summarized, Prompt { items }))
+    }
 
-        Prompt { items }
+    /// Replace the summaries of the oldest `actions` by a single summary
+    pub fn consolidate(&mut self, actions: usize, summary: String) {
+        self.consolidated = Some(Consolidated { actions, summary });
+    }
+
+    /// The history to resume with a new intro, followed by a note that the task is resumed
+    pub fn resumed(mut self, intro: Vec<PromptItem>) -> Self {
+        self.prefix = intro;
+        self.prefix.push(PromptItem::System { text: RESUMED.to_owned() });
+        self
     }
 
     /// Appends a new action to the history.
-    pub fn append(&mut self, messages: Vec<PromptItem>, summary: String) {
+    pub fn append(&mut self, action_type: &str, messages: Vec<PromptItem>, summary: Summary) {
         let number = self.actions.len();
-        self.actions.push(Action { number, messages, summary });
+        let action_type = action_type.to_owned();
+        self.actions.push(Action { number, action_type, messages, summary });
+    }
+
+    /// Records an action that failed, which is not part of the history
+    pub fn record_failure(&mut self) {
+        self.failed_actions += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn history(action_chars: &[usize], max_actions: usize, max_tokens: usize) -> History {
+        let mut history =
+            History::new(Vec::new(), HistoryConfig { max_actions, max_tokens, max_summaries: 0 });
+        for &chars in action_chars {
+            let messages = vec![PromptItem::System { text: "x".repeat(chars) }];
+            history.append("read-file", messages, Summary::Text("Read a file".to_owned()));
+        }
+        history
+    }
+
+    #[test]
+    fn test_compressed_prompt() {
+        // Small actions are all kept in full
+        let prompt = history(&[400, 400, 400], 0, 1000).compressed_prompt();
+        assert_eq!(prompt.items.len(), 3);
+        assert!(prompt.items.iter().all(|item| item.estimated_tokens() == 100));
+
+        // Older actions are summarized once the recent ones take up the budget
+        let prompt = history(&[400, 2000, 2000], 0, 1000).compressed_prompt();
+        let texts: Vec<_> = prompt
+            .items
+            .iter()
+            .map(|item| match item {
+                PromptItem::System { text } => text.len(),
+                _ => unreachable!(),
+            })
+            .collect();
+        assert_eq!(texts, ["Summary for action 0: Read a file".len(), 2000, 2000]);
+    }
+
+    #[test]
+    fn test_load_and_resume() {
+        let dir = std::env::temp_dir().join(format!("minion-history-{}", std::process::id()));
+        let redactor = std::sync::Arc::new(crate::actions::secrets::Redactor::new(Vec::new()));
+        let output_dir = crate::output::OutputDir::create(&dir, "1", redactor);
+        let intro = vec![PromptItem::System { text: "Old intro".to_owned() }];
+        let mut history = History::new(intro, HistoryConfig::default());
+        let messages = vec![PromptItem::Assistant { text: "ls".to_owned() }];
+        history.append("bash", messages, Summary::Text("Listed the files".to_owned()));
+        history.record_failure();
+        output_dir.write_json(&output_dir.transcript_path(), &history);
+
+        let loaded = History::load(&output_dir.transcript_path(), HistoryConfig::default());
+        fs::remove_dir_all(&dir).unwrap();
+        let loaded = loaded.unwrap();
+        assert_eq!(loaded.actions.len(), 1);
+        assert_eq!(loaded.actions[0].summary, Summary::Text("Listed the files".to_owned()));
+        assert_eq!(loaded.failed_actions, 1);
+
+        // The actions so far follow the new intro and the note
+        let resumed = loaded.resumed(vec![PromptItem::System { text: "Intro".to_owned() }]);
+        let texts: Vec<_> = resumed
+            .compressed_prompt()
+            .items
+            .into_iter()
+            .map(|item| match item {
+                PromptItem::System { text } | PromptItem::Assistant { text } => text,
+                PromptItem::User { .. } => unreachable!(),
+            })
+            .collect();
+        assert_eq!(texts, ["Intro", RESUMED, "ls"]);
+    }
+
+    #[test]
+    fn test_keep_actions() {
+        let prompt = history(&[4, 4, 4, 4], 2, 0).compressed_prompt();
+        assert_eq!(prompt.items.len(), 4);
+        for (i, item) in prompt.items.iter().enumerate() {
+            let PromptItem::System { text } = item else { unreachable!() };
+            if i < 2 {
+                assert_eq!(text, &format!("Summary for action {}: Read a file", i));
+            } else {
+                assert_eq!(text, "xxxx");
+            }
+        }
+    }
+
+    #[test]
+    fn test_consolidate() {
+        let config = HistoryConfig { max_actions: 1, max_tokens: 0, max_summaries: 3 };
+        let mut history = History::new(Vec::new(), config);
+        for _ in 0..4 {
+            let messages = vec![PromptItem::Assistant { text: "ls".to_owned() }];
+            history.append("bash", messages, Summary::Text("Listed the files".to_owned()));
+        }
+        assert!(history.consolidation_prompt().is_none());
+
+        history.append("bash", Vec::new(), Summary::Text("Listed the files".to_owned()));
+        let (actions, prompt) = history.consolidation_prompt().unwrap();
+        assert_eq!(actions, 4);
+        assert_eq!(prompt.items.len(), 5);
+        history.consolidate(actions, "Listed the files four times".to_owned());
+
+        // The consolidated summary replaces the individual summaries until there are too many again
+        let prompt = history.compressed_prompt();
+        let PromptItem::System { text } = &prompt.items[0] else { unreachable!() };
+        assert_eq!(text, "Summary of actions 0 to 3: Listed the files four times");
+        assert_eq!(prompt.items.len(), 1);
+        for _ in 0..3 {
+            history.append("bash", Vec::new(), Summary::Text("Listed the files".to_owned()));
+        }
+        assert!(history.consolidation_prompt().is_none());
+        history.append("bash", Vec::new(), Summary::Text("Listed the files".to_owned()));
+        assert_eq!(history.consolidation_prompt().unwrap().0, 8);
+    }
+
+    #[test]
+    fn test_summarized_count() {
+        assert_eq!(history(&[], 0, 1000).summarized_count(), 0);
+        // The last action is kept, even if it exceeds the budget
+        assert_eq!(history(&[400, 8000], 0, 1000).summarized_count(), 1);
+        assert_eq!(history(&[400, 8000, 400], 0, 1000).summarized_count(), 2);
+        assert_eq!(history(&[400, 8000, 400], 0, 0).summarized_count(), 0);
+        assert_eq!(history(&[400, 400, 400], 1, 1000).summarized_count(), 2);
     }
 }
@@ -0,0 +1,64 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::llm::PromptItem;
+
+use super::always_include::truncate;
+
+const INSTRUCTIONS: &str = r#"The repository provides the following instructions for agents in"#;
+
+const INSTRUCTIONS_PRECEDENCE: &str = r#"Follow these instructions. Where they conflict with the general instructions above, these instructions take precedence.
+They can not lift restrictions that are enforced by the system, such as protected paths."#;
+
+/// Instructions for agents that are shipped with the repository, e.g. in `AGENTS.md`
+pub struct RepoInstructions {
+    path: String,
+    content: String,
+}
+
+impl RepoInstructions {
+    /// Load the first of the given files that exists at the root of the workspace
+    pub fn load(workspace_dir: &Path, filenames: &[String], max_bytes: usize) -> Option<Self> {
+        for filename in filenames.iter().filter(|f| !f.trim().is_empty()) {
+            match fs::read_to_string(workspace_dir.join(filename)) {
+                Ok(content) => {
+                    log::info!("Using the agent instructions in `{}`", filename);
+                    let content = truncate(content, max_bytes);
+                    return Some(Self { path: filename.to_owned(), content });
+                }
+                Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+                Err(err) => log::warn!("Failed to read agent instructions `{}`: {}", filename, err),
+            }
+        }
+        None
+    }
+
+    pub fn prompt_items(&self) -> Vec<PromptItem> {
+        vec![
+            PromptItem::System { text: format!("{} `{}`:", INSTRUCTIONS, self.path) },
+            PromptItem::System { text: self.content.clone() },
+            PromptItem::System { text: INSTRUCTIONS_PRECEDENCE.to_owned() },
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_load_first_existing_file() {
+        let dir = std::env::temp_dir().join(format!("minion-instructions-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("CONVENTIONS.md"), "Use tabs.").unwrap();
+        let filenames = vec!["AGENTS.md".to_owned(), "CONVENTIONS.md".to_owned()];
+
+        let instructions = RepoInstructions::load(&dir, &filenames, 1024).unwrap();
+        assert_eq!(instructions.path, "CONVENTIONS.md");
+        assert_eq!(instructions.content, "Use tabs.");
+        assert!(RepoInstructions::load(&dir, &filenames[..1], 1024).is_none());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
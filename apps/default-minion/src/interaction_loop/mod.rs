@@ -1,5 +1,19 @@
+mod always_include;
+mod budget;
+mod control;
 mod history;
+mod instructions;
 mod resources;
+mod retrieval;
+mod review;
+mod router;
 mod run;
+mod stats;
+mod summary;
 
-pub use run::{run, TaskOutcome};
+pub use budget::ModelPrice;
+pub use history::{History, HistoryConfig};
+pub use instructions::RepoInstructions;
+pub use router::TaskDifficulty;
+pub use run::{intro, run, TaskOutcome, Workspace};
+pub use summary::SummaryFormat;
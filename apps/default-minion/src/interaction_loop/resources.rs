@@ -1,12 +1,216 @@
-use std::collections::HashSet;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeSet, HashMap};
+use std::hash::{Hash, Hasher};
+
+use crate::actions::secrets::Redactor;
+use crate::container::{Container, ReadFileError};
+use crate::llm::PromptItem;
+
+use super::always_include::truncate;
 
 #[derive(Default)]
 pub struct Resources {
-    pub open_files: HashSet<String>,
+    /// Files the model has read or changed, from the least to the most recently touched
+    pub open_files: Vec<String>,
+    /// Files whose current content is always part of the prompt, regardless of compression
+    pub focus: BTreeSet<String>,
+    /// The hashes of the open files as of the start of the last action, `None` for missing files
+    ///
+    /// Files the model touched in the last action have no hash yet, so its own changes are not
+    /// reported as changes.
+    hashes: HashMap<String, Option<u64>>,
 }
 
 impl Resources {
     pub fn add_file(&mut self, filename: &str) {
-        self.open_files.insert(filename.to_owned());
+        self.open_files.retain(|file| file != filename);
+        self.open_files.push(filename.to_owned());
+        self.hashes.remove(filename);
+    }
+
+    /// Stop tracking a file that has been deleted
+    pub fn remove_file(&mut self, filename: &str) {
+        self.open_files.retain(|file| file != filename);
+        self.focus.remove(filename);
+        self.hashes.remove(filename);
+    }
+
+    /// Track a file under its new name after it has been moved, replacing the file at `to`
+    pub fn rename_file(&mut self, from: &str, to: &str) {
+        if self.open_files.iter().any(|file| file == from) {
+            self.open_files.retain(|file| file != to);
+            self.hashes.remove(from);
+            self.hashes.remove(to);
+            for file in &mut self.open_files {
+                if file == from {
+                    *file = to.to_owned();
+                }
+            }
+        }
+        if self.focus.remove(from) {
+            self.focus.insert(to.to_owned());
+        }
+    }
+
+    /// The open files that changed since the start of the last action, other than by the model
+    /// touching them, e.g. because a command formatted or deleted them
+    ///
+    /// The files are read from the container, and their hashes are updated for the next call.
+    pub async fn changed_files(&mut self, container: &Container) -> Vec<String> {
+        let mut changed = Vec::new();
+        for path in &self.open_files {
+            let hash = match container.read_file_bytes(path).await {
+                Ok(content) => Some(hash(&content)),
+                Err(ReadFileError::NotFound) => None,
+                Err(err) => {
+                    log::warn!("Failed to read open file `{}`: {}", path, err);
+                    continue;
+                }
+            };
+            if self.hashes.insert(path.clone(), hash).is_some_and(|previous| previous != hash) {
+                changed.push(path.clone());
+            }
+        }
+        changed
+    }
+
+    /// The current content of the most recently touched files, read from the container
+    ///
+    /// Focused files are skipped, as they are part of the prompt prefix anyway. The content is
+    /// limited to `max_bytes` in total, the last file that fits is truncated. Secrets are redacted
+    /// without logging, as the files are read again for every action.
+    pub async fn open_file_items(
+        &self,
+        container: &Container,
+        redactor: &Redactor,
+        max_bytes: usize,
+    ) -> Vec<PromptItem> {
+        let mut files = Vec::new();
+        let mut bytes = 0;
+        for path in self.recent_files() {
+            if bytes >= max_bytes {
+                break;
+            }
+            match container.read_file(path).await {
+                Ok(content) => {
+                    let content = redactor.redact_silently(&content);
+                    bytes += content.len();
+                    files.push((path.as_str(), content));
+                }
+                Err(ReadFileError::NotFound) => {}
+                Err(err) => log::warn!("Failed to read open file `{}`: {}", path, err),
+            }
+        }
+        open_file_items(files, max_bytes)
+    }
+
+    /// The open files that are not focused, from the most to the least recently touched
+    fn recent_files(&self) -> impl Iterator<Item = &String> {
+        self.open_files.iter().rev().filter(|file| !self.focus.contains(*file))
+    }
+}
+
+fn hash(content: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    content.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// The prompt items of the files and their content, limited to `max_bytes` of content in total
+fn open_file_items(files: Vec<(&str, String)>, max_bytes: usize) -> Vec<PromptItem> {
+    let mut items = Vec::new();
+    let mut remaining = max_bytes;
+    for (path, content) in files {
+        if remaining == 0 {
+            break;
+        }
+        let content = truncate(content, remaining);
+        remaining = remaining.saturating_sub(content.len());
+        items.push(PromptItem::System {
+            text: format!("The current content of the open file `{}` is:", path),
+        });
+        items.push(PromptItem::System { text: content });
+    }
+    items
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::container::TestContainer;
+
+    use super::*;
+
+    #[test]
+    fn test_remove_tracked_file() {
+        let mut resources = Resources::default();
+        resources.add_file("src/main.rs");
+        resources.focus.insert("src/main.rs".to_owned());
+        resources.remove_file("src/main.rs");
+        assert!(resources.open_files.is_empty());
+        assert!(resources.focus.is_empty());
+    }
+
+    #[test]
+    fn test_rename_over_tracked_file() {
+        let mut resources = Resources::default();
+        resources.add_file("old.rs");
+        resources.add_file("new.rs");
+        resources.focus.insert("old.rs".to_owned());
+        resources.rename_file("old.rs", "new.rs");
+        assert_eq!(resources.open_files, ["new.rs"]);
+        assert_eq!(resources.focus, BTreeSet::from(["new.rs".to_owned()]));
+
+        resources.rename_file("untracked.rs", "other.rs");
+        assert!(!resources.open_files.contains(&"other.rs".to_owned()));
+    }
+
+    #[test]
+    fn test_open_file_items() {
+        let mut resources = Resources::default();
+        resources.add_file("a.rs");
+        resources.add_file("b.rs");
+        resources.add_file("focused.rs");
+        resources.add_file("a.rs");
+        resources.focus.insert("focused.rs".to_owned());
+        let recent: Vec<&String> = resources.recent_files().collect();
+        assert_eq!(recent, ["a.rs", "b.rs"]);
+
+        let files = vec![("a.rs", "fn a() {}".to_owned()), ("b.rs", "fn b() {}".to_owned())];
+        let texts: Vec<String> = open_file_items(files, 12)
+            .into_iter()
+            .map(|item| match item {
+                PromptItem::System { text } => text,
+                _ => unreachable!(),
+            })
+            .collect();
+        assert_eq!(
+            texts,
+            [
+                "The current content of the open file `a.rs` is:",
+                "fn a() {}",
+                "The current content of the open file `b.rs` is:",
+                "fn \n[truncated]",
+            ]
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore = "requires Docker"]
+    async fn test_changed_files() {
+        let files = [("a.txt", "a"), ("b.txt", "b")];
+        let container = TestContainer::start("changed", r#"{"image": "alpine:3"}"#, &files).await;
+        let mut resources = Resources::default();
+        resources.add_file("a.txt");
+        resources.add_file("b.txt");
+        assert!(resources.changed_files(&container).await.is_empty());
+
+        container.run_script_streaming("echo changed > a.txt", &mut |_| {}).await.unwrap();
+        assert_eq!(resources.changed_files(&container).await, ["a.txt"]);
+        assert!(resources.changed_files(&container).await.is_empty());
+
+        // Changes of the model itself are not reported
+        container.write_file("b.txt", "edited").await.unwrap();
+        resources.add_file("b.txt");
+        assert!(resources.changed_files(&container).await.is_empty());
     }
 }
@@ -0,0 +1,181 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::actions::git::Repo;
+use crate::llm::{LLMClient, PromptError};
+
+/// The number of lines per chunk
+const CHUNK_LINES: usize = 40;
+/// The maximum number of characters of a chunk that are embedded
+const MAX_EMBEDDED_CHARS: usize = 8000;
+/// Larger files are not indexed, they are usually generated or data
+const MAX_INDEXED_FILE_BYTES: u64 = 100 * 1024;
+/// The number of chunks that are embedded per request
+const EMBEDDING_BATCH_SIZE: usize = 64;
+
+/// A range of lines of a file
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Chunk {
+    pub path: String,
+    /// The first line, starting at 1
+    pub start_line: usize,
+    /// The last line, inclusive
+    pub end_line: usize,
+    pub text: String,
+}
+
+/// Split a file into chunks of [`CHUNK_LINES`] lines, skipping chunks that are only whitespace
+fn chunk_file(path: &str, content: &str) -> Vec<Chunk> {
+    let lines: Vec<&str> = content.lines().collect();
+    lines
+        .chunks(CHUNK_LINES)
+        .enumerate()
+        .filter(|(_, lines)| lines.iter().any(|line| !line.trim().is_empty()))
+        .map(|(i, lines)| Chunk {
+            path: path.to_owned(),
+            start_line: i * CHUNK_LINES + 1,
+            end_line: i * CHUNK_LINES + lines.len(),
+            text: lines.join("\n"),
+        })
+        .collect()
+}
+
+fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+    let norm_a = a.iter().map(|x| x * x).sum::<f32>().sqrt();
+    let norm_b = b.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm_a == 0.0 || norm_b == 0.0 {
+        return 0.0;
+    }
+    dot / (norm_a * norm_b)
+}
+
+/// An embedding index over the chunks of the tracked files of a repository at a commit
+#[derive(Serialize, Deserialize)]
+pub struct Index {
+    model: String,
+    commit: String,
+    chunks: Vec<Chunk>,
+    embeddings: Vec<Vec<f32>>,
+}
+
+impl Index {
+    /// Load the index of the current commit from the cache, or build (and cache) it
+    pub async fn load_or_build(
+        llm_client: &LLMClient,
+        git_repo: &Repo,
+        repo_url: &str,
+        model: &str,
+        max_chunks: usize,
+        cache_dir: &Path,
+    ) -> Result<Self, PromptError> {
+        let commit = git_repo.head_commit().expect("Failed to determine the commit");
+        let cache_path = cache_path(cache_dir, repo_url, &commit, model);
+        if let Some(index) =
+            fs::read(&cache_path).ok().and_then(|c| serde_json::from_slice(&c).ok())
+        {
+            log::info!("Loaded retrieval index from `{}`", cache_path.display());
+            return Ok(index);
+        }
+
+        let mut chunks = Vec::new();
+        for path in git_repo.tracked_files().expect("Failed to list the tracked files") {
+            let host_path = git_repo.workdir().join(&path);
+            let is_small = fs::metadata(&host_path)
+                .map(|m| m.is_file() && m.len() <= MAX_INDEXED_FILE_BYTES)
+                .unwrap_or(false);
+            // Binary files are skipped, as they are not valid UTF-8
+            if let Some(content) = is_small.then(|| fs::read_to_string(&host_path).ok()).flatten() {
+                chunks.extend(chunk_file(&path, &content));
+            }
+        }
+        if chunks.len() > max_chunks {
+            log::warn!("Only indexing {} of {} chunks", max_chunks, chunks.len());
+            chunks.truncate(max_chunks);
+        }
+
+        log::info!("Embedding {} chunks for retrieval", chunks.len());
+        let mut embeddings = Vec::with_capacity(chunks.len());
+        for batch in chunks.chunks(EMBEDDING_BATCH_SIZE) {
+            let inputs = batch.iter().map(embedding_input).collect();
+            embeddings.extend(llm_client.embed(model, inputs).await?);
+        }
+
+        let index = Self { model: model.to_owned(), commit, chunks, embeddings };
+        let json = serde_json::to_vec(&index).expect("Failed to serialize retrieval index");
+        if let Err(err) = fs::create_dir_all(cache_dir).and_then(|()| fs::write(&cache_path, json))
+        {
+            log::warn!("Failed to cache retrieval index: {}", err);
+        }
+        Ok(index)
+    }
+
+    /// The commit the index was built for
+    pub fn commit(&self) -> &str {
+        &self.commit
+    }
+
+    /// The `k` chunks that are most similar to the query
+    pub async fn search(
+        &self,
+        llm_client: &LLMClient,
+        query: &str,
+        k: usize,
+    ) -> Result<Vec<&Chunk>, PromptError> {
+        let query = llm_client.embed(&self.model, vec![query.to_owned()]).await?;
+        let query = query.first().ok_or(PromptError::MissingCompletion)?;
+        let mut scored: Vec<(f32, &Chunk)> = self
+            .embeddings
+            .iter()
+            .zip(&self.chunks)
+            .map(|(embedding, chunk)| (cosine_similarity(query, embedding), chunk))
+            .collect();
+        scored.sort_by(|a, b| b.0.total_cmp(&a.0));
+        Ok(scored.into_iter().take(k).map(|(_, chunk)| chunk).collect())
+    }
+}
+
+/// The text that is embedded for a chunk, which includes its path as context
+fn embedding_input(chunk: &Chunk) -> String {
+    let text: String = chunk.text.chars().take(MAX_EMBEDDED_CHARS).collect();
+    format!("{}:{}-{}\n{}", chunk.path, chunk.start_line, chunk.end_line, text)
+}
+
+/// The cache file of an index, which is specific to the repository, commit and model
+fn cache_path(cache_dir: &Path, repo_url: &str, commit: &str, model: &str) -> PathBuf {
+    let sanitize = |s: &str| -> String {
+        s.chars().map(|c| if c.is_ascii_alphanumeric() || c == '-' { c } else { '_' }).collect()
+    };
+    cache_dir.join(format!("{}-{}-{}.json", sanitize(repo_url), commit, sanitize(model)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chunk_file() {
+        let content: String = (1..=90).map(|i| format!("line {}\n", i)).collect();
+        let chunks = chunk_file("src/lib.rs", &content);
+        let ranges: Vec<(usize, usize)> =
+            chunks.iter().map(|c| (c.start_line, c.end_line)).collect();
+        assert_eq!(ranges, [(1, 40), (41, 80), (81, 90)]);
+        assert!(chunks[2].text.starts_with("line 81\n"));
+        assert!(chunk_file("empty.txt", "\n\n").is_empty());
+    }
+
+    #[test]
+    fn test_cosine_similarity() {
+        assert_eq!(cosine_similarity(&[1.0, 0.0], &[2.0, 0.0]), 1.0);
+        assert_eq!(cosine_similarity(&[1.0, 0.0], &[0.0, 1.0]), 0.0);
+        assert_eq!(cosine_similarity(&[0.0, 0.0], &[0.0, 1.0]), 0.0);
+    }
+
+    #[test]
+    fn test_cache_path() {
+        let path = cache_path(Path::new("cache"), "https://host/org/repo.git", "abc123", "m-1");
+        assert_eq!(path, Path::new("cache/https___host_org_repo_git-abc123-m-1.json"));
+    }
+}
@@ -0,0 +1,81 @@
+use serde::Serialize;
+
+pub const REVIEW_INTRO: &str = r#"This task is a review of the following changes.
+You can not modify any files. Instead, you will provide review comments on the changes when you end the task.
+The changes are:"#;
+
+pub const ACTION_REVIEW_COMMENTS: &str = r#"Provide your review comments, one per line, in the following format:
+
+<path>:<line>: <comment>
+
+The line refers to the new version of the file. If you have no comments, write:
+
+none
+"#;
+
+/// A comment on a line of a reviewed file
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub struct ReviewComment {
+    pub file: String,
+    pub line: usize,
+    pub comment: String,
+}
+
+/// Parse review comments in the `<path>:<line>: <comment>` format
+///
+/// Lines that do not match the format are skipped.
+pub fn parse_review_comments(message: &str) -> Vec<ReviewComment> {
+    message
+        .lines()
+        .filter_map(|line| {
+            let line = line.trim().trim_start_matches("- ").trim_start_matches("* ");
+            let (file, rest) = line.split_once(':')?;
+            let (number, comment) = rest.split_once(':')?;
+            let file = file.trim().trim_matches('`');
+            let comment = comment.trim();
+            if file.is_empty() || comment.is_empty() {
+                return None;
+            }
+            Some(ReviewComment {
+                file: file.to_owned(),
+                line: number.trim().parse().ok()?,
+                comment: comment.to_owned(),
+            })
+        })
+        .collect()
+}
+
+/// A Markdown list of review comments
+pub fn format_review_comments(comments: &[ReviewComment]) -> String {
+    comments.iter().map(|c| format!("* `{}` line {}: {}\n", c.file, c.line, c.comment)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_review_comments() {
+        let message = "src/main.rs:12: Handle the error instead of unwrapping\n\
+                       - `src/lib.rs`:3: Typo in the doc comment: \"teh\"\n\
+                       Overall, the change looks good.\n\
+                       src/lib.rs:x: Not a line number";
+        let comments = parse_review_comments(message);
+        assert_eq!(
+            comments,
+            vec![
+                ReviewComment {
+                    file: "src/main.rs".to_owned(),
+                    line: 12,
+                    comment: "Handle the error instead of unwrapping".to_owned(),
+                },
+                ReviewComment {
+                    file: "src/lib.rs".to_owned(),
+                    line: 3,
+                    comment: "Typo in the doc comment: \"teh\"".to_owned(),
+                },
+            ]
+        );
+        assert!(parse_review_comments("none").is_empty());
+    }
+}
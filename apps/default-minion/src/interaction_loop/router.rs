@@ -0,0 +1,403 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use agent_api::types::task::Task;
+use async_openai::types::ResponseFormatJsonSchema;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+
+use crate::config::Config;
+use crate::llm::{is_model_of, LLMClient, Prompt, PromptError, PromptOptions};
+
+/// The maximum number of characters per prompt item when a prompt exceeds the context length
+const TRIM_MAX_ITEM_CHARS: usize = 2000;
+/// The number of most recent prompt items that are never trimmed, as they contain the instructions
+const TRIM_KEEP_LAST_ITEMS: usize = 2;
+/// The number of characters of a streamed completion after which its progress is logged again
+const STREAM_PROGRESS_CHARS: usize = 1000;
+
+/// The estimated difficulty of a step
+#[derive(Clone, Copy, Debug)]
+pub enum Difficulty {
+    /// Bare responses (action names, file paths, summaries), always handled by the basic model
+    Trivial,
+    /// Steps the basic model can usually handle, routed to it if model routing is enabled
+    Easy,
+    /// Steps that require the smart model, as long as the thinking budget allows
+    Hard,
+}
+
+/// The difficulty of a task as a whole, which shifts the routing of its steps
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TaskDifficulty {
+    /// All steps are handled by the basic model
+    Trivial,
+    /// Steps are routed by their difficulty
+    #[default]
+    Normal,
+    /// All steps that are not trivial are handled by the smart model, even with model routing
+    Hard,
+}
+
+/// The prefix of a line in the task description that hints at the difficulty of the task
+const DIFFICULTY_HINT: &str = "difficulty:";
+
+impl TaskDifficulty {
+    /// The configured difficulty, or the one hinted at in the task description
+    ///
+    /// The task has no metadata of its own, so hints are given as a line like
+    /// `Difficulty: hard` in the description.
+    pub fn of_task(task: &Task, config: &Config) -> Self {
+        config
+            .task_difficulty
+            .or_else(|| Self::from_description(&task.description))
+            .unwrap_or_default()
+    }
+
+    fn from_description(description: &str) -> Option<Self> {
+        description.lines().find_map(|line| {
+            let line = line.trim().to_lowercase();
+            let hint = line.strip_prefix(DIFFICULTY_HINT)?.trim();
+            match hint {
+                "trivial" | "easy" => Some(Self::Trivial),
+                "normal" | "medium" => Some(Self::Normal),
+                "hard" | "difficult" => Some(Self::Hard),
+                _ => {
+                    log::warn!("Unknown difficulty hint `{}`", hint);
+                    None
+                }
+            }
+        })
+    }
+}
+
+/// A single prompt in the interaction loop
+#[derive(Clone, Copy, Debug)]
+pub enum Step {
+    Plan,
+    Discuss,
+    DiscussAction,
+    SelectAction,
+    Bash,
+    Interactive,
+    TestCommand,
+    EditFilepath,
+    CreateFile,
+    EditDiscuss,
+    EditReplace,
+    ReplaceFilepath,
+    ReplaceInFile,
+    PatchFilepath,
+    Patch,
+    DeletePath,
+    MovePaths,
+    WriteFiles,
+    ReadFilepath,
+    ReadRefFilepath,
+    StatPath,
+    ListDirPath,
+    SearchPattern,
+    FocusPaths,
+    PackageNames,
+    RetrieveQuery,
+    AskUser,
+    EndTaskDiscuss,
+    EndTaskSelect,
+    ReviewComments,
+    CompleteDescription,
+    FailureDescription,
+    FailureReasonDiscuss,
+    FailureReasonSelect,
+    Summarize,
+    ConsolidateSummaries,
+}
+
+impl Step {
+    pub fn phase(&self) -> Phase {
+        match self {
+            Step::Plan
+            | Step::Discuss
+            | Step::EditDiscuss
+            | Step::AskUser
+            | Step::RetrieveQuery
+            | Step::ReviewComments
+            | Step::EndTaskDiscuss
+            | Step::FailureReasonDiscuss => Phase::Discuss,
+            Step::DiscussAction
+            | Step::SelectAction
+            | Step::EditFilepath
+            | Step::ReplaceFilepath
+            | Step::PatchFilepath
+            | Step::DeletePath
+            | Step::MovePaths
+            | Step::ReadFilepath
+            | Step::ReadRefFilepath
+            | Step::StatPath
+            | Step::ListDirPath
+            | Step::SearchPattern
+            | Step::TestCommand
+            | Step::FocusPaths
+            | Step::PackageNames
+            | Step::EndTaskSelect
+            | Step::FailureReasonSelect => Phase::Select,
+            Step::Bash | Step::Interactive => Phase::Bash,
+            Step::CreateFile
+            | Step::EditReplace
+            | Step::ReplaceInFile
+            | Step::Patch
+            | Step::WriteFiles => Phase::Edit,
+            Step::Summarize
+            | Step::ConsolidateSummaries
+            | Step::CompleteDescription
+            | Step::FailureDescription => Phase::Summarize,
+        }
+    }
+}
+
+/// Groups of steps that share the same model and sampling configuration
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Phase {
+    /// Planning and discussing
+    Discuss,
+    /// Choosing actions, file paths and other bare responses
+    Select,
+    /// Writing bash scripts
+    Bash,
+    /// Writing file contents
+    Edit,
+    /// Summarizing actions and the task
+    Summarize,
+}
+
+/// Configured overrides of a phase
+struct PhaseOverride {
+    model: Option<String>,
+    temperature: Option<f32>,
+}
+
+/// Routes prompts to the smart or basic model depending on the difficulty of the step
+///
+/// Models and temperatures configured for the phase of a step take precedence.
+pub struct ModelRouter {
+    llm_client: LLMClient,
+    task_difficulty: TaskDifficulty,
+    routing: bool,
+    thinking_budget: Option<usize>,
+    smart_calls: AtomicUsize,
+    overrides: HashMap<Phase, PhaseOverride>,
+    vision_models: Vec<String>,
+    vision_fallback_model: Option<String>,
+    smart_model: String,
+    basic_model: String,
+    stream: bool,
+    /// Models that can respond with JSON constrained to a schema
+    json_models: Vec<String>,
+    max_completion_tokens: Option<u32>,
+}
+
+impl ModelRouter {
+    pub fn new(llm_client: &LLMClient, config: &Config, task_difficulty: TaskDifficulty) -> Self {
+        let overrides = HashMap::from([
+            (
+                Phase::Discuss,
+                PhaseOverride {
+                    model: config.discuss_model.clone(),
+                    temperature: config.discuss_temperature,
+                },
+            ),
+            (
+                Phase::Select,
+                PhaseOverride {
+                    model: config.select_model.clone(),
+                    temperature: config.select_temperature,
+                },
+            ),
+            (
+                Phase::Bash,
+                PhaseOverride {
+                    model: config.bash_model.clone(),
+                    temperature: config.bash_temperature,
+                },
+            ),
+            (
+                Phase::Edit,
+                PhaseOverride {
+                    model: config.edit_model.clone(),
+                    temperature: config.edit_temperature,
+                },
+            ),
+            (
+                Phase::Summarize,
+                PhaseOverride {
+                    model: config.summarize_model.clone(),
+                    temperature: config.summarize_temperature,
+                },
+            ),
+        ]);
+        Self {
+            llm_client: llm_client.clone(),
+            task_difficulty,
+            routing: config.model_routing,
+            thinking_budget: config.thinking_budget,
+            smart_calls: AtomicUsize::new(0),
+            overrides,
+            vision_models: config.vision_models.clone(),
+            vision_fallback_model: config.vision_fallback_model.clone(),
+            smart_model: config.smart_model.clone(),
+            basic_model: config.basic_model.clone(),
+            stream: config.stream_completions,
+            json_models: config.json_mode_models.clone(),
+            max_completion_tokens: config.max_completion_tokens,
+        }
+    }
+
+    /// The client the router prompts the models with
+    pub fn llm_client(&self) -> &LLMClient {
+        &self.llm_client
+    }
+
+    pub async fn prompt(
+        &self,
+        step: Step,
+        difficulty: Difficulty,
+        prompt: &Prompt,
+    ) -> Result<String, PromptError> {
+        let (model, prompt) = self.select_model(step, difficulty, prompt);
+        let options = self.options(step);
+        match self.complete(step, model, &options, &prompt).await {
+            Err(PromptError::ContextLengthExceeded) => {
+                // Retry once with a trimmed prompt, and only fail if it still does not fit
+                log::warn!("Prompt of step {:?} exceeds the context length, trimming", step);
+                let prompt = prompt.trimmed(TRIM_MAX_ITEM_CHARS, TRIM_KEEP_LAST_ITEMS);
+                self.complete(step, model, &options, &prompt).await
+            }
+            result => result,
+        }
+    }
+
+    /// Whether the model of the step can respond with JSON, see [`Self::prompt_json`]
+    pub fn json_mode(&self, step: Step, difficulty: Difficulty) -> bool {
+        let model = match &self.overrides[&step.phase()].model {
+            Some(model) => model.as_str(),
+            None => self.route(difficulty, false),
+        };
+        is_model_of(model, &self.json_models)
+    }
+
+    /// Prompt for JSON constrained to the schema, only if the model supports it, see
+    /// [`Self::json_mode`]
+    pub async fn prompt_json<T: DeserializeOwned>(
+        &self,
+        step: Step,
+        difficulty: Difficulty,
+        prompt: &Prompt,
+        schema: &ResponseFormatJsonSchema,
+    ) -> Result<T, PromptError> {
+        let (model, prompt) = self.select_model(step, difficulty, prompt);
+        let options = self.options(step);
+        match self.llm_client.prompt_json(model, &options, &prompt, schema).await {
+            Err(PromptError::ContextLengthExceeded) => {
+                log::warn!("Prompt of step {:?} exceeds the context length, trimming", step);
+                let prompt = prompt.trimmed(TRIM_MAX_ITEM_CHARS, TRIM_KEEP_LAST_ITEMS);
+                self.llm_client.prompt_json(model, &options, &prompt, schema).await
+            }
+            result => result,
+        }
+    }
+
+    /// The model that handles the step, with the prompt adapted to it
+    fn select_model<'a>(
+        &'a self,
+        step: Step,
+        difficulty: Difficulty,
+        prompt: &'a Prompt,
+    ) -> (&'a str, Cow<'a, Prompt>) {
+        let model = match &self.overrides[&step.phase()].model {
+            Some(model) => model.as_str(),
+            None => self.route(difficulty, true),
+        };
+        // Models without vision fail on images, so prompts with images are rerouted or degraded
+        let (model, prompt) = if prompt.has_images() && !is_model_of(model, &self.vision_models) {
+            match &self.vision_fallback_model {
+                Some(fallback) => (fallback.as_str(), Cow::Borrowed(prompt)),
+                None => (model, Cow::Owned(prompt.without_images())),
+            }
+        } else {
+            (model, Cow::Borrowed(prompt))
+        };
+        log::info!("Step {:?} ({:?}) is handled by `{}`", step, difficulty, model);
+        (model, prompt)
+    }
+
+    /// Prompt the model, streaming the completion and logging its progress if configured
+    async fn complete(
+        &self,
+        step: Step,
+        model: &str,
+        options: &PromptOptions,
+        prompt: &Prompt,
+    ) -> Result<String, PromptError> {
+        if !self.stream {
+            return self.llm_client.prompt(model, options, prompt).await;
+        }
+        let received = AtomicUsize::new(0);
+        let on_token = |token: &str| {
+            let before = received.fetch_add(token.chars().count(), Ordering::Relaxed);
+            let after = before + token.chars().count();
+            if before / STREAM_PROGRESS_CHARS < after / STREAM_PROGRESS_CHARS {
+                log::info!("Step {:?} received {} characters so far", step, after);
+            }
+        };
+        self.llm_client.prompt_stream(model, options, prompt, Some(&on_token)).await
+    }
+
+    /// The options of the prompts of the step
+    fn options(&self, step: Step) -> PromptOptions {
+        PromptOptions {
+            temperature: self.overrides[&step.phase()].temperature,
+            max_tokens: self.max_completion_tokens,
+            ..PromptOptions::default()
+        }
+    }
+
+    /// The model for the difficulty, counting calls of the smart model only if `count` is set
+    fn route(&self, difficulty: Difficulty, count: bool) -> &str {
+        match (self.task_difficulty, difficulty) {
+            (TaskDifficulty::Trivial, _) | (_, Difficulty::Trivial) => &self.basic_model,
+            (TaskDifficulty::Normal, Difficulty::Easy) if self.routing => &self.basic_model,
+            (_, Difficulty::Easy | Difficulty::Hard) => {
+                let smart_calls = if count {
+                    self.smart_calls.fetch_add(1, Ordering::Relaxed)
+                } else {
+                    self.smart_calls.load(Ordering::Relaxed)
+                };
+                match self.thinking_budget {
+                    Some(budget) if smart_calls >= budget => {
+                        if count && smart_calls == budget {
+                            log::warn!("Thinking budget of {} smart model calls exhausted", budget);
+                        }
+                        &self.basic_model
+                    }
+                    _ => &self.smart_model,
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_difficulty_hint_in_description() {
+        let description = "Refactor the parser.\n\nDifficulty: Hard\n";
+        assert_eq!(TaskDifficulty::from_description(description), Some(TaskDifficulty::Hard));
+        let description = "Fix a typo\ndifficulty: trivial";
+        assert_eq!(TaskDifficulty::from_description(description), Some(TaskDifficulty::Trivial));
+        assert_eq!(TaskDifficulty::from_description("Fix a typo"), None);
+        assert_eq!(TaskDifficulty::from_description("Difficulty: unknown"), None);
+    }
+}
@@ -1,15 +1,53 @@
+use std::any::Any;
+use std::collections::BTreeSet;
+use std::panic::AssertUnwindSafe;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
 use agent_api::types::task::{Task, TaskComplete, TaskFailure, TaskFailureReason, TaskStatus};
+use async_openai::types::ResponseFormatJsonSchema;
 
-use crate::actions::files::{read_file, write_file};
-use crate::actions::markdown::strip_wrapping_markdown_code_fences;
-use crate::container::{Container, Output, ReadFileError};
-use crate::llm::{self, Prompt, PromptItem};
+use futures_util::FutureExt;
+use serde::Deserialize;
 
+use crate::actions::files::{
+    number_lines, parse_files, parse_line_range_edit, parse_search_replace, read_file,
+    replace_unique, splice_lines, write_file, write_files, LineEnding, WriteFileError,
+};
+use crate::actions::git::Repo;
+use crate::actions::interactive::parse_interactive_script;
+use crate::actions::markdown::strip_wrapping_markdown_code_fences;
+use crate::actions::packages::{
+    is_valid_package_name, looks_offline, PackageAllowlist, PackageManager, PackageState,
+    DETECT_PACKAGE_MANAGER,
+};
+use crate::actions::patch::{apply_patch, parse_patch};
+use crate::actions::protected::ProtectedPaths;
+use crate::actions::secrets::Redactor;
+use crate::config::Config;
+use crate::container::{
+    Container, FileOpError, ListDirError, Output, OutputChunk, PathStat, ReadFileError,
+    WorkspaceMount,
+};
+use crate::llm::{self, ContentItem, Prompt, PromptItem};
+use crate::output::OutputDir;
+use crate::project::ProjectKind;
+
+use super::always_include::AlwaysInclude;
+use super::budget::Budget;
+use super::control::{next_control, recv_control, spawn_stdin_reader, Control};
 use super::history::History;
+use super::instructions::RepoInstructions;
 use super::resources::Resources;
-
-const SMART_MODEL: &str = "o1-mini";
-const BASIC_MODEL: &str = "gpt-4o-mini";
+use super::retrieval::Index;
+use super::review::{
+    format_review_comments, parse_review_comments, ReviewComment, ACTION_REVIEW_COMMENTS,
+    REVIEW_INTRO,
+};
+use super::router::{Difficulty, ModelRouter, Step, TaskDifficulty};
+use super::stats::{ActionStats, TestRun};
+use super::summary::{Summary, SummaryFormat, SUMMARIZE_STRUCTURED};
 
 const INTRO_1: &str = r#"You are an autonomous agent that solves coding tasks.
 You keep your explanations as concise as possible.
@@ -17,58 +55,357 @@ You are connected to a Linux-based development environment. You are in the proje
 Your current task is as follows:"#;
 
 const INTRO_2: &str = r#"In order to complete the task, the system will guide you through a series of actions.
-In each action, you will be able to interact with the environment using the following actions:
+In each action, you will be able to interact with the environment using the following actions:"#;
 
-* `bash`: Execute bash code
-* `read-file`: Read the contents of a file
-* `edit-file`: Read, and optionally replace the contents of a file
-* `end-task`: End your task because it is completed, or because there is an insurmountable issue preventing you from completing it.
-
-You will be instructed when to choose an action.
+const INTRO_3: &str = r#"You will be instructed when to choose an action.
 You can use the `bash` action to install and execute arbitrary command line tools that are helpful for your task.
-You can use `ls` or `tree` to explore the file system, or `curl` to download files.
-You do not need to use `sudo` as you are already running as a privileged user.
-"#;
+You can use the `search` action to locate code, the `list-dir` action to explore the file system, or `curl` to download files.
+Use the `run-tests` action to verify your changes."#;
+
+const INTRO_PRIVILEGED: &str =
+    r#"You do not need to use `sudo` as you are already running as a privileged user."#;
+
+const INTRO_UNPRIVILEGED: &str = r#"You are running as an unprivileged user.
+Use `sudo`, if it is available, for commands that need elevated privileges, e.g. installing packages."#;
 
 pub enum TaskOutcome {
     Complete(TaskComplete),
+    /// A review is complete, the comments are also part of the description
+    Reviewed {
+        info: TaskComplete,
+        comments: Vec<ReviewComment>,
+    },
     Failure(TaskFailure),
 }
 
-pub async fn run(llm_client: &llm::LLMClient, container: &Container, task: &Task) -> TaskOutcome {
-    let mut resources = Resources::default();
+/// The environment actions are executed in
+struct Env<'a> {
+    router: ModelRouter,
+    container: &'a Container,
+    git_repo: &'a Repo,
+    config: &'a Config,
+    redactor: &'a Redactor,
+    protected_paths: ProtectedPaths,
+    /// Whether the task is a review, which must not modify any files
+    review: bool,
+    /// The embedding index of the repository, if retrieval is enabled and the index was built
+    retrieval: Option<Index>,
+    /// How often the container has been restarted because it stopped responding
+    container_restarts: AtomicUsize,
+    /// Whether the task is obviously answered without changes, e.g. a question
+    read_only_task: bool,
+    /// Whether the model was challenged for completing the task without changes
+    no_changes_challenged: AtomicBool,
+    /// The packages the model may install with the `install-packages` action
+    package_allowlist: PackageAllowlist,
+    packages: Mutex<PackageState>,
+    /// The kind of the project, whose test command is the default of the `run-tests` action
+    project: Option<ProjectKind>,
+    /// The result of the last `run-tests` action
+    last_test_run: Mutex<Option<TestRun>>,
+}
 
-    assert_eq!(task.status, TaskStatus::Running);
+/// The workspace of a task, which the actions work in
+pub struct Workspace<'a> {
+    pub container: &'a Container,
+    pub git_repo: &'a Repo,
+    /// The kind of the project, as detected when the repository was cloned
+    pub project: Option<ProjectKind>,
+}
 
-    let prefix = vec![
+/// The beginning of the prompt, which introduces the task
+///
+/// For reviews, the `review_diff` contains the changes to review. The `instructions` of the
+/// repository come last, as they take precedence over the general instructions.
+pub fn intro(
+    task: &Task,
+    project: Option<ProjectKind>,
+    attachments: Vec<ContentItem>,
+    review_diff: Option<&str>,
+    instructions: Option<&RepoInstructions>,
+    config: &Config,
+    privileged: bool,
+) -> Vec<PromptItem> {
+    let mut intro = vec![
         PromptItem::System { text: INTRO_1.to_owned() },
-        PromptItem::User { content: task.description.to_owned().into() },
-        PromptItem::System { text: INTRO_2.to_owned() },
+        PromptItem::User {
+            content: [vec![ContentItem::Text { text: task.description.to_owned() }], attachments]
+                .concat()
+                .into(),
+        },
     ];
+    if let Some(diff) = review_diff {
+        intro.push(PromptItem::System { text: REVIEW_INTRO.to_owned() });
+        intro.push(PromptItem::System { text: diff.to_owned() });
+    }
+    let actions = Action::list(config);
+    let privileges = if privileged { INTRO_PRIVILEGED } else { INTRO_UNPRIVILEGED };
+    let text = format!("{}\n\n{}\n{}\n{}\n", INTRO_2, actions, INTRO_3, privileges);
+    intro.push(PromptItem::System { text });
+    if let Some(project) = project {
+        intro.push(PromptItem::System { text: project.describe() });
+    }
+    if let Some(instructions) = instructions {
+        intro.extend(instructions.prompt_items());
+    }
+    intro
+}
 
-    let mut history = History::new(prefix);
+pub async fn run(
+    llm_client: &llm::LLMClient,
+    workspace: Workspace<'_>,
+    task: &Task,
+    config: &Config,
+    redactor: &Redactor,
+    mut history: History,
+    output_dir: Option<&OutputDir>,
+) -> TaskOutcome {
+    let Workspace { container, git_repo, project } = workspace;
+    let mut resources = Resources::default();
+    resources.focus.extend(config.focus.iter().cloned());
 
-    loop {
-        let action_result =
-            single_action(llm_client, container, &mut history, &mut resources).await;
+    assert_eq!(task.status, TaskStatus::Running);
+
+    let mut always_include =
+        AlwaysInclude::new(&config.always_include, config.always_include_max_bytes);
+    let mut focused = AlwaysInclude::new(&[], config.always_include_max_bytes);
+
+    // The prefix of a new or resumed history is just the intro
+    let intro = history.prefix.clone();
+
+    let retrieval = if config.retrieval {
+        let index = Index::load_or_build(
+            llm_client,
+            git_repo,
+            task.git_repo_url.as_str(),
+            &config.embedding_model,
+            config.retrieval_max_chunks,
+            &config.retrieval_cache_dir,
+        )
+        .await;
+        index.inspect_err(|err| log::error!("Failed to build retrieval index: {}", err)).ok()
+    } else {
+        None
+    };
+
+    let env = Env {
+        router: ModelRouter::new(llm_client, config, TaskDifficulty::of_task(task, config)),
+        container,
+        git_repo,
+        config,
+        redactor,
+        protected_paths: ProtectedPaths::new(
+            container.workspace_dir_container(),
+            &config.protected_paths,
+        ),
+        review: config.review_base.is_some(),
+        retrieval,
+        container_restarts: AtomicUsize::new(0),
+        read_only_task: looks_read_only(&task.description),
+        no_changes_challenged: AtomicBool::new(false),
+        package_allowlist: PackageAllowlist::new(&config.allowed_packages),
+        packages: Mutex::new(PackageState::default()),
+        project,
+        last_test_run: Mutex::new(None),
+    };
+
+    // In interactive mode, the operator can cancel and steer actions and answer questions via stdin
+    let mut control = config.interactive.then(spawn_stdin_reader);
+    let mut pending_message = None;
+    let mut failed_actions = 0;
+
+    let budget = Budget::new(config);
+    let mut unpriced_models = BTreeSet::new();
+
+    let outcome = loop {
+        // The budget is checked between actions, so the last action may exceed it
+        let usage = llm_client.usage();
+        for model in budget.unpriced_models(&usage) {
+            if unpriced_models.insert(model.to_owned()) {
+                log::warn!("No price for model `{}`, its cost is not taken into account", model);
+            }
+        }
+        if let Some(reason) = budget.exhausted(&usage) {
+            log::error!("{}", reason);
+            break TaskOutcome::Failure(TaskFailure {
+                reason: Some(TaskFailureReason::TechnicalIssues),
+                description: reason,
+            });
+        }
+        if let Some(reason) = action_limit_reached(history.actions.len(), config.max_actions) {
+            log::error!("{}", reason);
+            break TaskOutcome::Failure(TaskFailure {
+                reason: Some(TaskFailureReason::TechnicalIssues),
+                description: reason,
+            });
+        }
+
+        // Keep the always-included and focused files in the prefix up to date
+        let unfocused = focused.set_paths(&resources.focus);
+        let always_include_changed = always_include.refresh(container, env.redactor).await;
+        let focus_changed = focused.refresh(container, env.redactor).await || unfocused;
+        if always_include_changed || focus_changed {
+            log::info!("Always-included or focused files changed, updating prompt prefix");
+            history.prefix =
+                [intro.clone(), always_include.prompt_items(), focused.prompt_items()].concat();
+        }
+
+        // Keep the summaries of older actions from outgrowing the prompt in long tasks
+        if let Some((actions, prompt)) = history.consolidation_prompt() {
+            log::info!("Consolidating the summaries of the first {} actions", actions);
+            let step = Step::ConsolidateSummaries;
+            match env.router.prompt(step, Difficulty::Trivial, &prompt).await {
+                Ok(summary) => history.consolidate(actions, summary),
+                Err(err) => log::warn!("Failed to consolidate summaries: {}", err),
+            }
+        }
+
+        // Only the paths written by the next action are of interest if it is cancelled
+        container.take_written_paths();
+        let action = single_action(&env, &mut history, &mut resources, pending_message.take());
+        // A panic (e.g. due to an unexpected response of the model) only fails the action
+        let action = AssertUnwindSafe(action).catch_unwind();
+        let action_result = tokio::select! {
+            action_result = action => action_result,
+            // Dropping the action future discards the partial action, as actions are only
+            // appended to the history once they are complete. Its commands keep running in the
+            // container unless they are killed, and the files it wrote stay written.
+            Control::Message { message } = next_control(&mut control) => {
+                log::info!("Action cancelled by the operator");
+                if let Err(err) = container.kill_running_commands().await {
+                    log::warn!("Failed to kill the commands of the cancelled action: {}", err);
+                }
+                let written_paths = container.take_written_paths().into_iter().collect();
+                pending_message = Some(PendingMessage::Steering { message, written_paths });
+                continue;
+            }
+        };
+        let action_result = match action_result {
+            Ok(action_result) => action_result,
+            Err(panic) => {
+                history.record_failure();
+                failed_actions += 1;
+                let reason = panic_message(panic.as_ref());
+                log::error!("Action failed ({} in a row): {}", failed_actions, reason);
+                if failed_actions >= MAX_CONSECUTIVE_FAILED_ACTIONS {
+                    break TaskOutcome::Failure(TaskFailure {
+                        reason: Some(TaskFailureReason::TechnicalIssues),
+                        description: format!(
+                            "{} actions in a row failed, the last one with: {}",
+                            failed_actions,
+                            env.redactor.redact(&reason)
+                        ),
+                    });
+                }
+                pending_message = Some(PendingMessage::ActionFailed(reason));
+                continue;
+            }
+        };
+        failed_actions = 0;
+        if let Some(output_dir) = output_dir {
+            output_dir.write_json(&output_dir.transcript_path(), &history);
+        }
         match action_result {
             ActionResult::EndTask(outcome) => break outcome,
             ActionResult::Continue => continue,
+            // Questions are only answered by the operator via stdin, as the agent API has no
+            // means to ask them
+            ActionResult::AskUser(question) => {
+                if control.is_some() {
+                    let question = env.redactor.redact(&question);
+                    log::info!("Question from the agent, answer it on stdin: {}", question);
+                }
+                match recv_control(&mut control).await {
+                    Some(Control::Message { message }) => {
+                        pending_message = Some(PendingMessage::Answer(message));
+                    }
+                    // Without an operator, the question can not be answered
+                    None => {
+                        break TaskOutcome::Failure(TaskFailure {
+                            reason: Some(TaskFailureReason::TaskIssues),
+                            description: env.redactor.redact(&question),
+                        });
+                    }
+                }
+            }
         }
+    };
+
+    let mut stats = ActionStats::from_history(&history);
+    stats.last_test_run = env.last_test_run.lock().unwrap().clone();
+    log::info!(
+        "{} actions ({} failed): {}",
+        stats.actions,
+        stats.failed_actions,
+        stats.per_type.iter().map(|(t, n)| format!("{} {}", n, t)).collect::<Vec<_>>().join(", ")
+    );
+    if let Some(output_dir) = output_dir {
+        output_dir.write_json(&output_dir.stats_path(), &stats);
     }
+    outcome
 }
 
-async fn summarize_action(
-    prompt: &Prompt,
-    llm_client: &llm::LLMClient,
-    action_number: usize,
-) -> String {
+async fn summarize_action(env: &Env<'_>, prompt: &Prompt, action_number: usize) -> Summary {
     let mut prompt = prompt.clone();
     let summarize_message = format!("Summarize what you have done in action {}.", action_number);
     prompt.items.push(PromptItem::System { text: summarize_message });
-    llm_client.prompt(BASIC_MODEL, &prompt).await.unwrap()
+    if env.config.summary_format == SummaryFormat::Structured {
+        prompt.items.push(PromptItem::System { text: SUMMARIZE_STRUCTURED.to_owned() });
+    }
+    let message = env.router.prompt(Step::Summarize, Difficulty::Trivial, &prompt).await.unwrap();
+    match env.config.summary_format {
+        SummaryFormat::Text => Summary::Text(message),
+        SummaryFormat::Structured => Summary::parse(&message),
+    }
+}
+
+/// The number of failed actions in a row after which the task is failed
+const MAX_CONSECUTIVE_FAILED_ACTIONS: usize = 3;
+
+/// The reason why no more actions may be taken, if `max_actions` (unless `0`) have been taken
+fn action_limit_reached(actions: usize, max_actions: usize) -> Option<String> {
+    (max_actions > 0 && actions >= max_actions).then(|| {
+        format!(
+            "The task was not completed within the limit of {} actions, the agent may be going in circles",
+            max_actions
+        )
+    })
 }
 
+/// The message of a panic, which is usually a `&str` or a `String`
+fn panic_message(panic: &(dyn Any + Send)) -> String {
+    if let Some(message) = panic.downcast_ref::<&str>() {
+        (*message).to_owned()
+    } else if let Some(message) = panic.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "unknown error".to_owned()
+    }
+}
+
+/// A message that is passed to the model at the beginning of the next action
+enum PendingMessage {
+    /// Guidance of the operator, who cancelled the previous action, and the paths the action
+    /// wrote, deleted or moved before it was cancelled
+    Steering { message: String, written_paths: Vec<String> },
+    /// The answer of the operator to a question
+    Answer(String),
+    /// The reason why the previous action failed
+    ActionFailed(String),
+}
+
+const STEERING: &str = r#"The operator cancelled your previous action, which stopped the commands it was running, and provided the following guidance.
+Take it into account for this and all following actions:"#;
+
+const ACTION_FAILED: &str = r#"Your previous action failed due to an unexpected error and has been discarded.
+Make sure to follow the instructions on the format of your messages exactly. The error was:"#;
+
+const CANCELLED_WRITES: &str = r#"Before it was cancelled, your previous action already changed the following files, which remain changed:"#;
+
+const ANSWER: &str = r#"The user answered your question as follows:"#;
+
+const FILES_CHANGED: &str = r#"The following files you read or changed were changed since, e.g. by a command, so their content in earlier actions is outdated:"#;
+
 const DISCUSS_FIRST: &str = r#"Plan the first step of your approach without writing any code, yet.
 Let's think step by step."#;
 
@@ -80,6 +417,9 @@ const DISCUSS_READ_FILE: &str = r#"Discuss the file content.
 Then, plan what you want to do next without writing any code, yet.
 Let's think step by step."#;
 
+const DISCUSS_STAT: &str = r#"Plan what you want to do next without writing any code, yet.
+Let's think step by step."#;
+
 const DISCUSS_EDIT_FILE: &str = r#"Discuss your edits.
 Then, plan what you want to do next without writing any code, yet.
 Let's think step by step."#;
@@ -87,73 +427,358 @@ Let's think step by step."#;
 pub enum ActionResult {
     EndTask(TaskOutcome),
     Continue,
+    /// Wait for the answer to a question
+    AskUser(String),
 }
 
 async fn single_action(
-    llm_client: &llm::LLMClient,
-    container: &Container,
+    env: &Env<'_>,
     history: &mut History,
     resources: &mut Resources,
+    pending_message: Option<PendingMessage>,
 ) -> ActionResult {
     let mut p = history.compressed_prompt();
+    // The current content of open files replaces their stale content in older actions
+    if env.config.open_files_max_bytes > 0 {
+        let max_bytes = env.config.open_files_max_bytes;
+        p.items.extend(resources.open_file_items(env.container, env.redactor, max_bytes).await);
+    }
     let action_number = history.actions.len();
     let start_idx = p.items.len();
     p.items.push(PromptItem::System { text: format!("BEGIN ACTION {}", action_number) });
 
+    match pending_message {
+        Some(PendingMessage::Steering { message, written_paths }) => {
+            p.items.push(PromptItem::System { text: STEERING.to_owned() });
+            p.items.push(PromptItem::User { content: message.into() });
+            if !written_paths.is_empty() {
+                let paths = written_paths.iter().map(|path| format!("- `{}`", path));
+                let text =
+                    format!("{}\n{}", CANCELLED_WRITES, paths.collect::<Vec<_>>().join("\n"));
+                p.items.push(PromptItem::System { text });
+            }
+        }
+        Some(PendingMessage::Answer(message)) => {
+            p.items.push(PromptItem::System { text: ANSWER.to_owned() });
+            p.items.push(PromptItem::User { content: message.into() });
+        }
+        Some(PendingMessage::ActionFailed(reason)) => {
+            p.items.push(PromptItem::System { text: format!("{}\n{}", ACTION_FAILED, reason) });
+        }
+        None => {}
+    }
+
+    let changed_files = resources.changed_files(env.container).await;
+    if !changed_files.is_empty() {
+        log::info!("Files changed outside of file actions: {}", changed_files.join(", "));
+        let files = changed_files.iter().map(|file| format!("- `{}`", file)).collect::<Vec<_>>();
+        p.items
+            .push(PromptItem::System { text: format!("{}\n{}", FILES_CHANGED, files.join("\n")) });
+    }
+
     if action_number == 0 {
         p.items.push(PromptItem::System { text: DISCUSS_FIRST.to_owned() });
-        let completion = llm_client.prompt(SMART_MODEL, &p).await.unwrap();
+        let completion = env.router.prompt(Step::Plan, Difficulty::Hard, &p).await.unwrap();
         p.items.push(PromptItem::Assistant { text: completion });
     }
 
-    let action = select_action(llm_client, &mut p).await;
+    let action = match select_action(&env.router, &mut p, env.config).await {
+        Ok(action) => action,
+        Err(failure) => return ActionResult::EndTask(TaskOutcome::Failure(failure)),
+    };
 
-    match action {
+    // Discussing the contents of a file is easier than making sense of command output or edits
+    let discuss_difficulty = match action {
         Action::Bash => {
-            action_bash(llm_client, container, &mut p).await;
+            if let Err(failure) = action_bash(env, &mut p).await {
+                return ActionResult::EndTask(TaskOutcome::Failure(failure));
+            }
             p.items.push(PromptItem::System { text: DISCUSS_BASH.to_owned() });
+            Difficulty::Hard
+        }
+        Action::InstallPackages => {
+            if let Err(failure) = action_install_packages(env, &mut p).await {
+                return ActionResult::EndTask(TaskOutcome::Failure(failure));
+            }
+            p.items.push(PromptItem::System { text: DISCUSS_STAT.to_owned() });
+            Difficulty::Easy
+        }
+        Action::RunInteractive => {
+            action_run_interactive(env, &mut p).await;
+            p.items.push(PromptItem::System { text: DISCUSS_BASH.to_owned() });
+            Difficulty::Hard
+        }
+        Action::RunTests => {
+            if let Err(failure) = action_run_tests(env, &mut p).await {
+                return ActionResult::EndTask(TaskOutcome::Failure(failure));
+            }
+            p.items.push(PromptItem::System { text: DISCUSS_BASH.to_owned() });
+            Difficulty::Hard
         }
         Action::ReadFile => {
-            action_read_file(llm_client, container, &mut p, resources).await;
+            action_read_file(env, &mut p, resources).await;
             p.items.push(PromptItem::System { text: DISCUSS_READ_FILE.to_owned() });
+            Difficulty::Easy
+        }
+        Action::ReadFileAtRef => {
+            action_read_file_at_ref(env, &mut p).await;
+            p.items.push(PromptItem::System { text: DISCUSS_READ_FILE.to_owned() });
+            Difficulty::Easy
+        }
+        Action::Stat => {
+            action_stat(env, &mut p).await;
+            p.items.push(PromptItem::System { text: DISCUSS_STAT.to_owned() });
+            Difficulty::Easy
+        }
+        Action::ListDir => {
+            action_list_dir(env, &mut p).await;
+            p.items.push(PromptItem::System { text: DISCUSS_STAT.to_owned() });
+            Difficulty::Easy
+        }
+        Action::Search => {
+            action_search(env, &mut p).await;
+            p.items.push(PromptItem::System { text: DISCUSS_STAT.to_owned() });
+            Difficulty::Easy
+        }
+        Action::Focus => {
+            action_focus(env, &mut p, resources).await;
+            p.items.push(PromptItem::System { text: DISCUSS_STAT.to_owned() });
+            Difficulty::Easy
+        }
+        Action::Retrieve => {
+            action_retrieve(env, &mut p).await;
+            p.items.push(PromptItem::System { text: DISCUSS_READ_FILE.to_owned() });
+            Difficulty::Easy
         }
         Action::EditFile => {
-            action_edit_file(llm_client, container, &mut p, resources).await;
+            action_edit_file(env, &mut p, resources).await;
             p.items.push(PromptItem::System { text: DISCUSS_EDIT_FILE.to_owned() });
+            Difficulty::Hard
         }
-        Action::EndTask => {
-            return action_end_task(llm_client, &mut p).await;
+        Action::ReplaceInFile => {
+            action_replace_in_file(env, &mut p, resources).await;
+            p.items.push(PromptItem::System { text: DISCUSS_EDIT_FILE.to_owned() });
+            Difficulty::Hard
         }
-    }
+        Action::ApplyPatch => {
+            action_apply_patch(env, &mut p, resources).await;
+            p.items.push(PromptItem::System { text: DISCUSS_EDIT_FILE.to_owned() });
+            Difficulty::Hard
+        }
+        Action::DeleteFile => {
+            action_delete_file(env, &mut p, resources).await;
+            p.items.push(PromptItem::System { text: DISCUSS_EDIT_FILE.to_owned() });
+            Difficulty::Easy
+        }
+        Action::MoveFile => {
+            action_move_file(env, &mut p, resources).await;
+            p.items.push(PromptItem::System { text: DISCUSS_EDIT_FILE.to_owned() });
+            Difficulty::Easy
+        }
+        Action::WriteFiles => {
+            action_write_files(env, &mut p, resources).await;
+            p.items.push(PromptItem::System { text: DISCUSS_EDIT_FILE.to_owned() });
+            Difficulty::Hard
+        }
+        Action::AskUser => {
+            let question = action_ask_user(env, &mut p).await;
+            p.items.push(PromptItem::System { text: format!("END ACTION {}", action_number) });
+            let summary = summarize_action(env, &p, action_number).await;
+            history.append(action.name(), p.items[start_idx..].to_vec(), summary);
+            return ActionResult::AskUser(question);
+        }
+        Action::EndTask => match action_end_task(env, &mut p).await {
+            // The model is challenged to reconsider, so the action continues with a discussion
+            ActionResult::Continue => Difficulty::Hard,
+            result => return result,
+        },
+    };
 
-    let completion = llm_client.prompt(SMART_MODEL, &p).await.unwrap();
+    let completion = env.router.prompt(Step::Discuss, discuss_difficulty, &p).await.unwrap();
     p.items.push(PromptItem::Assistant { text: completion });
 
     p.items.push(PromptItem::System { text: format!("END ACTION {}", action_number) });
 
-    let summary = summarize_action(&p, llm_client, action_number).await;
-    history.append(p.items[start_idx..].to_vec(), summary);
+    let summary = summarize_action(env, &p, action_number).await;
+    history.append(action.name(), p.items[start_idx..].to_vec(), summary);
 
     ActionResult::Continue
 }
 
+#[derive(Clone, Copy, Deserialize)]
+#[serde(try_from = "String")]
 enum Action {
     Bash,
+    InstallPackages,
+    RunInteractive,
+    RunTests,
     ReadFile,
+    ReadFileAtRef,
+    Stat,
+    ListDir,
+    Search,
+    Focus,
+    Retrieve,
     EditFile,
+    ReplaceInFile,
+    ApplyPatch,
+    WriteFiles,
+    DeleteFile,
+    MoveFile,
+    AskUser,
     EndTask,
 }
 
-const DISCUSS_ACTION: &str = r#"To realize the first step of your plan, you must now choose one of the following actions:
+impl Action {
+    const ALL: [Action; 19] = [
+        Action::Bash,
+        Action::InstallPackages,
+        Action::RunInteractive,
+        Action::RunTests,
+        Action::ReadFile,
+        Action::Stat,
+        Action::ListDir,
+        Action::Search,
+        Action::ReadFileAtRef,
+        Action::Focus,
+        Action::Retrieve,
+        Action::EditFile,
+        Action::ReplaceInFile,
+        Action::ApplyPatch,
+        Action::WriteFiles,
+        Action::DeleteFile,
+        Action::MoveFile,
+        Action::AskUser,
+        Action::EndTask,
+    ];
+
+    fn name(&self) -> &'static str {
+        match self {
+            Action::Bash => "bash",
+            Action::InstallPackages => "install-packages",
+            Action::RunInteractive => "run-interactive",
+            Action::RunTests => "run-tests",
+            Action::ReadFile => "read-file",
+            Action::ReadFileAtRef => "read-file-at-ref",
+            Action::Stat => "stat",
+            Action::ListDir => "list-dir",
+            Action::Search => "search",
+            Action::Focus => "focus",
+            Action::Retrieve => "retrieve",
+            Action::EditFile => "edit-file",
+            Action::ReplaceInFile => "replace-in-file",
+            Action::ApplyPatch => "apply-patch",
+            Action::WriteFiles => "write-files",
+            Action::DeleteFile => "delete-file",
+            Action::MoveFile => "move-file",
+            Action::AskUser => "ask-user",
+            Action::EndTask => "end-task",
+        }
+    }
+
+    fn description(&self) -> &'static str {
+        match self {
+            Action::Bash => "Execute bash code",
+            Action::InstallPackages => "Install system packages with the package manager of the environment, e.g. compilers or libraries",
+            Action::RunInteractive => "Run a command that prompts for input, answering its prompts with predefined responses",
+            Action::RunTests => "Run the tests of the project and learn whether they pass, e.g. to verify your changes",
+            Action::ReadFile => "Read the contents of a file",
+            Action::ReadFileAtRef => "Read the contents of a file as it is at a git ref, e.g. a commit or another branch",
+            Action::Stat => "Check whether a path exists and whether it is a file or a directory, without reading it",
+            Action::ListDir => "List the files and directories in a directory, with their sizes",
+            Action::Search => "Search the files for lines matching a regular expression, e.g. to find where a function is defined or used before reading a file",
+            Action::Focus => "Choose files that are always shown with their current content, e.g. the files the task centers on",
+            Action::Retrieve => "Search the repository for the code that is most relevant to a description, e.g. where a feature is implemented",
+            Action::EditFile => "Read, and optionally replace the contents of a file",
+            Action::ReplaceInFile => "Replace a unique snippet of a file, without rewriting the whole file",
+            Action::ApplyPatch => "Change a file with a unified diff, e.g. several places of a large file at once",
+            Action::WriteFiles => "Create or overwrite several related files at once, e.g. a module and its tests",
+            Action::DeleteFile => "Delete a file",
+            Action::MoveFile => "Move or rename a file, replacing the file at the destination",
+            Action::AskUser => "Ask the user a clarifying question, if the task is ambiguous",
+            Action::EndTask => "End your task because it is completed, or because there is an insurmountable issue preventing you from completing it.",
+        }
+    }
+
+    /// Whether the action modifies files
+    fn edits(&self) -> bool {
+        matches!(
+            self,
+            Action::EditFile
+                | Action::ReplaceInFile
+                | Action::ApplyPatch
+                | Action::WriteFiles
+                | Action::DeleteFile
+                | Action::MoveFile
+        )
+    }
+
+    /// The actions that are available, edits are not available for reviews, and retrieval and
+    /// package installation only if they are enabled
+    fn available(config: &Config) -> impl Iterator<Item = Action> + '_ {
+        let review = config.review_base.is_some();
+        Action::ALL.into_iter().filter(move |action| match action {
+            Action::Retrieve => config.retrieval,
+            Action::InstallPackages => config.allowed_packages.iter().any(|p| !p.trim().is_empty()),
+            action => !(review && action.edits()),
+        })
+    }
+
+    /// A Markdown list of the available actions
+    fn list(config: &Config) -> String {
+        Action::available(config)
+            .map(|action| format!("* `{}`: {}\n", action.name(), action.description()))
+            .collect()
+    }
+}
+
+const DISCUSS_ACTION: &str =
+    r#"To realize the first step of your plan, you must now choose one of the following actions:"#;
 
-* `bash`: Execute bash code
-* `read-file`: Read the contents of a file
-* `edit-file`: Read, and optionally replace the contents of a file
-* `end-task`: End your task because it is completed, or because there is an insurmountable issue preventing you from completing it.
+const DISCUSS_ACTION_EDIT: &str = r#"To write code, you must use the `edit-file`, `replace-in-file`, `apply-patch` or `write-files` action.
+To delete or move files, use the `delete-file` or `move-file` action."#;
 
-To write code, you must use the `edit-file` action.
-Discuss which action you choose. Let's think step by step.
-"#;
+const DISCUSS_ACTION_CHOOSE: &str = r#"Discuss which action you choose. Let's think step by step."#;
+
+const DISCUSS_ACTION_CHOOSE_JSON: &str = r#"Discuss which action you choose in `reasoning`. Let's think step by step.
+Then give the name of the action you chose in `action`."#;
+
+/// The choice of an action in JSON mode
+#[derive(Deserialize)]
+struct ActionChoice {
+    reasoning: String,
+    action: Action,
+}
+
+impl TryFrom<String> for Action {
+    type Error = String;
+
+    fn try_from(name: String) -> Result<Self, Self::Error> {
+        Action::ALL
+            .into_iter()
+            .find(|action| action.name() == name)
+            .ok_or_else(|| format!("Unknown action `{}`", name))
+    }
+}
+
+/// The JSON schema of an [`ActionChoice`] of the available actions
+fn action_choice_schema(config: &Config) -> ResponseFormatJsonSchema {
+    let names: Vec<&str> = Action::available(config).map(|action| action.name()).collect();
+    ResponseFormatJsonSchema {
+        description: None,
+        name: "action_choice".to_owned(),
+        schema: Some(serde_json::json!({
+            "type": "object",
+            "properties": {
+                "reasoning": {"type": "string"},
+                "action": {"type": "string", "enum": names},
+            },
+            "required": ["reasoning", "action"],
+            "additionalProperties": false,
+        })),
+        strict: Some(true),
+    }
+}
 
 const SELECT_ACTION: &str = r#"Give the name of the action you chose above.
 No prose, your message must consist solely of the action name.
@@ -162,18 +787,91 @@ For instance, if you chose the bash action, you would write:
 bash
 "#;
 
-async fn select_action(llm_client: &llm::LLMClient, prompt: &mut Prompt) -> Action {
-    prompt.items.push(PromptItem::System { text: DISCUSS_ACTION.to_owned() });
-    let completion = llm_client.prompt(BASIC_MODEL, prompt).await.unwrap();
+async fn select_action(
+    router: &ModelRouter,
+    prompt: &mut Prompt,
+    config: &Config,
+) -> Result<Action, TaskFailure> {
+    let edit = if config.review_base.is_some() {
+        String::new()
+    } else {
+        format!("{}\n", DISCUSS_ACTION_EDIT)
+    };
+    let text = |choose: &str| {
+        format!("{}\n\n{}\n{}{}\n", DISCUSS_ACTION, Action::list(config), edit, choose)
+    };
+
+    // Discuss and choose the action in a single prompt, if the model supports it
+    if router.json_mode(Step::DiscussAction, Difficulty::Trivial) {
+        prompt.items.push(PromptItem::System { text: text(DISCUSS_ACTION_CHOOSE_JSON) });
+        let schema = action_choice_schema(config);
+        let choice = router
+            .prompt_json::<ActionChoice>(Step::DiscussAction, Difficulty::Trivial, prompt, &schema)
+            .await;
+        match choice {
+            Ok(choice) if Action::available(config).any(|a| a.name() == choice.action.name()) => {
+                prompt.items.push(PromptItem::Assistant { text: choice.reasoning });
+                return Ok(choice.action);
+            }
+            Ok(choice) => log::warn!("Unavailable action `{}` chosen", choice.action.name()),
+            Err(err) => log::warn!("Failed to choose an action in JSON mode: {:?}", err),
+        }
+        log::warn!("Choosing the action without JSON mode");
+        prompt.items.pop();
+    }
+
+    prompt.items.push(PromptItem::System { text: text(DISCUSS_ACTION_CHOOSE) });
+    let completion = router.prompt(Step::DiscussAction, Difficulty::Trivial, prompt).await.unwrap();
     prompt.items.push(PromptItem::Assistant { text: completion });
     prompt.items.push(PromptItem::System { text: SELECT_ACTION.to_owned() });
-    let completion = llm_client.prompt(BASIC_MODEL, prompt).await.unwrap();
-    match completion.as_str() {
-        "bash" => Action::Bash,
-        "read-file" => Action::ReadFile,
-        "edit-file" => Action::EditFile,
-        "end-task" => Action::EndTask,
-        _ => panic!("Unexpected action: {}", completion),
+    let options: Vec<(&str, Action)> =
+        Action::available(config).map(|action| (action.name(), action)).collect();
+    select_option(router, config, Step::SelectAction, prompt, &options).await
+}
+
+const SELECT_OPTION_AGAIN: &str = r#"Your response is not one of the options.
+No prose, your message must consist solely of one of the following:"#;
+
+/// Prompt for a bare response that must be one of the options, re-prompting if it is not
+///
+/// After `max_reprompts` unrecognized responses, the task fails.
+async fn select_option<T: Clone>(
+    router: &ModelRouter,
+    config: &Config,
+    step: Step,
+    prompt: &mut Prompt,
+    options: &[(&str, T)],
+) -> Result<T, TaskFailure> {
+    let mut attempt = 0;
+    loop {
+        let completion = router.prompt(step, Difficulty::Trivial, prompt).await.unwrap();
+        let response = completion.trim().trim_matches('`');
+        if let Some((_, option)) = options.iter().find(|(name, _)| *name == response) {
+            return Ok(option.clone());
+        }
+        if attempt >= config.max_reprompts {
+            return Err(TaskFailure {
+                reason: Some(TaskFailureReason::TechnicalIssues),
+                description: format!(
+                    "The model gave no valid response to step {:?} in {} attempts, the last one was: {}",
+                    step,
+                    attempt + 1,
+                    completion
+                ),
+            });
+        }
+        attempt += 1;
+        log::warn!(
+            "Unexpected response to step {:?}, re-prompting ({}/{}): {}",
+            step,
+            attempt,
+            config.max_reprompts,
+            completion
+        );
+        let names: Vec<String> = options.iter().map(|(name, _)| format!("`{}`", name)).collect();
+        prompt.items.push(PromptItem::Assistant { text: completion });
+        let text = format!("{}\n{}", SELECT_OPTION_AGAIN, names.join(", "));
+        prompt.items.push(PromptItem::System { text });
     }
 }
 
@@ -181,19 +879,434 @@ const ACTION_BASH: &str = r#"Provide the bash script you want to run.
 No prose. Your message should only consist of bash code:
 "#;
 
-async fn action_bash(llm_client: &llm::LLMClient, container: &Container, prompt: &mut Prompt) {
+const ACTION_BASH_NO_BASH: &str = r#"Bash is not available in the container, the script is run with `/bin/sh`.
+Only use POSIX shell syntax.
+"#;
+
+const BASH_TIMED_OUT: &str = r#"The script did not finish in time and has been aborted, it may still be running in the background.
+Avoid commands that run indefinitely or wait for input, e.g. run servers in the background and pass flags to skip prompts."#;
+
+const CONTAINER_RESET: &str = r#"The script did not finish in time or could not be run, and the environment stopped responding.
+The environment has been reset: the container was recreated, so installed packages, running processes and files outside of the project directory are gone."#;
+
+const CONTAINER_RESET_COPY: &str =
+    r#"Changes to the project directory since the start of the task are gone as well."#;
+
+/// Run a bash script provided by the model
+///
+/// If the script times out or can not be run and the container stopped responding, the container is
+/// restarted.
+/// Once the restarts are exhausted, the task fails.
+async fn action_bash(env: &Env<'_>, prompt: &mut Prompt) -> Result<(), TaskFailure> {
     prompt.items.push(PromptItem::System { text: ACTION_BASH.to_owned() });
-    let code = llm_client.prompt(SMART_MODEL, prompt).await.unwrap();
+    if !env.container.has_bash() {
+        prompt.items.push(PromptItem::System { text: ACTION_BASH_NO_BASH.to_owned() });
+    }
+    let code = env.router.prompt(Step::Bash, Difficulty::Easy, prompt).await.unwrap();
     prompt.items.push(PromptItem::Assistant { text: code.clone() });
 
     let code = strip_wrapping_markdown_code_fences(&code);
 
-    let Output { stdout, stderr, exit_code } = container.run_script(&code).await;
-
-    let msg = format!(
-        "Stdout: \n```\n{}\n```\nStderr: \n```\n{}\n```\nExit status: {}\n",
-        stdout, stderr, exit_code
+    let mut log_chunk = |chunk: OutputChunk| {
+        if env.config.bash_stream_output {
+            match chunk {
+                OutputChunk::Stdout(text) => log::info!("[stdout] {}", text.trim_end()),
+                OutputChunk::Stderr(text) => log::info!("[stderr] {}", text.trim_end()),
+            }
+        }
+    };
+    let timeout = Duration::from_secs(env.config.command_timeout);
+    let script = async {
+        if env.config.persistent_shell {
+            env.container.run_session_script_streaming(&code, &mut log_chunk).await
+        } else {
+            env.container.run_script_streaming(&code, &mut log_chunk).await
+        }
+    };
+    let Output { stdout, stderr, combined, exit_code } =
+        match tokio::time::timeout(timeout, script).await {
+            Ok(Ok(output)) => output,
+            Ok(Err(err)) => {
+                log::warn!("Failed to run the script: {}", err);
+                let text = recover_container(env, script_failed(&err)).await?;
+                prompt.items.push(PromptItem::System { text });
+                return Ok(());
+            }
+            Err(_) => {
+                log::warn!("Script timed out after {}s", env.config.command_timeout);
+                let text = recover_container(env, BASH_TIMED_OUT.to_owned()).await?;
+                prompt.items.push(PromptItem::System { text });
+                return Ok(());
+            }
+        };
+
+    // The exit status is always shown, the output streams only if configured. If both are shown,
+    // they are interleaved by default, as errors are easier to make sense of in their context.
+    let mut msg = String::new();
+    let show_both = env.config.bash_show_stdout && env.config.bash_show_stderr;
+    let max_bytes = env.config.bash_max_output_bytes;
+    let (combined, stdout, stderr) = (
+        truncate_output(&env.redactor.redact(&combined), max_bytes),
+        truncate_output(&env.redactor.redact(&stdout), max_bytes),
+        truncate_output(&env.redactor.redact(&stderr), max_bytes),
     );
+    if show_both && env.config.bash_combined_output {
+        msg.push_str(&format!("Output (stdout and stderr): \n```\n{}\n```\n", combined));
+    } else {
+        if env.config.bash_show_stdout {
+            msg.push_str(&format!("Stdout: \n```\n{}\n```\n", stdout));
+        } else {
+            msg.push_str("Stdout: (not shown)\n");
+        }
+        if env.config.bash_show_stderr {
+            msg.push_str(&format!("Stderr: \n```\n{}\n```\n", stderr));
+        } else {
+            msg.push_str("Stderr: (not shown)\n");
+        }
+    }
+    msg.push_str(&format!("Exit status: {}\n", exit_code));
+    if exit_code != 0 && (looks_offline(&stdout) || looks_offline(&stderr)) {
+        env.packages.lock().unwrap().offline = true;
+        msg.push_str(OFFLINE);
+    }
+    prompt.items.push(PromptItem::System { text: msg });
+    Ok(())
+}
+
+/// Keep the beginning and the end of an output of more than `max_bytes` bytes, if not `0`
+///
+/// The beginning usually shows what a command does and the end how it failed, e.g. the summary of
+/// a test suite.
+fn truncate_output(output: &str, max_bytes: usize) -> String {
+    if max_bytes == 0 || output.len() <= max_bytes {
+        return output.to_owned();
+    }
+    let mut head_end = max_bytes / 2;
+    while !output.is_char_boundary(head_end) {
+        head_end -= 1;
+    }
+    let mut tail_start = output.len() - max_bytes / 2;
+    while !output.is_char_boundary(tail_start) {
+        tail_start += 1;
+    }
+    format!(
+        "{}\n...[{} bytes truncated]...\n{}",
+        &output[..head_end],
+        tail_start - head_end,
+        &output[tail_start..]
+    )
+}
+
+const OFFLINE: &str = r#"The environment has no network access, so packages and other files can not be downloaded.
+Do not retry, work with the tools that are already installed instead."#;
+
+const ACTION_INSTALL_PACKAGES: &str = r#"Provide the names of the system packages you want to install, one per line.
+No prose. For instance, to install `jq` and `ripgrep`, write:
+
+jq
+ripgrep
+"#;
+
+/// Check that the packages may be installed, or return the reason why not
+fn check_packages(env: &Env<'_>, packages: &[String]) -> Result<(), String> {
+    if let Some(invalid) = packages.iter().find(|p| !is_valid_package_name(p)) {
+        return Err(format!("`{}` is not a valid package name, nothing was installed.", invalid));
+    }
+    let denied: Vec<String> = packages
+        .iter()
+        .filter(|p| !env.package_allowlist.allows(p))
+        .map(|p| format!("`{}`", p))
+        .collect();
+    if !denied.is_empty() {
+        return Err(format!(
+            "Installing {} is not allowed, nothing was installed.",
+            denied.join(", ")
+        ));
+    }
+    if env.packages.lock().unwrap().offline {
+        return Err(OFFLINE.to_owned());
+    }
+    Ok(())
+}
+
+/// The number of lines of the output of a failed installation that are shown
+const INSTALL_OUTPUT_LINES: usize = 20;
+
+async fn action_install_packages(env: &Env<'_>, prompt: &mut Prompt) -> Result<(), TaskFailure> {
+    prompt.items.push(PromptItem::System { text: ACTION_INSTALL_PACKAGES.to_owned() });
+    let message = env.router.prompt(Step::PackageNames, Difficulty::Trivial, prompt).await.unwrap();
+    prompt.items.push(PromptItem::Assistant { text: message.clone() });
+
+    let packages: Vec<String> = message
+        .lines()
+        .map(|line| line.trim().trim_matches('`').to_owned())
+        .filter(|line| !line.is_empty())
+        .collect();
+    if let Err(text) = check_packages(env, &packages) {
+        prompt.items.push(PromptItem::System { text });
+        return Ok(());
+    }
+
+    let (manager, index_refreshed, packages) = {
+        let state = env.packages.lock().unwrap();
+        let missing: Vec<String> =
+            packages.into_iter().filter(|p| !state.installed.contains(p)).collect();
+        (state.manager, state.index_refreshed, missing)
+    };
+    if packages.is_empty() {
+        let text = "The packages are already installed.".to_owned();
+        prompt.items.push(PromptItem::System { text });
+        return Ok(());
+    }
+    let manager = match manager {
+        Some(manager) => manager,
+        None => {
+            let detected =
+                env.container.run_script_streaming(DETECT_PACKAGE_MANAGER, &mut |_| {}).await;
+            let output = match detected {
+                Ok(output) => output,
+                Err(err) => {
+                    log::warn!("Failed to detect the package manager: {}", err);
+                    let text = recover_container(env, script_failed(&err)).await?;
+                    prompt.items.push(PromptItem::System { text });
+                    return Ok(());
+                }
+            };
+            let Some(manager) = PackageManager::from_detected(&output.stdout) else {
+                let text =
+                    "No supported package manager is available, packages can not be installed.";
+                prompt.items.push(PromptItem::System { text: text.to_owned() });
+                return Ok(());
+            };
+            env.packages.lock().unwrap().manager = Some(manager);
+            manager
+        }
+    };
+
+    log::info!("Installing packages with {:?}: {}", manager, packages.join(" "));
+    let script = manager.install_script(&packages, !index_refreshed);
+    let timeout = Duration::from_secs(env.config.command_timeout);
+    let mut ignore_chunk = |_: OutputChunk| {};
+    let script = env.container.run_script_streaming(&script, &mut ignore_chunk);
+    let Output { combined, exit_code, .. } = match tokio::time::timeout(timeout, script).await {
+        Ok(Ok(output)) => output,
+        Ok(Err(err)) => {
+            log::warn!("Failed to install packages: {}", err);
+            let text = recover_container(env, script_failed(&err)).await?;
+            prompt.items.push(PromptItem::System { text });
+            return Ok(());
+        }
+        Err(_) => {
+            log::warn!("Package installation timed out after {}s", env.config.command_timeout);
+            let text = recover_container(env, BASH_TIMED_OUT.to_owned()).await?;
+            prompt.items.push(PromptItem::System { text });
+            return Ok(());
+        }
+    };
+
+    let listed: Vec<String> = packages.iter().map(|p| format!("`{}`", p)).collect();
+    let mut state = env.packages.lock().unwrap();
+    let text = if exit_code == 0 {
+        state.index_refreshed = true;
+        state.installed.extend(packages);
+        format!("Installed {}.", listed.join(", "))
+    } else if looks_offline(&combined) {
+        state.offline = true;
+        OFFLINE.to_owned()
+    } else {
+        let lines: Vec<&str> = combined.lines().collect();
+        let tail = lines[lines.len().saturating_sub(INSTALL_OUTPUT_LINES)..].join("\n");
+        format!(
+            "Failed to install {} (exit status {}):\n```\n{}\n```",
+            listed.join(", "),
+            exit_code,
+            env.redactor.redact(&tail)
+        )
+    };
+    prompt.items.push(PromptItem::System { text });
+    Ok(())
+}
+
+/// The message for the model if a script could not be run, e.g. because the container died
+fn script_failed(err: &bollard::errors::Error) -> String {
+    format!("The script could not be run: {}", err)
+}
+
+/// Check whether the container survived a script that timed out or could not be run, and restart
+/// it if it did not
+///
+/// Returns the message for the model, which is `problem` if the container is still responsive.
+async fn recover_container(env: &Env<'_>, problem: String) -> Result<String, TaskFailure> {
+    if env.container.is_responsive().await {
+        return Ok(problem);
+    }
+    let restarts = env.container_restarts.fetch_add(1, Ordering::Relaxed) + 1;
+    if restarts > env.config.max_container_restarts {
+        return Err(TaskFailure {
+            reason: Some(TaskFailureReason::TechnicalIssues),
+            description: format!(
+                "The container stopped responding after {} restarts",
+                env.config.max_container_restarts
+            ),
+        });
+    }
+    log::warn!("Container is unresponsive, restarting it ({} so far)", restarts);
+    if let Err(err) = env.container.restart().await {
+        return Err(TaskFailure {
+            reason: Some(TaskFailureReason::TechnicalIssues),
+            description: format!("The container stopped responding: {}", err),
+        });
+    }
+    // The installed packages are gone with the old container
+    *env.packages.lock().unwrap() = PackageState::default();
+    if env.config.workspace_mount == WorkspaceMount::Copy {
+        Ok(format!("{}\n{}", CONTAINER_RESET, CONTAINER_RESET_COPY))
+    } else {
+        Ok(CONTAINER_RESET.to_owned())
+    }
+}
+
+const ACTION_RUN_INTERACTIVE: &str = r#"Provide the command and the responses to its prompts.
+Every `expect:` line contains text the command prints when it waits for input, the following `send:` line the response, which is followed by Enter.
+The prompts must appear in the given order. No prose. For instance, to run `npm init`, write:
+
+command: npm init
+expect: package name:
+send: my-package
+expect: version:
+send: 1.0.0
+"#;
+
+const ACTION_RUN_TESTS: &str = r#"Provide the command that runs the tests, e.g. only the tests that are related to your changes.
+No prose."#;
+
+/// The number of lines at the end of the test output that are shown, as they usually summarize
+/// the results
+const TEST_OUTPUT_TAIL_LINES: usize = 50;
+
+async fn action_run_tests(env: &Env<'_>, prompt: &mut Prompt) -> Result<(), TaskFailure> {
+    let default = env
+        .config
+        .test_command
+        .as_deref()
+        .or_else(|| env.project.and_then(|project| project.test_command()));
+    let mut text = ACTION_RUN_TESTS.to_owned();
+    if let Some(default) = default {
+        text.push_str(&format!(" To run all tests with `{}`, write:\n\ndefault\n", default));
+    }
+    prompt.items.push(PromptItem::System { text });
+    let message = env.router.prompt(Step::TestCommand, Difficulty::Trivial, prompt).await.unwrap();
+    prompt.items.push(PromptItem::Assistant { text: message.clone() });
+    let message = strip_wrapping_markdown_code_fences(&message);
+    let command = match (message.trim(), default) {
+        ("default" | "", Some(default)) => default.to_owned(),
+        ("default" | "", None) => {
+            let text =
+                "No test command was given and the project has no known one, no tests were run.";
+            prompt.items.push(PromptItem::System { text: text.to_owned() });
+            return Ok(());
+        }
+        (command, _) => command.to_owned(),
+    };
+
+    log::info!("Running tests with `{}`", command);
+    let mut log_chunk = |chunk: OutputChunk| {
+        if env.config.bash_stream_output {
+            match chunk {
+                OutputChunk::Stdout(text) => log::info!("[stdout] {}", text.trim_end()),
+                OutputChunk::Stderr(text) => log::info!("[stderr] {}", text.trim_end()),
+            }
+        }
+    };
+    let timeout = Duration::from_secs(env.config.command_timeout);
+    let script = env.container.run_script_streaming(&command, &mut log_chunk);
+    let Output { combined, exit_code, .. } = match tokio::time::timeout(timeout, script).await {
+        Ok(Ok(output)) => output,
+        Ok(Err(err)) => {
+            log::warn!("Failed to run the tests: {}", err);
+            let text = recover_container(env, script_failed(&err)).await?;
+            prompt.items.push(PromptItem::System { text });
+            return Ok(());
+        }
+        Err(_) => {
+            log::warn!("Tests timed out after {}s", env.config.command_timeout);
+            let text = recover_container(env, BASH_TIMED_OUT.to_owned()).await?;
+            prompt.items.push(PromptItem::System { text });
+            return Ok(());
+        }
+    };
+
+    let passed = exit_code == 0;
+    log::info!("Tests {}", if passed { "passed" } else { "failed" });
+    let output = env.redactor.redact(&combined);
+    let text = describe_test_run(&command, exit_code, &output, env.config.bash_max_output_bytes);
+    prompt.items.push(PromptItem::System { text });
+    *env.last_test_run.lock().unwrap() = Some(TestRun { command, passed, exit_code });
+    Ok(())
+}
+
+/// Whether the tests passed, followed by the end of their output
+fn describe_test_run(command: &str, exit_code: i64, output: &str, max_bytes: usize) -> String {
+    let mut text = if exit_code == 0 {
+        "The tests passed.\n".to_owned()
+    } else {
+        format!("The tests failed with exit status {}.\n", exit_code)
+    };
+    let lines: Vec<&str> = output.lines().collect();
+    let tail = &lines[lines.len().saturating_sub(TEST_OUTPUT_TAIL_LINES)..];
+    text.push_str(&format!("Output of `{}`", command));
+    if tail.len() < lines.len() {
+        text.push_str(&format!(" (the first {} lines are not shown)", lines.len() - tail.len()));
+    }
+    let tail = truncate_output(&tail.join("\n"), max_bytes);
+    text.push_str(&format!(":\n```\n{}\n```\n", tail));
+    text
+}
+
+/// The maximum time an interactive command may take
+const INTERACTIVE_TIMEOUT_IN_SECS: u64 = 300;
+
+async fn action_run_interactive(env: &Env<'_>, prompt: &mut Prompt) {
+    prompt.items.push(PromptItem::System { text: ACTION_RUN_INTERACTIVE.to_owned() });
+    let message = env.router.prompt(Step::Interactive, Difficulty::Easy, prompt).await.unwrap();
+    prompt.items.push(PromptItem::Assistant { text: message.clone() });
+
+    let script = match parse_interactive_script(&message) {
+        Ok(script) => script,
+        Err(err) => {
+            prompt.items.push(PromptItem::System {
+                text: format!("The command has not been run: {}", err),
+            });
+            return;
+        }
+    };
+
+    let timeout = Duration::from_secs(INTERACTIVE_TIMEOUT_IN_SECS.min(env.config.command_timeout));
+    let output =
+        env.container.run_interactive(&script.command, &script.interactions, timeout).await;
+    let output = match output {
+        Ok(output) => output,
+        Err(err) => {
+            log::warn!("Failed to run the interactive command: {}", err);
+            prompt.items.push(PromptItem::System {
+                text: format!("The command could not be run: {}", err),
+            });
+            return;
+        }
+    };
+
+    let transcript = env.redactor.redact(&output.transcript);
+    let mut msg = format!("Output: \n```\n{}\n```\n", transcript);
+    if let Some(expect) = output.missing_prompt {
+        msg.push_str(&format!("The prompt `{}` did not appear.\n", expect));
+    }
+    match output.exit_code {
+        Some(exit_code) => msg.push_str(&format!("Exit status: {}\n", exit_code)),
+        None => msg.push_str(&format!(
+            "The command did not finish within {} seconds, it may still be running in the background.\n",
+            timeout.as_secs()
+        )),
+    }
     prompt.items.push(PromptItem::System { text: msg });
 }
 
@@ -209,7 +1322,13 @@ const ACTION_EDIT_DISCUSS: &str =
 
 const ACTION_EDIT_REPLACE: &str = r#"Provide the file content with your edits applied.
 If you do not want to edit the file, restate the current file contents.
-No prose. Your message must list the whole updated file, because the file will be overwritten with your new content:
+The line numbers above are not part of the file, do not include them.
+To only replace some lines of a large file, start your message with a line `LINES <start>-<end>` followed by the new content of these lines (inclusive, an empty content removes them). For instance, to replace lines 12 to 14 by a single line, write:
+
+LINES 12-14
+    let x = 2;
+
+Otherwise, no prose. Your message must list the whole updated file, because the file will be overwritten with your new content:
 "#;
 
 const ACTION_EDIT_CREATE: &str = r#"Provide the new file contents.
@@ -219,31 +1338,32 @@ Your message must only consist of the new file contents:
 
 const ACTION_EDITED: &str = r#"The edited file has been saved."#;
 
-async fn action_edit_file(
-    llm_client: &llm::LLMClient,
-    container: &Container,
-    prompt: &mut Prompt,
-    resources: &mut Resources,
-) {
+async fn action_edit_file(env: &Env<'_>, prompt: &mut Prompt, resources: &mut Resources) {
     prompt.items.push(PromptItem::System { text: ACTION_EDIT_FILEPATH.to_owned() });
-    let filepath = llm_client.prompt(BASIC_MODEL, prompt).await.unwrap();
+    let filepath =
+        env.router.prompt(Step::EditFilepath, Difficulty::Trivial, prompt).await.unwrap();
     prompt.items.push(PromptItem::Assistant { text: filepath.clone() });
 
-    let content = match read_file(container, &filepath).await {
+    if env.protected_paths.is_protected(&filepath) {
+        prompt.items.push(PromptItem::System { text: protected_path_message(&filepath) });
+        return;
+    }
+
+    let content = match read_file(env.container, &filepath).await {
         Ok(content) => content,
         Err(ReadFileError::NotFound) => {
             prompt.items.push(PromptItem::System {
                 text: "The file does not exist. It will be created.".to_owned(),
             });
             prompt.items.push(PromptItem::System { text: ACTION_EDIT_CREATE.to_owned() });
-            let contents = llm_client.prompt(SMART_MODEL, prompt).await.unwrap();
+            let contents =
+                env.router.prompt(Step::CreateFile, Difficulty::Hard, prompt).await.unwrap();
             prompt.items.push(PromptItem::Assistant { text: contents.clone() });
             resources.add_file(&filepath);
-            write_file(container, &filepath, &contents).await;
-            prompt.items.push(PromptItem::System { text: ACTION_EDITED.to_owned() });
+            write_file_and_report(env, prompt, &filepath, &contents, "").await;
             return;
         }
-        Err(ReadFileError::Other(err)) => {
+        Err(err) => {
             prompt.items.push(PromptItem::System {
                 text: format!("An error occured while reading the file: {}", err),
             });
@@ -253,16 +1373,322 @@ async fn action_edit_file(
 
     resources.add_file(&filepath);
 
-    prompt.items.push(PromptItem::System { text: format!("The content of `{}` is:", filepath) });
-    prompt.items.push(PromptItem::System { text: content });
+    prompt.items.push(PromptItem::System {
+        text: format!("The content of `{}` is, with line numbers:", filepath),
+    });
+    prompt.items.push(PromptItem::System { text: number_lines(&content) });
     prompt.items.push(PromptItem::System { text: ACTION_EDIT_DISCUSS.to_owned() });
-    let completion = llm_client.prompt(SMART_MODEL, prompt).await.unwrap();
+    let completion = env.router.prompt(Step::EditDiscuss, Difficulty::Hard, prompt).await.unwrap();
     prompt.items.push(PromptItem::Assistant { text: completion });
     prompt.items.push(PromptItem::System { text: ACTION_EDIT_REPLACE.to_owned() });
-    let contents = llm_client.prompt(SMART_MODEL, prompt).await.unwrap();
-    prompt.items.push(PromptItem::Assistant { text: contents.clone() });
-    write_file(container, &filepath, &contents).await;
-    prompt.items.push(PromptItem::System { text: ACTION_EDITED.to_owned() });
+    let message = env.router.prompt(Step::EditReplace, Difficulty::Hard, prompt).await.unwrap();
+    prompt.items.push(PromptItem::Assistant { text: message.clone() });
+
+    let contents = match parse_line_range_edit(&message) {
+        Some((start, end, replacement)) => match splice_lines(&content, start, end, replacement) {
+            // The lines are spliced with `\n`, even if the file uses `\r\n`
+            Ok(spliced) => LineEnding::detect(&content).unwrap_or_default().apply(&spliced),
+            Err(err) => {
+                let text = format!("The file has not been changed: {}", err);
+                prompt.items.push(PromptItem::System { text });
+                return;
+            }
+        },
+        None => message,
+    };
+    write_file_and_report(env, prompt, &filepath, &contents, &content).await;
+}
+
+/// Write a file and tell the model whether it has been saved
+async fn write_file_and_report(
+    env: &Env<'_>,
+    prompt: &mut Prompt,
+    filepath: &str,
+    contents: &str,
+    original: &str,
+) {
+    let result = write_file(
+        env.container,
+        env.redactor,
+        &env.protected_paths,
+        env.config.line_endings,
+        filepath,
+        contents,
+        original,
+    )
+    .await;
+    let text = match result {
+        Ok(()) => ACTION_EDITED.to_owned(),
+        Err(WriteFileError::Protected) => protected_path_message(filepath),
+        Err(WriteFileError::Other(err)) => {
+            format!("An error occured while writing the file: {}", err)
+        }
+    };
+    prompt.items.push(PromptItem::System { text });
+}
+
+fn protected_path_message(filepath: &str) -> String {
+    format!("The path `{}` is protected. You are not allowed to modify it.", filepath)
+}
+
+const ACTION_REPLACE_FILEPATH: &str = r#"Provide the path of the file you want to change.
+No prose. Your message should only consist of the filepath.
+For instance, to change `foo/bar/example.txt`, write:
+
+foo/bar/example.txt
+"#;
+
+const ACTION_REPLACE: &str = r#"Provide the snippet you want to replace and its replacement in the following format:
+
+<<<<<<< SEARCH
+exact lines of the current file
+=======
+new lines
+>>>>>>> REPLACE
+
+The search lines must match the current file exactly, including whitespace, and must only occur once in the file.
+No prose. Your message must only consist of the search/replace block:
+"#;
+
+async fn action_replace_in_file(env: &Env<'_>, prompt: &mut Prompt, resources: &mut Resources) {
+    prompt.items.push(PromptItem::System { text: ACTION_REPLACE_FILEPATH.to_owned() });
+    let filepath =
+        env.router.prompt(Step::ReplaceFilepath, Difficulty::Trivial, prompt).await.unwrap();
+    prompt.items.push(PromptItem::Assistant { text: filepath.clone() });
+
+    if env.protected_paths.is_protected(&filepath) {
+        prompt.items.push(PromptItem::System { text: protected_path_message(&filepath) });
+        return;
+    }
+
+    let content = match read_file(env.container, &filepath).await {
+        Ok(content) => content,
+        Err(err) => {
+            prompt.items.push(PromptItem::System {
+                text: format!("An error occured while reading the file: {}", err),
+            });
+            return;
+        }
+    };
+    resources.add_file(&filepath);
+
+    prompt.items.push(PromptItem::System { text: format!("The content of `{}` is:", filepath) });
+    prompt.items.push(PromptItem::System { text: content.clone() });
+    prompt.items.push(PromptItem::System { text: ACTION_REPLACE.to_owned() });
+    let message = env.router.prompt(Step::ReplaceInFile, Difficulty::Hard, prompt).await.unwrap();
+    prompt.items.push(PromptItem::Assistant { text: message.clone() });
+
+    // The search lines of the model use `\n`, even if the file uses `\r\n`
+    let line_ending = LineEnding::detect(&content).unwrap_or_default();
+    let replaced = parse_search_replace(&message).and_then(|(search, replace)| {
+        let (search, replace) = (line_ending.apply(&search), line_ending.apply(&replace));
+        replace_unique(&content, &search, &replace).map_err(|err| err.to_string())
+    });
+    match replaced {
+        Ok(replaced) => write_file_and_report(env, prompt, &filepath, &replaced, &content).await,
+        Err(err) => {
+            let text = format!(
+                "The file has not been changed: {}\nIf you try again, include more surrounding lines, so the search lines match exactly once.",
+                err
+            );
+            prompt.items.push(PromptItem::System { text });
+        }
+    }
+}
+
+const ACTION_PATCH: &str = r#"Provide the changes to the file as a unified diff, with a few lines of context around each change.
+Hunks that do not match the current file exactly are applied where they match closest to their line numbers.
+No prose. Your message must only consist of the diff. For instance:
+
+@@ -12,3 +12,3 @@
+ fn main() {
+-    let x = 1;
++    let x = 2;
+"#;
+
+async fn action_apply_patch(env: &Env<'_>, prompt: &mut Prompt, resources: &mut Resources) {
+    prompt.items.push(PromptItem::System { text: ACTION_REPLACE_FILEPATH.to_owned() });
+    let filepath =
+        env.router.prompt(Step::PatchFilepath, Difficulty::Trivial, prompt).await.unwrap();
+    prompt.items.push(PromptItem::Assistant { text: filepath.clone() });
+
+    if env.protected_paths.is_protected(&filepath) {
+        prompt.items.push(PromptItem::System { text: protected_path_message(&filepath) });
+        return;
+    }
+
+    let content = match read_file(env.container, &filepath).await {
+        Ok(content) => content,
+        Err(err) => {
+            prompt.items.push(PromptItem::System {
+                text: format!("An error occured while reading the file: {}", err),
+            });
+            return;
+        }
+    };
+    resources.add_file(&filepath);
+
+    prompt.items.push(PromptItem::System { text: format!("The content of `{}` is:", filepath) });
+    prompt.items.push(PromptItem::System { text: content.clone() });
+    prompt.items.push(PromptItem::System { text: ACTION_PATCH.to_owned() });
+    let message = env.router.prompt(Step::Patch, Difficulty::Hard, prompt).await.unwrap();
+    prompt.items.push(PromptItem::Assistant { text: message.clone() });
+
+    let hunks = match parse_patch(&strip_wrapping_markdown_code_fences(&message)) {
+        Ok(hunks) => hunks,
+        Err(err) => {
+            let text = format!("The file has not been changed: {}", err);
+            prompt.items.push(PromptItem::System { text });
+            return;
+        }
+    };
+    // Only complete patches are applied, so a retry does not have to account for partial changes
+    let applied = apply_patch(&content, &hunks);
+    if !applied.rejected.is_empty() {
+        let text = format!(
+            "The file has not been changed, as these hunks do not match the file:\n```\n{}```\nIf you try again, make sure the context and removed lines match the current file.",
+            applied.rejected.join("")
+        );
+        prompt.items.push(PromptItem::System { text });
+        return;
+    }
+    if applied.fuzzy > 0 {
+        log::info!("{} hunks only applied with whitespace ignored", applied.fuzzy);
+    }
+    let line_ending = LineEnding::detect(&content).unwrap_or_default();
+    let patched = line_ending.apply(&applied.content);
+    write_file_and_report(env, prompt, &filepath, &patched, &content).await;
+}
+
+const ACTION_DELETE_FILE: &str = r#"Provide the path of the file you want to delete.
+No prose. Your message should only consist of the filepath.
+For instance, to delete `foo/bar/example.txt`, write:
+
+foo/bar/example.txt
+"#;
+
+async fn action_delete_file(env: &Env<'_>, prompt: &mut Prompt, resources: &mut Resources) {
+    prompt.items.push(PromptItem::System { text: ACTION_DELETE_FILE.to_owned() });
+    let filepath = env.router.prompt(Step::DeletePath, Difficulty::Trivial, prompt).await.unwrap();
+    prompt.items.push(PromptItem::Assistant { text: filepath.clone() });
+    let filepath = filepath.trim();
+
+    if env.protected_paths.is_protected(filepath) {
+        prompt.items.push(PromptItem::System { text: protected_path_message(filepath) });
+        return;
+    }
+    let text = match env.container.delete_file(filepath).await {
+        Ok(()) => {
+            resources.remove_file(filepath);
+            format!("`{}` has been deleted.", filepath)
+        }
+        Err(FileOpError::NotFound) => format!("`{}` does not exist.", filepath),
+        Err(err) => format!("An error occured while deleting the file: {}", err),
+    };
+    prompt.items.push(PromptItem::System { text });
+}
+
+const ACTION_MOVE_FILE: &str = r#"Provide the current path of the file and its new path, each on its own line.
+No prose. For instance, to rename `src/util.py` to `src/helpers.py`, write:
+
+src/util.py
+src/helpers.py
+"#;
+
+async fn action_move_file(env: &Env<'_>, prompt: &mut Prompt, resources: &mut Resources) {
+    prompt.items.push(PromptItem::System { text: ACTION_MOVE_FILE.to_owned() });
+    let message = env.router.prompt(Step::MovePaths, Difficulty::Trivial, prompt).await.unwrap();
+    prompt.items.push(PromptItem::Assistant { text: message.clone() });
+
+    let paths: Vec<&str> = message.lines().map(str::trim).filter(|l| !l.is_empty()).collect();
+    let [from, to] = paths[..] else {
+        prompt.items.push(PromptItem::System {
+            text: "Give exactly two paths, the current one and the new one.".to_owned(),
+        });
+        return;
+    };
+    if let Some(protected) = [from, to].into_iter().find(|p| env.protected_paths.is_protected(p)) {
+        prompt.items.push(PromptItem::System { text: protected_path_message(protected) });
+        return;
+    }
+    let text = match env.container.move_file(from, to).await {
+        Ok(()) => {
+            resources.remove_file(to);
+            resources.rename_file(from, to);
+            format!("`{}` has been moved to `{}`.", from, to)
+        }
+        Err(FileOpError::NotFound) => format!("`{}` does not exist.", from),
+        Err(err) => format!("An error occured while moving the file: {}", err),
+    };
+    prompt.items.push(PromptItem::System { text });
+}
+
+const ACTION_WRITE_FILES: &str = r#"Provide the paths and the complete contents of the files you want to write.
+Existing files will be overwritten. Start every file with a header line `--- FILE: <path> ---`.
+No prose. For instance, to write `src/foo.py` and `tests/test_foo.py`, write:
+
+--- FILE: src/foo.py ---
+def foo():
+    return 42
+--- FILE: tests/test_foo.py ---
+from src.foo import foo
+
+def test_foo():
+    assert foo() == 42
+"#;
+
+async fn action_write_files(env: &Env<'_>, prompt: &mut Prompt, resources: &mut Resources) {
+    prompt.items.push(PromptItem::System { text: ACTION_WRITE_FILES.to_owned() });
+    let message = env.router.prompt(Step::WriteFiles, Difficulty::Hard, prompt).await.unwrap();
+    prompt.items.push(PromptItem::Assistant { text: message.clone() });
+
+    let files = match parse_files(&message) {
+        Ok(files) => files,
+        Err(err) => {
+            prompt.items.push(PromptItem::System {
+                text: format!("The files could not be parsed, none were written: {}", err),
+            });
+            return;
+        }
+    };
+
+    if let Some((filepath, _)) = files.iter().find(|(f, _)| env.protected_paths.is_protected(f)) {
+        let text = format!("{}\nNone of the files were written.", protected_path_message(filepath));
+        prompt.items.push(PromptItem::System { text });
+        return;
+    }
+
+    // Existing secrets in overwritten files are kept, new ones are redacted
+    let mut originals = Vec::new();
+    for (filepath, _) in &files {
+        originals.push(read_file(env.container, filepath).await.unwrap_or_default());
+    }
+
+    let result = write_files(
+        env.container,
+        env.redactor,
+        &env.protected_paths,
+        env.config.line_endings,
+        &files,
+        &originals,
+    )
+    .await;
+    let text = match result {
+        Ok(()) => {
+            let filepaths: Vec<String> = files.iter().map(|(f, _)| format!("`{}`", f)).collect();
+            for (filepath, _) in &files {
+                resources.add_file(filepath);
+            }
+            format!("The files {} have been saved.", filepaths.join(", "))
+        }
+        Err(WriteFileError::Protected) => {
+            "Some of the paths are protected. None of the files were written.".to_owned()
+        }
+        Err(WriteFileError::Other(err)) => {
+            format!("An error occured while writing the files, none were written: {}", err)
+        }
+    };
+    prompt.items.push(PromptItem::System { text });
 }
 
 const ACTION_READ_FILEPATH: &str = r#"Provide the path of the file you want to read.
@@ -272,23 +1698,19 @@ For instance, to read `foo/bar/example.txt`, write:
 foo/bar/example.txt
 "#;
 
-async fn action_read_file(
-    llm_client: &llm::LLMClient,
-    container: &Container,
-    prompt: &mut Prompt,
-    resources: &mut Resources,
-) {
+async fn action_read_file(env: &Env<'_>, prompt: &mut Prompt, resources: &mut Resources) {
     prompt.items.push(PromptItem::System { text: ACTION_READ_FILEPATH.to_owned() });
-    let filepath = llm_client.prompt(BASIC_MODEL, prompt).await.unwrap();
+    let filepath =
+        env.router.prompt(Step::ReadFilepath, Difficulty::Trivial, prompt).await.unwrap();
     prompt.items.push(PromptItem::Assistant { text: filepath.clone() });
 
-    let content = match read_file(container, &filepath).await {
+    let content = match read_file(env.container, &filepath).await {
         Ok(content) => content,
         Err(ReadFileError::NotFound) => {
             prompt.items.push(PromptItem::System { text: "The file does not exist.".to_owned() });
             return;
         }
-        Err(ReadFileError::Other(err)) => {
+        Err(err) => {
             prompt.items.push(PromptItem::System {
                 text: format!("An error occured while reading the file: {}", err),
             });
@@ -298,7 +1720,239 @@ async fn action_read_file(
     resources.add_file(&filepath);
 
     prompt.items.push(PromptItem::System { text: format!("The content of `{}` is:", filepath) });
-    prompt.items.push(PromptItem::System { text: content });
+    prompt.items.push(PromptItem::System { text: env.redactor.redact(&content) });
+}
+
+const ACTION_READ_FILE_AT_REF: &str = r#"Provide the git ref and the path of the file you want to read, separated by a colon.
+No prose. Your message must only consist of the ref and the filepath.
+For instance, to read `foo/bar/example.txt` as it was two commits ago, write:
+
+HEAD~2:foo/bar/example.txt
+"#;
+
+async fn action_read_file_at_ref(env: &Env<'_>, prompt: &mut Prompt) {
+    prompt.items.push(PromptItem::System { text: ACTION_READ_FILE_AT_REF.to_owned() });
+    let spec = env.router.prompt(Step::ReadRefFilepath, Difficulty::Trivial, prompt).await.unwrap();
+    prompt.items.push(PromptItem::Assistant { text: spec.clone() });
+
+    let Some((reference, filepath)) = spec.trim().split_once(':') else {
+        prompt.items.push(PromptItem::System {
+            text: "The ref and the filepath must be separated by a colon.".to_owned(),
+        });
+        return;
+    };
+    match env.git_repo.read_file_at(reference, filepath) {
+        Ok(content) => {
+            prompt.items.push(PromptItem::System {
+                text: format!("The content of `{}` at `{}` is:", filepath, reference),
+            });
+            prompt.items.push(PromptItem::System { text: env.redactor.redact(&content) });
+        }
+        Err(err) => {
+            prompt.items.push(PromptItem::System {
+                text: format!("An error occured while reading the file: {}", err),
+            });
+        }
+    }
+}
+
+const ACTION_STAT_PATH: &str = r#"Provide the path you want to check.
+No prose. Your message must only consist of the path.
+For instance, to check `foo/bar/example.txt`, write:
+
+foo/bar/example.txt
+"#;
+
+async fn action_stat(env: &Env<'_>, prompt: &mut Prompt) {
+    prompt.items.push(PromptItem::System { text: ACTION_STAT_PATH.to_owned() });
+    let path = env.router.prompt(Step::StatPath, Difficulty::Trivial, prompt).await.unwrap();
+    prompt.items.push(PromptItem::Assistant { text: path.clone() });
+
+    let text = match env.container.stat(&path).await {
+        Ok(PathStat::Missing) => format!("`{}` does not exist.", path),
+        Ok(PathStat::Directory) => format!("`{}` is a directory.", path),
+        Ok(PathStat::File { size: Some(size) }) => {
+            format!("`{}` is a file of {} bytes.", path, size)
+        }
+        Ok(PathStat::File { size: None }) => format!("`{}` is a file.", path),
+        Err(err) => format!("An error occured while checking the path: {}", err),
+    };
+    prompt.items.push(PromptItem::System { text });
+}
+
+const ACTION_LIST_DIR_PATH: &str = r#"Provide the path of the directory you want to list.
+No prose. Your message must only consist of the path.
+For instance, to list the project directory, write:
+
+.
+"#;
+
+/// The maximum number of directory entries that are shown
+const MAX_DIR_ENTRIES: usize = 200;
+
+async fn action_list_dir(env: &Env<'_>, prompt: &mut Prompt) {
+    prompt.items.push(PromptItem::System { text: ACTION_LIST_DIR_PATH.to_owned() });
+    let path = env.router.prompt(Step::ListDirPath, Difficulty::Trivial, prompt).await.unwrap();
+    prompt.items.push(PromptItem::Assistant { text: path.clone() });
+    let path = path.trim();
+
+    let text = match env.container.list_dir(path).await {
+        Ok(entries) if entries.is_empty() => format!("`{}` is empty.", path),
+        Ok(entries) => {
+            let mut text = format!("`{}` contains:\n", path);
+            for entry in entries.iter().take(MAX_DIR_ENTRIES) {
+                if entry.is_dir {
+                    text.push_str(&format!("{}/\n", entry.name));
+                } else {
+                    text.push_str(&format!("{} ({} bytes)\n", entry.name, entry.size));
+                }
+            }
+            if entries.len() > MAX_DIR_ENTRIES {
+                text.push_str(&format!("... and {} more\n", entries.len() - MAX_DIR_ENTRIES));
+            }
+            text
+        }
+        Err(ListDirError::NotFound) => format!("`{}` does not exist.", path),
+        Err(ListDirError::NotADirectory) => {
+            format!("`{}` is a file, not a directory. Use `read-file` to read it.", path)
+        }
+        Err(err) => format!("An error occured while listing the directory: {}", err),
+    };
+    prompt.items.push(PromptItem::System { text });
+}
+
+const ACTION_SEARCH: &str = r#"Provide the regular expression you want to search the files of the project for on the first line.
+Optionally, provide a glob of the files to search on the second line.
+No prose. For instance, to find where the function `parse_config` is defined in Rust files, write:
+
+fn parse_config
+*.rs
+"#;
+
+/// The maximum number of matching lines that are shown
+const MAX_SEARCH_MATCHES: usize = 100;
+
+async fn action_search(env: &Env<'_>, prompt: &mut Prompt) {
+    prompt.items.push(PromptItem::System { text: ACTION_SEARCH.to_owned() });
+    let message =
+        env.router.prompt(Step::SearchPattern, Difficulty::Trivial, prompt).await.unwrap();
+    prompt.items.push(PromptItem::Assistant { text: message.clone() });
+    let message = strip_wrapping_markdown_code_fences(&message);
+    let mut lines = message.lines().map(str::trim).filter(|line| !line.is_empty());
+    let Some(pattern) = lines.next() else {
+        let text = "No pattern was given, nothing was searched.".to_owned();
+        prompt.items.push(PromptItem::System { text });
+        return;
+    };
+    let glob = lines.next();
+
+    let text = match env.container.search(pattern, glob, MAX_SEARCH_MATCHES).await {
+        Ok(result) if result.matches.is_empty() => format!("No lines match `{}`.", pattern),
+        Ok(result) => {
+            let mut text = format!("Lines matching `{}`:\n", pattern);
+            for m in &result.matches {
+                text.push_str(&format!("{}:{}: {}\n", m.path, m.line, m.text));
+            }
+            if result.truncated {
+                text.push_str(&format!(
+                    "... more matches are not shown, only the first {}. Use a more specific pattern or glob.\n",
+                    MAX_SEARCH_MATCHES
+                ));
+            }
+            text
+        }
+        Err(err) => format!("The search failed: {}", err),
+    };
+    prompt.items.push(PromptItem::System { text: env.redactor.redact(&text) });
+}
+
+const ACTION_FOCUS: &str = r#"Provide the paths of the files you want to keep in focus, one per line.
+The current content of focused files is shown at the beginning of every action, even after older actions have been summarized.
+Files that are not listed are no longer focused. No prose. To focus no files, write:
+
+none
+"#;
+
+/// The maximum number of focused files, as they take up space in every prompt
+const MAX_FOCUSED_FILES: usize = 5;
+
+async fn action_focus(env: &Env<'_>, prompt: &mut Prompt, resources: &mut Resources) {
+    let currently = if resources.focus.is_empty() {
+        "Currently, no files are focused.".to_owned()
+    } else {
+        let paths: Vec<String> = resources.focus.iter().map(|f| format!("`{}`", f)).collect();
+        format!("Currently, the files {} are focused.", paths.join(", "))
+    };
+    prompt.items.push(PromptItem::System { text: format!("{}\n{}", ACTION_FOCUS, currently) });
+    let message = env.router.prompt(Step::FocusPaths, Difficulty::Trivial, prompt).await.unwrap();
+    prompt.items.push(PromptItem::Assistant { text: message.clone() });
+
+    let paths: BTreeSet<String> = message
+        .lines()
+        .map(|line| line.trim().trim_matches('`').to_owned())
+        .filter(|line| !line.is_empty() && line != "none")
+        .collect();
+    let text = if paths.len() > MAX_FOCUSED_FILES {
+        format!(
+            "At most {} files can be focused, the focus has not been changed.",
+            MAX_FOCUSED_FILES
+        )
+    } else if paths.is_empty() {
+        resources.focus.clear();
+        "No files are focused anymore.".to_owned()
+    } else {
+        let listed: Vec<String> = paths.iter().map(|f| format!("`{}`", f)).collect();
+        resources.focus = paths;
+        format!("The files {} are focused from the next action on.", listed.join(", "))
+    };
+    prompt.items.push(PromptItem::System { text });
+}
+
+const ACTION_RETRIEVE: &str = r#"Describe the code you are looking for, e.g. `where HTTP requests are retried`.
+No prose. Your message must only consist of the description:
+"#;
+
+/// The number of chunks returned by the `retrieve` action
+const RETRIEVED_CHUNKS: usize = 5;
+
+async fn action_retrieve(env: &Env<'_>, prompt: &mut Prompt) {
+    prompt.items.push(PromptItem::System { text: ACTION_RETRIEVE.to_owned() });
+    let query = env.router.prompt(Step::RetrieveQuery, Difficulty::Easy, prompt).await.unwrap();
+    prompt.items.push(PromptItem::Assistant { text: query.clone() });
+
+    let Some(index) = &env.retrieval else {
+        let text = "Retrieval is not available, as the index of the repository could not be built.";
+        prompt.items.push(PromptItem::System { text: text.to_owned() });
+        return;
+    };
+    let chunks = match index.search(env.router.llm_client(), &query, RETRIEVED_CHUNKS).await {
+        Ok(chunks) => chunks,
+        Err(err) => {
+            log::warn!("Failed to search the index: {}", err);
+            let text = format!("Retrieval is not available, the search failed: {}", err);
+            prompt.items.push(PromptItem::System { text: env.redactor.redact(&text) });
+            return;
+        }
+    };
+    let mut text = format!("The most relevant code as of commit `{}`:\n", index.commit());
+    for chunk in chunks {
+        text.push_str(&format!(
+            "\n`{}:{}-{}`\n```\n{}\n```\n",
+            chunk.path, chunk.start_line, chunk.end_line, chunk.text
+        ));
+    }
+    prompt.items.push(PromptItem::System { text: env.redactor.redact(&text) });
+}
+
+const ACTION_ASK_USER: &str = r#"Provide the question you want to ask the user.
+No prose. Your message must only consist of the question, as it will be shown to the user as is.
+"#;
+
+async fn action_ask_user(env: &Env<'_>, prompt: &mut Prompt) -> String {
+    prompt.items.push(PromptItem::System { text: ACTION_ASK_USER.to_owned() });
+    let question = env.router.prompt(Step::AskUser, Difficulty::Easy, prompt).await.unwrap();
+    prompt.items.push(PromptItem::Assistant { text: question.clone() });
+    question
 }
 
 const ACTION_END_TASK_DISCUSS: &str = r#"You have decided to end the task.
@@ -345,50 +1999,201 @@ For instance, if you chose the technical-issues category, you would write:
 technical-issues
 "#;
 
-async fn action_end_task(llm_client: &llm::LLMClient, prompt: &mut Prompt) -> ActionResult {
+const NO_CHANGES: &str = r#"You have not made any changes to the repository. Are you sure the task is completed?
+Discuss whether the task requires changes. If it does, continue working on it. If it does not, end the task again."#;
+
+/// Phrases that indicate a task is answered without changing the repository
+const READ_ONLY_PHRASES: &[&str] = &[
+    "explain",
+    "investigate",
+    "analyze",
+    "analyse",
+    "describe",
+    "summarize",
+    "summarise",
+    "review",
+    "check whether",
+    "check if",
+    "find out",
+    "what ",
+    "why ",
+    "how does",
+    "is there",
+    "are there",
+];
+
+/// Whether the task is obviously answered without changes, e.g. it is a question
+fn looks_read_only(description: &str) -> bool {
+    let description = description.trim().to_lowercase();
+    description.ends_with('?')
+        || READ_ONLY_PHRASES.iter().any(|phrase| description.starts_with(phrase))
+}
+
+/// Whether the workspace has any changes, either by edits or by scripts
+async fn has_changes(env: &Env<'_>) -> bool {
+    // With a copied workspace, changes are only visible on the host after syncing
+    if let Err(err) = env.container.sync_workspace_to_host().await {
+        log::warn!("Failed to sync workspace to host: {}", err);
+        return true;
+    }
+    match env.git_repo.changed_paths() {
+        Ok(paths) => !paths.is_empty(),
+        Err(err) => {
+            log::warn!("Failed to check the workspace for changes: {}", err);
+            true
+        }
+    }
+}
+
+/// End the task
+///
+/// If the model completes a task without any changes, it is challenged once to reconsider,
+/// unless the task is obviously answered without changes. The action then continues, which is
+/// signalled by [`ActionResult::Continue`].
+async fn action_end_task(env: &Env<'_>, prompt: &mut Prompt) -> ActionResult {
+    let router = &env.router;
     prompt.items.push(PromptItem::System { text: ACTION_END_TASK_DISCUSS.to_owned() });
-    let completion = llm_client.prompt(SMART_MODEL, prompt).await.unwrap();
+    let completion = router.prompt(Step::EndTaskDiscuss, Difficulty::Hard, prompt).await.unwrap();
     prompt.items.push(PromptItem::Assistant { text: completion });
 
     prompt.items.push(PromptItem::System { text: ACTION_END_TASK_SELECT.to_owned() });
-    let completion = llm_client.prompt(BASIC_MODEL, prompt).await.unwrap();
-    prompt.items.push(PromptItem::Assistant { text: completion.clone() });
-
-    let outcome = match completion.as_str() {
+    let options = [("complete", "complete"), ("failure", "failure")];
+    let choice =
+        match select_option(router, env.config, Step::EndTaskSelect, prompt, &options).await {
+            Ok(choice) => choice,
+            Err(failure) => return ActionResult::EndTask(TaskOutcome::Failure(failure)),
+        };
+    prompt.items.push(PromptItem::Assistant { text: choice.to_owned() });
+
+    let outcome = match choice {
+        "complete"
+            if !env.review
+                && env.config.challenge_no_changes
+                && !env.read_only_task
+                && !env.no_changes_challenged.load(Ordering::Relaxed)
+                && !has_changes(env).await =>
+        {
+            log::info!("The task was completed without changes, challenging the model");
+            env.no_changes_challenged.store(true, Ordering::Relaxed);
+            prompt.items.push(PromptItem::System { text: NO_CHANGES.to_owned() });
+            return ActionResult::Continue;
+        }
         "complete" => {
             prompt
                 .items
                 .push(PromptItem::System { text: ACTION_COMPLETE_TASK_DESCRIPTION.to_owned() });
-            let description = llm_client.prompt(SMART_MODEL, prompt).await.unwrap();
-            TaskOutcome::Complete(TaskComplete { description })
+            let description =
+                router.prompt(Step::CompleteDescription, Difficulty::Easy, prompt).await.unwrap();
+            if !env.review {
+                let description = env.redactor.redact(&description);
+                return ActionResult::EndTask(TaskOutcome::Complete(TaskComplete { description }));
+            }
+            prompt.items.push(PromptItem::Assistant { text: description.clone() });
+            prompt.items.push(PromptItem::System { text: ACTION_REVIEW_COMMENTS.to_owned() });
+            let message =
+                router.prompt(Step::ReviewComments, Difficulty::Hard, prompt).await.unwrap();
+            let comments: Vec<ReviewComment> =
+                parse_review_comments(&env.redactor.redact(&message));
+            let description = format!(
+                "{}\n\n## Review comments\n\n{}",
+                description,
+                format_review_comments(&comments)
+            );
+            let description = env.redactor.redact(&description);
+            TaskOutcome::Reviewed { info: TaskComplete { description }, comments }
         }
         "failure" => {
             prompt.items.push(PromptItem::System { text: ACTION_FAIL_TASK_DESCRIPTION.to_owned() });
-            let description = llm_client.prompt(SMART_MODEL, prompt).await.unwrap();
+            let description =
+                router.prompt(Step::FailureDescription, Difficulty::Easy, prompt).await.unwrap();
             prompt.items.push(PromptItem::Assistant { text: description.clone() });
 
             prompt
                 .items
                 .push(PromptItem::System { text: ACTION_FAIL_TASK_REASON_DISCUSS.to_owned() });
-            let completion = llm_client.prompt(SMART_MODEL, prompt).await.unwrap();
+            let completion =
+                router.prompt(Step::FailureReasonDiscuss, Difficulty::Easy, prompt).await.unwrap();
             prompt.items.push(PromptItem::Assistant { text: completion.clone() });
 
             prompt
                 .items
                 .push(PromptItem::System { text: ACTION_FAIL_TASK_REASON_SELECT.to_owned() });
-            let reason_str = llm_client.prompt(BASIC_MODEL, prompt).await.unwrap();
-
-            let reason = match reason_str.as_str() {
-                "technical-issues" => Some(TaskFailureReason::TechnicalIssues),
-                "task-issues" => Some(TaskFailureReason::TaskIssues),
-                "problem-solving" => Some(TaskFailureReason::ProblemSolving),
-                _ => None,
-            };
-
+            let options = [
+                ("technical-issues", TaskFailureReason::TechnicalIssues),
+                ("task-issues", TaskFailureReason::TaskIssues),
+                ("problem-solving", TaskFailureReason::ProblemSolving),
+            ];
+            let step = Step::FailureReasonSelect;
+            // The task fails either way, so the reason is only left out if there is none
+            let reason = select_option(router, env.config, step, prompt, &options).await.ok();
+
+            let description = env.redactor.redact(&description);
             TaskOutcome::Failure(TaskFailure { reason, description })
         }
-        _ => panic!("Unknown task ending choice: {}", completion),
+        _ => unreachable!("Unknown task ending choice: {}", choice),
     };
 
     ActionResult::EndTask(outcome)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_action_choice() {
+        let completion = r#"{"reasoning": "Let's run the tests.", "action": "bash"}"#;
+        let choice: ActionChoice = llm::parse_json(completion).unwrap();
+        assert!(matches!(choice.action, Action::Bash));
+        assert_eq!(choice.reasoning, "Let's run the tests.");
+        let choice: ActionChoice =
+            llm::parse_json(r#"{"reasoning": "", "action": "edit-file"}"#).unwrap();
+        assert!(matches!(choice.action, Action::EditFile));
+
+        let result = llm::parse_json::<ActionChoice>(r#"{"reasoning": "", "action": "sudo"}"#);
+        assert!(matches!(result, Err(llm::PromptError::InvalidJson(_))));
+    }
+
+    #[test]
+    fn test_action_limit_reached() {
+        assert_eq!(action_limit_reached(49, 50), None);
+        assert!(action_limit_reached(50, 50).unwrap().contains("limit of 50 actions"));
+        assert_eq!(action_limit_reached(1000, 0), None);
+    }
+
+    #[test]
+    fn test_describe_test_run() {
+        let output: String = (1..=60).map(|i| format!("line {}\n", i)).collect();
+        let text = describe_test_run("cargo test", 101, &output, 0);
+        assert!(text.starts_with("The tests failed with exit status 101.\n"));
+        assert!(text.contains("Output of `cargo test` (the first 10 lines are not shown):"));
+        assert!(!text.contains("line 10\n"));
+        assert!(text.contains("line 11\n"));
+        assert!(text.ends_with("line 60\n```\n"));
+
+        let text = describe_test_run("pytest", 0, "1 passed\n", 0);
+        assert_eq!(text, "The tests passed.\nOutput of `pytest`:\n```\n1 passed\n```\n");
+    }
+
+    #[test]
+    fn test_truncate_output() {
+        let output = format!("head{}tail", "x".repeat(1024 * 1024));
+        let truncated = truncate_output(&output, 1000);
+        assert!(truncated.starts_with("headxxx"));
+        assert!(truncated.ends_with("xxxtail"));
+        assert!(truncated.contains(&format!("\n...[{} bytes truncated]...\n", output.len() - 1000)));
+        assert!(truncated.len() < 1100);
+
+        assert_eq!(truncate_output("short", 1000), "short");
+        assert_eq!(truncate_output(&output, 0), output);
+        assert_eq!(truncate_output("äöüäöü", 5), "ä\n...[8 bytes truncated]...\nü");
+    }
+
+    #[test]
+    fn test_looks_read_only() {
+        assert!(looks_read_only("Explain how the router chooses a model"));
+        assert!(looks_read_only("Which modules use the container directly?"));
+        assert!(!looks_read_only("Add a retry to the API client"));
+        assert!(!looks_read_only("Fix the failing test. Why does it fail?\nThen add a new one."));
+    }
+}
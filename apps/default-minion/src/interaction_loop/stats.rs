@@ -0,0 +1,87 @@
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+
+use super::history::History;
+
+/// Statistics about the actions of a task, for analyzing the behavior of the agent across runs
+#[derive(Debug, PartialEq, Serialize)]
+pub struct ActionStats {
+    /// The number of completed actions
+    pub actions: usize,
+    /// The number of actions that failed and were discarded
+    pub failed_actions: usize,
+    /// The share of all attempted actions that failed
+    pub failure_rate: f64,
+    /// The number of completed actions per action type
+    pub per_type: BTreeMap<String, usize>,
+    /// The average length of the action summaries in characters
+    pub average_summary_chars: f64,
+    /// The result of the last `run-tests` action, if the tests were run
+    pub last_test_run: Option<TestRun>,
+}
+
+/// The result of running the tests of the project
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct TestRun {
+    pub command: String,
+    pub passed: bool,
+    pub exit_code: i64,
+}
+
+impl ActionStats {
+    pub fn from_history(history: &History) -> Self {
+        let actions = history.actions.len();
+        let mut per_type = BTreeMap::new();
+        let mut summary_chars = 0;
+        for action in &history.actions {
+            *per_type.entry(action.action_type.clone()).or_default() += 1;
+            summary_chars += action.summary.to_string().chars().count();
+        }
+        let attempted = actions + history.failed_actions;
+        Self {
+            actions,
+            failed_actions: history.failed_actions,
+            failure_rate: ratio(history.failed_actions, attempted),
+            per_type,
+            average_summary_chars: ratio(summary_chars, actions),
+            last_test_run: None,
+        }
+    }
+}
+
+/// The ratio of two counts, or 0 if there is nothing to count
+fn ratio(count: usize, total: usize) -> f64 {
+    if total == 0 {
+        0.0
+    } else {
+        count as f64 / total as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interaction_loop::history::HistoryConfig;
+    use crate::interaction_loop::summary::Summary;
+
+    #[test]
+    fn test_stats_from_history() {
+        let mut history = History::new(Vec::new(), HistoryConfig::default());
+        history.append("bash", Vec::new(), Summary::Text("1234".to_owned()));
+        history.append("bash", Vec::new(), Summary::Text("12".to_owned()));
+        history.append("read-file", Vec::new(), Summary::Text("123456".to_owned()));
+        history.record_failure();
+
+        let stats = ActionStats::from_history(&history);
+        assert_eq!(stats.actions, 3);
+        assert_eq!(stats.failure_rate, 0.25);
+        assert_eq!(
+            stats.per_type,
+            BTreeMap::from([("bash".to_owned(), 2), ("read-file".to_owned(), 1)])
+        );
+        assert_eq!(stats.average_summary_chars, 4.0);
+        let history = History::new(Vec::new(), HistoryConfig::default());
+        assert_eq!(ActionStats::from_history(&history).failure_rate, 0.0);
+    }
+}
@@ -0,0 +1,100 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::actions::markdown::strip_wrapping_markdown_code_fences;
+
+/// How actions are summarized for the compressed history
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SummaryFormat {
+    /// A free-text summary
+    Text,
+    /// A JSON record, see [`ActionRecord`], falling back to free text if it can not be parsed
+    #[default]
+    Structured,
+}
+
+pub const SUMMARIZE_STRUCTURED: &str = r#"Respond with a JSON object with the following fields and nothing else:
+- `action_type`: the name of the action, e.g. `bash` or `edit-file`
+- `target`: what the action was applied to, e.g. the command or the file path
+- `result`: the outcome of the action in one sentence
+- `key_findings`: a list of facts you learned that are relevant for the rest of the task"#;
+
+/// A structured summary of an action
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ActionRecord {
+    pub action_type: String,
+    pub target: String,
+    pub result: String,
+    #[serde(default)]
+    pub key_findings: Vec<String>,
+}
+
+/// The summary of an action
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Summary {
+    Structured(ActionRecord),
+    Text(String),
+}
+
+impl Summary {
+    /// Parse a structured summary, keeping the message as free text if it is not a valid record
+    pub fn parse(message: &str) -> Self {
+        let json = strip_wrapping_markdown_code_fences(message);
+        match serde_json::from_str(json.trim()) {
+            Ok(record) => Self::Structured(record),
+            Err(err) => {
+                log::warn!("Failed to parse structured summary, using it as text: {}", err);
+                Self::Text(message.to_owned())
+            }
+        }
+    }
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Structured(record) => {
+                write!(f, "{} `{}`: {}", record.action_type, record.target, record.result)?;
+                for finding in &record.key_findings {
+                    write!(f, "\n- {}", finding)?;
+                }
+                Ok(())
+            }
+            Self::Text(text) => f.write_str(text),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_structured_summary() {
+        let message = "```json\n{\"action_type\": \"bash\", \"target\": \"cargo test\", \"result\": \"Two tests failed\", \"key_findings\": [\"`parse` rejects empty input\"]}\n```";
+        let summary = Summary::parse(message);
+        assert_eq!(
+            summary,
+            Summary::Structured(ActionRecord {
+                action_type: "bash".to_owned(),
+                target: "cargo test".to_owned(),
+                result: "Two tests failed".to_owned(),
+                key_findings: vec!["`parse` rejects empty input".to_owned()],
+            })
+        );
+        assert_eq!(
+            summary.to_string(),
+            "bash `cargo test`: Two tests failed\n- `parse` rejects empty input"
+        );
+    }
+
+    #[test]
+    fn test_parse_falls_back_to_text() {
+        let message = "I ran the tests, two of them failed.";
+        assert_eq!(Summary::parse(message), Summary::Text(message.to_owned()));
+        assert_eq!(Summary::parse(message).to_string(), message);
+    }
+}
@@ -1,29 +1,97 @@
-use std::sync::Arc;
-use std::time::Duration;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
 
 use async_openai::config::OpenAIConfig;
 use async_openai::error::OpenAIError;
 use async_openai::types::{
-    ChatCompletionRequestAssistantMessage, ChatCompletionRequestMessage,
+    ChatChoice, ChatCompletionRequestAssistantMessage, ChatCompletionRequestMessage,
     ChatCompletionRequestMessageContentPartImage, ChatCompletionRequestMessageContentPartText,
     ChatCompletionRequestSystemMessage, ChatCompletionRequestUserMessage,
     ChatCompletionRequestUserMessageContent, ChatCompletionRequestUserMessageContentPart,
-    CreateChatCompletionRequest, ImageDetail, ImageUrl,
+    ChatCompletionStreamOptions, CompletionUsage, CreateChatCompletionRequest,
+    CreateChatCompletionStreamResponse, CreateEmbeddingRequest, EmbeddingInput, FinishReason,
+    ImageDetail, ImageUrl, ResponseFormat, ResponseFormatJsonSchema, Stop,
 };
 use backoff::{Error as BackoffError, ExponentialBackoffBuilder};
 use base64::engine::general_purpose::STANDARD;
 use base64::Engine;
+use futures_util::{Stream, StreamExt};
 use image::codecs::webp::WebPEncoder;
 use image::{ColorType, ImageEncoder};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
 use crate::enclose;
+use crate::output::TraceFile;
+use crate::prompt_cache::PromptCache;
+use crate::replay::{Divergence, Replay};
 
 const MAX_ELAPSED_TIME_IN_SECS: u64 = 60;
 
 #[derive(Clone)]
 pub struct LLMClient {
     client: Arc<async_openai::Client<OpenAIConfig>>,
+    traces: Vec<Arc<TraceFile>>,
+    usage: Arc<Mutex<BTreeMap<String, Usage>>>,
+    replay: Option<Arc<Mutex<Replay>>>,
+    /// Models that take neither system messages nor a temperature, including their versions
+    restricted_models: Arc<Vec<String>>,
+    /// Models that are prompted in order if prompting a model fails
+    fallback_models: Arc<Vec<String>>,
+    /// The time after which a single attempt of a request is aborted and retried
+    request_timeout: Option<Duration>,
+    cache: Option<Arc<PromptCache>>,
+}
+
+/// The accumulated token usage of a model
+#[derive(Clone, Default, Serialize)]
+pub struct Usage {
+    pub calls: u64,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+}
+
+/// A line of the LLM trace
+#[derive(Serialize)]
+struct TraceEntry<'a> {
+    model: &'a str,
+    temperature: Option<f32>,
+    prompt: &'a Prompt,
+    /// The messages as they were sent to the model, unless the completion was replayed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    messages: Option<&'a [ChatCompletionRequestMessage]>,
+    completion: &'a str,
+    usage: Option<&'a CompletionUsage>,
+    /// When the model was prompted, in milliseconds since the Unix epoch
+    started_at: u64,
+    /// How long it took to get the completion, including retries, in milliseconds
+    duration_ms: u64,
+}
+
+impl<'a> TraceEntry<'a> {
+    fn new(
+        request: &'a CreateChatCompletionRequest,
+        prompt: &'a Prompt,
+        completion: &'a str,
+        usage: Option<&'a CompletionUsage>,
+        started: SystemTime,
+    ) -> Self {
+        Self {
+            model: &request.model,
+            temperature: request.temperature,
+            prompt,
+            messages: Some(&request.messages),
+            completion,
+            usage,
+            started_at: started
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u64,
+            duration_ms: started.elapsed().unwrap_or_default().as_millis() as u64,
+        }
+    }
 }
 
 #[derive(Error, Debug)]
@@ -32,51 +100,457 @@ pub enum PromptError {
     OpenAI(#[from] async_openai::error::OpenAIError),
     #[error("Missing completion from response")]
     MissingCompletion,
+    #[error("The response contains no choices")]
+    NoChoices,
+    #[error("The completion was withheld by the content filter")]
+    ContentFiltered,
+    #[error("The prompt exceeds the context length of the model")]
+    ContextLengthExceeded,
+    #[error("The replayed trace has no more prompts")]
+    ReplayExhausted,
+    #[error("The model did not respond within {0:?}")]
+    Timeout(Duration),
+    #[error("The completion is not valid JSON of the requested format")]
+    InvalidJson(#[source] serde_json::Error),
 }
 
 impl LLMClient {
-    pub fn new(base_url: &str, openai_key: &str) -> Self {
+    pub fn new(base_url: &str, openai_key: &str, http_client: reqwest::Client) -> Self {
         let config = OpenAIConfig::new().with_api_base(base_url).with_api_key(openai_key);
         let strategy = ExponentialBackoffBuilder::default()
             .with_max_elapsed_time(Some(Duration::from_secs(MAX_ELAPSED_TIME_IN_SECS)))
             .build();
-        let client = Arc::new(async_openai::Client::with_config(config).with_backoff(strategy));
-        Self { client }
+        let client = Arc::new(
+            async_openai::Client::with_config(config)
+                .with_http_client(http_client)
+                .with_backoff(strategy),
+        );
+        Self {
+            client,
+            traces: Vec::new(),
+            usage: Arc::default(),
+            replay: None,
+            restricted_models: Arc::default(),
+            fallback_models: Arc::default(),
+            request_timeout: None,
+            cache: None,
+        }
     }
 
-    pub async fn prompt(&self, model: &str, prompt: &Prompt) -> Result<String, PromptError> {
-        let ctx = RenderCtx { model: model.to_owned() };
-        let messages: Vec<ChatCompletionRequestMessage> = prompt.render(&ctx);
-        let temperature = if ["o1-mini", "o1-preview"].contains(&model) { None } else { Some(0.0) };
+    /// Return the cached completions of identical prompts instead of prompting again
+    pub fn with_cache(mut self, cache: PromptCache) -> Self {
+        self.cache = Some(Arc::new(cache));
+        self
+    }
 
-        let request = CreateChatCompletionRequest {
+    /// Abort and retry attempts of requests that take longer than the timeout, including
+    /// streaming the whole completion
+    pub fn with_request_timeout(mut self, timeout: Duration) -> Self {
+        self.request_timeout = Some(timeout);
+        self
+    }
+
+    /// Prompt these models in order if prompting a model fails, even after retrying
+    pub fn with_fallback_models(mut self, models: Vec<String>) -> Self {
+        self.fallback_models = Arc::new(models);
+        self
+    }
+
+    /// Send system messages as user messages and no temperature to these models (e.g. `o1-mini`)
+    pub fn with_restricted_models(mut self, models: Vec<String>) -> Self {
+        self.restricted_models = Arc::new(models);
+        self
+    }
+
+    /// Append every prompt with its completion to a JSON Lines file, in addition to the files
+    /// added before
+    pub fn with_trace(mut self, trace: TraceFile) -> Self {
+        self.traces.push(Arc::new(trace));
+        self
+    }
+
+    /// Return the completions of a recorded trace instead of prompting the models
+    pub fn with_replay(mut self, replay: Replay) -> Self {
+        self.replay = Some(Arc::new(Mutex::new(replay)));
+        self
+    }
+
+    /// The divergences from the replayed trace, if a trace is replayed
+    pub fn replay_divergences(&self) -> Option<Vec<Divergence>> {
+        self.replay.as_ref().map(|replay| replay.lock().unwrap().divergences())
+    }
+
+    /// The token usage per model so far
+    pub fn usage(&self) -> BTreeMap<String, Usage> {
+        self.usage.lock().unwrap().clone()
+    }
+
+    /// Embed the inputs with the given embedding model, in the order of the inputs
+    pub async fn embed(
+        &self,
+        model: &str,
+        inputs: Vec<String>,
+    ) -> Result<Vec<Vec<f32>>, PromptError> {
+        let request = CreateEmbeddingRequest {
             model: model.to_owned(),
-            messages,
-            temperature,
-            stop: None,
-            ..Default::default()
+            input: EmbeddingInput::StringArray(inputs),
+            encoding_format: None,
+            user: None,
+            dimensions: None,
         };
         let client = self.client.clone();
-        let response = retry_exp(move || {
+        let response = retry_exp(self.request_timeout, move || {
             enclose! {
                 (client, request)
-                async move { client.chat().create(request).await }
+                async move { client.embeddings().create(request).await }
             }
         })
         .await?;
 
-        let completion =
-            response.choices[0].message.content.clone().ok_or(PromptError::MissingCompletion)?;
+        {
+            let mut total = self.usage.lock().unwrap();
+            let total = total.entry(model.to_owned()).or_default();
+            total.calls += 1;
+            total.prompt_tokens += u64::from(response.usage.prompt_tokens);
+        }
+
+        let mut data = response.data;
+        data.sort_by_key(|embedding| embedding.index);
+        Ok(data.into_iter().map(|embedding| embedding.embedding).collect())
+    }
+
+    /// Prompt the model with the options
+    ///
+    /// If prompting the model fails, the fallback models are prompted in order.
+    pub async fn prompt(
+        &self,
+        model: &str,
+        options: &PromptOptions,
+        prompt: &Prompt,
+    ) -> Result<String, PromptError> {
+        if let Some(replay) = &self.replay {
+            return self.replay(replay, model, options, prompt);
+        }
+        with_fallbacks(&self.models(model), |model| self.prompt_model(model, options, prompt, None))
+            .await
+    }
+
+    /// Prompt the model for JSON constrained to the schema, which not all models support
+    ///
+    /// The fallback models must support it as well.
+    pub async fn prompt_json<T: DeserializeOwned>(
+        &self,
+        model: &str,
+        options: &PromptOptions,
+        prompt: &Prompt,
+        schema: &ResponseFormatJsonSchema,
+    ) -> Result<T, PromptError> {
+        let completion = match &self.replay {
+            Some(replay) => self.replay(replay, model, options, prompt)?,
+            None => {
+                let format = ResponseFormat::JsonSchema { json_schema: schema.clone() };
+                with_fallbacks(&self.models(model), |model| {
+                    self.prompt_model(model, options, prompt, Some(&format))
+                })
+                .await?
+            }
+        };
+        parse_json(&completion)
+    }
+
+    async fn prompt_model(
+        &self,
+        model: &str,
+        options: &PromptOptions,
+        prompt: &Prompt,
+        response_format: Option<&ResponseFormat>,
+    ) -> Result<String, PromptError> {
+        let started = SystemTime::now();
+        let mut request = self.request(model, options, prompt);
+        request.response_format = response_format.cloned();
+        let client = self.client.clone();
+        let (completion, usage) = self
+            .cached(&request, || async {
+                let response = retry_exp(self.request_timeout, || {
+                    enclose! {
+                        (client, request)
+                        async move { client.chat().create(request).await }
+                    }
+                })
+                .await?;
+                let choice = response.choices.into_iter().next().map(Choice::from);
+                Ok((completion(choice)?, response.usage))
+            })
+            .await?;
 
+        self.record(&TraceEntry::new(&request, prompt, &completion, usage.as_ref(), started));
         Ok(completion)
     }
+
+    /// Prompt the model like [`Self::prompt`], but stream the completion
+    ///
+    /// The tokens are passed to `on_token` as they arrive, e.g. to log progress. If the stream
+    /// is retried, e.g. because the rate limit is exceeded, the tokens are passed again.
+    pub async fn prompt_stream(
+        &self,
+        model: &str,
+        options: &PromptOptions,
+        prompt: &Prompt,
+        on_token: Option<&(dyn Fn(&str) + Sync)>,
+    ) -> Result<String, PromptError> {
+        if let Some(replay) = &self.replay {
+            return self.replay(replay, model, options, prompt);
+        }
+        with_fallbacks(&self.models(model), |model| {
+            self.prompt_model_stream(model, options, prompt, on_token)
+        })
+        .await
+    }
+
+    async fn prompt_model_stream(
+        &self,
+        model: &str,
+        options: &PromptOptions,
+        prompt: &Prompt,
+        on_token: Option<&(dyn Fn(&str) + Sync)>,
+    ) -> Result<String, PromptError> {
+        let started = SystemTime::now();
+        let mut request = self.request(model, options, prompt);
+        request.stream_options = Some(ChatCompletionStreamOptions { include_usage: true });
+        let client = self.client.clone();
+        let (completion, usage) = self
+            .cached(&request, || async {
+                let (choice, usage) = retry_exp(self.request_timeout, || {
+                    enclose! {
+                        (client, request)
+                        async move {
+                            let stream = client.chat().create_stream(request).await?;
+                            collect_stream(stream, on_token).await
+                        }
+                    }
+                })
+                .await?;
+                Ok((completion(choice)?, usage))
+            })
+            .await?;
+
+        self.record(&TraceEntry::new(&request, prompt, &completion, usage.as_ref(), started));
+        Ok(completion)
+    }
+
+    /// The cached completion of the request if there is a cache, or the completion of prompting
+    async fn cached<F, Fut>(
+        &self,
+        request: &CreateChatCompletionRequest,
+        prompt: F,
+    ) -> Result<(String, Option<CompletionUsage>), PromptError>
+    where
+        F: FnOnce() -> Fut,
+        Fut: std::future::Future<Output = Result<(String, Option<CompletionUsage>), PromptError>>,
+    {
+        match &self.cache {
+            Some(cache) => cache.get_or_prompt(request, prompt).await,
+            None => prompt().await,
+        }
+    }
+
+    /// The model followed by the fallback models
+    fn models<'a>(&'a self, model: &'a str) -> Vec<&'a str> {
+        let fallbacks = self.fallback_models.iter().map(String::as_str);
+        std::iter::once(model).chain(fallbacks.filter(|&fallback| fallback != model)).collect()
+    }
+
+    /// The recorded completion instead of prompting the model
+    fn replay(
+        &self,
+        replay: &Mutex<Replay>,
+        model: &str,
+        options: &PromptOptions,
+        prompt: &Prompt,
+    ) -> Result<String, PromptError> {
+        let started = SystemTime::now();
+        let completion = replay
+            .lock()
+            .unwrap()
+            .next_completion(model, prompt)
+            .ok_or(PromptError::ReplayExhausted)?;
+        let started_at = started.duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default();
+        self.record(&TraceEntry {
+            model,
+            temperature: options.temperature,
+            prompt,
+            messages: None,
+            completion: &completion,
+            usage: None,
+            started_at: started_at.as_millis() as u64,
+            duration_ms: 0,
+        });
+        Ok(completion)
+    }
+
+    fn request(
+        &self,
+        model: &str,
+        options: &PromptOptions,
+        prompt: &Prompt,
+    ) -> CreateChatCompletionRequest {
+        let restricted = is_model_of(model, &self.restricted_models);
+        let ctx = RenderCtx { system_messages: !restricted };
+        let messages: Vec<ChatCompletionRequestMessage> = prompt.render(&ctx);
+        let mut request = CreateChatCompletionRequest {
+            model: model.to_owned(),
+            messages,
+            max_completion_tokens: options.max_tokens,
+            ..Default::default()
+        };
+        // Restricted models only support the default sampling
+        if !restricted {
+            request.temperature = Some(options.temperature.unwrap_or(0.0));
+            request.top_p = options.top_p;
+            request.stop =
+                (!options.stop.is_empty()).then(|| Stop::StringArray(options.stop.clone()));
+        }
+        request
+    }
+
+    /// Add the usage to the total and append the completion to the traces
+    fn record(&self, entry: &TraceEntry) {
+        if let Some(usage) = entry.usage {
+            let mut total = self.usage.lock().unwrap();
+            let total = total.entry(entry.model.to_owned()).or_default();
+            total.calls += 1;
+            total.prompt_tokens += u64::from(usage.prompt_tokens);
+            total.completion_tokens += u64::from(usage.completion_tokens);
+        }
+
+        for trace in &self.traces {
+            trace.append(entry);
+        }
+    }
+}
+
+/// Prompt the models in order until one succeeds
+///
+/// Exceeding the context length is not a reason to fall back, as the prompt is trimmed instead.
+async fn with_fallbacks<'a, F, Fut>(models: &[&'a str], prompt: F) -> Result<String, PromptError>
+where
+    F: Fn(&'a str) -> Fut,
+    Fut: std::future::Future<Output = Result<String, PromptError>>,
+{
+    for (i, &model) in models.iter().enumerate() {
+        match prompt(model).await {
+            Ok(completion) => {
+                log::info!("The completion was served by `{}`", model);
+                return Ok(completion);
+            }
+            Err(err)
+                if i + 1 < models.len() && !matches!(err, PromptError::ContextLengthExceeded) =>
+            {
+                log::warn!(
+                    "Prompting `{}` failed, falling back to `{}`: {:?}",
+                    model,
+                    models[i + 1],
+                    err
+                );
+            }
+            Err(err) => return Err(err),
+        }
+    }
+    unreachable!("There is always a model to prompt")
+}
+
+/// Parse a completion of [`LLMClient::prompt_json`]
+pub fn parse_json<T: DeserializeOwned>(completion: &str) -> Result<T, PromptError> {
+    serde_json::from_str(completion.trim()).map_err(PromptError::InvalidJson)
+}
+
+fn prompt_error(err: OpenAIError) -> PromptError {
+    match &err {
+        OpenAIError::ApiError(api_error)
+            if api_error.code.as_deref() == Some("context_length_exceeded") =>
+        {
+            PromptError::ContextLengthExceeded
+        }
+        _ => PromptError::OpenAI(err),
+    }
+}
+
+/// The first choice of a response
+#[derive(Debug, Default)]
+struct Choice {
+    content: Option<String>,
+    finish_reason: Option<FinishReason>,
+}
+
+impl From<ChatChoice> for Choice {
+    fn from(choice: ChatChoice) -> Self {
+        Self { content: choice.message.content, finish_reason: choice.finish_reason }
+    }
+}
+
+/// The completion of the first choice of a response, if there is one
+///
+/// Completions that were cut off at the maximum number of tokens are returned all the same, as
+/// the beginning of a plan or script is usually still of use.
+fn completion(choice: Option<Choice>) -> Result<String, PromptError> {
+    let choice = choice.ok_or(PromptError::NoChoices)?;
+    match choice.finish_reason {
+        Some(FinishReason::ContentFilter) => return Err(PromptError::ContentFiltered),
+        Some(FinishReason::Length) => log::warn!("The completion reached the maximum length"),
+        _ => {}
+    }
+    choice.content.ok_or(PromptError::MissingCompletion)
+}
+
+/// Assemble the first choice from the chunks of a stream, with the usage that is sent with the
+/// last chunk
+///
+/// The choice is `None` if no chunk contains the first choice.
+async fn collect_stream<S>(
+    mut stream: S,
+    on_token: Option<&(dyn Fn(&str) + Sync)>,
+) -> Result<(Option<Choice>, Option<CompletionUsage>), OpenAIError>
+where
+    S: Stream<Item = Result<CreateChatCompletionStreamResponse, OpenAIError>> + Unpin,
+{
+    let mut first: Option<Choice> = None;
+    let mut usage = None;
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk?;
+        usage = chunk.usage.or(usage);
+        for choice in chunk.choices.into_iter().filter(|choice| choice.index == 0) {
+            let first = first.get_or_insert_with(Choice::default);
+            first.finish_reason = choice.finish_reason.or(first.finish_reason);
+            if let Some(token) = choice.delta.content {
+                if let Some(on_token) = on_token {
+                    on_token(&token);
+                }
+                first.content.get_or_insert_with(String::new).push_str(&token);
+            }
+        }
+    }
+    Ok((first, usage))
+}
+
+/// Options of a prompt, which default to a temperature of 0 and no limits
+#[derive(Clone, Debug, Default)]
+pub struct PromptOptions {
+    /// The sampling temperature, 0 if not set
+    pub temperature: Option<f32>,
+    /// The maximum number of tokens of the completion
+    pub max_tokens: Option<u32>,
+    /// Nucleus sampling, only the most likely tokens with this probability mass are considered
+    pub top_p: Option<f32>,
+    /// Sequences at which the completion stops, up to 4
+    pub stop: Vec<String>,
 }
 
 pub struct RenderCtx {
-    pub model: String,
+    /// Whether the model takes system messages, otherwise they are sent as user messages
+    pub system_messages: bool,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Prompt {
     pub items: Vec<PromptItem>,
 }
@@ -87,20 +561,133 @@ impl Prompt {
     }
 }
 
+impl Prompt {
+    /// Shorten all but the last `keep_last` items to at most `max_chars` characters each
+    ///
+    /// The beginning and the end of long texts are kept, as these tend to be the most relevant
+    /// parts of file contents and command output.
+    pub fn trimmed(&self, max_chars: usize, keep_last: usize) -> Prompt {
+        let trim_count = self.items.len().saturating_sub(keep_last);
+        let items = self
+            .items
+            .iter()
+            .enumerate()
+            .map(|(i, item)| if i < trim_count { item.trimmed(max_chars) } else { item.clone() })
+            .collect();
+        Prompt { items }
+    }
+}
+
+impl Prompt {
+    /// Whether the prompt contains images, which only vision models support
+    pub fn has_images(&self) -> bool {
+        self.items.iter().any(|item| match item {
+            PromptItem::User { content } => {
+                content.items.iter().any(|item| matches!(item, ContentItem::Image { .. }))
+            }
+            _ => false,
+        })
+    }
+
+    /// Replace all images with a note, for models without vision
+    pub fn without_images(&self) -> Prompt {
+        let items = self
+            .items
+            .iter()
+            .map(|item| match item {
+                PromptItem::User { content } => {
+                    let items = content
+                        .items
+                        .iter()
+                        .map(|item| match item {
+                            ContentItem::Image { .. } => {
+                                ContentItem::Text { text: IMAGE_OMITTED.to_owned() }
+                            }
+                            item => item.clone(),
+                        })
+                        .collect();
+                    PromptItem::User { content: Content { items } }
+                }
+                item => item.clone(),
+            })
+            .collect();
+        Prompt { items }
+    }
+}
+
+const IMAGE_OMITTED: &str = "[image omitted: model lacks vision]";
+
+/// Whether the model is one of the models or a version of one (e.g. `gpt-4o-2024-08-06` for
+/// `gpt-4o`), e.g. to check whether it supports images
+pub fn is_model_of(model: &str, models: &[String]) -> bool {
+    models.iter().any(|other| {
+        model == other
+            || model.strip_prefix(other.as_str()).is_some_and(|rest| rest.starts_with('-'))
+    })
+}
+
 impl From<Vec<PromptItem>> for Prompt {
     fn from(items: Vec<PromptItem>) -> Self {
         Self { items }
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "role", rename_all = "kebab-case")]
 pub enum PromptItem {
     User { content: Content },
     System { text: String },
     Assistant { text: String },
 }
 
+/// The approximate number of characters per token of text and code
+const CHARS_PER_TOKEN: usize = 4;
+/// The approximate number of tokens of an image
+const TOKENS_PER_IMAGE: usize = 1000;
+
+/// A rough estimate of the number of tokens of a text
+pub fn estimate_tokens(text: &str) -> usize {
+    text.chars().count().div_ceil(CHARS_PER_TOKEN)
+}
+
 impl PromptItem {
+    /// A rough estimate of the number of tokens of the item
+    pub fn estimated_tokens(&self) -> usize {
+        match self {
+            PromptItem::User { content } => content
+                .items
+                .iter()
+                .map(|item| match item {
+                    ContentItem::Text { text } => estimate_tokens(text),
+                    ContentItem::Image { .. } => TOKENS_PER_IMAGE,
+                })
+                .sum(),
+            PromptItem::System { text } | PromptItem::Assistant { text } => estimate_tokens(text),
+        }
+    }
+
+    fn trimmed(&self, max_chars: usize) -> PromptItem {
+        match self {
+            PromptItem::User { content } => {
+                let items = content
+                    .items
+                    .iter()
+                    .map(|item| match item {
+                        ContentItem::Text { text } => {
+                            ContentItem::Text { text: trim_text(text, max_chars) }
+                        }
+                        item => item.clone(),
+                    })
+                    .collect();
+                PromptItem::User { content: Content { items } }
+            }
+            PromptItem::System { text } => PromptItem::System { text: trim_text(text, max_chars) },
+            PromptItem::Assistant { text } => {
+                PromptItem::Assistant { text: trim_text(text, max_chars) }
+            }
+        }
+    }
+
     fn render(&self, ctx: &RenderCtx) -> ChatCompletionRequestMessage {
         match self {
             PromptItem::User { content } => {
@@ -108,7 +695,7 @@ impl PromptItem {
                     .into()
             }
             PromptItem::System { text } => {
-                if ["o1-mini", "o1-preview"].contains(&ctx.model.as_str()) {
+                if !ctx.system_messages {
                     ChatCompletionRequestMessage::from(ChatCompletionRequestUserMessage {
                         content: ChatCompletionRequestUserMessageContent::Text(text.to_owned()),
                         ..Default::default()
@@ -130,7 +717,7 @@ impl PromptItem {
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Content {
     pub items: Vec<ContentItem>,
 }
@@ -153,19 +740,14 @@ impl From<String> for Content {
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
 pub enum ContentItem {
-    Text {
-        text: String,
-    },
-    #[allow(dead_code)]
-    Image {
-        image_base64_webp: String,
-    },
+    Text { text: String },
+    Image { image_base64_webp: String },
 }
 
 impl ContentItem {
-    #[allow(dead_code)]
     pub fn from_rgba_image(image: image::RgbaImage) -> Self {
         let mut image_webp = Vec::new();
         WebPEncoder::new_lossless(&mut image_webp)
@@ -192,35 +774,362 @@ impl ContentItem {
     }
 }
 
+/// Keep the beginning and the end of a text of more than `max_chars` characters
+fn trim_text(text: &str, max_chars: usize) -> String {
+    let char_count = text.chars().count();
+    if char_count <= max_chars {
+        return text.to_owned();
+    }
+    let head: String = text.chars().take(max_chars / 2).collect();
+    let tail: String = text.chars().skip(char_count - max_chars / 2).collect();
+    format!("{}\n[... {} characters trimmed ...]\n{}", head, char_count - 2 * (max_chars / 2), tail)
+}
+
 /// Executes an asynchronous operation with exponential backoff retry logic.
-/// The operation is retried if it fails with a rate limit error.
-async fn retry_exp<F, Fut, T>(f: F) -> Result<T, OpenAIError>
+/// The operation is retried if it fails with a transient error, see [`is_transient`], or if an
+/// attempt takes longer than the timeout. The timeout extends the maximum elapsed time, so that a
+/// timed out attempt is retried at least once.
+async fn retry_exp<F, Fut, T>(timeout: Option<Duration>, f: F) -> Result<T, PromptError>
 where
     F: Fn() -> Fut,
     Fut: std::future::Future<Output = Result<T, OpenAIError>>,
 {
-    let strategy = ExponentialBackoffBuilder::default()
-        .with_max_elapsed_time(Some(Duration::from_secs(MAX_ELAPSED_TIME_IN_SECS)))
-        .build();
+    let max_elapsed_time =
+        Duration::from_secs(MAX_ELAPSED_TIME_IN_SECS) + timeout.unwrap_or_default();
+    let strategy =
+        ExponentialBackoffBuilder::default().with_max_elapsed_time(Some(max_elapsed_time)).build();
 
     backoff::future::retry(strategy, || async {
-        let res = f().await;
-        match res {
-            Ok(value) => Ok(value),
-            Err(err) => {
-                if let OpenAIError::ApiError(api_error) = &err {
-                    if api_error.code.as_deref() == Some("rate_limit_exceeded") {
-                        log::warn!("Rate limit exceeded: {}", api_error);
-                        log::warn!("Retrying ...");
-                        Err(BackoffError::transient(err))
-                    } else {
-                        Err(BackoffError::Permanent(err))
-                    }
-                } else {
-                    Err(BackoffError::Permanent(err))
-                }
+        let result = match timeout {
+            Some(timeout) => tokio::time::timeout(timeout, f()).await.map_err(|_| timeout),
+            None => Ok(f().await),
+        };
+        match result {
+            Ok(Ok(value)) => Ok(value),
+            Ok(Err(err)) if is_transient(&err) => {
+                log::warn!("Transient error: {}", err);
+                log::warn!("Retrying ...");
+                Err(BackoffError::transient(prompt_error(err)))
+            }
+            Ok(Err(err)) => Err(BackoffError::Permanent(prompt_error(err))),
+            Err(timeout) => {
+                log::warn!("The model did not respond within {:?}", timeout);
+                log::warn!("Retrying ...");
+                Err(BackoffError::transient(PromptError::Timeout(timeout)))
             }
         }
     })
     .await
 }
+
+/// Whether the error is likely to go away when retrying
+///
+/// These are exceeded rate limits, server errors (5xx), timeouts and connection errors. Client
+/// errors, e.g. invalid requests or keys, are permanent. Server errors of the API lose their
+/// status code, so they are recognized by their type, while streams only report the status code.
+fn is_transient(err: &OpenAIError) -> bool {
+    match err {
+        OpenAIError::ApiError(api_error) => {
+            api_error.code.as_deref() == Some("rate_limit_exceeded")
+                || matches!(api_error.r#type.as_deref(), Some("server_error" | "overloaded_error"))
+        }
+        OpenAIError::Reqwest(err) => {
+            err.is_timeout()
+                || err.is_connect()
+                || err.is_request()
+                || err.is_body()
+                || err.status().is_some_and(|status| status.is_server_error())
+        }
+        OpenAIError::StreamError(message) => {
+            match message.split_once("status code: ").map(|(_, status)| status.get(..3)) {
+                Some(Some(status)) => status.starts_with('5') || status == "429",
+                // Transport errors, which are only passed on as the message of the reqwest error
+                _ => ["error sending request", "error reading a body", "operation timed out"]
+                    .iter()
+                    .any(|transport_error| message.contains(transport_error)),
+            }
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use async_openai::types::CreateChatCompletionResponse;
+    use serde_json::json;
+
+    use super::*;
+
+    fn chunk(
+        content: Option<&str>,
+        usage: Option<(u32, u32)>,
+    ) -> CreateChatCompletionStreamResponse {
+        let choices = match content {
+            Some(content) => json!([{"index": 0, "delta": {"content": content}}]),
+            None => json!([]),
+        };
+        let usage = usage.map(|(prompt_tokens, completion_tokens)| {
+            json!({
+                "prompt_tokens": prompt_tokens,
+                "completion_tokens": completion_tokens,
+                "total_tokens": prompt_tokens + completion_tokens,
+            })
+        });
+        serde_json::from_value(json!({
+            "id": "chatcmpl-1",
+            "choices": choices,
+            "created": 0,
+            "model": "gpt-4o-mini",
+            "object": "chat.completion.chunk",
+            "usage": usage,
+        }))
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_collect_stream() {
+        let chunks = vec![
+            Ok(chunk(Some("Hel"), None)),
+            Ok(chunk(Some("lo, "), None)),
+            Ok(chunk(Some("world"), None)),
+            Ok(chunk(None, Some((10, 3)))),
+        ];
+        let tokens = Mutex::new(Vec::new());
+        let on_token = |token: &str| tokens.lock().unwrap().push(token.to_owned());
+        let (choice, usage) =
+            collect_stream(futures_util::stream::iter(chunks), Some(&on_token)).await.unwrap();
+        assert_eq!(completion(choice).unwrap(), "Hello, world");
+        assert_eq!(usage.map(|usage| usage.completion_tokens), Some(3));
+        assert_eq!(*tokens.lock().unwrap(), ["Hel", "lo, ", "world"]);
+
+        let chunks = vec![Ok(chunk(Some("Hel"), None)), Err(OpenAIError::StreamError("x".into()))];
+        let result = collect_stream(futures_util::stream::iter(chunks), None).await;
+        assert!(matches!(result, Err(OpenAIError::StreamError(_))));
+    }
+
+    #[tokio::test]
+    async fn test_with_fallbacks() {
+        let prompted = Mutex::new(Vec::new());
+        let prompt = |model: &str| {
+            prompted.lock().unwrap().push(model.to_owned());
+            let result = match model {
+                "overloaded" => Err(PromptError::NoChoices),
+                "too-small" => Err(PromptError::ContextLengthExceeded),
+                _ => Ok(format!("Hello from {}", model)),
+            };
+            async move { result }
+        };
+
+        let completion = with_fallbacks(&["overloaded", "basic", "other"], prompt).await;
+        assert_eq!(completion.unwrap(), "Hello from basic");
+        assert_eq!(*prompted.lock().unwrap(), ["overloaded", "basic"]);
+
+        let completion = with_fallbacks(&["basic", "overloaded"], prompt).await;
+        assert_eq!(completion.unwrap(), "Hello from basic");
+
+        let completion = with_fallbacks(&["basic", "overloaded", "overloaded"], |model| {
+            prompt(if model == "basic" { "overloaded" } else { model })
+        })
+        .await;
+        assert!(matches!(completion, Err(PromptError::NoChoices)));
+
+        let completion = with_fallbacks(&["too-small", "basic"], prompt).await;
+        assert!(matches!(completion, Err(PromptError::ContextLengthExceeded)));
+    }
+
+    #[tokio::test]
+    async fn test_retry_exp_timeout() {
+        let attempts = AtomicUsize::new(0);
+        let result = retry_exp(Some(Duration::from_millis(50)), || {
+            let attempt = attempts.fetch_add(1, Ordering::SeqCst);
+            async move {
+                if attempt == 0 {
+                    // A request that hangs
+                    tokio::time::sleep(Duration::from_secs(60)).await;
+                }
+                Ok(attempt)
+            }
+        })
+        .await;
+        assert_eq!(result.unwrap(), 1);
+        assert_eq!(attempts.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_request_options() {
+        let client = LLMClient::new("http://localhost", "", reqwest::Client::new())
+            .with_restricted_models(vec!["o1-mini".to_owned()]);
+        let prompt = Prompt::from(vec![PromptItem::System { text: "Hi".to_owned() }]);
+        let options = PromptOptions {
+            temperature: Some(0.7),
+            max_tokens: Some(100),
+            top_p: Some(0.9),
+            stop: vec!["END".to_owned()],
+        };
+
+        let request = client.request("gpt-4o", &options, &prompt);
+        assert_eq!(request.temperature, Some(0.7));
+        assert_eq!(request.max_completion_tokens, Some(100));
+        assert_eq!(request.top_p, Some(0.9));
+        assert_eq!(request.stop, Some(Stop::StringArray(vec!["END".to_owned()])));
+
+        let request = client.request("gpt-4o", &PromptOptions::default(), &prompt);
+        assert_eq!(request.temperature, Some(0.0));
+        assert_eq!(
+            (request.max_completion_tokens, request.top_p, request.stop),
+            (None, None, None)
+        );
+
+        let request = client.request("o1-mini-2024-09-12", &options, &prompt);
+        assert_eq!((request.temperature, request.top_p, request.stop), (None, None, None));
+        assert_eq!(request.max_completion_tokens, Some(100));
+    }
+
+    #[tokio::test]
+    async fn test_trace_per_prompt() {
+        let dir = std::env::temp_dir().join(format!("minion-trace-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let prompt = Prompt::from(vec![PromptItem::System { text: "Hi".to_owned() }]);
+        let recorded = TraceEntry {
+            model: "gpt-4o-mini",
+            temperature: None,
+            prompt: &prompt,
+            messages: None,
+            completion: "Hello",
+            usage: None,
+            started_at: 0,
+            duration_ms: 0,
+        };
+        let recorded = serde_json::to_string(&recorded).unwrap();
+        std::fs::write(dir.join("recorded.jsonl"), format!("{}\n{}\n", recorded, recorded))
+            .unwrap();
+
+        let redactor = Arc::new(crate::actions::secrets::Redactor::new(Vec::new()));
+        let replay = Replay::load(dir.join("recorded.jsonl"), redactor.clone()).unwrap();
+        let client = LLMClient::new("http://localhost", "", reqwest::Client::new())
+            .with_replay(replay)
+            .with_trace(TraceFile::open(dir.join("trace.jsonl"), redactor));
+        let options = PromptOptions::default();
+        client.prompt("gpt-4o-mini", &options, &prompt).await.unwrap();
+        client.prompt("gpt-4o-mini", &options, &prompt).await.unwrap();
+
+        let trace = std::fs::read_to_string(dir.join("trace.jsonl")).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(trace.lines().count(), 2);
+        for line in trace.lines() {
+            let entry: serde_json::Value = serde_json::from_str(line).unwrap();
+            assert_eq!(entry["completion"], "Hello");
+            assert!(entry["started_at"].as_u64().unwrap() > 0);
+        }
+    }
+
+    fn api_error(r#type: &str, code: Option<&str>) -> OpenAIError {
+        OpenAIError::ApiError(async_openai::error::ApiError {
+            message: "error".to_owned(),
+            r#type: Some(r#type.to_owned()),
+            param: None,
+            code: code.map(str::to_owned),
+        })
+    }
+
+    #[test]
+    fn test_is_transient() {
+        // 503
+        assert!(is_transient(&api_error("server_error", None)));
+        let err = OpenAIError::StreamError("Invalid status code: 503 Service Unavailable".into());
+        assert!(is_transient(&err));
+        // 429
+        assert!(is_transient(&api_error("requests", Some("rate_limit_exceeded"))));
+        let err = OpenAIError::StreamError("error sending request for url (http://api)".into());
+        assert!(is_transient(&err));
+
+        // 400
+        assert!(!is_transient(&api_error("invalid_request_error", Some("invalid_value"))));
+        let err = OpenAIError::StreamError("Invalid status code: 400 Bad Request".into());
+        assert!(!is_transient(&err));
+        // 401
+        assert!(!is_transient(&api_error("invalid_request_error", Some("invalid_api_key"))));
+        assert!(!is_transient(&OpenAIError::InvalidArgument("stream".into())));
+    }
+
+    fn response(choices: serde_json::Value) -> CreateChatCompletionResponse {
+        serde_json::from_value(json!({
+            "id": "chatcmpl-1",
+            "choices": choices,
+            "created": 0,
+            "model": "gpt-4o-mini",
+            "object": "chat.completion",
+        }))
+        .unwrap()
+    }
+
+    fn first_choice(response: CreateChatCompletionResponse) -> Option<Choice> {
+        response.choices.into_iter().next().map(Choice::from)
+    }
+
+    #[test]
+    fn test_completion() {
+        let result = completion(first_choice(response(json!([]))));
+        assert!(matches!(result, Err(PromptError::NoChoices)));
+
+        let message = json!({"role": "assistant", "content": null});
+        let filtered = json!([{"index": 0, "message": message, "finish_reason": "content_filter"}]);
+        let result = completion(first_choice(response(filtered)));
+        assert!(matches!(result, Err(PromptError::ContentFiltered)));
+
+        let message = json!({"role": "assistant", "content": "Hel"});
+        let cut_off = json!([{"index": 0, "message": message, "finish_reason": "length"}]);
+        assert_eq!(completion(first_choice(response(cut_off))).unwrap(), "Hel");
+    }
+
+    #[test]
+    fn test_trim_text() {
+        assert_eq!(trim_text("short", 10), "short");
+        assert_eq!(trim_text("0123456789abcdef", 8), "0123\n[... 8 characters trimmed ...]\ncdef");
+        assert_eq!(trim_text("äöüäöüäöü", 4), "äö\n[... 5 characters trimmed ...]\nöü");
+    }
+
+    #[test]
+    fn test_prompt_trimmed_keeps_last_items() {
+        let prompt = Prompt::from(vec![
+            PromptItem::System { text: "a".repeat(100) },
+            PromptItem::Assistant { text: "b".repeat(100) },
+            PromptItem::System { text: "c".repeat(100) },
+        ]);
+        let trimmed = prompt.trimmed(10, 1);
+        let texts: Vec<usize> = trimmed
+            .items
+            .iter()
+            .map(|item| match item {
+                PromptItem::System { text } | PromptItem::Assistant { text } => text.len(),
+                PromptItem::User { .. } => unreachable!(),
+            })
+            .collect();
+        assert!(texts[0] < 100 && texts[1] < 100);
+        assert_eq!(texts[2], 100);
+    }
+
+    #[test]
+    fn test_prompt_without_images() {
+        let image = ContentItem::Image { image_base64_webp: String::new() };
+        let text = ContentItem::Text { text: "Fix the layout".to_owned() };
+        let prompt = Prompt::from(vec![PromptItem::User { content: vec![text, image].into() }]);
+        assert!(prompt.has_images());
+
+        let prompt = prompt.without_images();
+        assert!(!prompt.has_images());
+        let PromptItem::User { content } = &prompt.items[0] else { unreachable!() };
+        assert!(matches!(&content.items[1], ContentItem::Text { text } if text == IMAGE_OMITTED));
+    }
+
+    #[test]
+    fn test_is_model_of() {
+        let vision_models = vec!["gpt-4o".to_owned()];
+        assert!(is_model_of("gpt-4o", &vision_models));
+        assert!(is_model_of("gpt-4o-2024-08-06", &vision_models));
+        assert!(!is_model_of("gpt-4", &vision_models));
+        assert!(!is_model_of("gpt-4o1", &vision_models));
+    }
+}
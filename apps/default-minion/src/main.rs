@@ -1,68 +1,721 @@
-use std::{fs, path::PathBuf};
+use std::fs;
+use std::future::Future;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
 
+use agent_api::types::task::{Task, TaskComplete, TaskFailure, TaskFailureReason};
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
 use url::Url;
 
+use crate::actions::secrets::Redactor;
+use crate::api::ReportedOutcome;
+use crate::interaction_loop::HistoryConfig;
+
 mod actions;
+mod api;
+mod attachments;
 mod config;
+mod config_file;
 mod container;
 mod interaction_loop;
 mod llm;
 mod macros;
+mod output;
+mod project;
+mod prompt_cache;
+mod replay;
+mod tls;
 
 #[tokio::main]
 async fn main() {
-    env_logger::init();
+    // Print the devcontainer configuration of a workspace to check it before a run
+    let args: Vec<String> = std::env::args().collect();
+    if args.get(1).map(String::as_str) == Some("--describe-devcontainer") {
+        env_logger::init();
+        let directory = args.get(2).map(String::as_str).unwrap_or(".");
+        match devcontainer::resolve(directory) {
+            Ok(resolved) => println!("{}", serde_json::to_string_pretty(&resolved).unwrap()),
+            Err(err) => {
+                eprintln!("Failed to resolve the devcontainer configuration: {}", err);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    let config = match config::Config::load() {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+    };
+    // Secrets are redacted from everything that is logged or written to the output
+    let redactor = Arc::new(Redactor::from_config(&config));
+    init_logger(redactor.clone());
+
+    // Trust a custom CA certificate, e.g. of a corporate proxy
+    let ca_certificates = match &config.ca_cert {
+        Some(ca_cert) => {
+            tls::trust_globally(ca_cert);
+            tls::load_certificates(ca_cert)
+        }
+        None => Vec::new(),
+    };
+
+    let agent_client = api_client(&config, &redactor);
+
+    // Report an outcome that could not be reported by a previous run
+    if args.get(1).map(String::as_str) == Some("--resubmit-outcome") {
+        if let Err(err) = resubmit_outcome(agent_client, args.get(2)).await {
+            log::error!("{}", err);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    // Each task has its own workspace, container and output, only the configuration is shared
+    let http_client = tls::http_client(ca_certificates);
+    let max_tasks = config.max_tasks.get();
+    let max_concurrent_tasks = config.max_concurrent_tasks.get();
+    let shared = Arc::new(Shared { config, redactor, http_client });
+    let fetch = || agent_client.get_task();
+    let run = move |task| run_task(shared.clone(), task);
+    if let Err(err) = run_tasks(max_tasks, max_concurrent_tasks, fetch, run).await {
+        log::error!("Failed to get the task: {}", err);
+        std::process::exit(1);
+    }
+}
+
+/// What the tasks of a run share
+struct Shared {
+    config: config::Config,
+    redactor: Arc<Redactor>,
+    http_client: reqwest::Client,
+}
+
+/// Fetch up to `max_tasks` tasks and run them, at most `max_concurrent` at the same time
+///
+/// A task is only fetched once it can run. Fetching stops when it fails, which is an error only
+/// for the first task. If tasks panic, the first panic is resumed after all tasks ended.
+async fn run_tasks<T, E, F, FFut, R, RFut>(
+    max_tasks: usize,
+    max_concurrent: usize,
+    mut fetch: F,
+    run: R,
+) -> Result<usize, E>
+where
+    E: std::fmt::Display,
+    F: FnMut() -> FFut,
+    FFut: Future<Output = Result<T, E>>,
+    R: Fn(T) -> RFut,
+    RFut: Future<Output = ()> + Send + 'static,
+{
+    let semaphore = Arc::new(Semaphore::new(max_concurrent));
+    let mut running = JoinSet::new();
+    let mut fetched = 0;
+    while fetched < max_tasks {
+        let permit =
+            semaphore.clone().acquire_owned().await.expect("The semaphore is never closed");
+        let task = match fetch().await {
+            Ok(task) => task,
+            Err(err) if fetched == 0 => return Err(err),
+            Err(err) => {
+                log::info!("No further task was fetched after {} tasks: {}", fetched, err);
+                break;
+            }
+        };
+        fetched += 1;
+        let task = run(task);
+        running.spawn(async move {
+            task.await;
+            drop(permit);
+        });
+    }
+    let mut panic = None;
+    while let Some(result) = running.join_next().await {
+        if let Err(err) = result {
+            log::error!("A task panicked: {}", err);
+            panic = panic.or(err.try_into_panic().ok());
+        }
+    }
+    if let Some(panic) = panic {
+        std::panic::resume_unwind(panic);
+    }
+    Ok(fetched)
+}
+
+/// Run a task, with its own workspace, container and output, and report its outcome
+///
+/// In multi-task runs, the workspace of each task is in a folder named after its ID, so tasks of
+/// the same repository do not share it, and it is removed once the task ended.
+async fn run_task(shared: Arc<Shared>, task: Task) {
+    let config = &shared.config;
+    let workspaces_dir = match config.max_tasks.get() {
+        1 => config.workspaces_dir.clone(),
+        _ => config.workspaces_dir.join(task.id.to_string()),
+    };
+    process_task(&shared, &task, &workspaces_dir).await;
+    if config.max_tasks.get() > 1 {
+        if let Err(err) = fs::remove_dir_all(&workspaces_dir) {
+            log::warn!("Failed to remove the workspace `{}`: {}", workspaces_dir.display(), err);
+        }
+    }
+}
 
-    let config = config::Config::load();
-    let api_url = config.api_base_url.unwrap();
-    let api_token = config.api_token.unwrap();
-    let agent_client = agent_api::Client::new(api_url.clone(), api_token.clone());
-    let llm_client = llm::LLMClient::new(api_url.as_str(), &api_token);
+/// Clone the repository of a task into the directory, run the agent loop and report the outcome
+async fn process_task(shared: &Shared, task: &Task, workspaces_dir: &Path) {
+    let Shared { config, redactor, http_client } = shared;
+    let api_url = config.api_base_url.as_ref().expect("Checked when loading the config");
+    let api_token = config.api_token.as_ref().expect("Checked when loading the config");
+    let agent_client = api_client(config, redactor);
+    let output_dir = config
+        .output_dir
+        .as_ref()
+        .map(|dir| output::OutputDir::create(dir, &task.id.to_string(), redactor.clone()));
+    let agent_client = match &output_dir {
+        Some(output_dir) => agent_client.with_outcome_path(output_dir.outcome_path()),
+        // The outcomes of the tasks of a multi-task run must not overwrite each other
+        None if config.max_tasks.get() > 1 => {
+            agent_client.with_outcome_path(format!("minion-outcome-{}.json", task.id))
+        }
+        None => agent_client,
+    };
 
-    let task = agent_client.get_task().await.unwrap();
+    let mut llm_client = llm::LLMClient::new(api_url.as_str(), api_token, http_client.clone())
+        .with_restricted_models(config.restricted_models.clone())
+        .with_fallback_models(config.fallback_models.clone());
+    if config.llm_timeout > 0 {
+        llm_client = llm_client.with_request_timeout(Duration::from_secs(config.llm_timeout));
+    }
+    if let Some(output_dir) = &output_dir {
+        llm_client = llm_client.with_trace(output_dir.llm_trace());
+    }
+    if let Some(path) = &config.prompt_cache_file {
+        let cache = prompt_cache::PromptCache::on_disk(path.clone(), redactor.clone());
+        llm_client = llm_client.with_cache(cache);
+    } else if config.prompt_cache {
+        llm_client = llm_client.with_cache(prompt_cache::PromptCache::in_memory());
+    }
+    if let Some(dir) = &config.transcript_dir {
+        fs::create_dir_all(dir).expect("Failed to create transcript directory");
+        let path = dir.join(format!("{}.jsonl", task.id));
+        llm_client = llm_client.with_trace(output::TraceFile::open(path, redactor.clone()));
+    }
+    if let Some(path) = &config.replay_trace {
+        let replay = replay::Replay::load(path, redactor.clone()).expect("Failed to load trace");
+        log::info!("Replaying the trace `{}` instead of prompting the models", path.display());
+        llm_client = llm_client.with_replay(replay);
+    }
+    let attachments = attachments::load_images(http_client, &task.description).await;
 
-    let workspaces_dir = PathBuf::from("./workspaces");
-    fs::create_dir(&workspaces_dir).unwrap();
     let workspace_dir_name = workspace_folder_name(&task.git_repo_url);
-    let workspace_dir = workspaces_dir.join(&workspace_dir_name);
+    let workspace_dir = match prepare_workspace(workspaces_dir, &workspace_dir_name) {
+        Ok(workspace_dir) => workspace_dir,
+        Err(err) => {
+            log::error!("Failed to prepare the workspace: {}", err);
+            let info = TaskFailure {
+                reason: Some(TaskFailureReason::TechnicalIssues),
+                description: format!("Failed to prepare the workspace: {}", err),
+            };
+            agent_client.report(ReportedOutcome::Failure(info)).await;
+            return;
+        }
+    };
 
-    let mut git_url = task.git_repo_url.clone();
-    git_url.set_username("x-access-token").unwrap();
-    git_url.set_password(Some(api_token.as_str())).unwrap();
+    let git_credentials = match &config.git_credential_helper {
+        Some(helper) => actions::git::Credentials::Helper(helper.clone()),
+        None => actions::git::Credentials::Token(api_token.clone()),
+    };
 
     // Clone (and configure) the repository
     let git_repo = actions::git::Repo::clone(
         &workspace_dir,
-        &git_url,
+        &task.git_repo_url,
         &task.git_branch,
         &task.git_user_name,
         &task.git_user_email,
+        git_credentials,
+        config.clone_depth,
     );
+    let mut git_repo = match git_repo {
+        Ok(git_repo) => git_repo,
+        Err(err) => {
+            log::error!("Failed to clone the repository: {}", err);
+            let info = TaskFailure {
+                reason: Some(TaskFailureReason::TechnicalIssues),
+                description: redactor.redact(&format!("Failed to clone the repository: {}", err)),
+            };
+            agent_client.report(ReportedOutcome::Failure(info)).await;
+            return;
+        }
+    };
+
+    // The work is pushed to a separate branch, so the task branch is left untouched
+    if !config.push_to_task_branch {
+        let name = config
+            .branch_name
+            .clone()
+            .unwrap_or_else(|| format!("{}{}", config.branch_prefix, task.id));
+        match git_repo.checkout_new_branch(&name) {
+            Ok(branch) => {
+                log::info!("Pushing to branch `{}` instead of `{}`", branch, task.git_branch);
+            }
+            Err(err) => {
+                log::error!("Failed to create the branch `{}`: {}", name, err);
+                let info = TaskFailure {
+                    reason: Some(TaskFailureReason::TechnicalIssues),
+                    description: redactor
+                        .redact(&format!("Failed to create the branch `{}`: {}", name, err)),
+                };
+                agent_client.report(ReportedOutcome::Failure(info)).await;
+                return;
+            }
+        }
+    }
+
+    let project = project::detect_project(&workspace_dir);
+    log::info!("Detected project kind: {:?}", project);
+
+    let container = container::Container::start(
+        &workspace_dir,
+        &workspace_dir_name,
+        config.workspace_mount,
+        &config.volumes,
+        &config.scratch_dir,
+        container::ResourceLimits {
+            memory_mb: config.memory_limit_mb,
+            cpus: config.cpus,
+            pids: config.pids_limit,
+        },
+    )
+    .await;
+    let container = match container {
+        Ok(container) => {
+            log::info!("Started container `{}`", container.name());
+            container
+        }
+        Err(err) => {
+            log::error!("Failed to start container: {}", err);
+            let info = TaskFailure {
+                reason: Some(TaskFailureReason::TechnicalIssues),
+                description: format!("Failed to start the development container: {}", err),
+            };
+            let output_dir = output_dir.as_ref();
+            write_repro_bundle(output_dir, task, &workspace_dir, &git_repo, None, &info).await;
+            agent_client.report(ReportedOutcome::Failure(info)).await;
+            return;
+        }
+    };
 
-    let container = container::Container::start(&workspace_dir, &workspace_dir_name).await;
+    // The final commit contains all changes, so they must only be made by the agent
+    let changed_paths = match git_repo.changed_paths() {
+        Ok(changed_paths) => changed_paths,
+        Err(err) => {
+            log::error!("Failed to check the workspace for changes: {}", err);
+            let info = TaskFailure {
+                reason: Some(TaskFailureReason::TechnicalIssues),
+                description: format!("Failed to check the workspace for changes: {}", err),
+            };
+            let output_dir = output_dir.as_ref();
+            let container = Some(&container);
+            write_repro_bundle(output_dir, task, &workspace_dir, &git_repo, container, &info).await;
+            agent_client.report(ReportedOutcome::Failure(info)).await;
+            return;
+        }
+    };
+    if !changed_paths.is_empty() && config.dirty_workspace != actions::git::DirtyWorkspace::Ignore {
+        log::warn!("The workspace has changes before the start: {}", changed_paths.join(", "));
+        if config.dirty_workspace == actions::git::DirtyWorkspace::Fail {
+            let info = TaskFailure {
+                reason: Some(TaskFailureReason::TechnicalIssues),
+                description: format!(
+                    "The workspace has changes that were not made by the agent: {}",
+                    changed_paths.join(", ")
+                ),
+            };
+            let output_dir = output_dir.as_ref();
+            let container = Some(&container);
+            write_repro_bundle(output_dir, task, &workspace_dir, &git_repo, container, &info).await;
+            agent_client.report(ReportedOutcome::Failure(info)).await;
+            return;
+        }
+    }
 
-    // Change the current directory to the project directory
-    // The interaction loop will expect to be in the project directory
-    std::env::set_current_dir(workspace_dir).expect("Failed to change current working directory");
+    // For reviews, the changes of the task branch are shown to the model
+    let review_diff = match &config.review_base {
+        Some(base) => match git_repo.diff_since(base) {
+            Ok(diff) => Some(diff),
+            Err(err) => {
+                log::error!("Failed to diff against `{}`: {}", base, err);
+                let info = TaskFailure {
+                    reason: Some(TaskFailureReason::TaskIssues),
+                    description: format!("Failed to determine the changes to review: {}", err),
+                };
+                let output_dir = output_dir.as_ref();
+                let container = Some(&container);
+                write_repro_bundle(output_dir, task, &workspace_dir, &git_repo, container, &info)
+                    .await;
+                agent_client.report(ReportedOutcome::Failure(info)).await;
+                return;
+            }
+        },
+        None => None,
+    };
 
     // Run the agent loop
-    let outcome = interaction_loop::run(&llm_client, &container, &task).await;
+    let instructions = interaction_loop::RepoInstructions::load(
+        &workspace_dir,
+        &config.agent_instructions,
+        config.agent_instructions_max_bytes,
+    );
+    let intro = interaction_loop::intro(
+        task,
+        project,
+        attachments,
+        review_diff.as_deref(),
+        instructions.as_ref(),
+        config,
+        container.is_root(),
+    );
+    let history = match resumable_history(output_dir.as_ref(), config) {
+        Some(history) => {
+            log::info!("Resuming the task after {} actions", history.actions.len());
+            history.resumed(intro)
+        }
+        None => interaction_loop::History::new(intro, HistoryConfig::from_config(config)),
+    };
+    let workspace =
+        interaction_loop::Workspace { container: &container, git_repo: &git_repo, project };
+    let outcome = interaction_loop::run(
+        &llm_client,
+        workspace,
+        task,
+        config,
+        redactor,
+        history,
+        output_dir.as_ref(),
+    )
+    .await;
+
+    // Without the changes on the host, there is nothing to push
+    let outcome = match (container.sync_workspace_to_host().await, outcome) {
+        (Err(err), interaction_loop::TaskOutcome::Complete(_)) => {
+            log::error!("Failed to sync the workspace to the host: {}", err);
+            interaction_loop::TaskOutcome::Failure(TaskFailure {
+                reason: Some(TaskFailureReason::TechnicalIssues),
+                description: format!("Failed to sync the workspace to the host: {}", err),
+            })
+        }
+        (Err(err), outcome) => {
+            log::warn!("Failed to sync the workspace to the host: {}", err);
+            outcome
+        }
+        (Ok(()), outcome) => outcome,
+    };
+
+    if let Some(output_dir) = &output_dir {
+        output_dir.write_json(&output_dir.usage_path(), &llm_client.usage());
+        if let Some(divergences) = llm_client.replay_divergences() {
+            output_dir.write_json(&output_dir.divergences_path(), &divergences);
+        }
+        match git_repo.diff() {
+            Ok(diff) => output_dir.write(&output_dir.diff_path(), diff.as_bytes()),
+            Err(err) => log::warn!("Failed to create diff: {}", err),
+        }
+    }
+
+    // Replays only reproduce a previous run, so nothing is pushed
+    let outcome = match outcome {
+        interaction_loop::TaskOutcome::Complete(info) if config.replay_trace.is_none() => {
+            let message = config
+                .commit_message
+                .clone()
+                .unwrap_or_else(|| actions::git::commit_message(&info.description));
+            push_changes(&git_repo, info, &message, redactor)
+        }
+        outcome => outcome,
+    };
 
     // Handle the outcome
     match outcome {
         interaction_loop::TaskOutcome::Complete(info) => {
-            git_repo.commit_and_push();
-            agent_client.complete_task(info).await.unwrap();
+            agent_client.report(ReportedOutcome::Complete(info)).await;
+        }
+        // Reviews do not change the repository, so there is nothing to push
+        interaction_loop::TaskOutcome::Reviewed { info, comments } => {
+            if let Some(output_dir) = &output_dir {
+                output_dir.write_json(&output_dir.review_path(), &comments);
+            }
+            agent_client.report(ReportedOutcome::Complete(info)).await;
         }
         interaction_loop::TaskOutcome::Failure(info) => {
-            agent_client.fail_task(info).await.unwrap();
+            let output_dir = output_dir.as_ref();
+            let container = Some(&container);
+            write_repro_bundle(output_dir, task, &workspace_dir, &git_repo, container, &info).await;
+            agent_client.report(ReportedOutcome::Failure(info)).await;
+        }
+    }
+
+    if let Some(command) = &config.cleanup_command {
+        run_cleanup_command(&container, command, config.cleanup_timeout).await;
+    }
+    if let Err(err) = container.stop().await {
+        log::warn!("Failed to remove the container: {}", err);
+    }
+}
+
+/// Report the outcome in the file, which is removed once it was reported
+async fn resubmit_outcome(
+    agent_client: api::ApiClient,
+    path: Option<&String>,
+) -> Result<(), String> {
+    let path = path.ok_or("Missing path of the outcome to re-submit")?;
+    let outcome = fs::read_to_string(path)
+        .map_err(|err| format!("Failed to read the outcome `{}`: {}", path, err))?;
+    let outcome = serde_json::from_str(&outcome)
+        .map_err(|err| format!("Failed to parse the outcome `{}`: {}", path, err))?;
+    if !agent_client.with_outcome_path(path).report(outcome).await {
+        return Err(format!("Failed to re-submit the outcome `{}`", path));
+    }
+    fs::remove_file(path).map_err(|err| {
+        format!("The outcome was re-submitted, but removing `{}` failed: {}", path, err)
+    })
+}
+
+/// The agent API client, with the timeouts and retries of the configuration
+fn api_client(config: &config::Config, redactor: &Arc<Redactor>) -> api::ApiClient {
+    let api_url = config.api_base_url.clone().expect("Checked when loading the config");
+    let api_token = config.api_token.clone().expect("Checked when loading the config");
+    api::ApiClient::new(
+        agent_api::Client::new(api_url, api_token),
+        Duration::from_secs(config.api_timeout),
+        Duration::from_secs(config.api_max_retry_time),
+        redactor.clone(),
+    )
+}
+
+/// The history of a previous run of the task that was interrupted, if it is to be resumed
+///
+/// The transcript is written after every action and the stats when the task ends, so a transcript
+/// without stats is of an interrupted run.
+fn resumable_history(
+    output_dir: Option<&output::OutputDir>,
+    config: &config::Config,
+) -> Option<interaction_loop::History> {
+    let output_dir = output_dir.filter(|_| config.resume && config.replay_trace.is_none())?;
+    let path = output_dir.transcript_path();
+    if !path.is_file() || output_dir.stats_path().exists() {
+        return None;
+    }
+    interaction_loop::History::load(&path, HistoryConfig::from_config(config))
+        .inspect_err(|err| log::warn!("Failed to load `{}` to resume: {}", path.display(), err))
+        .ok()
+}
+
+/// Commit and push the changes of a completed task, which fails if they can not be pushed
+///
+/// Without changes, nothing is committed, as an empty commit would only clutter the history, e.g.
+/// of investigation tasks.
+fn push_changes(
+    git_repo: &actions::git::Repo,
+    mut info: TaskComplete,
+    message: &str,
+    redactor: &Redactor,
+) -> interaction_loop::TaskOutcome {
+    let has_changes = match git_repo.has_changes() {
+        Ok(has_changes) => has_changes,
+        Err(err) => {
+            log::error!("Failed to check the workspace for changes: {}", err);
+            return interaction_loop::TaskOutcome::Failure(TaskFailure {
+                reason: Some(TaskFailureReason::TechnicalIssues),
+                description: format!("Failed to check the workspace for changes: {}", err),
+            });
+        }
+    };
+    if !has_changes {
+        log::info!("No changes were made, nothing is committed or pushed");
+    } else if let Err(err) = git_repo.commit_and_push(&redactor.redact(message)) {
+        log::error!("Failed to push the changes: {}", err);
+        return interaction_loop::TaskOutcome::Failure(TaskFailure {
+            reason: Some(TaskFailureReason::TechnicalIssues),
+            description: redactor.redact(&format!("Failed to push the changes: {}", err)),
+        });
+    } else if git_repo.branch() != git_repo.base_branch() {
+        let pushed = format!("The changes were pushed to branch `{}`.", git_repo.branch());
+        info.description = format!("{}\n\n{}", info.description, pushed);
+    }
+    interaction_loop::TaskOutcome::Complete(info)
+}
+
+/// Run the cleanup command before the container is removed, e.g. to stop services
+///
+/// This is best-effort, as the outcome of the task has already been reported.
+async fn run_cleanup_command(container: &container::Container, command: &str, timeout: u64) {
+    log::info!("Running cleanup command: {}", command);
+    let mut ignore_chunk = |_: container::OutputChunk| {};
+    let script = container.run_script_streaming(command, &mut ignore_chunk);
+    match tokio::time::timeout(Duration::from_secs(timeout), script).await {
+        Ok(Ok(output)) => {
+            for line in output.stdout.lines().chain(output.stderr.lines()) {
+                log::info!("[cleanup] {}", line);
+            }
+            if output.exit_code != 0 {
+                log::warn!("Cleanup command failed with exit status {}", output.exit_code);
+            }
         }
+        Ok(Err(err)) => log::warn!("Failed to run the cleanup command: {}", err),
+        Err(_) => log::warn!("Cleanup command timed out after {}s", timeout),
     }
 }
 
+/// Collect everything needed to reproduce a failed task in the output directory, if configured
+///
+/// The bundle contains the task, the failure, the devcontainer configuration and image, the diff
+/// and the transcript.
+async fn write_repro_bundle(
+    output_dir: Option<&output::OutputDir>,
+    task: &Task,
+    workspace_dir: &Path,
+    git_repo: &actions::git::Repo,
+    container: Option<&container::Container>,
+    failure: &TaskFailure,
+) {
+    let Some(output_dir) = output_dir else {
+        return;
+    };
+    let mut bundle = output::ReproBundle::default();
+    bundle.add_json("task.json", task);
+    bundle.add_json("failure.json", failure);
+    match devcontainer::resolve(workspace_dir) {
+        Ok(resolved) => bundle.add_json("devcontainer.json", &resolved),
+        Err(err) => bundle.add("devcontainer.txt", format!("Failed to resolve: {}", err)),
+    }
+    if let Some(container) = container {
+        match container.image_id().await {
+            Ok(image_id) => bundle.add("image-id.txt", image_id),
+            Err(err) => log::warn!("Failed to determine the image of the container: {}", err),
+        }
+    }
+    match git_repo.diff() {
+        Ok(diff) => bundle.add("diff.patch", diff),
+        Err(err) => log::warn!("Failed to create diff: {}", err),
+    }
+    output_dir.write_repro_bundle(bundle);
+}
+
+/// Log to stderr like `env_logger` does by default, but with secrets redacted
+fn init_logger(redactor: Arc<Redactor>) {
+    env_logger::Builder::from_default_env()
+        .format(move |buf, record| {
+            let message = redactor.redact_silently(&record.args().to_string());
+            let timestamp = buf.timestamp();
+            writeln!(buf, "[{} {:<5} {}] {}", timestamp, record.level(), record.target(), message)
+        })
+        .init();
+}
+
+/// The directory to clone the repository into, without the workspace of a previous run
+fn prepare_workspace(workspaces_dir: &Path, name: &str) -> io::Result<PathBuf> {
+    fs::create_dir_all(workspaces_dir)?;
+    let workspace_dir = workspaces_dir.join(name);
+    if workspace_dir.exists() {
+        log::warn!("Removing the workspace `{}` of a previous run", workspace_dir.display());
+        fs::remove_dir_all(&workspace_dir)?;
+    }
+    Ok(workspace_dir)
+}
+
 fn workspace_folder_name(repo_url: &Url) -> String {
     let path = repo_url.path();
     let parts: Vec<&str> = path.split('/').collect();
     let repo_name = parts.last().unwrap_or(&"project");
     repo_name.replace(".git", "")
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Mutex;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_resubmit_invalid_outcome() {
+        let client = || {
+            let url = Url::parse("http://localhost:8080").unwrap();
+            let redactor = Arc::new(Redactor::new(Vec::new()));
+            let timeout = Duration::from_secs(1);
+            api::ApiClient::new(
+                agent_api::Client::new(url, "token".to_owned()),
+                timeout,
+                timeout,
+                redactor,
+            )
+        };
+        let err = resubmit_outcome(client(), None).await.unwrap_err();
+        assert_eq!(err, "Missing path of the outcome to re-submit");
+
+        let path =
+            std::env::temp_dir().join(format!("minion-resubmit-{}.json", std::process::id()));
+        fs::write(&path, "not json").unwrap();
+        let path = path.to_str().unwrap().to_owned();
+        let err = resubmit_outcome(client(), Some(&path)).await.unwrap_err();
+        fs::remove_file(&path).unwrap();
+        assert!(err.starts_with("Failed to parse the outcome"));
+    }
+
+    #[tokio::test]
+    async fn test_run_tasks() {
+        let running = Arc::new(AtomicUsize::new(0));
+        let max_running = Arc::new(AtomicUsize::new(0));
+        let done = Arc::new(Mutex::new(Vec::new()));
+        let mut tasks = 0..;
+        let fetch = || std::future::ready(Ok::<_, String>(tasks.next().unwrap()));
+        let run = |task| {
+            let (running, max_running, done) = (running.clone(), max_running.clone(), done.clone());
+            async move {
+                let now_running = running.fetch_add(1, Ordering::SeqCst) + 1;
+                max_running.fetch_max(now_running, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(20)).await;
+                running.fetch_sub(1, Ordering::SeqCst);
+                done.lock().unwrap().push(task);
+            }
+        };
+        assert_eq!(run_tasks(5, 2, fetch, run).await, Ok(5));
+        assert_eq!(max_running.load(Ordering::SeqCst), 2);
+        let mut done = done.lock().unwrap().clone();
+        done.sort();
+        assert_eq!(done, [0, 1, 2, 3, 4]);
+    }
+
+    #[tokio::test]
+    async fn test_run_tasks_until_fetching_fails() {
+        let mut tasks = vec![Ok(1), Ok(2), Err("No task")].into_iter();
+        let fetch = || std::future::ready(tasks.next().unwrap());
+        assert_eq!(run_tasks(5, 2, fetch, |_| async {}).await, Ok(2));
+
+        // Failing to fetch the first task is an error
+        let fetch = || std::future::ready(Err::<u32, _>("No task"));
+        assert_eq!(run_tasks(5, 2, fetch, |_| async {}).await, Err("No task"));
+    }
+
+    #[test]
+    fn test_prepare_workspace() {
+        let dir = std::env::temp_dir().join(format!("minion-workspaces-{}", std::process::id()));
+        let workspace_dir = prepare_workspace(&dir, "repo").unwrap();
+        assert_eq!(workspace_dir, dir.join("repo"));
+        assert!(dir.is_dir() && !workspace_dir.exists());
+
+        // The workspace of a previous run is removed, so the repository can be cloned again
+        fs::create_dir(&workspace_dir).unwrap();
+        fs::write(workspace_dir.join("file.txt"), "previous run").unwrap();
+        let again = prepare_workspace(&dir, "repo").unwrap();
+        let exists = again.exists();
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(!exists);
+    }
+}
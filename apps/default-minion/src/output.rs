@@ -0,0 +1,173 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use serde::Serialize;
+
+use crate::actions::secrets::Redactor;
+
+/// The output directory of a run, which collects everything about a task
+///
+/// ```text
+/// <output dir>/<task id>/
+///     transcript.json   the interaction history, updated after every action to resume from
+///     llm-trace.jsonl   every prompt with its completion and usage, one per line
+///     diff.patch        the changes to the repository
+///     usage.json        the token usage per model
+///     review.json       the review comments, for reviews
+///     outcome.json      the outcome, only if it could not be reported
+///     repro.tar         everything needed to reproduce a failure, for failed tasks
+/// ```
+///
+/// Everything written to the output directory is redacted.
+pub struct OutputDir {
+    dir: PathBuf,
+    redactor: Arc<Redactor>,
+}
+
+impl OutputDir {
+    pub fn create<P: AsRef<Path>>(base_dir: P, task_id: &str, redactor: Arc<Redactor>) -> Self {
+        let dir = base_dir.as_ref().join(task_id);
+        fs::create_dir_all(&dir).expect("Failed to create output directory");
+        log::info!("Writing run output to `{}`", dir.display());
+        Self { dir, redactor }
+    }
+
+    pub fn transcript_path(&self) -> PathBuf {
+        self.dir.join("transcript.json")
+    }
+
+    /// The LLM trace, which is appended to during the run
+    pub fn llm_trace(&self) -> TraceFile {
+        TraceFile::open(self.dir.join("llm-trace.jsonl"), self.redactor.clone())
+    }
+
+    pub fn diff_path(&self) -> PathBuf {
+        self.dir.join("diff.patch")
+    }
+
+    pub fn usage_path(&self) -> PathBuf {
+        self.dir.join("usage.json")
+    }
+
+    pub fn stats_path(&self) -> PathBuf {
+        self.dir.join("stats.json")
+    }
+
+    pub fn divergences_path(&self) -> PathBuf {
+        self.dir.join("divergences.json")
+    }
+
+    pub fn review_path(&self) -> PathBuf {
+        self.dir.join("review.json")
+    }
+
+    pub fn outcome_path(&self) -> PathBuf {
+        self.dir.join("outcome.json")
+    }
+
+    pub fn repro_path(&self) -> PathBuf {
+        self.dir.join("repro.tar")
+    }
+
+    /// Write a reproduction bundle, with every file in it redacted
+    pub fn write_repro_bundle(&self, bundle: ReproBundle) {
+        let mut files = bundle.files;
+        // The transcript is updated during the run, so it is read back from the output
+        if let Ok(transcript) = fs::read(self.transcript_path()) {
+            files.push(("transcript.json".to_owned(), transcript));
+        }
+
+        let mut tar_buffer = Vec::new();
+        {
+            let mut tar_builder = tar::Builder::new(&mut tar_buffer);
+            for (name, content) in files {
+                let content = match String::from_utf8(content) {
+                    Ok(text) => self.redactor.redact_silently(&text).into_bytes(),
+                    Err(err) => err.into_bytes(),
+                };
+                let mut header = tar::Header::new_gnu();
+                header.set_size(content.len() as u64);
+                header.set_mode(0o644);
+                header.set_cksum();
+                tar_builder
+                    .append_data(&mut header, &name, content.as_slice())
+                    .expect("Failed to append data to tar archive");
+            }
+            tar_builder.finish().expect("Failed to finish tar archive");
+        }
+        // The content is redacted already, redacting the archive itself could corrupt it
+        let path = self.repro_path();
+        match fs::write(&path, tar_buffer) {
+            Ok(()) => log::info!("Wrote reproduction bundle to `{}`", path.display()),
+            Err(err) => log::warn!("Failed to write `{}`: {}", path.display(), err),
+        }
+    }
+
+    /// Write a JSON file, logging instead of failing, as the output is only informational
+    pub fn write_json<T: Serialize>(&self, path: &Path, value: &T) {
+        let json = serde_json::to_string_pretty(value).expect("Failed to serialize output");
+        self.write(path, json.as_bytes());
+    }
+
+    /// Write a file, logging instead of failing, as the output is only informational
+    pub fn write(&self, path: &Path, content: &[u8]) {
+        if let Err(err) = write_redacted(&self.redactor, path, content) {
+            log::warn!("Failed to write `{}`: {}", path.display(), err);
+        }
+    }
+}
+
+/// The files of a reproduction bundle, see [`OutputDir::write_repro_bundle`]
+#[derive(Default)]
+pub struct ReproBundle {
+    files: Vec<(String, Vec<u8>)>,
+}
+
+impl ReproBundle {
+    pub fn add(&mut self, name: &str, content: impl Into<Vec<u8>>) {
+        self.files.push((name.to_owned(), content.into()));
+    }
+
+    pub fn add_json<T: Serialize>(&mut self, name: &str, value: &T) {
+        let json = serde_json::to_string_pretty(value).expect("Failed to serialize output");
+        self.add(name, json);
+    }
+}
+
+/// A JSON Lines file that is appended to, with every line redacted
+pub struct TraceFile {
+    file: Mutex<File>,
+    redactor: Arc<Redactor>,
+}
+
+impl TraceFile {
+    pub fn open(path: PathBuf, redactor: Arc<Redactor>) -> Self {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .expect("Failed to open trace file");
+        Self { file: Mutex::new(file), redactor }
+    }
+
+    pub fn append<T: Serialize>(&self, entry: &T) {
+        let line = serde_json::to_string(entry).expect("Failed to serialize trace entry");
+        let line = self.redactor.redact_silently(&line);
+        if let Err(err) = writeln!(self.file.lock().unwrap(), "{}", line) {
+            log::warn!("Failed to write trace: {}", err);
+        }
+    }
+}
+
+/// Write a file with all secrets redacted, text is redacted unless it is not valid UTF-8
+///
+/// Every file that is written to the output, or otherwise persisted, must be written with this
+/// function or a [`TraceFile`].
+pub fn write_redacted(redactor: &Redactor, path: &Path, content: &[u8]) -> io::Result<()> {
+    match std::str::from_utf8(content) {
+        Ok(text) => fs::write(path, redactor.redact_silently(text)),
+        Err(_) => fs::write(path, content),
+    }
+}
@@ -0,0 +1,111 @@
+use std::path::Path;
+
+/// The language and build system of a project
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProjectKind {
+    Rust,
+    Node,
+    Python,
+    Go,
+    Maven,
+}
+
+impl ProjectKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ProjectKind::Rust => "Rust (Cargo)",
+            ProjectKind::Node => "JavaScript/TypeScript (npm)",
+            ProjectKind::Python => "Python",
+            ProjectKind::Go => "Go",
+            ProjectKind::Maven => "Java (Maven)",
+        }
+    }
+
+    pub fn build_command(&self) -> Option<&'static str> {
+        match self {
+            ProjectKind::Rust => Some("cargo build"),
+            ProjectKind::Node => Some("npm run build"),
+            ProjectKind::Python => None,
+            ProjectKind::Go => Some("go build ./..."),
+            ProjectKind::Maven => Some("mvn compile"),
+        }
+    }
+
+    pub fn test_command(&self) -> Option<&'static str> {
+        match self {
+            ProjectKind::Rust => Some("cargo test"),
+            ProjectKind::Node => Some("npm test"),
+            ProjectKind::Python => Some("pytest"),
+            ProjectKind::Go => Some("go test ./..."),
+            ProjectKind::Maven => Some("mvn test"),
+        }
+    }
+
+    pub fn format_command(&self) -> Option<&'static str> {
+        match self {
+            ProjectKind::Rust => Some("cargo fmt"),
+            ProjectKind::Node => None,
+            ProjectKind::Python => None,
+            ProjectKind::Go => Some("gofmt -w ."),
+            ProjectKind::Maven => None,
+        }
+    }
+
+    /// A description of the project for the prompt
+    pub fn describe(&self) -> String {
+        let mut description = format!("The project is a {} project.", self.name());
+        let commands = [
+            ("build", self.build_command()),
+            ("test", self.test_command()),
+            ("format", self.format_command()),
+        ];
+        for (purpose, command) in commands {
+            if let Some(command) = command {
+                description
+                    .push_str(&format!("\nThe usual command to {} it is `{}`.", purpose, command));
+            }
+        }
+        description
+    }
+}
+
+/// Marker files of the supported project kinds, in order of precedence
+const MARKERS: &[(&str, ProjectKind)] = &[
+    ("Cargo.toml", ProjectKind::Rust),
+    ("package.json", ProjectKind::Node),
+    ("pyproject.toml", ProjectKind::Python),
+    ("setup.py", ProjectKind::Python),
+    ("go.mod", ProjectKind::Go),
+    ("pom.xml", ProjectKind::Maven),
+];
+
+/// Detect the kind of the project in the specified directory by looking for marker files
+pub fn detect_project<P: AsRef<Path>>(directory: P) -> Option<ProjectKind> {
+    let directory = directory.as_ref();
+    MARKERS.iter().find(|(marker, _)| directory.join(marker).is_file()).map(|(_, kind)| *kind)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use rand::{distributions::Alphanumeric, Rng};
+
+    use super::*;
+
+    #[test]
+    fn test_detect_project() {
+        let random_str: String =
+            rand::thread_rng().sample_iter(&Alphanumeric).take(16).map(char::from).collect();
+        let directory = std::env::temp_dir().join(format!("minion-test-{}", random_str));
+        fs::create_dir(&directory).unwrap();
+
+        assert_eq!(detect_project(&directory), None);
+        fs::write(directory.join("go.mod"), "module example.com/foo\n").unwrap();
+        assert_eq!(detect_project(&directory), Some(ProjectKind::Go));
+        fs::write(directory.join("Cargo.toml"), "[package]\n").unwrap();
+        assert_eq!(detect_project(&directory), Some(ProjectKind::Rust));
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
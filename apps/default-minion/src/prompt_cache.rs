@@ -0,0 +1,149 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::fs;
+use std::future::Future;
+use std::hash::{Hash, Hasher};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use async_openai::types::{CompletionUsage, CreateChatCompletionRequest};
+use serde::{Deserialize, Serialize};
+
+use crate::actions::secrets::Redactor;
+use crate::llm::PromptError;
+use crate::output::TraceFile;
+
+/// A cached completion, a line of the cache file
+#[derive(Deserialize, Serialize)]
+struct CacheEntry {
+    key: String,
+    completion: String,
+}
+
+/// Completions of previous prompts, returned for identical prompts instead of prompting again
+///
+/// Prompts are identical if they have the same model, messages, temperature and response format
+/// (e.g. a JSON schema). Only prompts with a temperature of 0 (or none) are cached, as other
+/// completions are meant to vary.
+pub struct PromptCache {
+    completions: Mutex<HashMap<String, String>>,
+    /// The file new completions are appended to, to reuse them in later runs
+    file: Option<TraceFile>,
+}
+
+impl PromptCache {
+    /// A cache that is kept in memory for the run
+    pub fn in_memory() -> Self {
+        Self { completions: Mutex::default(), file: None }
+    }
+
+    /// A cache that is loaded from and appended to a JSON Lines file, which is redacted
+    pub fn on_disk(path: PathBuf, redactor: Arc<Redactor>) -> Self {
+        let completions = fs::read_to_string(&path)
+            .unwrap_or_default()
+            .lines()
+            .filter_map(|line| serde_json::from_str::<CacheEntry>(line).ok())
+            .map(|entry| (entry.key, entry.completion))
+            .collect::<HashMap<_, _>>();
+        log::info!("Loaded {} cached completions from `{}`", completions.len(), path.display());
+        Self { completions: Mutex::new(completions), file: Some(TraceFile::open(path, redactor)) }
+    }
+
+    /// The cached completion of the request, or the completion and usage of prompting it
+    ///
+    /// Cached completions have no usage, as no tokens were used.
+    pub async fn get_or_prompt<F, Fut>(
+        &self,
+        request: &CreateChatCompletionRequest,
+        prompt: F,
+    ) -> Result<(String, Option<CompletionUsage>), PromptError>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<(String, Option<CompletionUsage>), PromptError>>,
+    {
+        if request.temperature.is_some_and(|temperature| temperature != 0.0) {
+            return prompt().await;
+        }
+        let key = cache_key(request);
+        if let Some(completion) = self.completions.lock().unwrap().get(&key) {
+            log::info!("Using the cached completion of an identical prompt");
+            return Ok((completion.clone(), None));
+        }
+
+        let (completion, usage) = prompt().await?;
+        if let Some(file) = &self.file {
+            file.append(&CacheEntry { key: key.clone(), completion: completion.clone() });
+        }
+        self.completions.lock().unwrap().insert(key, completion.clone());
+        Ok((completion, usage))
+    }
+}
+
+/// A hash of the model, messages, temperature and response format of the request
+fn cache_key(request: &CreateChatCompletionRequest) -> String {
+    let messages = serde_json::to_string(&request.messages).expect("Failed to serialize messages");
+    let response_format =
+        serde_json::to_string(&request.response_format).expect("Failed to serialize format");
+    let mut hasher = DefaultHasher::new();
+    request.model.hash(&mut hasher);
+    messages.hash(&mut hasher);
+    request.temperature.map(f32::to_bits).hash(&mut hasher);
+    response_format.hash(&mut hasher);
+    format!("{:016x}", hasher.finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use async_openai::types::{ChatCompletionRequestMessage, ChatCompletionRequestUserMessage};
+
+    use super::*;
+
+    fn request(text: &str, temperature: Option<f32>) -> CreateChatCompletionRequest {
+        let message = ChatCompletionRequestUserMessage { content: text.into(), name: None };
+        CreateChatCompletionRequest {
+            model: "gpt-4o-mini".to_owned(),
+            messages: vec![ChatCompletionRequestMessage::User(message)],
+            temperature,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_get_or_prompt() {
+        let cache = PromptCache::in_memory();
+        let calls = AtomicUsize::new(0);
+        let prompt = || async {
+            let call = calls.fetch_add(1, Ordering::SeqCst);
+            Ok((format!("completion {}", call), None))
+        };
+
+        let (first, _) = cache.get_or_prompt(&request("Hi", Some(0.0)), prompt).await.unwrap();
+        let (second, _) = cache.get_or_prompt(&request("Hi", Some(0.0)), prompt).await.unwrap();
+        assert_eq!(first, "completion 0");
+        assert_eq!(second, "completion 0");
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        cache.get_or_prompt(&request("Hello", Some(0.0)), prompt).await.unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+        // Completions of other temperatures are meant to vary
+        cache.get_or_prompt(&request("Hi", Some(0.7)), prompt).await.unwrap();
+        cache.get_or_prompt(&request("Hi", Some(0.7)), prompt).await.unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 4);
+    }
+
+    #[tokio::test]
+    async fn test_on_disk() {
+        let path = std::env::temp_dir().join(format!("minion-cache-{}.jsonl", std::process::id()));
+        let redactor = Arc::new(Redactor::new(Vec::new()));
+        let prompt = || async { Ok(("Hello".to_owned(), None)) };
+        let cache = PromptCache::on_disk(path.clone(), redactor.clone());
+        cache.get_or_prompt(&request("Hi", None), prompt).await.unwrap();
+
+        let cache = PromptCache::on_disk(path.clone(), redactor);
+        let result = cache.get_or_prompt(&request("Hi", None), || async { unreachable!() }).await;
+        fs::remove_file(&path).unwrap();
+        assert_eq!(result.unwrap().0, "Hello");
+    }
+}
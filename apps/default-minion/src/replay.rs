@@ -0,0 +1,122 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+
+use crate::actions::secrets::Redactor;
+use crate::llm::Prompt;
+
+/// A prompt of a recorded LLM trace with its completion
+#[derive(Deserialize)]
+struct RecordedPrompt {
+    model: String,
+    prompt: serde_json::Value,
+    completion: String,
+}
+
+/// A difference between a replayed prompt and the recorded one
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Divergence {
+    /// The number of the prompt, starting at 0
+    pub call: usize,
+    pub description: String,
+}
+
+/// A recorded LLM trace, whose completions are returned instead of prompting the models
+///
+/// This drives the container with the actions of a previous run, e.g. for regression testing.
+/// Where the prompts differ from the recording, e.g. because a command has a different output,
+/// a divergence is noted.
+pub struct Replay {
+    recorded: Vec<RecordedPrompt>,
+    next: usize,
+    divergences: Vec<Divergence>,
+    /// The recording is redacted, so the replayed prompts are as well before comparing them
+    redactor: Arc<Redactor>,
+}
+
+impl Replay {
+    /// Load an LLM trace, i.e. a `llm-trace.jsonl` file of the output directory
+    pub fn load<P: AsRef<Path>>(path: P, redactor: Arc<Redactor>) -> io::Result<Self> {
+        let recorded = fs::read_to_string(path)?
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(serde_json::from_str)
+            .collect::<Result<_, _>>()?;
+        Ok(Self { recorded, next: 0, divergences: Vec::new(), redactor })
+    }
+
+    /// The recorded completion of the next prompt, or `None` if the recording is exhausted
+    pub fn next_completion(&mut self, model: &str, prompt: &Prompt) -> Option<String> {
+        let call = self.next;
+        let recorded = self.recorded.get(call)?;
+        self.next += 1;
+
+        let prompt = serde_json::to_string(prompt).expect("Failed to serialize prompt");
+        let prompt = serde_json::from_str(&self.redactor.redact_silently(&prompt))
+            .expect("Failed to deserialize redacted prompt");
+        let mut descriptions = Vec::new();
+        if recorded.model != model {
+            descriptions.push(format!("model `{}` instead of `{}`", model, recorded.model));
+        }
+        descriptions.extend(compare_prompts(&recorded.prompt, &prompt));
+        if !descriptions.is_empty() {
+            let description = descriptions.join(", ");
+            log::warn!("Replayed prompt {} diverges from the recording: {}", call, description);
+            self.divergences.push(Divergence { call, description });
+        }
+        Some(recorded.completion.clone())
+    }
+
+    /// The divergences so far, plus the recorded prompts that have not been replayed
+    pub fn divergences(&self) -> Vec<Divergence> {
+        let mut divergences = self.divergences.clone();
+        if self.next < self.recorded.len() {
+            divergences.push(Divergence {
+                call: self.next,
+                description: format!(
+                    "{} recorded prompts were not replayed",
+                    self.recorded.len() - self.next
+                ),
+            });
+        }
+        divergences
+    }
+}
+
+/// Describe where the items of two serialized prompts first differ
+fn compare_prompts(recorded: &serde_json::Value, replayed: &serde_json::Value) -> Option<String> {
+    let empty = Vec::new();
+    let recorded = recorded["items"].as_array().unwrap_or(&empty);
+    let replayed = replayed["items"].as_array().unwrap_or(&empty);
+    if let Some(i) = recorded.iter().zip(replayed).position(|(a, b)| a != b) {
+        return Some(format!("prompt item {} differs", i));
+    }
+    (recorded.len() != replayed.len())
+        .then(|| format!("{} prompt items instead of {}", replayed.len(), recorded.len()))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_compare_prompts() {
+        let a = json!({"role": "system", "text": "a"});
+        let b = json!({"role": "system", "text": "b"});
+        let recorded = json!({"items": [a, b]});
+        assert_eq!(compare_prompts(&recorded, &json!({"items": [a, b]})), None);
+        assert_eq!(
+            compare_prompts(&recorded, &json!({"items": [a, a]})),
+            Some("prompt item 1 differs".to_owned())
+        );
+        assert_eq!(
+            compare_prompts(&recorded, &json!({"items": [a]})),
+            Some("1 prompt items instead of 2".to_owned())
+        );
+    }
+}
@@ -0,0 +1,46 @@
+use std::fs;
+use std::path::Path;
+
+/// Common locations of the system CA bundle
+const SYSTEM_CA_BUNDLES: &[&str] = &[
+    "/etc/ssl/certs/ca-certificates.crt",
+    "/etc/pki/tls/certs/ca-bundle.crt",
+    "/etc/ssl/cert.pem",
+];
+
+/// Load all certificates from a PEM file
+pub fn load_certificates<P: AsRef<Path>>(path: P) -> Vec<reqwest::Certificate> {
+    let pem = fs::read(path.as_ref()).expect("Failed to read CA certificate");
+    reqwest::Certificate::from_pem_bundle(&pem).expect("Failed to parse CA certificate")
+}
+
+/// Build an HTTP client that trusts the given CA certificates in addition to the default ones
+pub fn http_client(ca_certificates: Vec<reqwest::Certificate>) -> reqwest::Client {
+    ca_certificates
+        .into_iter()
+        .fold(reqwest::Client::builder(), |builder, cert| builder.add_root_certificate(cert))
+        .build()
+        .expect("Failed to build HTTP client")
+}
+
+/// Make clients that cannot be configured directly trust the given CA certificate
+///
+/// The agent API client and git use OpenSSL with the default trust store.
+/// Therefore, `SSL_CERT_FILE` is pointed to a bundle consisting of the system certificates and
+/// the given certificate.
+/// This must be called before any of these clients are created.
+pub fn trust_globally<P: AsRef<Path>>(path: P) {
+    let mut bundle = match SYSTEM_CA_BUNDLES.iter().find(|path| Path::new(path).exists()) {
+        Some(system_bundle) => fs::read(system_bundle).expect("Failed to read system CA bundle"),
+        None => {
+            log::warn!("No system CA bundle found, only the custom CA certificate is trusted");
+            Vec::new()
+        }
+    };
+    bundle.push(b'\n');
+    bundle.extend(fs::read(path.as_ref()).expect("Failed to read CA certificate"));
+
+    let bundle_path = std::env::temp_dir().join("minion-ca-bundle.pem");
+    fs::write(&bundle_path, bundle).expect("Failed to write CA bundle");
+    std::env::set_var("SSL_CERT_FILE", &bundle_path);
+}
@@ -1,7 +1,9 @@
 //! https://containers.dev/implementors/json_reference/
 
+use std::collections::{BTreeMap, HashMap};
 use std::fs;
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
 
 use serde::{Deserialize, Serialize};
 
@@ -10,6 +12,136 @@ use serde::{Deserialize, Serialize};
 #[serde(rename_all = "camelCase")]
 pub struct DevContainer {
     pub image: Option<String>,
+    /// How to build the image, if no `image` is specified
+    pub build: Option<BuildConfig>,
+    /// Whether to override the default command of the image with one that keeps the container
+    /// running (defaults to `true`)
+    pub override_command: Option<bool>,
+    /// The user the container runs as (defaults to the user of the image)
+    pub container_user: Option<String>,
+    /// The user that commands are run as (defaults to `containerUser`)
+    pub remote_user: Option<String>,
+    /// Environment variables of the container
+    pub container_env: Option<HashMap<String, String>>,
+    /// Environment variables of the commands that are run in the container, in addition to
+    /// `containerEnv`
+    pub remote_env: Option<HashMap<String, String>>,
+    /// Run when the container is created, before `postCreateCommand`
+    pub on_create_command: Option<LifecycleCommand>,
+    /// Run after `onCreateCommand`, e.g. to install dependencies
+    pub post_create_command: Option<LifecycleCommand>,
+    /// Run every time the container is started
+    pub post_start_command: Option<LifecycleCommand>,
+    /// The path of the workspace in the container (defaults to `/workspaces/<folder name>`)
+    pub workspace_folder: Option<String>,
+    /// How the workspace is mounted, in the format of `docker run --mount`, e.g.
+    /// `source=${localWorkspaceFolder},target=/app,type=bind`
+    pub workspace_mount: Option<String>,
+    /// Additional mounts of the container
+    pub mounts: Option<Vec<Mount>>,
+    /// Additional arguments of `docker run`, e.g. `--cap-add=SYS_PTRACE`
+    pub run_args: Option<Vec<String>>,
+}
+
+impl DevContainer {
+    /// The path the workspace is mounted to, the target of `workspaceMount` or `workspaceFolder`
+    pub fn workspace_mount_target(&self) -> Option<&str> {
+        let target = self.workspace_mount.as_deref().and_then(|mount| {
+            mount.split(',').find_map(|option| {
+                let (key, value) = option.split_once('=')?;
+                matches!(key.trim(), "target" | "destination" | "dst").then_some(value.trim())
+            })
+        });
+        target.or(self.workspace_folder.as_deref())
+    }
+}
+
+/// A lifecycle command such as `postCreateCommand`
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum LifecycleCommand {
+    /// A command that is run in a shell
+    Shell(String),
+    /// A program and its arguments, which are run without a shell
+    Exec(Vec<String>),
+    /// Named commands, which are meant to run in parallel
+    Parallel(BTreeMap<String, LifecycleCommand>),
+}
+
+/// An entry of `mounts`, either in the format of `docker run --mount` or as an object
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "MountSpec", rename_all = "camelCase")]
+pub struct Mount {
+    /// `bind`, `volume` or `tmpfs`
+    #[serde(rename = "type")]
+    pub mount_type: String,
+    /// The path on the host or the name of the volume
+    pub source: Option<String>,
+    pub target: String,
+    pub read_only: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum MountSpec {
+    String(String),
+    Object {
+        #[serde(rename = "type")]
+        mount_type: String,
+        source: Option<String>,
+        target: String,
+    },
+}
+
+impl TryFrom<MountSpec> for Mount {
+    type Error = String;
+
+    fn try_from(spec: MountSpec) -> Result<Self, Self::Error> {
+        match spec {
+            MountSpec::String(mount) => mount.parse(),
+            MountSpec::Object { mount_type, source, target } => {
+                Ok(Self { mount_type, source, target, read_only: false })
+            }
+        }
+    }
+}
+
+impl FromStr for Mount {
+    type Err = String;
+
+    /// Parse a mount in the format of `docker run --mount`, e.g.
+    /// `source=cache,target=/cache,type=volume`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // Like Docker, mounts are volumes unless specified otherwise
+        let mut mount_type = "volume".to_owned();
+        let (mut source, mut target, mut read_only) = (None, None, false);
+        for option in s.split(',').map(str::trim).filter(|option| !option.is_empty()) {
+            let (key, value) = option.split_once('=').unwrap_or((option, "true"));
+            match key {
+                "type" => mount_type = value.to_owned(),
+                "source" | "src" => source = Some(value.to_owned()),
+                "target" | "destination" | "dst" => target = Some(value.to_owned()),
+                "readonly" | "ro" => read_only = matches!(value, "true" | "1"),
+                // e.g. `consistency`, which only matters on macOS
+                _ => {}
+            }
+        }
+        let target = target.ok_or_else(|| format!("The mount `{}` has no target", s))?;
+        Ok(Self { mount_type, source, target, read_only })
+    }
+}
+
+/// The `build` property of a devcontainer.json file
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BuildConfig {
+    /// The Dockerfile, relative to the devcontainer.json file
+    pub dockerfile: String,
+    /// The build context, relative to the devcontainer.json file (defaults to its directory)
+    pub context: Option<String>,
+    /// Build arguments
+    #[serde(default)]
+    pub args: HashMap<String, String>,
 }
 
 /// Find a devcontainer.json file in the specified directory
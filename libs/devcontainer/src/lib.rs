@@ -1,20 +1,167 @@
 use std::fs;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+
+use serde::Serialize;
 
 mod json;
+mod variables;
 
 use json::*;
+pub use json::{LifecycleCommand, Mount};
+pub use variables::{resolve_env, Variables};
 
 #[derive(Debug)]
 pub struct ImageMetadata {
     pub devcontainer: DevContainer,
 }
 
+/// A devcontainer configuration, as it would be used for a workspace
+#[derive(Debug, Serialize)]
+pub struct Resolved {
+    /// The devcontainer.json file the configuration was loaded from
+    pub path: PathBuf,
+    /// The configuration, with variables substituted
+    pub devcontainer: DevContainer,
+}
+
 pub fn load<P: AsRef<Path>>(directory: P) -> Result<DevContainer, Box<dyn std::error::Error>> {
+    resolve(directory).map(|resolved| resolved.devcontainer)
+}
+
+impl Resolved {
+    /// The Dockerfile and the build context, for configurations that build their image
+    ///
+    /// Both are relative to the directory of the devcontainer.json file.
+    pub fn build_paths(&self) -> Option<(PathBuf, PathBuf)> {
+        let build = self.devcontainer.build.as_ref()?;
+        let base = self.path.parent().unwrap_or(Path::new(""));
+        let context = base.join(build.context.as_deref().unwrap_or("."));
+        Some((base.join(&build.dockerfile), context))
+    }
+}
+
+/// Find, parse and substitute the variables of the devcontainer configuration of a workspace
+///
+/// This does not access Docker, so it can be used to check a configuration before a run.
+pub fn resolve<P: AsRef<Path>>(directory: P) -> Result<Resolved, Box<dyn std::error::Error>> {
+    let directory = directory.as_ref();
     let devcontainer_json_path = find_devcontainer_json(directory)
         .ok_or("No devcontainer.json found in the specified directory")?;
 
-    let devcontainer_json = fs::File::open(devcontainer_json_path)?;
-    serde_json::from_reader(&devcontainer_json)
-        .map_err(|e| format!("Failed to parse devcontainer.json: {}", e).into())
+    let devcontainer_json = fs::File::open(&devcontainer_json_path)?;
+    let mut value: serde_json::Value = serde_json::from_reader(&devcontainer_json)
+        .map_err(|e| format!("Failed to parse devcontainer.json: {}", e))?;
+    let mut variables = Variables::for_workspace(directory);
+    // `${containerWorkspaceFolder}` refers to `workspaceFolder` if it is set
+    if let Some(workspace_folder) = value.get("workspaceFolder").and_then(|v| v.as_str()) {
+        variables.container_workspace_folder = variables.substitute(workspace_folder);
+    }
+    variables.substitute_all(&mut value);
+    let devcontainer = serde_json::from_value(value)
+        .map_err(|e| format!("Failed to parse devcontainer.json: {}", e))?;
+
+    Ok(Resolved { path: devcontainer_json_path, devcontainer })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lifecycle_commands() {
+        let devcontainer: DevContainer = serde_json::from_str(
+            r#"{
+                "image": "rust",
+                "onCreateCommand": "cargo fetch",
+                "postCreateCommand": ["npm", "install"],
+                "postStartCommand": {"db": "service postgresql start"}
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(
+            devcontainer.on_create_command,
+            Some(LifecycleCommand::Shell("cargo fetch".to_owned()))
+        );
+        assert_eq!(
+            devcontainer.post_create_command,
+            Some(LifecycleCommand::Exec(vec!["npm".to_owned(), "install".to_owned()]))
+        );
+        assert!(matches!(devcontainer.post_start_command, Some(LifecycleCommand::Parallel(_))));
+    }
+
+    #[test]
+    fn test_workspace_mount_target() {
+        let devcontainer: DevContainer = serde_json::from_str(
+            r#"{
+                "image": "rust",
+                "workspaceFolder": "/app",
+                "workspaceMount": "source=/home/user/project,target=/src,type=bind"
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(devcontainer.workspace_mount_target(), Some("/src"));
+        let devcontainer: DevContainer =
+            serde_json::from_str(r#"{"image": "rust", "workspaceFolder": "/app"}"#).unwrap();
+        assert_eq!(devcontainer.workspace_mount_target(), Some("/app"));
+        let devcontainer: DevContainer = serde_json::from_str(r#"{"image": "rust"}"#).unwrap();
+        assert_eq!(devcontainer.workspace_mount_target(), None);
+    }
+
+    #[test]
+    fn test_resolve_container_workspace_folder() {
+        let dir = std::env::temp_dir().join(format!("minion-devcontainer-{}", std::process::id()));
+        fs::create_dir_all(dir.join(".devcontainer")).unwrap();
+        let json = r#"{"image": "rust", "workspaceFolder": "/app", "remoteEnv": {"SRC": "${containerWorkspaceFolder}/src"}}"#;
+        fs::write(dir.join(".devcontainer/devcontainer.json"), json).unwrap();
+        let resolved = resolve(&dir);
+        fs::remove_dir_all(&dir).unwrap();
+        let remote_env = resolved.unwrap().devcontainer.remote_env.unwrap();
+        assert_eq!(remote_env["SRC"], "/app/src");
+    }
+
+    #[test]
+    fn test_mounts() {
+        let devcontainer: DevContainer = serde_json::from_str(
+            r#"{
+                "image": "rust",
+                "mounts": [
+                    "source=cargo-cache,target=/usr/local/cargo/registry,type=volume",
+                    "type=bind,src=/home/user/.ssh,dst=/root/.ssh,readonly",
+                    {"type": "tmpfs", "target": "/tmp/scratch"}
+                ],
+                "runArgs": ["--cap-add=SYS_PTRACE"]
+            }"#,
+        )
+        .unwrap();
+        let mounts = devcontainer.mounts.unwrap();
+        assert_eq!(
+            mounts[0],
+            Mount {
+                mount_type: "volume".to_owned(),
+                source: Some("cargo-cache".to_owned()),
+                target: "/usr/local/cargo/registry".to_owned(),
+                read_only: false,
+            }
+        );
+        assert_eq!(mounts[1].mount_type, "bind");
+        assert_eq!(mounts[1].source.as_deref(), Some("/home/user/.ssh"));
+        assert!(mounts[1].read_only);
+        assert_eq!(mounts[2].target, "/tmp/scratch");
+        assert_eq!(devcontainer.run_args.unwrap(), ["--cap-add=SYS_PTRACE"]);
+        assert!("source=cache,type=volume".parse::<Mount>().is_err());
+    }
+
+    #[test]
+    fn test_build_paths() {
+        let devcontainer: DevContainer = serde_json::from_str(
+            r#"{"build": {"dockerfile": "Dockerfile", "context": "..", "args": {"RUST": "1.80"}}}"#,
+        )
+        .unwrap();
+        assert_eq!(devcontainer.build.as_ref().unwrap().args["RUST"], "1.80");
+        let resolved =
+            Resolved { path: PathBuf::from("/repo/.devcontainer/devcontainer.json"), devcontainer };
+        let (dockerfile, context) = resolved.build_paths().unwrap();
+        assert_eq!(dockerfile, Path::new("/repo/.devcontainer/Dockerfile"));
+        assert_eq!(context, Path::new("/repo/.devcontainer/.."));
+    }
 }
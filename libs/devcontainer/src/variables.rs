@@ -0,0 +1,159 @@
+//! https://containers.dev/implementors/json_reference/#variables-in-devcontainerjson
+
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+
+/// The values of the variables that can be used in devcontainer.json
+pub struct Variables {
+    pub local_workspace_folder: String,
+    pub container_workspace_folder: String,
+}
+
+impl Variables {
+    /// The variables of a workspace, which is mounted to `/workspaces/<folder name>`
+    pub fn for_workspace<P: AsRef<Path>>(directory: P) -> Self {
+        let directory = directory.as_ref();
+        let directory = directory.canonicalize().unwrap_or_else(|_| directory.to_owned());
+        let basename = directory.file_name().unwrap_or_default().to_string_lossy();
+        Self {
+            container_workspace_folder: format!("/workspaces/{}", basename),
+            local_workspace_folder: directory.to_string_lossy().into_owned(),
+        }
+    }
+
+    /// Substitute the variables in all strings of a JSON value
+    pub fn substitute_all(&self, value: &mut serde_json::Value) {
+        match value {
+            serde_json::Value::String(string) => *string = self.substitute(string),
+            serde_json::Value::Array(values) => {
+                values.iter_mut().for_each(|value| self.substitute_all(value))
+            }
+            serde_json::Value::Object(map) => {
+                map.values_mut().for_each(|value| self.substitute_all(value))
+            }
+            _ => {}
+        }
+    }
+
+    /// Substitute the variables in a string
+    ///
+    /// Unknown variables are left as they are.
+    pub fn substitute(&self, string: &str) -> String {
+        substitute_with(string, |variable| self.value(variable))
+    }
+
+    fn value(&self, variable: &str) -> Option<String> {
+        let basename = |path: &str| path.rsplit('/').next().unwrap_or_default().to_owned();
+        match variable {
+            "localWorkspaceFolder" => Some(self.local_workspace_folder.clone()),
+            "localWorkspaceFolderBasename" => Some(basename(&self.local_workspace_folder)),
+            "containerWorkspaceFolder" => Some(self.container_workspace_folder.clone()),
+            "containerWorkspaceFolderBasename" => Some(basename(&self.container_workspace_folder)),
+            _ => {
+                // `${localEnv:NAME}` or `${localEnv:NAME:default}`
+                let (name, default) = match variable.strip_prefix("localEnv:")?.split_once(':') {
+                    Some((name, default)) => (name, default),
+                    None => (variable.strip_prefix("localEnv:")?, ""),
+                };
+                Some(std::env::var(name).unwrap_or_else(|_| default.to_owned()))
+            }
+        }
+    }
+}
+
+/// Resolve environment variables such as those of `containerEnv` or `remoteEnv`
+///
+/// `${containerEnv:NAME}` and `${containerEnv:NAME:default}` refer to the environment that is
+/// already set, e.g. the environment of the image for `containerEnv`, so that `PATH` can be
+/// extended with `${containerEnv:PATH}:/opt/bin`. Unset variables without a default are empty.
+pub fn resolve_env(
+    variables: &HashMap<String, String>,
+    env: &HashMap<String, String>,
+) -> BTreeMap<String, String> {
+    variables
+        .iter()
+        .map(|(name, value)| {
+            let value = substitute_with(value, |variable| {
+                let variable = variable.strip_prefix("containerEnv:")?;
+                let (name, default) = variable.split_once(':').unwrap_or((variable, ""));
+                Some(env.get(name).cloned().unwrap_or_else(|| default.to_owned()))
+            });
+            (name.clone(), value)
+        })
+        .collect()
+}
+
+/// Substitute the `${...}` variables in a string, leaving those without a value as they are
+fn substitute_with(string: &str, value: impl Fn(&str) -> Option<String>) -> String {
+    let mut result = String::new();
+    let mut rest = string;
+    while let Some(start) = rest.find("${") {
+        let Some(length) = rest[start..].find('}') else {
+            break;
+        };
+        result.push_str(&rest[..start]);
+        let variable = &rest[start + 2..start + length];
+        match value(variable) {
+            Some(value) => result.push_str(&value),
+            None => result.push_str(&rest[start..=start + length]),
+        }
+        rest = &rest[start + length + 1..];
+    }
+    result.push_str(rest);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn variables() -> Variables {
+        Variables {
+            local_workspace_folder: "/home/user/project".to_owned(),
+            container_workspace_folder: "/workspaces/project".to_owned(),
+        }
+    }
+
+    #[test]
+    fn test_substitute_workspace_folders() {
+        let variables = variables();
+        assert_eq!(
+            variables.substitute("${localWorkspaceFolder}:${containerWorkspaceFolder}"),
+            "/home/user/project:/workspaces/project"
+        );
+        assert_eq!(variables.substitute("app-${localWorkspaceFolderBasename}"), "app-project");
+    }
+
+    #[test]
+    fn test_substitute_local_env() {
+        let variables = variables();
+        assert_eq!(
+            variables.substitute("${localEnv:MINION_TEST_UNSET_VARIABLE:fallback}"),
+            "fallback"
+        );
+        assert_eq!(variables.substitute("${localEnv:MINION_TEST_UNSET_VARIABLE}"), "");
+    }
+
+    #[test]
+    fn test_resolve_env() {
+        let env = HashMap::from([("PATH".to_owned(), "/usr/bin".to_owned())]);
+        let variables = HashMap::from([
+            ("PATH".to_owned(), "${containerEnv:PATH}:/opt/bin".to_owned()),
+            ("MODE".to_owned(), "${containerEnv:MODE:dev}".to_owned()),
+            ("HOME_DIR".to_owned(), "${containerEnv:HOME}".to_owned()),
+        ]);
+        let resolved = resolve_env(&variables, &env);
+        assert_eq!(resolved["PATH"], "/usr/bin:/opt/bin");
+        assert_eq!(resolved["MODE"], "dev");
+        assert_eq!(resolved["HOME_DIR"], "");
+    }
+
+    #[test]
+    fn test_substitute_unknown_variables() {
+        let variables = variables();
+        assert_eq!(
+            variables.substitute("${unknown} and ${incomplete"),
+            "${unknown} and ${incomplete"
+        );
+    }
+}